@@ -0,0 +1,281 @@
+//! The `syncstream` command, for working with stores and repositories without writing code
+//! against the library. Trees are passed around as manifest files.
+//!
+//! Repositories are given as URLs, or as the name of a profile in the configuration file, whose
+//! store, compression and concurrency are then used unless given as options.
+use clap::{Parser, Subcommand};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+use syncstream::CompressionKind;
+use syncstream::config::{Config, RepoConfig, parse_compression};
+use syncstream::metrics::Metrics;
+use syncstream::repo::{Drift, Tree};
+use syncstream::store::Store;
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// The configuration file, instead of `~/.config/syncstream/config.toml`
+    #[arg(long, env = "SYNCSTREAM_CONFIG")]
+    config: Option<PathBuf>,
+    /// The local store objects are kept in [default: .syncstream]
+    #[arg(long, env = "SYNCSTREAM_STORE")]
+    store: Option<PathBuf>,
+    /// The compression of objects in repositories, like `zstd`, `xz` or `none` [default: zstd]
+    #[arg(long, value_parser = parse_compression)]
+    compression: Option<CompressionKind>,
+    /// Don't show progress
+    #[arg(long, short)]
+    quiet: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Stores a directory, writing its manifest
+    Create {
+        dir: PathBuf,
+        /// Where to write the manifest, instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Uploads a tree's streams to a repository
+    Push {
+        /// A URL, or the name of a profile
+        repo: String,
+        manifest: PathBuf,
+        /// Also points this reference at the tree, like `myapp/stable`
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Downloads the tree a reference points at, writing its manifest
+    Pull {
+        /// A URL, or the name of a profile
+        repo: String,
+        name: String,
+        /// Where to write the manifest, instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Deploys a tree from the store into an existing directory
+    Deploy {
+        manifest: PathBuf,
+        path: PathBuf,
+        /// Deploys next to `path`, then swaps it into place
+        #[arg(long)]
+        atomic: bool,
+    },
+    /// Deletes every object that none of the trees refer to
+    Gc { manifests: Vec<PathBuf> },
+    /// Checks that a deployment still matches its tree
+    Verify { manifest: PathBuf, path: PathBuf },
+}
+
+/// Which of the store's metrics a progress bar counts, as most bytes are reported more than once
+/// on their way into the store.
+#[derive(Clone, Copy)]
+enum Count {
+    Downloaded,
+    Written,
+    Hashed,
+}
+
+struct Progress {
+    bar: ProgressBar,
+    /// `None` for work the store doesn't report, which only shows a spinner
+    count: Option<Count>,
+}
+
+impl Progress {
+    fn new(quiet: bool, message: &'static str, count: Option<Count>) -> Arc<Self> {
+        let bar = if quiet {
+            ProgressBar::hidden()
+        } else {
+            ProgressBar::new_spinner()
+        };
+        let template = match count {
+            Some(_) => "{spinner} {msg} {bytes} ({bytes_per_sec}, {elapsed})",
+            None => "{spinner} {msg} ({elapsed})",
+        };
+        bar.set_style(ProgressStyle::with_template(template).expect("valid template"));
+        bar.set_message(message);
+        bar.enable_steady_tick(Duration::from_millis(100));
+        Arc::new(Self { bar, count })
+    }
+}
+
+impl Metrics for Progress {
+    fn downloaded(&self, bytes: u64) {
+        if matches!(self.count, Some(Count::Downloaded)) {
+            self.bar.inc(bytes);
+        }
+    }
+
+    fn written(&self, bytes: u64) {
+        if matches!(self.count, Some(Count::Written)) {
+            self.bar.inc(bytes);
+        }
+    }
+
+    fn hashed(&self, bytes: u64, _elapsed: Duration) {
+        if matches!(self.count, Some(Count::Hashed)) {
+            self.bar.inc(bytes);
+        }
+    }
+}
+
+async fn read_manifest(path: &Path) -> syncstream::Result<Tree> {
+    let file = tokio::fs::File::open(path).await?;
+    Tree::read_manifest(tokio::io::BufReader::new(file)).await
+}
+
+async fn write_manifest(tree: &Tree, output: Option<&Path>) -> syncstream::Result<()> {
+    let mut manifest = Vec::new();
+    tree.write_manifest(&mut manifest).await?;
+    match output {
+        Some(path) => std::fs::write(path, manifest)?,
+        None => std::io::stdout().write_all(&manifest)?,
+    }
+    Ok(())
+}
+
+fn describe(drift: &Drift) -> String {
+    match drift {
+        Drift::Missing { path } => format!("missing: {path}"),
+        Drift::WrongType { path } => format!("wrong type: {path}"),
+        Drift::Modified { path, .. } => format!("modified: {path}"),
+        Drift::Permissions {
+            path,
+            expected,
+            actual,
+        } => format!("permissions: {path} ({actual:o}, expected {expected:o})"),
+        Drift::SymlinkTarget {
+            path,
+            expected,
+            actual,
+        } => format!(
+            "symlink: {path} -> {} (expected {})",
+            actual.display(),
+            expected.display()
+        ),
+    }
+}
+
+/// A repository's URL, and its profile if it was given by name.
+fn resolve(config: &Config, repo: String) -> syncstream::Result<(String, Option<RepoConfig>)> {
+    if !config.repos.contains_key(&repo) {
+        return Ok((repo, None));
+    }
+    let profile = config.repo(&repo)?;
+    let url = profile.urls.first().cloned().ok_or_else(|| {
+        syncstream::Error::InvalidConfig(format!("no urls for the repository {repo}"))
+    })?;
+    Ok((url, Some(profile)))
+}
+
+async fn run(cli: Cli) -> syncstream::Result<ExitCode> {
+    let config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => Config::load_default()?,
+    };
+    let (repo, profile) = match cli.command {
+        Command::Push { ref repo, .. } | Command::Pull { ref repo, .. } => {
+            let (url, profile) = resolve(&config, repo.clone())?;
+            (url, profile.unwrap_or_default())
+        }
+        _ => (String::new(), RepoConfig::default()),
+    };
+
+    let create_options = profile.create_options();
+    let store_path = cli
+        .store
+        .or(profile.store)
+        .unwrap_or_else(|| ".syncstream".into());
+    std::fs::create_dir_all(&store_path)?;
+    let store = Store::new(store_path);
+    let compression = cli
+        .compression
+        .or(profile.compression)
+        .unwrap_or(CompressionKind::Zstd);
+
+    match cli.command {
+        Command::Create { dir, output } => {
+            let progress = Progress::new(cli.quiet, "Storing", Some(Count::Hashed));
+            let store = store.with_metrics(progress.clone());
+            let tree = Tree::create_with(&store, &dir, compression, &create_options).await?;
+            progress.bar.finish_and_clear();
+            write_manifest(&tree, output.as_deref()).await?;
+            eprintln!("{}", tree.hash());
+        }
+        Command::Push { manifest, name, .. } => {
+            let tree = read_manifest(&manifest).await?;
+            let progress = Progress::new(cli.quiet, "Pushing", None);
+            match name {
+                Some(name) => tree.publish(&repo, &name, &store, compression).await?,
+                None => tree.push(&repo, &store, compression).await?,
+            }
+            progress.bar.finish_and_clear();
+        }
+        Command::Pull { name, output, .. } => {
+            let progress = Progress::new(cli.quiet, "Downloading", Some(Count::Downloaded));
+            let store = store.with_metrics(progress.clone());
+            let tree = Tree::fetch_ref(&repo, &name, &store, compression).await?;
+            progress.bar.finish_and_clear();
+            write_manifest(&tree, output.as_deref()).await?;
+        }
+        Command::Deploy {
+            manifest,
+            path,
+            atomic,
+        } => {
+            let tree = read_manifest(&manifest).await?;
+            let progress = Progress::new(cli.quiet, "Deploying", Some(Count::Written));
+            let store = store.with_metrics(progress.clone());
+            if atomic {
+                tree.deploy_atomic(&store, &path)?;
+            } else {
+                tree.deploy(&store, &path)?;
+            }
+            progress.bar.finish_and_clear();
+        }
+        Command::Gc { manifests } => {
+            let mut roots = Vec::new();
+            for manifest in &manifests {
+                roots.push(read_manifest(manifest).await?);
+            }
+            let report = store.gc(&roots)?;
+            eprintln!(
+                "Removed {} objects, freeing {} bytes",
+                report.removed, report.bytes_freed
+            );
+        }
+        Command::Verify { manifest, path } => {
+            let tree = read_manifest(&manifest).await?;
+            let report = tree.verify_deployed(&path)?;
+            for drift in &report.drift {
+                println!("{}", describe(drift));
+            }
+            if !report.is_clean() {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run(Cli::parse()).await {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
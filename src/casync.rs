@@ -0,0 +1,496 @@
+//! Serving streams to casync and desync.
+//!
+//! [`Stream::to_casync`] cuts a stream's contents into content-defined chunks and writes them
+//! into a [`ChunkStore`], laid out like a casync `.castr` directory: every chunk compressed with
+//! zstd at `{first 4 hex digits of its id}/{id}.cacnk`, where the id is the SHA-512/256 hash of
+//! its uncompressed contents. The [`ChunkIndex`] it returns is written as a `.caibx` blob index,
+//! so `desync extract -s {chunk store} {hash}.caibx {file}` or `casync extract` rebuild the file
+//! from any static HTTP server or directory holding both.
+//!
+//! Chunk boundaries are found with a buzhash over the same window and sizes as casync, but not
+//! its exact hash table, so chunks only deduplicate against those made here.
+use sha2::{Digest, Sha512Trunc256};
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::store::Store;
+use crate::stream::Stream;
+use crate::tree::{Tree, all_streams};
+
+/// The extension of blob index files.
+pub const INDEX_EXTENSION: &str = "caibx";
+/// The extension of compressed chunks in a chunk store.
+pub const CHUNK_EXTENSION: &str = "cacnk";
+
+const INDEX_TYPE: u64 = 0x9682_4d9c_7b12_9ff9;
+const TABLE_TYPE: u64 = 0xe75b_9e11_2f17_417d;
+const TABLE_TAIL_MARKER: u64 = 0x4b4f_050e_5549_ecd1;
+/// The feature flag for SHA-512/256 chunk ids, rather than SHA-256.
+const SHA512_256: u64 = 0x2000_0000_0000_0000;
+const INDEX_HEADER_LEN: u64 = 48;
+const TABLE_HEADER_LEN: u64 = 16;
+const TABLE_ITEM_LEN: u64 = 40;
+
+/// How many bytes the rolling hash looks at.
+const WINDOW: u32 = 48;
+const READ_LEN: usize = 256 * 1024;
+
+/// The SHA-512/256 hash of a chunk's uncompressed contents.
+pub type ChunkId = [u8; 32];
+
+/// The sizes chunks are cut at, in bytes. Chunks are at least `min` and at most `max` bytes long,
+/// except for the last one, and `avg` long on average.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkSizes {
+    pub min: u64,
+    pub avg: u64,
+    pub max: u64,
+}
+
+/// casync's defaults: 16 KiB, 64 KiB and 256 KiB.
+impl Default for ChunkSizes {
+    fn default() -> Self {
+        Self {
+            min: 16 * 1024,
+            avg: 64 * 1024,
+            max: 256 * 1024,
+        }
+    }
+}
+
+/// The chunks a stream was cut into, in order, see the [module docs](self).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChunkIndex {
+    pub sizes: ChunkSizes,
+    pub chunks: Vec<IndexedChunk>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexedChunk {
+    pub id: ChunkId,
+    /// The offset in the stream where the chunk ends
+    pub end: u64,
+}
+
+impl ChunkIndex {
+    /// The size of the stream the index describes.
+    #[must_use]
+    pub fn blob_size(&self) -> u64 {
+        self.chunks.last().map_or(0, |chunk| chunk.end)
+    }
+
+    /// Reads a `.caibx` blob index.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidArchive`](crate::Error::InvalidArchive) for malformed indexes, and ones
+    ///   of archives (`.caidx`) or with SHA-256 chunk ids
+    /// - Errors reading from `reader`
+    pub fn read<R: Read>(mut reader: R) -> crate::Result<Self> {
+        let invalid =
+            |reason: &str| crate::Error::InvalidArchive(format!("casync index: {reason}"));
+
+        let [size, kind, flags, min, avg, max] = read_u64s(&mut reader)?;
+        if size != INDEX_HEADER_LEN || kind != INDEX_TYPE {
+            return Err(invalid("bad header"));
+        }
+        if flags & SHA512_256 == 0 {
+            return Err(invalid("only SHA-512/256 chunk ids are supported"));
+        }
+        let [size, kind] = read_u64s(&mut reader)?;
+        if size != u64::MAX || kind != TABLE_TYPE {
+            return Err(invalid("bad table header"));
+        }
+
+        let mut chunks = Vec::new();
+        loop {
+            let [end] = read_u64s(&mut reader)?;
+
+            // Chunks are never empty, so only the tail has a zero here
+            if end == 0 {
+                let [_, _, _, marker] = read_u64s(&mut reader)?;
+                if marker != TABLE_TAIL_MARKER {
+                    return Err(invalid("bad table tail"));
+                }
+                break;
+            }
+            let start = chunks.last().map_or(0, |chunk: &IndexedChunk| chunk.end);
+            if end <= start {
+                return Err(invalid("chunk offsets out of order"));
+            }
+            let mut id = [0; 32];
+            reader.read_exact(&mut id)?;
+            chunks.push(IndexedChunk { id, end });
+        }
+
+        Ok(Self {
+            sizes: ChunkSizes { min, avg, max },
+            chunks,
+        })
+    }
+
+    /// Writes the index in the `.caibx` format.
+    ///
+    /// # Errors
+    ///
+    /// - Errors writing to `writer`
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut buf = Vec::new();
+        for n in [
+            INDEX_HEADER_LEN,
+            INDEX_TYPE,
+            SHA512_256,
+            self.sizes.min,
+            self.sizes.avg,
+            self.sizes.max,
+            u64::MAX,
+            TABLE_TYPE,
+        ] {
+            buf.extend_from_slice(&n.to_le_bytes());
+        }
+        for chunk in &self.chunks {
+            buf.extend_from_slice(&chunk.end.to_le_bytes());
+            buf.extend_from_slice(&chunk.id);
+        }
+        let table_len = TABLE_HEADER_LEN + TABLE_ITEM_LEN * (self.chunks.len() as u64 + 1);
+        for n in [0, 0, INDEX_HEADER_LEN, table_len, TABLE_TAIL_MARKER] {
+            buf.extend_from_slice(&n.to_le_bytes());
+        }
+
+        writer.write_all(&buf)?;
+        writer.flush()
+    }
+
+    /// Rebuilds the stream from the chunks in `chunks`, checking each of them.
+    ///
+    /// # Errors
+    ///
+    /// - See [`ChunkStore::get`]
+    /// - Errors writing to `writer`
+    pub fn assemble<W: Write>(&self, chunks: &ChunkStore, mut writer: W) -> crate::Result<()> {
+        for chunk in &self.chunks {
+            writer.write_all(&chunks.get(&chunk.id)?)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// A directory of chunks, laid out like a casync `.castr` chunk store.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChunkStore {
+    root: PathBuf,
+}
+
+impl ChunkStore {
+    #[must_use]
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    #[must_use]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where the compressed chunk with `id` is kept.
+    #[must_use]
+    pub fn path_of(&self, id: &ChunkId) -> PathBuf {
+        self.dir_of(id)
+            .join(format!("{}.{CHUNK_EXTENSION}", to_hex(id)))
+    }
+
+    /// The directory a chunk is kept in, named after the start of its id.
+    fn dir_of(&self, id: &ChunkId) -> PathBuf {
+        self.root.join(&to_hex(id)[..4])
+    }
+
+    #[must_use]
+    pub fn contains(&self, id: &ChunkId) -> bool {
+        self.path_of(id).is_file()
+    }
+
+    /// Compresses and stores a chunk, returning its id. Chunks already in the store aren't
+    /// written again.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically out of space)
+    pub fn insert(&self, data: &[u8]) -> io::Result<ChunkId> {
+        let id = chunk_id(data);
+        let path = self.path_of(&id);
+        if path.is_file() {
+            return Ok(id);
+        }
+
+        let dir = self.dir_of(&id);
+        std::fs::create_dir_all(&dir)?;
+        let tmp = dir.join(format!(".{}.{}.tmp", to_hex(&id), std::process::id()));
+        std::fs::write(&tmp, zstd::encode_all(data, 0)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(id)
+    }
+
+    /// The uncompressed contents of the chunk with `id`.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically the chunk not being in the store)
+    /// - [`Error::HashError`](crate::Error::HashError) if the contents don't match `id`
+    pub fn get(&self, id: &ChunkId) -> crate::Result<Vec<u8>> {
+        let data = zstd::decode_all(std::fs::File::open(self.path_of(id))?)?;
+        let actual = chunk_id(&data);
+        if actual != *id {
+            return Err(crate::Error::HashError(to_hex(id), to_hex(&actual)));
+        }
+        Ok(data)
+    }
+}
+
+impl Stream {
+    /// Cuts the stream's contents into chunks of `sizes`, storing them in `chunks`, and returns
+    /// their index, see the [module docs](crate::casync).
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically the stream not being in the store)
+    /// - [`io::ErrorKind::InvalidInput`] unless `0 < min <= avg <= max`, with `min` above the
+    ///   hash window of 48 bytes
+    pub fn to_casync(
+        &self,
+        store: &Store,
+        chunks: &ChunkStore,
+        sizes: ChunkSizes,
+    ) -> crate::Result<ChunkIndex> {
+        let file = std::fs::File::open(store.path_of(&self.hash))?;
+        let mut index = ChunkIndex {
+            sizes,
+            chunks: Vec::new(),
+        };
+        let mut end = 0;
+        cut(file, sizes, |data| {
+            end += data.len() as u64;
+            index.chunks.push(IndexedChunk {
+                id: chunks.insert(data)?,
+                end,
+            });
+            Ok(())
+        })?;
+        Ok(index)
+    }
+}
+
+impl Tree {
+    /// Writes every stream in the tree to `chunks` with [`Stream::to_casync`], and its index to
+    /// `{index_dir}/{hash}.caibx`.
+    ///
+    /// # Errors
+    ///
+    /// - See [`Stream::to_casync`]
+    pub fn export_casync(
+        &self,
+        store: &Store,
+        chunks: &ChunkStore,
+        index_dir: &Path,
+        sizes: ChunkSizes,
+    ) -> crate::Result<()> {
+        std::fs::create_dir_all(index_dir)?;
+        for stream in all_streams(self) {
+            let path = index_dir.join(format!("{}.{INDEX_EXTENSION}", stream.hash));
+            if path.exists() {
+                continue;
+            }
+            let index = stream.to_casync(store, chunks, sizes)?;
+            let tmp = index_dir.join(format!(".{}.{}.tmp", stream.hash, std::process::id()));
+            index.write(io::BufWriter::new(std::fs::File::create(&tmp)?))?;
+            std::fs::rename(&tmp, path)?;
+        }
+        Ok(())
+    }
+}
+
+/// The id casync gives a chunk with these contents.
+#[must_use]
+pub fn chunk_id(data: &[u8]) -> ChunkId {
+    Sha512Trunc256::digest(data).into()
+}
+
+/// A chunk id the way chunk stores name it.
+#[must_use]
+pub fn to_hex(id: &ChunkId) -> String {
+    id.iter().fold(String::with_capacity(64), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+fn read_u64s<R: Read, const N: usize>(reader: &mut R) -> io::Result<[u64; N]> {
+    let mut values = [0; N];
+    for value in &mut values {
+        let mut bytes = [0; 8];
+        reader.read_exact(&mut bytes)?;
+        *value = u64::from_le_bytes(bytes);
+    }
+    Ok(values)
+}
+
+/// Splits everything `reader` returns into chunks, passing each to `each`.
+fn cut<R: Read, F: FnMut(&[u8]) -> crate::Result<()>>(
+    mut reader: R,
+    sizes: ChunkSizes,
+    mut each: F,
+) -> crate::Result<()> {
+    if sizes.min <= u64::from(WINDOW) || sizes.min > sizes.avg || sizes.avg > sizes.max {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid chunk sizes").into());
+    }
+    let discriminator = discriminator(sizes.avg);
+    let mut chunk = Vec::new();
+    let mut hash = 0u32;
+    let mut buf = vec![0; READ_LEN];
+
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for &byte in &buf[..n] {
+            chunk.push(byte);
+            hash = hash.rotate_left(1) ^ TABLE[usize::from(byte)];
+            if chunk.len() > WINDOW as usize {
+                let out = chunk[chunk.len() - 1 - WINDOW as usize];
+                // Rotated once for every byte since it came in
+                hash ^= TABLE[usize::from(out)].rotate_left(WINDOW);
+            }
+
+            let len = chunk.len() as u64;
+            if len >= sizes.max
+                || (len >= sizes.min && u64::from(hash) % discriminator == discriminator - 1)
+            {
+                each(&chunk)?;
+                chunk.clear();
+                hash = 0;
+            }
+        }
+    }
+
+    if !chunk.is_empty() {
+        each(&chunk)?;
+    }
+    Ok(())
+}
+
+/// The divisor casync uses to make chunks `avg` bytes long on average, once the minimum size
+/// is taken into account.
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_precision_loss,
+    clippy::cast_sign_loss
+)]
+fn discriminator(avg: u64) -> u64 {
+    let avg = avg as f64;
+    ((avg / (-1.428_888_52e-7 * avg + 1.332_375_15)) as u64).max(1)
+}
+
+/// Random values for each byte, fixed so that the same contents always give the same chunks.
+const TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut state = 0x5eed_cafe_f00d_u64;
+    let mut i = 0;
+    while i < 256 {
+        // splitmix64
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = (z >> 32) as u32;
+        i += 1;
+    }
+    table
+};
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::CompressionKind;
+    use crate::fs;
+
+    #[test]
+    fn test_chunk_id() {
+        // The SHA-512/256 test vector from FIPS 180-4
+        assert_eq!(
+            to_hex(&chunk_id(b"abc")),
+            "53048e2681941ef99b2e29b76b4c7dabe4c2d0c634fc6d46e0e2f13107e7af23"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_casync_export() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let original_dir = TempDir::new()?;
+        let casync_dir = TempDir::new()?;
+        let chunks = ChunkStore::new(casync_dir.path().join("default.castr"));
+        let index_dir = casync_dir.path().join("indexes");
+
+        // Something that doesn't compress or repeat, so it's cut at content-defined boundaries
+        let mut state = 1u64;
+        let contents: Vec<u8> = (0..600_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state.to_le_bytes()[0]
+            })
+            .collect();
+        fs::write(original_dir.path().join("big"), &contents).await?;
+        fs::write(original_dir.path().join("small"), b"small").await?;
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+        tree.export_casync(&store, &chunks, &index_dir, ChunkSizes::default())?;
+
+        let big = &tree.streams[0];
+        let file = std::fs::File::open(index_dir.join(format!("{}.caibx", big.hash)))?;
+        let index = ChunkIndex::read(io::BufReader::new(file))?;
+        assert_eq!(index.sizes, ChunkSizes::default());
+        assert_eq!(index.blob_size(), contents.len() as u64);
+        assert!(index.chunks.len() > 2);
+        let mut start = 0;
+        for (i, chunk) in index.chunks.iter().enumerate() {
+            let len = chunk.end - start;
+            assert!(len <= index.sizes.max);
+            assert!(len >= index.sizes.min || i == index.chunks.len() - 1);
+            assert!(chunks.path_of(&chunk.id).is_file());
+            start = chunk.end;
+        }
+
+        // The same contents give the same index, and are rebuilt from the chunks
+        assert_eq!(big.to_casync(&store, &chunks, index.sizes)?, index);
+        let mut rebuilt = Vec::new();
+        index.assemble(&chunks, &mut rebuilt)?;
+        assert_eq!(rebuilt, contents);
+
+        let mut written = Vec::new();
+        index.write(&mut written)?;
+        assert_eq!(written.len(), 48 + 16 + 40 * (index.chunks.len() + 1));
+        assert_eq!(ChunkIndex::read(&written[..])?, index);
+        written[8] ^= 1;
+        assert!(matches!(
+            ChunkIndex::read(&written[..]),
+            Err(crate::Error::InvalidArchive(_))
+        ));
+
+        // Corrupted chunks are noticed
+        let first = index.chunks[0].id;
+        std::fs::write(chunks.path_of(&first), zstd::encode_all(&b"bad"[..], 0)?)?;
+        assert!(matches!(
+            chunks.get(&first),
+            Err(crate::Error::HashError(..))
+        ));
+
+        Ok(())
+    }
+}
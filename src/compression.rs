@@ -1,26 +1,69 @@
 use crate::async_types::{AsyncBufRead, AsyncRead, AsyncWrite};
-use crate::async_types::{Lz4Decoder, Lz4Encoder, XzDecoder, XzEncoder, ZstdDecoder, ZstdEncoder};
+use crate::async_types::{BrotliDecoder, BrotliEncoder, BzDecoder, BzEncoder};
+use crate::async_types::{GzipDecoder, GzipEncoder, Lz4Decoder, Lz4Encoder};
+use crate::async_types::{XzDecoder, XzEncoder, ZstdDecoder, ZstdEncoder};
+use serde::{Deserialize, Serialize};
+use std::io;
 use std::pin::Pin;
+use std::task::{Context, Poll, ready};
 
-#[derive(Copy, Clone, Debug)]
+/// Bytes needed to recognise every format, see [`CompressionKind::sniff`].
+const MAGIC_LEN: usize = 6;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum CompressionKind {
     Zstd,
     Xz,
     Lz4,
+    /// For repositories shared with tools that only speak gzip
+    Gzip,
+    Brotli,
+    Bzip2,
     None,
 }
 
 impl CompressionKind {
+    /// Every kind, from the most to the least commonly used for repositories.
+    pub const ALL: [CompressionKind; 7] = [
+        CompressionKind::Zstd,
+        CompressionKind::Xz,
+        CompressionKind::Lz4,
+        CompressionKind::Gzip,
+        CompressionKind::Brotli,
+        CompressionKind::Bzip2,
+        CompressionKind::None,
+    ];
+
     #[must_use]
     pub fn try_get_extension(&self) -> Option<&'static str> {
         match self {
             CompressionKind::Zstd => Some("zstd"),
             CompressionKind::Lz4 => Some("lz4"),
             CompressionKind::Xz => Some("xz"),
+            CompressionKind::Gzip => Some("gz"),
+            CompressionKind::Brotli => Some("br"),
+            CompressionKind::Bzip2 => Some("bz2"),
             CompressionKind::None => None,
         }
     }
 
+    /// The kind stored with `extension`, the inverse of [`Self::try_get_extension`]. The common
+    /// `zst` and `gzip` spellings are accepted too.
+    #[must_use]
+    pub fn from_extension(extension: Option<&str>) -> Option<Self> {
+        match extension {
+            Some("zstd" | "zst") => Some(CompressionKind::Zstd),
+            Some("lz4") => Some(CompressionKind::Lz4),
+            Some("xz") => Some(CompressionKind::Xz),
+            Some("gz" | "gzip") => Some(CompressionKind::Gzip),
+            Some("br") => Some(CompressionKind::Brotli),
+            Some("bz2") => Some(CompressionKind::Bzip2),
+            None => Some(CompressionKind::None),
+            Some(_) => None,
+        }
+    }
+
     /// WARNING: This should only be used internally, and may be removed in a future release.
     #[must_use]
     pub fn get_extension_with_dot(&self) -> String {
@@ -38,10 +81,72 @@ impl CompressionKind {
             CompressionKind::Zstd => Box::pin(ZstdEncoder::new(sink)),
             CompressionKind::Xz => Box::pin(XzEncoder::new(sink)),
             CompressionKind::Lz4 => Box::pin(Lz4Encoder::new(sink)),
+            CompressionKind::Gzip => Box::pin(GzipEncoder::new(sink)),
+            CompressionKind::Brotli => Box::pin(BrotliEncoder::new(sink)),
+            CompressionKind::Bzip2 => Box::pin(BzEncoder::new(sink)),
             CompressionKind::None => Box::pin(sink),
         }
     }
 
+    /// The compression whose magic bytes `head` starts with. Brotli has none, and uncompressed
+    /// data could start with anything, so neither is ever recognised.
+    #[must_use]
+    pub fn sniff(head: &[u8]) -> Option<Self> {
+        const MAGIC: [(&[u8], CompressionKind); 5] = [
+            (&[0x28, 0xb5, 0x2f, 0xfd], CompressionKind::Zstd),
+            (&[0xfd, b'7', b'z', b'X', b'Z', 0x00], CompressionKind::Xz),
+            (&[0x04, 0x22, 0x4d, 0x18], CompressionKind::Lz4),
+            (&[0x1f, 0x8b], CompressionKind::Gzip),
+            (b"BZh", CompressionKind::Bzip2),
+        ];
+
+        MAGIC
+            .iter()
+            .find(|(magic, _)| head.starts_with(magic))
+            .map(|(_, kind)| *kind)
+    }
+
+    /// The compression an object expected to be compressed with `self` actually uses, judging
+    /// by its first bytes. Servers sometimes return another format than the one asked for, or
+    /// decompress objects on the fly.
+    ///
+    /// Data expected to be uncompressed is taken as is, as it may look compressed by chance.
+    #[must_use]
+    pub fn detect(self, head: &[u8]) -> Self {
+        match (self, Self::sniff(head)) {
+            (CompressionKind::None, _) => CompressionKind::None,
+            (_, Some(kind)) => kind,
+            (CompressionKind::Brotli, None) => CompressionKind::Brotli,
+            (_, None) => CompressionKind::None,
+        }
+    }
+
+    /// Like [`Self::decompress`], with the compression [detected](Self::detect) from the data.
+    pub(crate) async fn decompress_detected<'a, R: AsyncBufRead + Send + Unpin + 'a>(
+        self,
+        mut source: R,
+    ) -> io::Result<Pin<Box<dyn AsyncRead + Send + 'a>>> {
+        use crate::async_types::AsyncBufReadExt;
+
+        let mut head = Vec::with_capacity(MAGIC_LEN);
+        while head.len() < MAGIC_LEN {
+            let data = source.fill_buf().await?;
+            if data.is_empty() {
+                break;
+            }
+            let len = data.len().min(MAGIC_LEN - head.len());
+            head.extend_from_slice(&data[..len]);
+            Pin::new(&mut source).consume(len);
+        }
+
+        let kind = self.detect(&head);
+        Ok(kind.decompress(Prefixed {
+            head,
+            pos: 0,
+            inner: source,
+        }))
+    }
+
     pub fn decompress<'a, W: AsyncBufRead + Send + 'a>(
         &self,
         source: W,
@@ -50,11 +155,117 @@ impl CompressionKind {
             CompressionKind::Zstd => Box::pin(ZstdDecoder::new(source)),
             CompressionKind::Xz => Box::pin(XzDecoder::new(source)),
             CompressionKind::Lz4 => Box::pin(Lz4Decoder::new(source)),
+            CompressionKind::Gzip => Box::pin(GzipDecoder::new(source)),
+            CompressionKind::Brotli => Box::pin(BrotliDecoder::new(source)),
+            CompressionKind::Bzip2 => Box::pin(BzDecoder::new(source)),
             CompressionKind::None => Box::pin(source),
         }
     }
 }
 
+/// A reader with bytes already read from it put back in front.
+struct Prefixed<R> {
+    head: Vec<u8>,
+    pos: usize,
+    inner: R,
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncBufRead for Prefixed<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if this.pos < this.head.len() {
+            Poll::Ready(Ok(&this.head[this.pos..]))
+        } else {
+            Pin::new(&mut this.inner).poll_fill_buf(cx)
+        }
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        if self.pos < self.head.len() {
+            self.pos += amt;
+        } else {
+            Pin::new(&mut self.inner).consume(amt);
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<R: AsyncBufRead + Unpin> AsyncRead for Prefixed<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let data = ready!(self.as_mut().poll_fill_buf(cx))?;
+        let len = data.len().min(buf.remaining());
+        buf.put_slice(&data[..len]);
+        self.consume(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(not(feature = "tokio"))]
+impl<R: AsyncBufRead + Unpin> AsyncRead for Prefixed<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let data = ready!(self.as_mut().poll_fill_buf(cx))?;
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        self.consume(len);
+        Poll::Ready(Ok(len))
+    }
+}
+
+/// How a repository names compressed objects: `{hash}.{extension}`, or just `{hash}` where the
+/// repository implies the compression.
+///
+/// Defaults to the extensions from [`CompressionKind::try_get_extension`], which is also how the
+/// local store names them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ObjectNaming {
+    overrides: Vec<(CompressionKind, Option<String>)>,
+}
+
+impl ObjectNaming {
+    /// Names Zstd objects `{hash}.zst`, as most other tools do.
+    #[must_use]
+    pub fn zst() -> Self {
+        Self::default().extension(CompressionKind::Zstd, Some("zst"))
+    }
+
+    /// Names objects compressed with `kind` as `{hash}.{extension}`, or `{hash}` for `None`.
+    #[must_use]
+    pub fn extension(mut self, kind: CompressionKind, extension: Option<&str>) -> Self {
+        self.overrides.retain(|(overridden, _)| *overridden != kind);
+        self.overrides.push((kind, extension.map(str::to_string)));
+        self
+    }
+
+    #[must_use]
+    pub fn extension_of(&self, kind: CompressionKind) -> Option<&str> {
+        match self
+            .overrides
+            .iter()
+            .find(|(overridden, _)| *overridden == kind)
+        {
+            Some((_, extension)) => extension.as_deref(),
+            None => kind.try_get_extension(),
+        }
+    }
+
+    /// The name of a stream's object in the repository, relative to its `streams` directory.
+    #[must_use]
+    pub fn object_name(&self, hash: &str, kind: CompressionKind) -> String {
+        match self.extension_of(kind) {
+            Some(extension) => format!("{hash}.{extension}"),
+            None => hash.to_string(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -67,6 +278,9 @@ mod tests {
             CompressionKind::Zstd,
             CompressionKind::Xz,
             CompressionKind::Lz4,
+            CompressionKind::Gzip,
+            CompressionKind::Brotli,
+            CompressionKind::Bzip2,
             CompressionKind::None,
         ] {
             // Test random data
@@ -106,6 +320,9 @@ mod tests {
             CompressionKind::Zstd,
             CompressionKind::Xz,
             CompressionKind::Lz4,
+            CompressionKind::Gzip,
+            CompressionKind::Brotli,
+            CompressionKind::Bzip2,
         ] {
             // Test random data
             for input in [
@@ -125,19 +342,102 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_compression_detection() -> Result<(), std::io::Error> {
+        assert_eq!(CompressionKind::sniff(b"plain text"), None);
+        assert_eq!(CompressionKind::sniff(b""), None);
+        assert_eq!(
+            CompressionKind::Zstd.detect(b"plain text"),
+            CompressionKind::None
+        );
+        assert_eq!(
+            CompressionKind::Brotli.detect(b"\x0b\x02"),
+            CompressionKind::Brotli
+        );
+        assert_eq!(
+            CompressionKind::None.detect(&[0x1f, 0x8b]),
+            CompressionKind::None
+        );
+
+        for kind in [
+            CompressionKind::Zstd,
+            CompressionKind::Xz,
+            CompressionKind::Lz4,
+            CompressionKind::Gzip,
+            CompressionKind::Bzip2,
+        ] {
+            let mut compressed = Vec::new();
+            let mut compressor = kind.compress(&mut compressed);
+            compressor.write_all(b"This is some test data.").await?;
+            #[cfg(feature = "tokio")]
+            compressor.shutdown().await?;
+            #[cfg(not(feature = "tokio"))]
+            compressor.close().await?;
+            drop(compressor);
+            assert_eq!(CompressionKind::sniff(&compressed), Some(kind));
+
+            // Asked for the wrong kind, served this one, one byte at a time
+            let mut decompressed = Vec::new();
+            CompressionKind::Brotli
+                .decompress_detected(BufReader::with_capacity(1, &compressed[..]))
+                .await?
+                .read_to_end(&mut decompressed)
+                .await?;
+            assert_eq!(decompressed, b"This is some test data.");
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn test_compression_filenames_with_dot() {
         assert_eq!(CompressionKind::Zstd.get_extension_with_dot(), ".zstd");
         assert_eq!(CompressionKind::Lz4.get_extension_with_dot(), ".lz4");
         assert_eq!(CompressionKind::Xz.get_extension_with_dot(), ".xz");
+        assert_eq!(CompressionKind::Gzip.get_extension_with_dot(), ".gz");
+        assert_eq!(CompressionKind::Brotli.get_extension_with_dot(), ".br");
+        assert_eq!(CompressionKind::Bzip2.get_extension_with_dot(), ".bz2");
         assert_eq!(CompressionKind::None.get_extension_with_dot(), "");
     }
 
+    #[test]
+    fn test_object_naming() {
+        let default = ObjectNaming::default();
+        assert_eq!(
+            default.object_name("abc", CompressionKind::Zstd),
+            "abc.zstd"
+        );
+        assert_eq!(default.object_name("abc", CompressionKind::None), "abc");
+
+        let naming = ObjectNaming::zst().extension(CompressionKind::Xz, None);
+        assert_eq!(naming.object_name("abc", CompressionKind::Zstd), "abc.zst");
+        assert_eq!(naming.object_name("abc", CompressionKind::Xz), "abc");
+        assert_eq!(naming.object_name("abc", CompressionKind::Lz4), "abc.lz4");
+
+        let naming = naming.extension(CompressionKind::Zstd, Some("zstd"));
+        assert_eq!(naming.extension_of(CompressionKind::Zstd), Some("zstd"));
+    }
+
     #[test]
     fn test_compression_filenames() {
         assert_eq!(CompressionKind::Zstd.try_get_extension(), Some("zstd"));
         assert_eq!(CompressionKind::Lz4.try_get_extension(), Some("lz4"));
         assert_eq!(CompressionKind::Xz.try_get_extension(), Some("xz"));
         assert_eq!(CompressionKind::None.try_get_extension(), None);
+
+        for kind in [
+            CompressionKind::Gzip,
+            CompressionKind::Brotli,
+            CompressionKind::Bzip2,
+        ] {
+            assert_eq!(
+                CompressionKind::from_extension(kind.try_get_extension()),
+                Some(kind)
+            );
+        }
+        assert_eq!(
+            CompressionKind::from_extension(Some("gzip")),
+            Some(CompressionKind::Gzip)
+        );
     }
 }
@@ -0,0 +1,281 @@
+//! Named repository profiles, loaded from a TOML file and the environment.
+//!
+//! ```toml
+//! [repos.origin]
+//! urls = ["https://mirror.example.com/repo", "https://example.com/repo"]
+//! store = "/var/lib/syncstream"
+//! compression = "zstd"
+//! concurrency = 4
+//! token = "secret"
+//!
+//! [repos.origin.headers]
+//! X-Tenant = "acme"
+//! ```
+//!
+//! The environment overrides whichever profile is loaded: `SYNCSTREAM_URLS` (comma separated),
+//! `SYNCSTREAM_STORE`, `SYNCSTREAM_COMPRESSION`, `SYNCSTREAM_CONCURRENCY` and
+//! `SYNCSTREAM_TOKEN`, so that secrets don't have to be written to the file.
+use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+use serde::de::IntoDeserializer;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::store::Store;
+use crate::tree::create::CreateOptions;
+use crate::{CompressionKind, Mirrors};
+
+/// Every profile in a configuration file.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub repos: BTreeMap<String, RepoConfig>,
+}
+
+/// How to reach one repository, and where to keep what comes from it.
+#[derive(Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RepoConfig {
+    /// Mirrors of the repository, in priority order
+    #[serde(default)]
+    pub urls: Vec<String>,
+    pub store: Option<PathBuf>,
+    pub compression: Option<CompressionKind>,
+    /// Files stored at once, see [`CreateOptions::with_concurrency`]
+    pub concurrency: Option<usize>,
+    /// Sent as a bearer token with every HTTP request
+    pub token: Option<String>,
+    /// Sent with every HTTP request
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+/// Tokens and headers are left out, as they're often secrets.
+impl fmt::Debug for RepoConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RepoConfig")
+            .field("urls", &self.urls)
+            .field("store", &self.store)
+            .field("compression", &self.compression)
+            .field("concurrency", &self.concurrency)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Config {
+    /// Parses a configuration file's contents.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidConfig`](crate::Error::InvalidConfig) for malformed TOML or unknown
+    ///   settings
+    pub fn parse(text: &str) -> crate::Result<Self> {
+        toml::from_str(text).map_err(|e| crate::Error::InvalidConfig(e.to_string()))
+    }
+
+    /// Reads and parses the configuration file at `path`.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors, including the file not existing
+    /// - See [`Config::parse`]
+    pub fn load(path: &Path) -> crate::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Loads the [default file](Config::default_path), or no profiles at all if there is none.
+    ///
+    /// # Errors
+    ///
+    /// - See [`Config::load`]
+    pub fn load_default() -> crate::Result<Self> {
+        let Some(path) = Self::default_path() else {
+            return Ok(Self::default());
+        };
+        match Self::load(&path) {
+            Err(crate::Error::IoError(e)) if e.kind() == io::ErrorKind::NotFound => {
+                Ok(Self::default())
+            }
+            res => res,
+        }
+    }
+
+    /// `$XDG_CONFIG_HOME/syncstream/config.toml`, or `~/.config/syncstream/config.toml`.
+    #[must_use]
+    pub fn default_path() -> Option<PathBuf> {
+        let dir = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+        Some(dir.join("syncstream").join("config.toml"))
+    }
+
+    /// The profile called `name`, with the environment's overrides applied.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::NotFound`](crate::Error::NotFound) if there is no such profile
+    /// - [`Error::InvalidConfig`](crate::Error::InvalidConfig) for invalid overrides
+    pub fn repo(&self, name: &str) -> crate::Result<RepoConfig> {
+        self.repos
+            .get(name)
+            .cloned()
+            .ok_or_else(|| crate::Error::NotFound(format!("repository profile {name}")))?
+            .with_env(|key| std::env::var(key).ok())
+    }
+}
+
+impl RepoConfig {
+    /// Applies the overrides `var` returns for the [environment variables](self) it's called
+    /// with.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidConfig`](crate::Error::InvalidConfig) for unknown compression kinds
+    ///   and invalid numbers
+    pub fn with_env<F: Fn(&str) -> Option<String>>(mut self, var: F) -> crate::Result<Self> {
+        if let Some(urls) = var("SYNCSTREAM_URLS") {
+            self.urls = urls.split(',').map(|url| url.trim().to_string()).collect();
+        }
+        if let Some(store) = var("SYNCSTREAM_STORE") {
+            self.store = Some(store.into());
+        }
+        if let Some(compression) = var("SYNCSTREAM_COMPRESSION") {
+            self.compression = Some(parse_compression(&compression)?);
+        }
+        if let Some(concurrency) = var("SYNCSTREAM_CONCURRENCY") {
+            self.concurrency = Some(concurrency.parse().map_err(|_| {
+                crate::Error::InvalidConfig(format!("invalid concurrency: {concurrency}"))
+            })?);
+        }
+        if let Some(token) = var("SYNCSTREAM_TOKEN") {
+            self.token = Some(token);
+        }
+        Ok(self)
+    }
+
+    /// The repository's mirrors, sending the token and headers with every request.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidConfig`](crate::Error::InvalidConfig) if there are no URLs, or a
+    ///   header or the token isn't valid in a HTTP header
+    pub fn mirrors(&self) -> crate::Result<Mirrors> {
+        if self.urls.is_empty() {
+            return Err(crate::Error::InvalidConfig(
+                "no urls for the repository".to_string(),
+            ));
+        }
+
+        let invalid = |name: &str| crate::Error::InvalidConfig(format!("invalid header: {name}"));
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            headers.insert(
+                HeaderName::try_from(name).map_err(|_| invalid(name))?,
+                HeaderValue::try_from(value).map_err(|_| invalid(name))?,
+            );
+        }
+        if let Some(token) = &self.token {
+            let mut value = HeaderValue::try_from(format!("Bearer {token}"))
+                .map_err(|_| invalid(AUTHORIZATION.as_str()))?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+
+        Ok(Mirrors::new(&self.urls).headers(headers))
+    }
+
+    /// The store to use with the repository, if the profile has one.
+    #[must_use]
+    pub fn store(&self) -> Option<Store> {
+        Some(Store::new(self.store.as_ref()?))
+    }
+
+    /// How to create trees for the repository.
+    #[must_use]
+    pub fn create_options(&self) -> CreateOptions {
+        let options = CreateOptions::new();
+        match self.concurrency {
+            Some(concurrency) => options.with_concurrency(concurrency),
+            None => options,
+        }
+    }
+}
+
+/// Parses a compression kind by the name it has in configuration files, like `zstd` or `none`.
+///
+/// # Errors
+///
+/// - [`Error::InvalidConfig`](crate::Error::InvalidConfig) for unknown kinds
+pub fn parse_compression(name: &str) -> crate::Result<CompressionKind> {
+    CompressionKind::deserialize(name.into_deserializer()).map_err(|_: serde::de::value::Error| {
+        crate::Error::InvalidConfig(format!("unknown compression: {name}"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repo_config() -> crate::Result<()> {
+        let config = Config::parse(
+            r#"
+            [repos.origin]
+            urls = ["https://mirror.example.com/repo", "https://example.com/repo"]
+            store = "/var/lib/syncstream"
+            compression = "xz"
+            token = "secret"
+
+            [repos.origin.headers]
+            X-Tenant = "acme"
+            "#,
+        )?;
+        let repo = config.repos["origin"].clone();
+        assert_eq!(repo.compression, Some(CompressionKind::Xz));
+        assert!(!format!("{repo:?}").contains("secret"));
+
+        let mirrors = repo.mirrors()?;
+        assert_eq!(mirrors.urls().count(), 2);
+        assert_eq!(mirrors.request_headers()["X-Tenant"], "acme");
+        assert_eq!(mirrors.request_headers()[AUTHORIZATION], "Bearer secret");
+
+        // The environment wins over the file
+        let repo = repo.with_env(|key| match key {
+            "SYNCSTREAM_URLS" => Some("https://a.example.com, https://b.example.com".into()),
+            "SYNCSTREAM_COMPRESSION" => Some("none".into()),
+            "SYNCSTREAM_CONCURRENCY" => Some("4".into()),
+            _ => None,
+        })?;
+        assert_eq!(
+            repo.urls,
+            ["https://a.example.com", "https://b.example.com"]
+        );
+        assert_eq!(repo.compression, Some(CompressionKind::None));
+        let store = repo.store().expect("the profile has a store");
+        assert_eq!(store.root(), Path::new("/var/lib/syncstream"));
+        assert_eq!(repo.create_options().concurrency(), 4);
+
+        assert!(matches!(
+            config.repo("missing"),
+            Err(crate::Error::NotFound(_))
+        ));
+        assert!(matches!(
+            RepoConfig::default().with_env(|_| Some("fast".into())),
+            Err(crate::Error::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            Config::parse("[repos.origin]\nurl = 'typo'"),
+            Err(crate::Error::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            RepoConfig::default().mirrors(),
+            Err(crate::Error::InvalidConfig(_))
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,17 @@
+//! Low-level primitives: individual content-addressed streams, their compression and the local
+//! store they are kept in.
+//!
+//! These are the building blocks the rest of the crate is made of, and change far less often
+//! than the higher-level APIs in [`repo`](crate::repo).
+pub use crate::compression::{CompressionKind, ObjectNaming};
+pub use crate::dictionary::Dictionary;
+pub use crate::encryption::EncryptionKey;
+pub use crate::event::{Event, SlowThresholds};
+pub use crate::pool::CpuPool;
+pub use crate::store::{
+    DeployMode, GcReport, MaintenanceReport, MigrateReport, Store, StoreLayout, StoredObject,
+    VerifyReport,
+};
+#[cfg(target_os = "linux")]
+pub use crate::store::{StoreEvent, StoreWatcher};
+pub use crate::stream::{Block, BlockIndex, ObjectHash, Outboard, ReuseReport, Stream};
@@ -0,0 +1,121 @@
+//! Zstd dictionaries, for trees of many small, similar files like configs or shaders that compress
+//! poorly one at a time.
+//!
+//! A dictionary is trained on samples of those files and kept as an ordinary uncompressed object
+//! named after its hash, so it's pushed and downloaded like any stream. Streams compressed with
+//! one [record](crate::stream::Stream::dictionary) its hash, and their objects are named
+//! `{hash}.zd{prefix}` after the start of it, so that the same file compressed with another
+//! dictionary, or without one, is a different object.
+use async_compression::Level;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::async_types::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite};
+use crate::async_types::{ZstdDecoder, ZstdEncoder};
+use crate::store::Store;
+
+/// Characters of the dictionary's hash in the names of objects compressed with it.
+const PREFIX_LEN: usize = 16;
+
+/// A trained Zstd dictionary, see [`Tree::train_dictionary`](crate::tree::Tree::train_dictionary).
+#[derive(Clone, PartialEq, Eq)]
+pub struct Dictionary {
+    hash: String,
+    bytes: Arc<[u8]>,
+}
+
+impl fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dictionary")
+            .field("hash", &self.hash)
+            .field("len", &self.bytes.len())
+            .finish()
+    }
+}
+
+impl Dictionary {
+    /// The size Zstd's own tools train dictionaries to, 110 KiB.
+    pub const DEFAULT_MAX_SIZE: usize = 112_640;
+
+    /// Trains a dictionary of at most `max_size` bytes on `samples`.
+    ///
+    /// # Errors
+    ///
+    /// - If Zstd can't train one, typically because there are too few samples. A few dozen are
+    ///   needed at least, and a hundred times `max_size` worth of them is recommended.
+    pub fn train<S: AsRef<[u8]>>(samples: &[S], max_size: usize) -> io::Result<Self> {
+        zstd::dict::from_samples(samples, max_size).map(Self::from_bytes)
+    }
+
+    /// A dictionary that was trained before, like by the `zstd --train` command.
+    #[must_use]
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self {
+            hash: blake3::hash(&bytes).to_hex().to_string(),
+            bytes: bytes.into(),
+        }
+    }
+
+    #[must_use]
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Loads the dictionary called `hash` from the store.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically the dictionary not being in the store)
+    pub async fn load(store: &Store, hash: &str) -> io::Result<Self> {
+        let mut bytes = Vec::new();
+        store.open(hash).await?.read_to_end(&mut bytes).await?;
+        Ok(Self {
+            hash: hash.to_string(),
+            bytes: bytes.into(),
+        })
+    }
+
+    /// Adds the dictionary to the store, so that it's pushed along with the streams using it.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically out of space)
+    pub async fn insert(&self, store: &Store) -> crate::Result<()> {
+        if !store.contains(&self.hash) {
+            store.insert_from_reader(&self.hash, &*self.bytes).await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn compress<'a, W: AsyncWrite + Send + 'a>(
+        &self,
+        sink: W,
+    ) -> io::Result<Pin<Box<dyn AsyncWrite + Send + 'a>>> {
+        Ok(Box::pin(ZstdEncoder::with_dict(
+            sink,
+            Level::Default,
+            &self.bytes,
+        )?))
+    }
+
+    pub(crate) fn decompress<'a, R: AsyncBufRead + Send + 'a>(
+        &self,
+        source: R,
+    ) -> io::Result<Pin<Box<dyn AsyncRead + Send + 'a>>> {
+        Ok(Box::pin(ZstdDecoder::with_dict(source, &self.bytes)?))
+    }
+}
+
+/// The name of the object with the stream `hash` compressed with the dictionary `dictionary`,
+/// in the store or a repository's `streams` directory.
+pub(crate) fn object_name(hash: &str, dictionary: &str) -> String {
+    let prefix = dictionary.get(..PREFIX_LEN).unwrap_or(dictionary);
+    format!("{hash}.zd{prefix}")
+}
@@ -0,0 +1,318 @@
+//! Encryption at rest for compressed objects, so that repositories on untrusted storage never
+//! hold plaintext.
+//!
+//! Objects are encrypted after compression with XChaCha20-Poly1305, using the STREAM construction
+//! so that objects of any size are handled a segment at a time. An object is a random nonce
+//! prefix followed by segments of [`SEGMENT_LEN`] bytes, each with its own tag. The last segment
+//! is marked as such, so a truncated object fails to decrypt rather than silently losing its end.
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::{KeyInit, OsRng, rand_core::RngCore};
+use chacha20poly1305::{Key, XChaCha20Poly1305};
+use std::fmt;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use crate::async_types::{AsyncBufRead, AsyncRead};
+
+/// Plaintext bytes per segment.
+const SEGMENT_LEN: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+/// The random part of the nonce, with the rest being the segment counter and last flag.
+const PREFIX_LEN: usize = 19;
+
+/// A repository's key, see [`Store::with_encryption`](crate::store::Store::with_encryption).
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; 32]);
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+impl EncryptionKey {
+    #[must_use]
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// A new random key.
+    #[must_use]
+    pub fn generate() -> Self {
+        Self(XChaCha20Poly1305::generate_key(&mut OsRng).into())
+    }
+
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(Key::from_slice(&self.0))
+    }
+}
+
+fn decrypt_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "object failed to decrypt, either the key is wrong or it was tampered with",
+    )
+}
+
+/// Encrypts everything written to it, or passes it through unchanged without a key.
+/// [`Self::finish`] must be called once everything was written.
+pub(crate) struct EncryptingWriter<W> {
+    inner: W,
+    stream: Option<EncryptorBE32<XChaCha20Poly1305>>,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    pub(crate) fn new(mut inner: W, key: Option<&EncryptionKey>) -> io::Result<Self> {
+        let stream = match key {
+            Some(key) => {
+                let mut prefix = [0; PREFIX_LEN];
+                OsRng.fill_bytes(&mut prefix);
+                inner.write_all(&prefix)?;
+                Some(EncryptorBE32::from_aead(key.cipher(), &prefix.into()))
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            inner,
+            stream,
+            buf: Vec::new(),
+        })
+    }
+
+    /// Writes the last segment.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        if let Some(stream) = self.stream.take() {
+            let segment = stream
+                .encrypt_last(self.buf.as_slice())
+                .map_err(|_| io::Error::other("failed to encrypt object"))?;
+            self.inner.write_all(&segment)?;
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let Some(stream) = &mut self.stream else {
+            return self.inner.write(data);
+        };
+
+        self.buf.extend_from_slice(data);
+        // A full segment is only written once more follows, as the last one is marked
+        while self.buf.len() > SEGMENT_LEN {
+            let segment = stream
+                .encrypt_next(&self.buf[..SEGMENT_LEN])
+                .map_err(|_| io::Error::other("failed to encrypt object"))?;
+            self.inner.write_all(&segment)?;
+            self.buf.drain(..SEGMENT_LEN);
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts an object written by [`EncryptingWriter`], or returns `reader` as is without a key.
+pub(crate) fn decrypt<'a, R: AsyncBufRead + Send + Unpin + 'a>(
+    reader: R,
+    key: Option<&EncryptionKey>,
+) -> Pin<Box<dyn AsyncBufRead + Send + 'a>> {
+    match key {
+        Some(key) => Box::pin(DecryptingReader {
+            inner: reader,
+            cipher: Some(key.cipher()),
+            stream: None,
+            input: Vec::new(),
+            output: Vec::new(),
+            pos: 0,
+            done: false,
+        }),
+        None => Box::pin(reader),
+    }
+}
+
+struct DecryptingReader<R> {
+    inner: R,
+    /// Until the nonce prefix was read
+    cipher: Option<XChaCha20Poly1305>,
+    stream: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R> DecryptingReader<R> {
+    /// Decrypts every complete segment in the input, except one that could be the last.
+    fn decrypt_segments(&mut self) -> io::Result<()> {
+        if let Some(cipher) = self.cipher.take_if(|_| self.input.len() >= PREFIX_LEN) {
+            let prefix: [u8; PREFIX_LEN] = self.input[..PREFIX_LEN]
+                .try_into()
+                .expect("length checked above");
+            self.stream = Some(DecryptorBE32::from_aead(cipher, &prefix.into()));
+            self.input.drain(..PREFIX_LEN);
+        }
+        let Some(stream) = &mut self.stream else {
+            return Ok(());
+        };
+
+        while self.input.len() > SEGMENT_LEN + TAG_LEN {
+            let segment = stream
+                .decrypt_next(&self.input[..SEGMENT_LEN + TAG_LEN])
+                .map_err(|_| decrypt_error())?;
+            self.output.extend_from_slice(&segment);
+            self.input.drain(..SEGMENT_LEN + TAG_LEN);
+        }
+        Ok(())
+    }
+
+    /// Decrypts the last segment, once the input ended.
+    fn decrypt_last(&mut self) -> io::Result<()> {
+        self.done = true;
+        let stream = self.stream.take().ok_or_else(decrypt_error)?;
+        let segment = stream
+            .decrypt_last(self.input.as_slice())
+            .map_err(|_| decrypt_error())?;
+        self.output.extend_from_slice(&segment);
+        self.input.clear();
+        Ok(())
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncBufRead for DecryptingReader<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+
+        while this.pos == this.output.len() && !this.done {
+            this.output.clear();
+            this.pos = 0;
+
+            let data = ready!(Pin::new(&mut this.inner).poll_fill_buf(cx))?;
+            if data.is_empty() {
+                this.decrypt_last()?;
+            } else {
+                let len = data.len();
+                this.input.extend_from_slice(data);
+                Pin::new(&mut this.inner).consume(len);
+                this.decrypt_segments()?;
+            }
+        }
+
+        Poll::Ready(Ok(&this.output[this.pos..]))
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<R: AsyncBufRead + Unpin> AsyncRead for DecryptingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let data = ready!(self.as_mut().poll_fill_buf(cx))?;
+        let len = data.len().min(buf.remaining());
+        buf.put_slice(&data[..len]);
+        self.consume(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(not(feature = "tokio"))]
+impl<R: AsyncBufRead + Unpin> AsyncRead for DecryptingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let data = ready!(self.as_mut().poll_fill_buf(cx))?;
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        self.consume(len);
+        Poll::Ready(Ok(len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_types::AsyncReadExt;
+
+    async fn roundtrip(data: &[u8], key: &EncryptionKey) -> io::Result<Vec<u8>> {
+        let mut writer = EncryptingWriter::new(Vec::new(), Some(key))?;
+        writer.write_all(data)?;
+        let encrypted = writer.finish()?;
+        assert_eq!(
+            encrypted.len(),
+            PREFIX_LEN + data.len() + data.len().div_ceil(SEGMENT_LEN).max(1) * TAG_LEN
+        );
+
+        let mut decrypted = Vec::new();
+        decrypt(&encrypted[..], Some(key))
+            .read_to_end(&mut decrypted)
+            .await?;
+        Ok(decrypted)
+    }
+
+    #[tokio::test]
+    async fn test_encryption() -> io::Result<()> {
+        let key = EncryptionKey::generate();
+
+        for len in [
+            0,
+            1,
+            SEGMENT_LEN - 1,
+            SEGMENT_LEN,
+            SEGMENT_LEN + 1,
+            3 * SEGMENT_LEN,
+        ] {
+            let data: Vec<u8> = (0..len).map(|i| i.to_le_bytes()[0]).collect();
+            assert_eq!(roundtrip(&data, &key).await?, data, "{len} bytes");
+        }
+
+        let mut writer = EncryptingWriter::new(Vec::new(), Some(&key))?;
+        writer.write_all(&vec![7; 2 * SEGMENT_LEN])?;
+        let encrypted = writer.finish()?;
+        let read = |encrypted: Vec<u8>, key: EncryptionKey| async move {
+            let mut decrypted = Vec::new();
+            decrypt(&encrypted[..], Some(&key))
+                .read_to_end(&mut decrypted)
+                .await
+                .map(|_| decrypted)
+        };
+
+        // Wrong keys, tampering and truncation are all caught
+        assert!(
+            read(encrypted.clone(), EncryptionKey::generate())
+                .await
+                .is_err()
+        );
+        let mut tampered = encrypted.clone();
+        tampered[PREFIX_LEN + 10] ^= 1;
+        assert!(read(tampered, key.clone()).await.is_err());
+        let truncated = encrypted[..PREFIX_LEN + SEGMENT_LEN + TAG_LEN].to_vec();
+        assert!(read(truncated, key.clone()).await.is_err());
+        assert!(read(encrypted[..5].to_vec(), key.clone()).await.is_err());
+
+        // Without a key, nothing changes
+        let mut writer = EncryptingWriter::new(Vec::new(), None)?;
+        writer.write_all(b"plain")?;
+        assert_eq!(writer.finish()?, b"plain");
+
+        Ok(())
+    }
+}
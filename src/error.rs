@@ -3,10 +3,110 @@ pub type Result<T> = std::result::Result<T, Error>;
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("io error: {0:?}")]
-    IoError(#[from] std::io::Error),
+    IoError(#[source] std::io::Error),
     #[error("network error: {0:?}")]
-    NetworkError(#[from] reqwest::Error),
+    NetworkError(#[source] reqwest::Error),
+    #[error("network operation timed out")]
+    Timeout,
+    #[error("ssh error: {0}")]
+    SshError(String),
+    /// A git repository that can't be read, see [`Tree::from_git`](crate::tree::Tree::from_git)
+    #[error("git error: {0}")]
+    GitError(String),
+    /// An object missing from a local or SSH repository
+    #[error("not found: {0}")]
+    NotFound(String),
+    /// The manifest didn't change since it was last fetched, see
+    /// [`Tree::fetch_if_modified`](crate::tree::Tree::fetch_if_modified)
+    #[error("not modified")]
+    NotModified,
     /// Expected and Recieved
     #[error("hash error: expected {0}, got {1}")]
     HashError(String, String),
+    /// Not a stream hash, see [`ObjectHash`](crate::stream::ObjectHash)
+    #[error("invalid hash: {0:?}")]
+    InvalidHash(String),
+    #[error("manifest error: {0:?}")]
+    ManifestError(#[from] serde_json::Error),
+    #[error("invalid manifest: {0}")]
+    InvalidManifest(String),
+    /// A path that would escape the tree, see [`TreePath`](crate::tree::TreePath)
+    #[error("invalid tree path: {}", .0.display())]
+    InvalidPath(std::path::PathBuf),
+    /// The tree recorded as deployed isn't the one an update expected. Expected and Recorded
+    #[error("deployment mismatch: expected tree {0}, deployed is {1}")]
+    DeploymentMismatch(String, String),
+    /// A deploy into the directory was interrupted, see
+    /// [`Tree::resume_deploy`](crate::tree::Tree::resume_deploy) and
+    /// [`Tree::undo_deploy`](crate::tree::Tree::undo_deploy)
+    #[error("deployment at {} was interrupted, resume or undo it first", .0.display())]
+    InterruptedDeploy(std::path::PathBuf),
+    /// A name that isn't portable, refused or sanitized into another entry's name by the
+    /// store's [`NamePolicy`](crate::store::NamePolicy)
+    #[error("non-portable file name: {}", .0.display())]
+    NonPortableName(std::path::PathBuf),
+    /// A symlink whose target escapes the deployment, refused by
+    /// [`SymlinkPolicy::Sandbox`](crate::store::SymlinkPolicy::Sandbox). Symlink and Target
+    #[error("symlink {} escapes the deployment: {}", .0.display(), .1.display())]
+    UnsafeSymlink(std::path::PathBuf, std::path::PathBuf),
+    /// No more objects are fetched once this many bytes were downloaded, see
+    /// [`Mirrors::budget`](crate::Mirrors::budget)
+    #[error("transfer budget of {0} bytes exceeded")]
+    BudgetExceeded(u64),
+    /// A tree missing from the store's allowlist, see
+    /// [`Store::with_allowed_trees`](crate::store::Store::with_allowed_trees)
+    #[error("tree {0} is not allowed to be deployed")]
+    TreeNotAllowed(String),
+    /// Both sides of a [merge](crate::tree::Tree::merge) have something different at the path
+    #[error("merge conflict at {}", .0.display())]
+    MergeConflict(std::path::PathBuf),
+    /// A name that can't be used as a [reference](crate::tree::Tree::publish), like one
+    /// containing `..`
+    #[error("invalid reference name: {0}")]
+    InvalidRef(String),
+    /// A configuration file or override that can't be used, see the `config` module
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+    /// An archive or index that can't be read, like a corrupted zip file or casync index
+    #[error("invalid archive: {0}")]
+    InvalidArchive(String),
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            Self::Timeout
+        } else {
+            Self::NetworkError(e)
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        // Network errors while reading a response body are wrapped as IO errors
+        let timed_out = e
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<reqwest::Error>())
+            .is_some_and(reqwest::Error::is_timeout);
+
+        // Readers fail with the crate's own errors too, like blocks that don't verify
+        let ours = e
+            .get_ref()
+            .is_some_and(<dyn std::error::Error + Send + Sync>::is::<Self>);
+
+        if timed_out {
+            Self::Timeout
+        } else if ours {
+            match e
+                .into_inner()
+                .map(<dyn std::error::Error + Send + Sync>::downcast::<Self>)
+            {
+                Some(Ok(inner)) => *inner,
+                _ => unreachable!("checked above"),
+            }
+        } else {
+            Self::IoError(e)
+        }
+    }
 }
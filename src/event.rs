@@ -0,0 +1,130 @@
+//! Events for things that didn't fail an operation, but that callers may want to know about.
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Event {
+    /// A stream couldn't be hardlinked, so it was copied instead, which is slower and takes up
+    /// more space
+    LinkFallback { hash: String, error: io::ErrorKind },
+    /// A stream downloaded slower than the [minimum rate](SlowThresholds::download_rate), in
+    /// uncompressed bytes
+    SlowDownload {
+        hash: String,
+        bytes: u64,
+        elapsed: Duration,
+    },
+    /// Hashing a file into the store was slower than the
+    /// [minimum rate](SlowThresholds::hash_rate)
+    SlowHashing {
+        hash: String,
+        bytes: u64,
+        elapsed: Duration,
+    },
+    /// Linking or copying a stream into a deployment took longer than
+    /// [allowed](SlowThresholds::deploy_time)
+    SlowDeploy { hash: String, elapsed: Duration },
+}
+
+/// Rates are only judged for operations that took at least this long, as latency dominates
+/// shorter ones.
+const MIN_RATE_SAMPLE: Duration = Duration::from_secs(1);
+
+/// When operations are slow enough to report, which usually points to a failing disk or a
+/// throttled network. See [`Store::with_slow_thresholds`](crate::store::Store::with_slow_thresholds).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SlowThresholds {
+    download_rate: Option<u64>,
+    hash_rate: Option<u64>,
+    deploy_time: Option<Duration>,
+}
+
+impl SlowThresholds {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reports [downloads](Event::SlowDownload) slower than this many bytes per second.
+    #[must_use]
+    pub fn download_rate(mut self, bytes_per_second: u64) -> Self {
+        self.download_rate = Some(bytes_per_second);
+        self
+    }
+
+    /// Reports [hashing](Event::SlowHashing) slower than this many bytes per second.
+    #[must_use]
+    pub fn hash_rate(mut self, bytes_per_second: u64) -> Self {
+        self.hash_rate = Some(bytes_per_second);
+        self
+    }
+
+    /// Reports [linking or copying](Event::SlowDeploy) a stream taking longer than this.
+    #[must_use]
+    pub fn deploy_time(mut self, max: Duration) -> Self {
+        self.deploy_time = Some(max);
+        self
+    }
+
+    pub(crate) fn is_slow_download(&self, bytes: u64, elapsed: Duration) -> bool {
+        is_below(self.download_rate, bytes, elapsed)
+    }
+
+    pub(crate) fn is_slow_hashing(&self, bytes: u64, elapsed: Duration) -> bool {
+        is_below(self.hash_rate, bytes, elapsed)
+    }
+
+    pub(crate) fn is_slow_deploy(&self, elapsed: Duration) -> bool {
+        self.deploy_time.is_some_and(|max| elapsed > max)
+    }
+}
+
+fn is_below(min_rate: Option<u64>, bytes: u64, elapsed: Duration) -> bool {
+    min_rate.is_some_and(|min_rate| {
+        elapsed >= MIN_RATE_SAMPLE
+            && u128::from(bytes) * 1000 < u128::from(min_rate) * elapsed.as_millis()
+    })
+}
+
+type Callback = dyn Fn(&Event) + Send + Sync;
+
+/// Where events are sent, see [`Store::with_events`](crate::store::Store::with_events).
+#[derive(Clone, Default)]
+pub(crate) struct EventSink(Option<Arc<Callback>>);
+
+impl EventSink {
+    pub(crate) fn new<F: Fn(&Event) + Send + Sync + 'static>(callback: F) -> Self {
+        Self(Some(Arc::new(callback)))
+    }
+
+    pub(crate) fn emit(&self, event: &Event) {
+        if let Some(callback) = &self.0 {
+            callback(event);
+        }
+    }
+}
+
+impl fmt::Debug for EventSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(_) => f.write_str("EventSink(Some(..))"),
+            None => f.write_str("EventSink(None)"),
+        }
+    }
+}
+
+/// Sinks are only equal to their clones.
+impl PartialEq for EventSink {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for EventSink {}
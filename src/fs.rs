@@ -1,8 +1,8 @@
 // Exception due to general structure needing to be the same
 #![allow(clippy::unused_async)]
 
-use crate::async_types::{AsyncWrite, AsyncWriteExt, Stream, unfold};
-use std::io;
+use crate::async_types::{AsyncBufRead, AsyncReadExt, AsyncWrite, BufReader, Stream, unfold};
+use std::io::{self, Seek, SeekFrom};
 use std::path::Path;
 use std::pin::Pin;
 use std::task::{Context, Poll};
@@ -38,6 +38,20 @@ impl File {
             inner: Box::pin(inner),
         })
     }
+
+    #[cfg(feature = "tokio")]
+    pub fn from_std(file: std::fs::File) -> Self {
+        Self {
+            inner: Box::pin(tokio::fs::File::from_std(file)),
+        }
+    }
+
+    #[cfg(not(feature = "tokio"))]
+    pub fn from_std(file: std::fs::File) -> Self {
+        Self {
+            inner: Box::pin(AllowStdIo::new(file)),
+        }
+    }
 }
 
 impl AsyncWrite for File {
@@ -64,7 +78,64 @@ impl AsyncWrite for File {
     }
 }
 
+/// Opens a file for buffered reading.
+/// Writes straight through to a blocking writer, so async encoders can run on
+/// [pool](crate::pool::CpuPool) threads. Every operation completes immediately.
+pub(crate) struct BlockingWriter<W>(pub W);
+
+impl<W: io::Write + Unpin> AsyncWrite for BlockingWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.0.write(buf))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.0.flush())
+    }
+
+    #[cfg(feature = "tokio")]
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.0.flush())
+    }
+
+    #[cfg(not(feature = "tokio"))]
+    fn poll_close(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.0.flush())
+    }
+}
+
+pub async fn open_buffered<P: AsRef<Path>>(
+    path: P,
+) -> io::Result<Pin<Box<dyn AsyncBufRead + Send>>> {
+    #[cfg(feature = "tokio")]
+    let file = tokio::fs::File::open(path).await?;
+    #[cfg(not(feature = "tokio"))]
+    let file = AllowStdIo::new(std::fs::File::open(path)?);
+
+    Ok(Box::pin(BufReader::new(file)))
+}
+
+/// Opens the `len` bytes of a file from `offset` on.
+pub async fn open_range<P: AsRef<Path>>(
+    path: P,
+    offset: u64,
+    len: u64,
+) -> io::Result<Pin<Box<dyn AsyncBufRead + Send>>> {
+    let mut file = std::fs::File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    #[cfg(feature = "tokio")]
+    let file = tokio::fs::File::from_std(file);
+    #[cfg(not(feature = "tokio"))]
+    let file = AllowStdIo::new(file);
+
+    Ok(Box::pin(BufReader::new(AsyncReadExt::take(file, len))))
+}
+
 /// Not recommended outside of tests, as loads entire file into memory.
+#[cfg(test)]
 pub async fn read_to_end<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, std::io::Error> {
     #[cfg(feature = "tokio")]
     let data = tokio::fs::read(path).await?;
@@ -124,6 +195,7 @@ pub async fn read_chunked<P: AsRef<Path>>(
     })))
 }
 
+#[cfg(test)]
 pub async fn write<P: AsRef<Path>, C: AsRef<[u8]>>(
     path: P,
     contents: C,
@@ -136,6 +208,30 @@ pub async fn write<P: AsRef<Path>, C: AsRef<[u8]>>(
     Ok(())
 }
 
+/// Whether a failed hardlink should fall back onto copying: across filesystems, past the link
+/// limit, or on filesystems without hardlinks. Anything else is a real problem, like an
+/// immutable file, and is better surfaced than hidden behind a slow copy.
+///
+/// `target_fs` is only queried for `EPERM`, which is also how some filesystems report not
+/// supporting hardlinks.
+#[cfg(unix)]
+pub(crate) fn should_copy<F: FnOnce() -> nix::Result<nix::sys::statfs::Statfs>>(
+    error: &io::Error,
+    target_fs: F,
+) -> bool {
+    use nix::errno::Errno;
+    use nix::sys::statfs::{FsType, MSDOS_SUPER_MAGIC};
+
+    const EXFAT_SUPER_MAGIC: FsType = FsType(0x2011_BAB0);
+
+    match error.raw_os_error().map(Errno::from_raw) {
+        Some(Errno::EXDEV | Errno::EMLINK | Errno::EOPNOTSUPP) => true,
+        Some(Errno::EPERM) => target_fs()
+            .is_ok_and(|fs| [MSDOS_SUPER_MAGIC, EXFAT_SUPER_MAGIC].contains(&fs.filesystem_type())),
+        _ => false,
+    }
+}
+
 /// Atomic Rename (on supported platforms)
 #[cfg(unix)]
 pub fn rename<P: AsRef<Path>>(original_path: P, new_path: P) -> io::Result<()> {
@@ -189,6 +285,7 @@ pub fn rename<P: AsRef<Path>>(original_path: P, new_path: P) -> io::Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::async_types::AsyncWriteExt;
     use futures_util::StreamExt;
     use temp_dir::TempDir;
     use temp_file::TempFile;
@@ -265,7 +362,7 @@ mod tests {
 
         // Effectively the entire test
         let mut file = File::create_new(&file_path).await?;
-        file.write(test_data).await?;
+        file.write_all(test_data).await?;
         drop(file);
 
         assert!(file_path.exists());
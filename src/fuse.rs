@@ -0,0 +1,647 @@
+//! Mounting a tree read-only, fetching files as they're opened.
+//!
+//! A [`FuseMount`] shows a tree at a mountpoint without downloading or deploying it first. Names,
+//! sizes, modes and symlinks all come from the tree itself, so huge trees can be browsed right
+//! away. The first time a file is opened, its stream is downloaded into the store and checked
+//! against its hash like any other download. After that the file is read straight from the
+//! store.
+//!
+//! The kernel's FUSE protocol is spoken directly over `/dev/fuse`, so no libfuse is needed, but
+//! mounting takes `CAP_SYS_ADMIN`. Requests are answered one at a time, so reads wait for any
+//! download in progress.
+use nix::errno::Errno;
+use nix::mount::{MntFlags, MsFlags};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+use tokio::runtime::Handle;
+
+use crate::store::Store;
+use crate::stream::Stream;
+use crate::tree::{SpecialKind, Tree};
+use crate::{CompressionKind, Mirrors};
+
+const ROOT_ID: u64 = 1;
+/// How long the kernel may cache names and attributes, in seconds. Trees never change.
+const TTL: u64 = 24 * 60 * 60;
+const MAX_WRITE: u32 = 128 * 1024;
+const BLOCK_SIZE: u32 = 4096;
+
+const IN_HEADER_LEN: usize = 40;
+const OUT_HEADER_LEN: usize = 16;
+
+// Request opcodes, from `linux/fuse.h`
+const LOOKUP: u32 = 1;
+const FORGET: u32 = 2;
+const GETATTR: u32 = 3;
+const READLINK: u32 = 5;
+const OPEN: u32 = 14;
+const READ: u32 = 15;
+const STATFS: u32 = 17;
+const RELEASE: u32 = 18;
+const INIT: u32 = 26;
+const OPENDIR: u32 = 27;
+const READDIR: u32 = 28;
+const RELEASEDIR: u32 = 29;
+const INTERRUPT: u32 = 36;
+const DESTROY: u32 = 38;
+const BATCH_FORGET: u32 = 42;
+
+/// The page cache may keep a file's contents between opens.
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+const S_IFIFO: u32 = 0o10_000;
+const S_IFDIR: u32 = 0o40_000;
+const S_IFREG: u32 = 0o100_000;
+const S_IFLNK: u32 = 0o120_000;
+
+/// A tree mounted with FUSE, unmounted when dropped. See the [module docs](self).
+#[derive(Debug)]
+pub struct FuseMount {
+    mountpoint: PathBuf,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FuseMount {
+    /// Mounts `tree` at `mountpoint`, an existing directory, downloading streams the store
+    /// doesn't have from `mirrors` when their files are opened. Has to be called from a
+    /// multi-threaded tokio runtime, which downloads run on.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors opening `/dev/fuse` or mounting, typically permissions
+    /// - [`io::ErrorKind::Other`] outside of a tokio runtime
+    pub fn mount<C: Into<Option<CompressionKind>>>(
+        tree: &Tree,
+        mirrors: Mirrors,
+        store: Store,
+        compression: C,
+        mountpoint: &Path,
+    ) -> crate::Result<Self> {
+        let handle = Handle::try_current().map_err(io::Error::other)?;
+        let device = File::options().read(true).write(true).open("/dev/fuse")?;
+        let options = format!(
+            "fd={},rootmode={:o},user_id={},group_id={},default_permissions",
+            device.as_raw_fd(),
+            S_IFDIR,
+            nix::unistd::getuid(),
+            nix::unistd::getgid(),
+        );
+        nix::mount::mount(
+            Some("syncstream"),
+            mountpoint,
+            Some("fuse.syncstream"),
+            MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            Some(options.as_str()),
+        )
+        .map_err(io::Error::from)?;
+
+        let mut fs = Filesystem {
+            nodes: nodes(tree),
+            mirrors,
+            store,
+            compression: compression.into(),
+            handle,
+            files: HashMap::new(),
+            next_fh: 1,
+        };
+        let thread = std::thread::Builder::new()
+            .name("syncstream-fuse".to_string())
+            .spawn(move || fs.serve(device))?;
+
+        Ok(Self {
+            mountpoint: mountpoint.to_path_buf(),
+            thread: Some(thread),
+        })
+    }
+
+    #[must_use]
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// Unmounts the tree, waiting for the request in progress.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors, like the mount being busy
+    pub fn unmount(mut self) -> crate::Result<()> {
+        nix::mount::umount2(&self.mountpoint, MntFlags::empty()).map_err(io::Error::from)?;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FuseMount {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = nix::mount::umount2(&self.mountpoint, MntFlags::MNT_DETACH);
+            let _ = thread.join();
+        }
+    }
+}
+
+enum Node {
+    Dir {
+        mode: u32,
+        parent: u64,
+        /// Sorted by name
+        children: Vec<(OsString, u64)>,
+    },
+    File(Stream),
+    Symlink(PathBuf),
+    Special {
+        mode: u32,
+        kind: SpecialKind,
+    },
+}
+
+/// Every entry of the tree, where an entry's inode number is its index plus one.
+fn nodes(tree: &Tree) -> Vec<Node> {
+    fn add_dir(nodes: &mut Vec<Node>, tree: &Tree, parent: u64) -> u64 {
+        nodes.push(Node::Dir {
+            mode: tree.permissions,
+            parent,
+            children: Vec::new(),
+        });
+        let id = nodes.len() as u64;
+        let mut children = Vec::new();
+        let mut add = |nodes: &mut Vec<Node>, name: &OsStr, node| {
+            nodes.push(node);
+            children.push((name.to_os_string(), nodes.len() as u64));
+        };
+
+        for stream in &tree.streams {
+            add(nodes, &stream.file_name, Node::File(stream.clone()));
+        }
+        for symlink in &tree.symlinks {
+            add(
+                nodes,
+                &symlink.file_name,
+                Node::Symlink(symlink.target.clone()),
+            );
+        }
+        for special in &tree.specials {
+            let node = Node::Special {
+                mode: special.mode,
+                kind: special.kind,
+            };
+            add(nodes, &special.file_name, node);
+        }
+        for (name, subtree) in &tree.subtrees {
+            let child = add_dir(nodes, subtree, id);
+            children.push((name.as_os_str().to_os_string(), child));
+        }
+
+        children.sort_unstable();
+        if let Node::Dir { children: slot, .. } = &mut nodes[index(id)] {
+            *slot = children;
+        }
+        id
+    }
+
+    let mut nodes = Vec::new();
+    add_dir(&mut nodes, tree, ROOT_ID);
+    nodes
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn index(id: u64) -> usize {
+    (id - 1) as usize
+}
+
+struct Filesystem {
+    nodes: Vec<Node>,
+    mirrors: Mirrors,
+    store: Store,
+    compression: Option<CompressionKind>,
+    handle: Handle,
+    files: HashMap<u64, File>,
+    next_fh: u64,
+}
+
+/// A request from the kernel.
+struct Request<'a> {
+    opcode: u32,
+    unique: u64,
+    node: u64,
+    body: &'a [u8],
+}
+
+impl Filesystem {
+    fn serve(&mut self, mut device: File) {
+        let mut buf = vec![0; MAX_WRITE as usize + 4096];
+        loop {
+            let len = match device.read(&mut buf) {
+                Ok(len) => len,
+                // Interrupted, or a request the kernel took back
+                Err(e)
+                    if matches!(
+                        Errno::from_raw(e.raw_os_error().unwrap_or(0)),
+                        Errno::EINTR | Errno::ENOENT | Errno::EAGAIN
+                    ) =>
+                {
+                    continue;
+                }
+                // Unmounted
+                Err(_) => return,
+            };
+            let Some(request) = parse(&buf[..len]) else {
+                return;
+            };
+
+            let reply = match request.opcode {
+                FORGET | BATCH_FORGET | INTERRUPT => continue,
+                DESTROY => {
+                    let _ = device.write_all(&reply_bytes(request.unique, Ok(Vec::new())));
+                    return;
+                }
+                _ => self.answer(&request),
+            };
+            // The kernel may have given up on the request
+            let _ = device.write_all(&reply_bytes(request.unique, reply));
+        }
+    }
+
+    fn answer(&mut self, request: &Request) -> Result<Vec<u8>, Errno> {
+        match request.opcode {
+            INIT => Ok(init(request.body)),
+            LOOKUP => {
+                let name = request.body.split(|&b| b == 0).next().unwrap_or_default();
+                let id = self.child(request.node, OsStr::from_bytes(name))?;
+                let mut out = Vec::new();
+                for n in [id, 0, TTL, TTL] {
+                    push_u64(&mut out, n);
+                }
+                push_u32(&mut out, 0);
+                push_u32(&mut out, 0);
+                self.attr(id, &mut out)?;
+                Ok(out)
+            }
+            GETATTR => {
+                let mut out = Vec::new();
+                push_u64(&mut out, TTL);
+                push_u32(&mut out, 0);
+                push_u32(&mut out, 0);
+                self.attr(request.node, &mut out)?;
+                Ok(out)
+            }
+            READLINK => match self.node(request.node)? {
+                Node::Symlink(target) => Ok(target.as_os_str().as_bytes().to_vec()),
+                _ => Err(Errno::EINVAL),
+            },
+            OPEN => {
+                let flags = read_u32(request.body, 0);
+                if flags & 0o3 != 0 {
+                    return Err(Errno::EROFS);
+                }
+                let Node::File(stream) = self.node(request.node)? else {
+                    return Err(Errno::EISDIR);
+                };
+                let path = self.fetch(&stream.clone())?;
+                let file = File::open(path).map_err(|e| errno(&e))?;
+                let fh = self.next_fh;
+                self.next_fh += 1;
+                self.files.insert(fh, file);
+                Ok(open_out(fh, FOPEN_KEEP_CACHE))
+            }
+            READ => {
+                let (fh, offset, size) = read_in(request.body);
+                let file = self.files.get(&fh).ok_or(Errno::EBADF)?;
+                let mut out = vec![0; size.min(MAX_WRITE) as usize];
+                let mut filled = 0;
+                while filled < out.len() {
+                    match file.read_at(&mut out[filled..], offset + filled as u64) {
+                        Ok(0) => break,
+                        Ok(n) => filled += n,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(e) => return Err(errno(&e)),
+                    }
+                }
+                out.truncate(filled);
+                Ok(out)
+            }
+            RELEASE => {
+                self.files.remove(&read_u64(request.body, 0));
+                Ok(Vec::new())
+            }
+            OPENDIR => match self.node(request.node)? {
+                Node::Dir { .. } => Ok(open_out(0, 0)),
+                _ => Err(Errno::ENOTDIR),
+            },
+            READDIR => {
+                let (_, offset, size) = read_in(request.body);
+                self.readdir(request.node, offset, size as usize)
+            }
+            RELEASEDIR => Ok(Vec::new()),
+            STATFS => {
+                let mut out = Vec::new();
+                for n in [0, 0, 0, self.nodes.len() as u64, 0] {
+                    push_u64(&mut out, n);
+                }
+                for n in [BLOCK_SIZE, 255, BLOCK_SIZE, 0, 0, 0, 0, 0, 0, 0] {
+                    push_u32(&mut out, n);
+                }
+                Ok(out)
+            }
+            _ => Err(Errno::ENOSYS),
+        }
+    }
+
+    fn node(&self, id: u64) -> Result<&Node, Errno> {
+        let i = id.checked_sub(1).ok_or(Errno::ENOENT)?;
+        usize::try_from(i)
+            .ok()
+            .and_then(|i| self.nodes.get(i))
+            .ok_or(Errno::ENOENT)
+    }
+
+    fn child(&self, parent: u64, name: &OsStr) -> Result<u64, Errno> {
+        let Node::Dir {
+            parent: grandparent,
+            children,
+            ..
+        } = self.node(parent)?
+        else {
+            return Err(Errno::ENOTDIR);
+        };
+        match name.as_bytes() {
+            b"." => Ok(parent),
+            b".." => Ok(*grandparent),
+            _ => children
+                .binary_search_by(|(child, _)| child.as_os_str().cmp(name))
+                .map(|i| children[i].1)
+                .map_err(|_| Errno::ENOENT),
+        }
+    }
+
+    /// The stream's contents in the store, downloading them first if need be.
+    fn fetch(&self, stream: &Stream) -> Result<PathBuf, Errno> {
+        let path = self.store.path_of(&stream.hash);
+        if path.is_file() {
+            return Ok(path);
+        }
+        self.handle
+            .block_on(stream.download_mirrored(&self.mirrors, &self.store, self.compression))
+            .map_err(|_| Errno::EIO)
+    }
+
+    /// Writes a `fuse_attr`.
+    fn attr(&self, id: u64, out: &mut Vec<u8>) -> Result<(), Errno> {
+        let (mode, size, nlink, mtime, owner) = match self.node(id)? {
+            Node::Dir { mode, .. } => (S_IFDIR | (mode & 0o7777), 0, 2, None, None),
+            Node::File(stream) => {
+                let size = match stream.disk_size {
+                    Some(size) => size,
+                    None => self.fetch(stream)?.metadata().map_err(|e| errno(&e))?.len(),
+                };
+                let mode = stream.mode.map_or(0o644, |mode| mode & 0o7777);
+                (S_IFREG | mode, size, 1, stream.mtime, stream.owner)
+            }
+            Node::Symlink(target) => {
+                let size = target.as_os_str().len() as u64;
+                (S_IFLNK | 0o777, size, 1, None, None)
+            }
+            Node::Special { mode, kind } => {
+                let kind = match kind {
+                    SpecialKind::Fifo => S_IFIFO,
+                };
+                (kind | (mode & 0o7777), 0, 1, None, None)
+            }
+        };
+        let mtime = mtime
+            .and_then(|mtime| u64::try_from(mtime).ok())
+            .unwrap_or(0);
+
+        for n in [id, size, size.div_ceil(512), mtime, mtime, mtime] {
+            push_u64(out, n);
+        }
+        let (uid, gid) = owner.map_or((0, 0), |owner| (owner.uid, owner.gid));
+        for n in [0, 0, 0, mode, nlink, uid, gid, 0, BLOCK_SIZE, 0] {
+            push_u32(out, n);
+        }
+        Ok(())
+    }
+
+    /// Writes as many `fuse_dirent`s as fit in `size`, starting after the `offset`th entry.
+    fn readdir(&self, id: u64, offset: u64, size: usize) -> Result<Vec<u8>, Errno> {
+        let Node::Dir {
+            parent, children, ..
+        } = self.node(id)?
+        else {
+            return Err(Errno::ENOTDIR);
+        };
+        let entries = [(OsStr::new("."), id), (OsStr::new(".."), *parent)]
+            .into_iter()
+            .chain(children.iter().map(|(name, id)| (name.as_os_str(), *id)));
+
+        let mut out = Vec::new();
+        for (i, (name, child)) in entries
+            .enumerate()
+            .skip(usize::try_from(offset).unwrap_or(usize::MAX))
+        {
+            let name = name.as_bytes();
+            let len = (24 + name.len()).next_multiple_of(8);
+            if out.len() + len > size {
+                break;
+            }
+            let kind = match self.node(child)? {
+                Node::Dir { .. } => libc_dt::DIR,
+                Node::File(_) => libc_dt::REG,
+                Node::Symlink(_) => libc_dt::LNK,
+                Node::Special { .. } => libc_dt::FIFO,
+            };
+            push_u64(&mut out, child);
+            push_u64(&mut out, i as u64 + 1);
+            push_u32(
+                &mut out,
+                u32::try_from(name.len()).map_err(|_| Errno::ENAMETOOLONG)?,
+            );
+            push_u32(&mut out, kind);
+            out.extend_from_slice(name);
+            out.resize(out.len().next_multiple_of(8), 0);
+        }
+        Ok(out)
+    }
+}
+
+/// `d_type` values of directory entries.
+mod libc_dt {
+    pub const FIFO: u32 = 1;
+    pub const DIR: u32 = 4;
+    pub const REG: u32 = 8;
+    pub const LNK: u32 = 10;
+}
+
+fn parse(buf: &[u8]) -> Option<Request<'_>> {
+    if buf.len() < IN_HEADER_LEN {
+        return None;
+    }
+    Some(Request {
+        opcode: read_u32(buf, 4),
+        unique: read_u64(buf, 8),
+        node: read_u64(buf, 16),
+        body: &buf[IN_HEADER_LEN..],
+    })
+}
+
+/// Answers `fuse_init_in` with a `fuse_init_out`, asking for no optional features.
+fn init(body: &[u8]) -> Vec<u8> {
+    let minor = read_u32(body, 4).min(31);
+    let max_readahead = read_u32(body, 8);
+    let mut out = Vec::new();
+    for n in [7, minor, max_readahead, 0] {
+        push_u32(&mut out, n);
+    }
+    // max_background and congestion_threshold
+    out.extend_from_slice(&[0; 4]);
+    push_u32(&mut out, MAX_WRITE);
+    // time_gran, then max_pages, map_alignment, flags2 and unused fields
+    push_u32(&mut out, 1);
+    out.resize(64, 0);
+    out
+}
+
+fn open_out(fh: u64, flags: u32) -> Vec<u8> {
+    let mut out = Vec::new();
+    push_u64(&mut out, fh);
+    push_u32(&mut out, flags);
+    push_u32(&mut out, 0);
+    out
+}
+
+/// The file handle, offset and size of a `fuse_read_in`.
+fn read_in(body: &[u8]) -> (u64, u64, u32) {
+    (read_u64(body, 0), read_u64(body, 8), read_u32(body, 16))
+}
+
+fn reply_bytes(unique: u64, reply: Result<Vec<u8>, Errno>) -> Vec<u8> {
+    let (error, body) = match reply {
+        Ok(body) => (0, body),
+        Err(errno) => (-(errno as i32), Vec::new()),
+    };
+    let mut out = Vec::with_capacity(OUT_HEADER_LEN + body.len());
+    // Replies never get near 4 GiB, and the kernel rejects ones with the wrong length
+    push_u32(
+        &mut out,
+        u32::try_from(OUT_HEADER_LEN + body.len()).unwrap_or(u32::MAX),
+    );
+    out.extend_from_slice(&error.to_le_bytes());
+    push_u64(&mut out, unique);
+    out.extend_from_slice(&body);
+    out
+}
+
+fn errno(e: &io::Error) -> Errno {
+    Errno::from_raw(e.raw_os_error().unwrap_or(Errno::EIO as i32))
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    buf.get(at..at + 4)
+        .and_then(|bytes| bytes.try_into().ok())
+        .map_or(0, u32::from_le_bytes)
+}
+
+fn read_u64(buf: &[u8], at: usize) -> u64 {
+    buf.get(at..at + 8)
+        .and_then(|bytes| bytes.try_into().ok())
+        .map_or(0, u64::from_le_bytes)
+}
+
+fn push_u32(out: &mut Vec<u8>, n: u32) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+fn push_u64(out: &mut Vec<u8>, n: u64) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::fs;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_fuse_mount() -> crate::Result<()> {
+        let compression = CompressionKind::Zstd;
+        let publisher_dir = TempDir::new()?;
+        let publisher = Store::new(publisher_dir.path());
+        let repo_dir = TempDir::new()?;
+        let repo_url = repo_dir.path().to_str().unwrap();
+        let original_dir = TempDir::new()?;
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let mountpoint = TempDir::new()?;
+
+        std::fs::create_dir(original_dir.path().join("bin"))?;
+        fs::write(original_dir.path().join("bin/tool"), b"#!/bin/sh").await?;
+        std::fs::set_permissions(
+            original_dir.path().join("bin/tool"),
+            std::fs::Permissions::from_mode(0o755),
+        )?;
+        fs::write(original_dir.path().join("readme"), b"hello").await?;
+        std::os::unix::fs::symlink("bin/tool", original_dir.path().join("tool"))?;
+        let tree = Tree::create(&publisher, original_dir.path(), compression).await?;
+        tree.push(repo_url, &publisher, compression).await?;
+
+        let mount = match FuseMount::mount(
+            &tree,
+            Mirrors::from(repo_url),
+            store.clone(),
+            compression,
+            mountpoint.path(),
+        ) {
+            Ok(mount) => mount,
+            // Not everywhere tests run can mount
+            Err(crate::Error::IoError(e))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied
+                ) =>
+            {
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let root = mount.mountpoint().to_path_buf();
+
+        // Browsing doesn't download anything
+        let mut names: Vec<_> = std::fs::read_dir(&root)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<io::Result<_>>()?;
+        names.sort();
+        assert_eq!(names, ["bin", "readme", "tool"]);
+        let metadata = std::fs::metadata(root.join("bin/tool"))?;
+        assert_eq!(metadata.len(), 9);
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o755);
+        assert_eq!(
+            std::fs::read_link(root.join("tool"))?,
+            Path::new("bin/tool")
+        );
+        assert!(!store.path_of(&tree.streams[0].hash).exists());
+
+        // Reading fetches only the file's stream
+        assert_eq!(std::fs::read(root.join("readme"))?, b"hello");
+        assert!(store.path_of(&tree.streams[0].hash).exists());
+        assert!(!store.path_of(&tree.subtrees[0].1.streams[0].hash).exists());
+        assert_eq!(std::fs::read(root.join("tool"))?, b"#!/bin/sh");
+        assert!(std::fs::write(root.join("readme"), b"changed").is_err());
+        assert!(std::fs::metadata(root.join("missing")).is_err());
+
+        mount.unmount()?;
+        assert!(!root.join("readme").exists());
+
+        Ok(())
+    }
+}
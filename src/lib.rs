@@ -2,11 +2,36 @@
 #![doc = include_str!("../README.md")]
 
 mod async_types;
+#[cfg(feature = "casync")]
+pub mod casync;
+pub mod clock;
 mod compression;
+#[cfg(feature = "config")]
+pub mod config;
+pub mod core;
+mod dictionary;
+mod encryption;
 mod error;
+mod event;
 mod fs;
+#[cfg(feature = "fuse")]
+pub mod fuse;
+pub mod metrics;
+mod mirrors;
+mod net;
+mod pool;
+pub mod repo;
+mod repo_set;
+pub mod rollout;
+#[cfg(feature = "server")]
+pub mod server;
+mod session;
+mod ssh;
+pub mod store;
 pub mod stream;
 pub mod tree;
 
 pub use compression::CompressionKind;
 pub use error::{Error, Result};
+pub use mirrors::Mirrors;
+pub use net::Timeouts;
@@ -0,0 +1,281 @@
+//! Counters for what downloads and deploys did, for feeding a monitoring system.
+//!
+//! Unlike [events](crate::core::Event), which report single operations worth a look, metrics are
+//! called for everything, see [`Store::with_metrics`](crate::store::Store::with_metrics).
+//! [`Counters`] keeps running totals, which it renders for Prometheus.
+use std::fmt::{self, Write as _};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Called by downloads and deploys as they go. Every method does nothing by default, so
+/// implementations only pick what they need. Calls come from whichever thread does the work,
+/// so implementations should be cheap.
+pub trait Metrics: Send + Sync {
+    /// Bytes received from a mirror, as stored in the repository, so usually compressed
+    fn downloaded(&self, _bytes: u64) {}
+
+    /// Bytes written into the store after decompressing, or copied into a deployment
+    fn written(&self, _bytes: u64) {}
+
+    /// A stream that wasn't downloaded or stored again, as the tree or store already had it
+    fn deduped(&self) {}
+
+    /// Time spent hashing `bytes` to verify a stream
+    fn hashed(&self, _bytes: u64, _elapsed: Duration) {}
+}
+
+/// Metrics that are thrown away, which stores use by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoMetrics;
+
+impl Metrics for NoMetrics {}
+
+/// Running totals of every metric, shared by cloning the [`Arc`] it's passed to stores in.
+#[derive(Debug, Default)]
+pub struct Counters {
+    downloaded: AtomicU64,
+    written: AtomicU64,
+    deduped: AtomicU64,
+    hashed: AtomicU64,
+    hash_nanos: AtomicU64,
+}
+
+impl Counters {
+    #[must_use]
+    pub fn new() -> Arc<Self> {
+        Arc::default()
+    }
+
+    #[must_use]
+    pub fn downloaded_bytes(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    #[must_use]
+    pub fn written_bytes(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    #[must_use]
+    pub fn deduped_streams(&self) -> u64 {
+        self.deduped.load(Ordering::Relaxed)
+    }
+
+    #[must_use]
+    pub fn hashed_bytes(&self) -> u64 {
+        self.hashed.load(Ordering::Relaxed)
+    }
+
+    #[must_use]
+    pub fn hash_time(&self) -> Duration {
+        Duration::from_nanos(self.hash_nanos.load(Ordering::Relaxed))
+    }
+
+    /// The totals in the Prometheus text format, as counters named `{prefix}_...`.
+    #[must_use]
+    pub fn to_prometheus(&self, prefix: &str) -> String {
+        let mut text = String::new();
+        let counters: [(&str, &str, &dyn fmt::Display); 5] = [
+            (
+                "downloaded_bytes_total",
+                "Bytes received from mirrors",
+                &self.downloaded_bytes(),
+            ),
+            (
+                "written_bytes_total",
+                "Bytes written into the store or deployments",
+                &self.written_bytes(),
+            ),
+            (
+                "deduped_streams_total",
+                "Streams that were already present",
+                &self.deduped_streams(),
+            ),
+            (
+                "hashed_bytes_total",
+                "Bytes hashed to verify streams",
+                &self.hashed_bytes(),
+            ),
+            (
+                "hash_seconds_total",
+                "Time spent hashing to verify streams",
+                &self.hash_time().as_secs_f64(),
+            ),
+        ];
+        for (name, help, value) in counters {
+            // Writing to a string can't fail
+            let _ = writeln!(
+                text,
+                "# HELP {prefix}_{name} {help}\n# TYPE {prefix}_{name} counter\n{prefix}_{name} {value}"
+            );
+        }
+        text
+    }
+}
+
+impl Metrics for Counters {
+    fn downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn written(&self, bytes: u64) {
+        self.written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn deduped(&self) {
+        self.deduped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn hashed(&self, bytes: u64, elapsed: Duration) {
+        self.hashed.fetch_add(bytes, Ordering::Relaxed);
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.hash_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+}
+
+/// Where a store sends metrics.
+#[derive(Clone)]
+pub(crate) struct MetricsSink(Arc<dyn Metrics>);
+
+impl MetricsSink {
+    pub(crate) fn new(metrics: Arc<dyn Metrics>) -> Self {
+        Self(metrics)
+    }
+
+    pub(crate) fn get(&self) -> &dyn Metrics {
+        &*self.0
+    }
+
+    /// A sink that reports to `metrics` as well as this one.
+    pub(crate) fn and(&self, metrics: Arc<dyn Metrics>) -> Self {
+        Self(Arc::new(Both(self.0.clone(), metrics)))
+    }
+}
+
+struct Both(Arc<dyn Metrics>, Arc<dyn Metrics>);
+
+impl Metrics for Both {
+    fn downloaded(&self, bytes: u64) {
+        self.0.downloaded(bytes);
+        self.1.downloaded(bytes);
+    }
+
+    fn written(&self, bytes: u64) {
+        self.0.written(bytes);
+        self.1.written(bytes);
+    }
+
+    fn deduped(&self) {
+        self.0.deduped();
+        self.1.deduped();
+    }
+
+    fn hashed(&self, bytes: u64, elapsed: Duration) {
+        self.0.hashed(bytes, elapsed);
+        self.1.hashed(bytes, elapsed);
+    }
+}
+
+impl Default for MetricsSink {
+    fn default() -> Self {
+        Self(Arc::new(NoMetrics))
+    }
+}
+
+impl fmt::Debug for MetricsSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MetricsSink(..)")
+    }
+}
+
+/// Sinks are only equal to their clones.
+impl PartialEq for MetricsSink {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for MetricsSink {}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::store::Store;
+    use crate::tree::Tree;
+    use crate::{CompressionKind, Mirrors, fs};
+
+    #[test]
+    fn test_counters() {
+        let counters = Counters::new();
+        counters.downloaded(10);
+        counters.written(20);
+        counters.written(5);
+        counters.deduped();
+        counters.hashed(25, Duration::from_millis(1500));
+
+        assert_eq!(counters.downloaded_bytes(), 10);
+        assert_eq!(counters.written_bytes(), 25);
+        assert_eq!(counters.deduped_streams(), 1);
+        assert_eq!(counters.hash_time(), Duration::from_millis(1500));
+
+        let text = counters.to_prometheus("syncstream");
+        assert!(text.contains("# TYPE syncstream_written_bytes_total counter\n"));
+        assert!(text.contains("\nsyncstream_written_bytes_total 25\n"));
+        assert!(text.contains("\nsyncstream_hash_seconds_total 1.5\n"));
+
+        // Sinks are only equal to their clones
+        let sink = MetricsSink::new(counters);
+        assert_eq!(sink, sink.clone());
+        assert_ne!(sink, MetricsSink::default());
+    }
+
+    #[tokio::test]
+    async fn test_create_metrics() -> crate::Result<()> {
+        let original_dir = TempDir::new()?;
+        fs::write(original_dir.path().join("a"), b"contents").await?;
+        fs::write(original_dir.path().join("b"), b"other contents").await?;
+        fs::write(original_dir.path().join("copy of a"), b"contents").await?;
+
+        // Uncompressed objects are the contents, which mustn't count as already stored
+        for compression in [CompressionKind::None, CompressionKind::Zstd] {
+            let counters = Counters::new();
+            let store_dir = TempDir::new()?;
+            let store = Store::new(store_dir.path()).with_metrics(counters.clone());
+            Tree::create(&store, original_dir.path(), compression).await?;
+            assert_eq!(counters.deduped_streams(), 1);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_metrics() -> crate::Result<()> {
+        let compression = CompressionKind::Zstd;
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let repo_dir = TempDir::new()?;
+        let repo_url = repo_dir.path().to_str().unwrap();
+        let original_dir = TempDir::new()?;
+
+        fs::write(original_dir.path().join("a"), b"contents").await?;
+        fs::write(original_dir.path().join("copy of a"), b"contents").await?;
+        let tree = Tree::create(&store, original_dir.path(), compression).await?;
+        tree.push(repo_url, &store, compression).await?;
+
+        let counters = Counters::new();
+        let local_dir = TempDir::new()?;
+        let local_store = Store::new(local_dir.path()).with_metrics(counters.clone());
+        tree.download_mirrored(&Mirrors::from(repo_url), &local_store, compression)
+            .await?;
+
+        assert!(counters.downloaded_bytes() > 0);
+        assert_eq!(counters.written_bytes(), 8);
+        assert_eq!(counters.hashed_bytes(), 8);
+        assert_eq!(counters.deduped_streams(), 1);
+
+        Ok(())
+    }
+}
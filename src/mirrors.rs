@@ -0,0 +1,425 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::compression::ObjectNaming;
+use crate::encryption::EncryptionKey;
+use crate::net;
+use crate::{CompressionKind, Timeouts};
+
+/// A prioritised list of repository URLs serving the same streams.
+///
+/// Downloads try each mirror in order, falling through to the next one when a stream is missing
+/// (404), the server errors (5xx), or the connection fails. Mirrors that fail repeatedly are
+/// considered dead and skipped for later streams, until every mirror is dead.
+#[derive(Debug)]
+pub struct Mirrors {
+    entries: Vec<Mirror>,
+    max_failures: u32,
+    compression_fallbacks: Vec<CompressionKind>,
+    naming: ObjectNaming,
+    timeouts: Timeouts,
+    client: reqwest::Client,
+    transfer: Transfer,
+    budget: Option<u64>,
+    resolver: Option<Resolver>,
+    headers: reqwest::header::HeaderMap,
+    encryption: Option<EncryptionKey>,
+    verify_blocks: bool,
+}
+
+type ResolveFn = dyn Fn(&str) -> String + Send + Sync;
+
+/// See [`Mirrors::url_resolver`].
+struct Resolver(Box<ResolveFn>);
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Resolver(..)")
+    }
+}
+
+#[derive(Debug)]
+struct Mirror {
+    url: String,
+    /// Consecutive failures, reset on success
+    failures: AtomicU32,
+    /// The compression kind the last stream was found in, tried first for the next one
+    compression: Mutex<Option<CompressionKind>>,
+}
+
+impl Mirrors {
+    /// # Panics
+    ///
+    /// - If the TLS backend can't be initialized, like `reqwest::Client::new`
+    pub fn new<I: IntoIterator<Item = S>, S: Into<String>>(urls: I) -> Self {
+        let timeouts = Timeouts::default();
+
+        Self {
+            entries: urls
+                .into_iter()
+                .map(|url| Mirror {
+                    url: url.into(),
+                    failures: AtomicU32::new(0),
+                    compression: Mutex::new(None),
+                })
+                .collect(),
+            max_failures: 3,
+            compression_fallbacks: Vec::new(),
+            naming: ObjectNaming::default(),
+            client: net::default_client().clone(),
+            timeouts,
+            transfer: Transfer::default(),
+            budget: None,
+            resolver: None,
+            headers: reqwest::header::HeaderMap::new(),
+            encryption: None,
+            verify_blocks: false,
+        }
+    }
+
+    /// How many consecutive failures it takes for a mirror to be skipped. Defaults to 3.
+    #[must_use]
+    pub fn max_failures(mut self, max_failures: u32) -> Self {
+        self.max_failures = max_failures;
+        self
+    }
+
+    /// Other compression kinds to try, in order, when a mirror doesn't have a stream in the
+    /// recorded or requested one. Useful for repositories with mixed or migrated compression.
+    #[must_use]
+    pub fn compression_fallbacks<I: IntoIterator<Item = CompressionKind>>(
+        mut self,
+        kinds: I,
+    ) -> Self {
+        self.compression_fallbacks = kinds.into_iter().collect();
+        self
+    }
+
+    /// Finds streams in whichever compression kind each mirror has them, trying every kind in
+    /// the order of [`CompressionKind::ALL`] after the recorded and requested ones. Once a
+    /// mirror served a stream, the kind it was in is tried first for the next ones, so a
+    /// repository that was compressed differently only costs extra requests for the first
+    /// stream.
+    #[must_use]
+    pub fn negotiate_compression(self) -> Self {
+        self.compression_fallbacks(CompressionKind::ALL)
+    }
+
+    /// The kinds to try on the mirror at `url`: the one the stream was
+    /// [recorded](crate::stream::Stream::compression) with, then the requested one, then the
+    /// fallbacks. With neither a recorded nor a requested kind, every kind is tried. When
+    /// there's more than one, the kind the mirror last served goes first.
+    pub(crate) fn compression_kinds(
+        &self,
+        url: &str,
+        requested: Option<CompressionKind>,
+        recorded: Option<CompressionKind>,
+    ) -> Vec<CompressionKind> {
+        let mut kinds = Vec::new();
+        for kind in recorded
+            .into_iter()
+            .chain(requested)
+            .chain(self.compression_fallbacks.iter().copied())
+        {
+            if !kinds.contains(&kind) {
+                kinds.push(kind);
+            }
+        }
+        if kinds.is_empty() {
+            kinds.extend(CompressionKind::ALL);
+        }
+
+        let negotiated = self
+            .mirror(url)
+            .and_then(|mirror| *mirror.compression.lock().expect("lock poisoned"));
+        if let Some(index) = negotiated.and_then(|kind| kinds.iter().position(|&k| k == kind)) {
+            kinds[..=index].rotate_right(1);
+        }
+        kinds
+    }
+
+    /// Remembers that the mirror at `url` had a stream in `kind`.
+    pub(crate) fn record_compression(&self, url: &str, kind: CompressionKind) {
+        if let Some(mirror) = self.mirror(url) {
+            *mirror.compression.lock().expect("lock poisoned") = Some(kind);
+        }
+    }
+
+    /// How the mirrors name compressed objects, for repositories that don't use the default
+    /// extensions.
+    #[must_use]
+    pub fn object_naming(mut self, naming: ObjectNaming) -> Self {
+        self.naming = naming;
+        self
+    }
+
+    pub(crate) fn naming(&self) -> &ObjectNaming {
+        &self.naming
+    }
+
+    /// Timeouts for every download from these mirrors. A mirror that times out counts as failed.
+    ///
+    /// # Panics
+    ///
+    /// - If the TLS backend can't be initialized, like `reqwest::Client::new`
+    #[must_use]
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.client = timeouts.client();
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub(crate) fn client(&self) -> (&reqwest::Client, &Timeouts) {
+        (&self.client, &self.timeouts)
+    }
+
+    /// Rewrites the URL of every object fetched over HTTP before requesting it, for repositories
+    /// behind pre-signed URLs or CDN tokens. When a request is refused with 403, the URL is
+    /// resolved again and requested once more, in case the previous one expired.
+    #[must_use]
+    pub fn url_resolver<F: Fn(&str) -> String + Send + Sync + 'static>(
+        mut self,
+        resolver: F,
+    ) -> Self {
+        self.resolver = Some(Resolver(Box::new(resolver)));
+        self
+    }
+
+    /// The URL to request for an object, see [`Mirrors::url_resolver`].
+    pub(crate) fn resolve(&self, url: &str) -> String {
+        match &self.resolver {
+            Some(resolver) => (resolver.0)(url),
+            None => url.to_string(),
+        }
+    }
+
+    pub(crate) fn has_resolver(&self) -> bool {
+        self.resolver.is_some()
+    }
+
+    /// Headers sent with every HTTP request, like `Authorization` for private repositories.
+    #[must_use]
+    pub fn headers(mut self, headers: reqwest::header::HeaderMap) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    pub(crate) fn request_headers(&self) -> &reqwest::header::HeaderMap {
+        &self.headers
+    }
+
+    /// Decrypts objects before decompressing them, for repositories created with
+    /// [`Store::with_encryption`](crate::store::Store::with_encryption).
+    #[must_use]
+    pub fn encryption(mut self, key: EncryptionKey) -> Self {
+        self.encryption = Some(key);
+        self
+    }
+
+    pub(crate) fn encryption_key(&self) -> Option<&EncryptionKey> {
+        self.encryption.as_ref()
+    }
+
+    /// Verifies each block of a stream before it's written, using its
+    /// [outboard](crate::stream::Outboard) when the mirror has one. Streams without one are
+    /// still verified once they're complete.
+    #[must_use]
+    pub fn verify_blocks(mut self) -> Self {
+        self.verify_blocks = true;
+        self
+    }
+
+    pub(crate) fn verifies_blocks(&self) -> bool {
+        self.verify_blocks
+    }
+
+    pub(crate) fn transfer(&self) -> &Transfer {
+        &self.transfer
+    }
+
+    /// Bytes downloaded through these mirrors so far, across every mirror. Keep one `Mirrors`
+    /// per session to account for it separately.
+    #[must_use]
+    pub fn transfer_totals(&self) -> TransferTotals {
+        self.transfer.totals()
+    }
+
+    /// Starts counting transferred bytes from zero again.
+    pub fn reset_transfer_totals(&self) {
+        self.transfer.downloaded.store(0, Ordering::Relaxed);
+        self.transfer.decompressed.store(0, Ordering::Relaxed);
+    }
+
+    /// Stops fetching new objects once `bytes` were downloaded, for metered connections.
+    /// Downloads already in progress still finish, so the budget can be overshot by up to one
+    /// object per concurrent download.
+    ///
+    /// The budget covers the same bytes as [`TransferTotals::downloaded`]. For a daily or monthly
+    /// cap, call [`Mirrors::reset_transfer_totals`] at the start of each period.
+    #[must_use]
+    pub fn budget(mut self, bytes: u64) -> Self {
+        self.budget = Some(bytes);
+        self
+    }
+
+    /// Fails with [`Error::BudgetExceeded`](crate::Error::BudgetExceeded) once the budget is
+    /// used up.
+    pub(crate) fn check_budget(&self) -> crate::Result<()> {
+        match self.budget {
+            Some(budget) if self.transfer.downloaded.load(Ordering::Relaxed) >= budget => {
+                Err(crate::Error::BudgetExceeded(budget))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// All mirror URLs, in priority order.
+    pub fn urls(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|m| m.url.as_str())
+    }
+
+    /// Whether the mirror is still being tried. Unknown URLs are never healthy.
+    #[must_use]
+    pub fn is_healthy(&self, url: &str) -> bool {
+        self.entries
+            .iter()
+            .any(|m| m.url == url && self.mirror_is_healthy(m))
+    }
+
+    /// Forgets all previous failures, so that dead mirrors are tried again.
+    pub fn reset(&self) {
+        for mirror in &self.entries {
+            mirror.failures.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn mirror_is_healthy(&self, mirror: &Mirror) -> bool {
+        mirror.failures.load(Ordering::Relaxed) < self.max_failures
+    }
+
+    /// The mirrors to try, in order. When every mirror is dead, all of them are tried anyway.
+    pub(crate) fn candidates(&self) -> Vec<&str> {
+        let healthy: Vec<&str> = self
+            .entries
+            .iter()
+            .filter(|m| self.mirror_is_healthy(m))
+            .map(|m| m.url.as_str())
+            .collect();
+
+        if healthy.is_empty() {
+            self.urls().collect()
+        } else {
+            healthy
+        }
+    }
+
+    fn mirror(&self, url: &str) -> Option<&Mirror> {
+        self.entries.iter().find(|m| m.url == url)
+    }
+
+    pub(crate) fn record_success(&self, url: &str) {
+        if let Some(mirror) = self.mirror(url) {
+            mirror.failures.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_failure(&self, url: &str) {
+        if let Some(mirror) = self.mirror(url) {
+            mirror.failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Bytes transferred, see [`Mirrors::transfer_totals`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TransferTotals {
+    /// Bytes received, as stored in the repository, so usually compressed
+    pub downloaded: u64,
+    /// Bytes written into the store after decompressing
+    pub decompressed: u64,
+}
+
+/// Running totals, shared by every download through the same mirrors.
+#[derive(Debug, Default)]
+pub(crate) struct Transfer {
+    pub(crate) downloaded: AtomicU64,
+    pub(crate) decompressed: AtomicU64,
+}
+
+impl Transfer {
+    pub(crate) fn totals(&self) -> TransferTotals {
+        TransferTotals {
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            decompressed: self.decompressed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl From<&str> for Mirrors {
+    fn from(url: &str) -> Self {
+        Self::new([url])
+    }
+}
+
+/// How a failed download from a single mirror should be handled.
+pub(crate) enum Fallthrough {
+    /// The mirror works, but doesn't have the object
+    Missing,
+    /// The mirror is misbehaving or unreachable
+    Unhealthy,
+    /// The error is local, so other mirrors won't help
+    Fatal,
+}
+
+impl Fallthrough {
+    pub(crate) fn classify(error: &crate::Error) -> Self {
+        match error {
+            crate::Error::NetworkError(e) if e.status() == Some(reqwest::StatusCode::NOT_FOUND) => {
+                Self::Missing
+            }
+            crate::Error::NotFound(_) => Self::Missing,
+            crate::Error::NetworkError(_)
+            | crate::Error::SshError(_)
+            | crate::Error::Timeout
+            | crate::Error::HashError(..) => Self::Unhealthy,
+            // Errors while reading the response body are wrapped as IO errors
+            crate::Error::IoError(e)
+                if e.get_ref()
+                    .is_some_and(<dyn std::error::Error + Send + Sync>::is::<reqwest::Error>) =>
+            {
+                Self::Unhealthy
+            }
+            _ => Self::Fatal,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mirrors_health() {
+        let mirrors = Mirrors::new(["a", "b"]).max_failures(2);
+        assert_eq!(mirrors.candidates(), ["a", "b"]);
+
+        mirrors.record_failure("a");
+        assert!(mirrors.is_healthy("a"));
+        mirrors.record_failure("a");
+        assert!(!mirrors.is_healthy("a"));
+        assert_eq!(mirrors.candidates(), ["b"]);
+
+        // Every mirror being dead means they're all tried again
+        mirrors.record_failure("b");
+        mirrors.record_failure("b");
+        assert_eq!(mirrors.candidates(), ["a", "b"]);
+
+        mirrors.record_success("b");
+        assert_eq!(mirrors.candidates(), ["b"]);
+
+        mirrors.reset();
+        assert!(mirrors.is_healthy("a"));
+        assert!(!mirrors.is_healthy("c"));
+    }
+}
@@ -0,0 +1,174 @@
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::async_types::{AsyncBufRead, AsyncRead};
+use crate::metrics::Metrics;
+
+use crate::ssh::Remote;
+
+/// Timeouts applied to network operations.
+///
+/// By default connections and reads time out, so that a stalled server can't hang a download
+/// forever, but a whole stream may take as long as it needs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Maximum time to establish a connection
+    pub connect: Option<Duration>,
+    /// Maximum time to wait for each read, including the response headers
+    pub read: Option<Duration>,
+    /// Maximum time for a whole stream, from connecting until the last byte is received
+    pub stream: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Some(Duration::from_secs(30)),
+            read: Some(Duration::from_secs(60)),
+            stream: None,
+        }
+    }
+}
+
+impl Timeouts {
+    /// No timeouts at all.
+    #[must_use]
+    pub fn none() -> Self {
+        Self {
+            connect: None,
+            read: None,
+            stream: None,
+        }
+    }
+
+    /// Builds a HTTP client with the connection and read timeouts applied.
+    ///
+    /// # Panics
+    ///
+    /// - If the TLS backend can't be initialized, like `reqwest::Client::new`
+    pub(crate) fn client(&self) -> reqwest::Client {
+        let mut builder = reqwest::Client::builder();
+        if let Some(connect) = self.connect {
+            builder = builder.connect_timeout(connect);
+        }
+        if let Some(read) = self.read {
+            builder = builder.read_timeout(read);
+        }
+
+        builder
+            .build()
+            .expect("failed to initialize the HTTP client")
+    }
+
+    /// Applies the whole stream timeout to a request.
+    pub(crate) fn apply(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match self.stream {
+            Some(stream) => request.timeout(stream),
+            None => request,
+        }
+    }
+}
+
+/// A strong `ETag` for a file in a repository, which changes whenever it's replaced or written
+/// to: its size and mtime, like most web servers use.
+pub(crate) fn etag(metadata: &std::fs::Metadata) -> Option<String> {
+    let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(format!("\"{:x}-{:x}\"", metadata.len(), mtime.as_nanos()))
+}
+
+/// A shared client using the default timeouts, for one-off downloads.
+pub(crate) fn default_client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| Timeouts::default().client())
+}
+
+/// Where a repository URL points to.
+pub(crate) enum Location<'a> {
+    Http(&'a str),
+    /// `file://` URLs and plain paths refer to repositories on the local filesystem, such as an
+    /// NFS mount or USB disk
+    Local(&'a Path),
+    Ssh(Remote<'a>),
+}
+
+impl<'a> Location<'a> {
+    pub(crate) fn parse(url: &'a str) -> Self {
+        if let Some(remote) = Remote::parse(url) {
+            Self::Ssh(remote)
+        } else if let Some(path) = url.strip_prefix("file://") {
+            Self::Local(Path::new(path))
+        } else if url.contains("://") {
+            Self::Http(url)
+        } else {
+            Self::Local(Path::new(url))
+        }
+    }
+}
+
+/// A reader that adds every byte read from it to a counter, and reports them as
+/// [downloaded](Metrics::downloaded).
+pub(crate) struct Counted<'a, R> {
+    inner: R,
+    count: &'a AtomicU64,
+    metrics: &'a dyn Metrics,
+}
+
+impl<'a, R> Counted<'a, R> {
+    pub(crate) fn new(inner: R, count: &'a AtomicU64, metrics: &'a dyn Metrics) -> Self {
+        Self {
+            inner,
+            count,
+            metrics,
+        }
+    }
+
+    fn add(&self, n: usize) {
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        self.metrics.downloaded(n as u64);
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<R: AsyncRead + Unpin> AsyncRead for Counted<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.add(buf.filled().len() - before);
+        res
+    }
+}
+
+#[cfg(not(feature = "tokio"))]
+impl<R: AsyncRead + Unpin> AsyncRead for Counted<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.add(n);
+        }
+        res
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncBufRead for Counted<'_, R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().inner).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.inner).consume(amt);
+        self.add(amt);
+    }
+}
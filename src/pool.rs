@@ -0,0 +1,218 @@
+//! Where CPU-bound work like hashing and compression runs.
+//!
+//! By default it's handed to the runtime's blocking threads, so it doesn't stall async tasks.
+//! Applications embedding SyncStream can instead give it a fixed number of dedicated threads,
+//! optionally at a lower priority, to bound how much CPU it takes from everything else.
+use futures_channel::oneshot;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::pin;
+use std::sync::{Arc, Mutex, mpsc};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Runs CPU-bound work, see [`Store::with_cpu_pool`](crate::store::Store::with_cpu_pool).
+///
+/// Pools are cheap to clone, and clones share their threads. Dedicated threads are started on
+/// the first job and stop once every clone is dropped.
+#[derive(Clone, Default)]
+pub struct CpuPool {
+    threads: Option<usize>,
+    nice: Option<i32>,
+    workers: Arc<Mutex<Option<mpsc::Sender<Job>>>>,
+}
+
+impl fmt::Debug for CpuPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CpuPool")
+            .field("threads", &self.threads)
+            .field("nice", &self.nice)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for CpuPool {
+    fn eq(&self, other: &Self) -> bool {
+        self.threads == other.threads && self.nice == other.nice
+    }
+}
+
+impl Eq for CpuPool {}
+
+impl CpuPool {
+    /// Runs work on the runtime's blocking threads, or a new thread per job without the `tokio`
+    /// feature.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs work on this many dedicated threads, at least one.
+    #[must_use]
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads.max(1));
+        self.workers = Arc::default();
+        self
+    }
+
+    /// Runs work on dedicated threads at this nice level, like `10` to yield to everything else.
+    /// Uses a thread per CPU unless [limited](Self::threads). Only applied on Linux, where
+    /// raising it past the process' own level takes `CAP_SYS_NICE`.
+    #[must_use]
+    pub fn nice(mut self, level: i32) -> Self {
+        self.nice = Some(level);
+        self.workers = Arc::default();
+        self
+    }
+
+    fn is_dedicated(&self) -> bool {
+        self.threads.is_some() || self.nice.is_some()
+    }
+
+    /// Runs `job` on the pool and waits for it.
+    ///
+    /// # Errors
+    ///
+    /// - Failing to start threads
+    /// - The job panicking
+    pub async fn run<T, F>(&self, job: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        #[cfg(feature = "tokio")]
+        if !self.is_dedicated() {
+            return tokio::task::spawn_blocking(job)
+                .await
+                .map_err(io::Error::other);
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            // The caller may have stopped waiting
+            let _ = sender.send(job());
+        });
+
+        if self.is_dedicated() {
+            self.submit(job)?;
+        } else {
+            thread::Builder::new()
+                .name("syncstream-cpu".to_string())
+                .spawn(job)?;
+        }
+
+        receiver
+            .await
+            .map_err(|_| io::Error::other("CPU pool job panicked"))
+    }
+
+    /// Queues a job on the dedicated threads, starting them first if needed.
+    fn submit(&self, job: Job) -> io::Result<()> {
+        let mut workers = self
+            .workers
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+
+        if workers.is_none() {
+            let threads = self
+                .threads
+                .unwrap_or_else(|| thread::available_parallelism().map_or(1, usize::from));
+            let (sender, receiver) = mpsc::channel::<Job>();
+            let receiver = Arc::new(Mutex::new(receiver));
+
+            for _ in 0..threads {
+                let receiver = Arc::clone(&receiver);
+                let nice = self.nice;
+                thread::Builder::new()
+                    .name("syncstream-cpu".to_string())
+                    .spawn(move || worker(&receiver, nice))?;
+            }
+            *workers = Some(sender);
+        }
+
+        workers
+            .as_ref()
+            .and_then(|sender| sender.send(job).ok())
+            .ok_or_else(|| io::Error::other("CPU pool threads stopped"))
+    }
+}
+
+fn worker(receiver: &Mutex<mpsc::Receiver<Job>>, nice: Option<i32>) {
+    #[cfg(target_os = "linux")]
+    if let Some(nice) = nice {
+        // Best effort, the work still gets done at the default priority
+        let _ = rustix::process::setpriority_process(Some(rustix::thread::gettid()), nice);
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = nice;
+
+    loop {
+        let job = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+        // Every pool handle was dropped
+        let Ok(job) = job else { return };
+        // A panicking job only loses its own result
+        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(job));
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Drives a future to completion on the current thread, for async encoders writing to blocking
+/// sinks inside pool jobs.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cpu_pool() -> io::Result<()> {
+        assert_eq!(CpuPool::new().run(|| 1 + 1).await?, 2);
+
+        let pool = CpuPool::new().threads(2).nice(5);
+        let names: Vec<Option<String>> = {
+            let mut names = Vec::new();
+            for _ in 0..4 {
+                names.push(
+                    pool.run(|| thread::current().name().map(str::to_string))
+                        .await?,
+                );
+            }
+            names
+        };
+        assert!(
+            names
+                .iter()
+                .all(|name| name.as_deref() == Some("syncstream-cpu"))
+        );
+
+        // A panicking job fails on its own, and the threads keep working
+        assert!(pool.run(|| panic!("job")).await.is_err());
+        assert_eq!(pool.run(|| "still running").await?, "still running");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,28 @@
+//! High-level APIs for working with repositories: trees and their manifests, download planning,
+//! mirrors and deployment.
+//!
+//! New subsystems are added here, so they can evolve without affecting users who only need the
+//! primitives in [`core`](crate::core).
+pub use crate::mirrors::{Mirrors, TransferTotals};
+pub use crate::net::Timeouts;
+pub use crate::repo_set::{RepoSet, SourceReport};
+pub use crate::session::{SessionStats, SyncSession};
+pub use crate::tree::builder::TreeBuilder;
+pub use crate::tree::compact::CompactTree;
+pub use crate::tree::create::{CreateOptions, FollowSymlinks};
+pub use crate::tree::delta::TreeDelta;
+pub use crate::tree::deploy_plan::{DeployAction, DeployPlan};
+pub use crate::tree::deployment::Deployment;
+pub use crate::tree::diff::{Change, DiffStats, Node, TreeDiff};
+pub use crate::tree::filter::Filter;
+pub use crate::tree::manifest::{Entry, ManifestReader, ManifestValidators};
+pub use crate::tree::merge::MergePolicy;
+pub use crate::tree::meta::TreeMeta;
+pub use crate::tree::plan::DownloadPlan;
+pub use crate::tree::profile::{Generation, Profile};
+pub use crate::tree::refs::{RefCheck, Repair, Update};
+pub use crate::tree::report::{StreamFailure, TreeReport};
+pub use crate::tree::verify::{Drift, DriftReport};
+pub use crate::tree::view::{EntryRef, SpecialRef, StreamRef, SymlinkRef, TreeRef};
+pub use crate::tree::walk::TreeEntry;
+pub use crate::tree::{SpecialFile, SpecialKind, Symlink, Tree, TreePath};
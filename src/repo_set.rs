@@ -0,0 +1,204 @@
+//! Fetching from several repositories, like a local cache, a regional mirror and the origin.
+//!
+//! Unlike [`Mirrors`], which are copies of one repository sharing the same settings, every
+//! repository in a [`RepoSet`] has its own mirrors, credentials and compression. Each object is
+//! fetched from the first repository that has it, and the [`SourceReport`] says which one that
+//! was.
+use std::collections::BTreeMap;
+
+use crate::CompressionKind;
+use crate::mirrors::{Fallthrough, Mirrors};
+use crate::store::Store;
+use crate::stream::Stream;
+
+/// Repositories in priority order, see the [module docs](self).
+#[derive(Debug, Default)]
+pub struct RepoSet {
+    repos: Vec<Repo>,
+}
+
+#[derive(Debug)]
+struct Repo {
+    name: String,
+    mirrors: Mirrors,
+    compression: CompressionKind,
+}
+
+impl RepoSet {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a repository, tried after every one added before it. `name` identifies it in
+    /// reports.
+    #[must_use]
+    pub fn repo<S: Into<String>>(
+        mut self,
+        name: S,
+        mirrors: Mirrors,
+        compression: CompressionKind,
+    ) -> Self {
+        self.repos.push(Repo {
+            name: name.into(),
+            mirrors,
+            compression,
+        });
+        self
+    }
+
+    /// The repositories' names, in priority order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.repos.iter().map(|repo| repo.name.as_str())
+    }
+
+    /// The mirrors of the repository called `name`, for their health and transfer totals.
+    #[must_use]
+    pub fn mirrors(&self, name: &str) -> Option<&Mirrors> {
+        self.repos
+            .iter()
+            .find(|repo| repo.name == name)
+            .map(|repo| &repo.mirrors)
+    }
+
+    /// Downloads a stream from the first repository that can serve it, returning that
+    /// repository's name.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically out of space)
+    /// - The error from the last repository tried, if none could serve the stream
+    pub async fn download_stream(&self, stream: &Stream, store: &Store) -> crate::Result<&str> {
+        let mut last_error = None;
+
+        for repo in &self.repos {
+            match stream
+                .download_mirrored(&repo.mirrors, store, repo.compression)
+                .await
+            {
+                Ok(_) => return Ok(&repo.name),
+                // Another repository may still have it, or be reachable
+                Err(e) if !matches!(Fallthrough::classify(&e), Fallthrough::Fatal) => {
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(last_error
+            .unwrap_or_else(|| std::io::Error::other("no repositories configured").into()))
+    }
+
+    /// Downloads every stream, each from the first repository that can serve it. Streams
+    /// sharing a hash are only downloaded once.
+    ///
+    /// # Errors
+    ///
+    /// - See [`RepoSet::download_stream`]
+    pub async fn download_streams<'a, I: IntoIterator<Item = &'a Stream>>(
+        &self,
+        streams: I,
+        store: &Store,
+    ) -> crate::Result<SourceReport> {
+        store.clean_before_download()?;
+        let mut report = SourceReport::default();
+
+        for stream in streams {
+            if report.sources.contains_key(stream.hash.as_str()) {
+                continue;
+            }
+            let name = self.download_stream(stream, store).await?;
+            report
+                .sources
+                .insert(stream.hash.to_string(), name.to_string());
+        }
+
+        Ok(report)
+    }
+}
+
+/// Where each object came from, see [`RepoSet::download_streams`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SourceReport {
+    /// The name of the repository that served each stream, by hash
+    pub sources: BTreeMap<String, String>,
+}
+
+impl SourceReport {
+    /// The repository that served the stream, if it was downloaded.
+    #[must_use]
+    pub fn source_of(&self, hash: &str) -> Option<&str> {
+        self.sources.get(hash).map(String::as_str)
+    }
+
+    /// How many streams the repository served.
+    #[must_use]
+    pub fn count(&self, name: &str) -> usize {
+        self.sources
+            .values()
+            .filter(|source| *source == name)
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::fs;
+    use crate::stream::ObjectHash;
+    use crate::tree::Tree;
+
+    #[tokio::test]
+    async fn test_repo_set() -> crate::Result<()> {
+        let cache_dir = TempDir::new()?;
+        let origin_dir = TempDir::new()?;
+        let original_dir = TempDir::new()?;
+        let local_dir = TempDir::new()?;
+        std::fs::create_dir(cache_dir.path().join("streams"))?;
+        std::fs::create_dir(origin_dir.path().join("streams"))?;
+
+        // The cache only has some of the tree, and uses other compression than the origin
+        fs::write(original_dir.path().join("cached"), b"cached").await?;
+        let cache_store = Store::new(cache_dir.path().join("streams"));
+        Tree::create(&cache_store, original_dir.path(), CompressionKind::None).await?;
+        fs::write(original_dir.path().join("other"), b"other").await?;
+        fs::write(original_dir.path().join("copy"), b"other").await?;
+        let origin_store = Store::new(origin_dir.path().join("streams"));
+        let tree = Tree::create(&origin_store, original_dir.path(), CompressionKind::Zstd).await?;
+
+        let repos = RepoSet::new()
+            .repo(
+                "cache",
+                Mirrors::from(cache_dir.path().to_str().unwrap()),
+                CompressionKind::None,
+            )
+            .repo(
+                "origin",
+                Mirrors::from(origin_dir.path().to_str().unwrap()),
+                CompressionKind::Zstd,
+            );
+        assert_eq!(repos.names().collect::<Vec<_>>(), ["cache", "origin"]);
+
+        let local_store = Store::new(local_dir.path());
+        let report = repos.download_streams(&tree.streams, &local_store).await?;
+
+        let cached = blake3::hash(b"cached").to_hex().to_string();
+        let other = blake3::hash(b"other").to_hex().to_string();
+        assert_eq!(report.source_of(&cached), Some("cache"));
+        assert_eq!(report.source_of(&other), Some("origin"));
+        // Both files with the same contents only took one download
+        assert_eq!((report.count("cache"), report.count("origin")), (1, 1));
+        assert!(local_store.contains(&other));
+
+        // Nothing serves streams missing everywhere
+        let missing = Stream {
+            hash: ObjectHash::of(b"missing"),
+            ..tree.streams[0].clone()
+        };
+        assert!(repos.download_stream(&missing, &local_store).await.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+//! Staging updates across a fleet.
+//!
+//! A [`Rollout`] decides whether a machine should move to a new tree yet, so that agents built on
+//! this crate don't all update at once. Machines are selected by hashing their id with the tree's
+//! hash, and raising the percentage later only adds machines, never drops ones already updated.
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// A daily window, in seconds since midnight UTC. Windows ending before they start wrap around
+/// midnight.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Window {
+    start: u64,
+    end: u64,
+}
+
+impl Window {
+    fn contains(self, seconds: u64) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&seconds)
+        } else {
+            seconds >= self.start || seconds < self.end
+        }
+    }
+}
+
+/// When and where updates are applied. By default every machine updates at any time.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rollout {
+    percentage: u8,
+    windows: Vec<Window>,
+}
+
+impl Default for Rollout {
+    fn default() -> Self {
+        Self {
+            percentage: 100,
+            windows: Vec::new(),
+        }
+    }
+}
+
+impl Rollout {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only updates this percentage of machines, up to 100.
+    #[must_use]
+    pub fn percentage(mut self, percent: u8) -> Self {
+        self.percentage = percent.min(100);
+        self
+    }
+
+    /// Only updates between `start` and `end` after midnight UTC, like `22:00` to `04:00`.
+    /// Can be called more than once for several windows.
+    #[must_use]
+    pub fn window(mut self, start: Duration, end: Duration) -> Self {
+        self.windows.push(Window {
+            start: start.as_secs() % DAY,
+            end: end.as_secs() % DAY,
+        });
+        self
+    }
+
+    /// Whether the machine is among those the tree is rolled out to.
+    #[must_use]
+    pub fn is_selected(&self, machine_id: &str, tree_hash: &str) -> bool {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&(machine_id.len() as u64).to_le_bytes());
+        hasher.update(machine_id.as_bytes());
+        hasher.update(tree_hash.as_bytes());
+
+        let mut bucket = [0; 8];
+        bucket.copy_from_slice(&hasher.finalize().as_bytes()[..8]);
+        u64::from_le_bytes(bucket) % 100 < u64::from(self.percentage)
+    }
+
+    /// Whether `now` is inside a maintenance window, or there are none.
+    #[must_use]
+    pub fn in_window(&self, now: SystemTime) -> bool {
+        let seconds = now
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs() % DAY);
+        self.windows.is_empty() || self.windows.iter().any(|window| window.contains(seconds))
+    }
+
+    /// Whether the machine should update to the tree at `now`.
+    #[must_use]
+    pub fn allows(&self, machine_id: &str, tree_hash: &str, now: SystemTime) -> bool {
+        self.in_window(now) && self.is_selected(machine_id, tree_hash)
+    }
+
+    /// This machine's id, from `/etc/machine-id`.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically the file missing outside systemd)
+    pub fn machine_id() -> io::Result<String> {
+        Ok(std::fs::read_to_string("/etc/machine-id")?
+            .trim()
+            .to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rollout_percentage() {
+        let machines: Vec<String> = (0..1000).map(|i| format!("machine-{i}")).collect();
+        let selected = |rollout: &Rollout| -> Vec<&String> {
+            machines
+                .iter()
+                .filter(|machine| rollout.is_selected(machine, "tree"))
+                .collect()
+        };
+
+        assert!(selected(&Rollout::new().percentage(0)).is_empty());
+        assert_eq!(selected(&Rollout::new()).len(), machines.len());
+
+        let canaries = selected(&Rollout::new().percentage(10));
+        assert!((50..150).contains(&canaries.len()));
+
+        // Widening the rollout keeps the machines that already updated
+        let wider = selected(&Rollout::new().percentage(50));
+        assert!(canaries.iter().all(|machine| wider.contains(machine)));
+    }
+
+    #[test]
+    fn test_rollout_windows() {
+        let at = |hour: u64| UNIX_EPOCH + Duration::from_secs(DAY * 1000 + hour * 60 * 60);
+        let hours = |hour: u64| Duration::from_secs(hour * 60 * 60);
+
+        assert!(Rollout::new().in_window(at(12)));
+
+        let overnight = Rollout::new().window(hours(22), hours(4));
+        assert!(overnight.in_window(at(23)));
+        assert!(overnight.in_window(at(3)));
+        assert!(!overnight.in_window(at(12)));
+
+        let daytime = overnight.window(hours(12), hours(13));
+        assert!(daytime.in_window(at(12)));
+        assert!(!daytime.allows("machine", "tree", at(14)));
+    }
+}
@@ -0,0 +1,778 @@
+//! A minimal HTTP server for hosting a stream store, so that a plain directory created with
+//! [`Stream::create`](crate::stream::Stream::create) or [`Tree::create`](crate::tree::Tree::create)
+//! can be served without a separate web server.
+//!
+//! Objects are served under `/streams/{hash}.{ext}`, with support for `HEAD` and `Range` requests.
+//! They are streamed from disk in chunks, so objects of any size never have to fit in memory.
+//! Every response carries an `ETag` built from the file's size and mtime, which `If-Range` and
+//! `If-None-Match` compare, so that interrupted downloads can be resumed safely and pollers only
+//! download manifests that changed.
+//!
+//! A repository's manifests, `/trees/{name}` and `/index.json`, can be served too. Unlike
+//! objects, which are already compressed, they are compressed on the fly with zstd for clients
+//! that accept it. Names may contain `/`, for references like `myapp/stable`, see
+//! [`Tree::publish`](crate::tree::Tree::publish).
+use axum::Router;
+use axum::body::Body;
+use axum::extract::{Path as UrlPath, Request, State};
+use axum::http::{HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::net::TcpListener;
+use tower_http::compression::CompressionLayer;
+use tower_http::services::ServeFile;
+
+use crate::async_types::{AsyncWriteExt, StreamExt};
+use crate::encryption::EncryptionKey;
+use crate::store::{hash_object, object_hash};
+use crate::tree::refs::is_valid_ref;
+use crate::{CompressionKind, fs, net};
+
+#[derive(Clone, Debug)]
+pub struct Server {
+    stream_dir: PathBuf,
+    allow_uploads: bool,
+    fanout: usize,
+    manifest_dir: Option<PathBuf>,
+    encryption: Option<EncryptionKey>,
+}
+
+impl Server {
+    /// Creates a read-only server for the given stream directory.
+    #[must_use]
+    pub fn new<P: Into<PathBuf>>(stream_dir: P) -> Self {
+        Self {
+            stream_dir: stream_dir.into(),
+            allow_uploads: false,
+            fanout: 0,
+            manifest_dir: None,
+            encryption: None,
+        }
+    }
+
+    /// Also serve `{dir}/trees/{name}` as `/trees/{name}` and `{dir}/index.json` as
+    /// `/index.json`, compressed for clients that accept `zstd`.
+    #[must_use]
+    pub fn manifests<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.manifest_dir = Some(dir.into());
+        self
+    }
+
+    /// Keeps objects in nested directories named after the first bytes of their hash, one
+    /// level per byte, so that `ab/cd/abcd…` is served as `/streams/abcd…`. Each level divides
+    /// the objects per directory by 256, for repositories with millions of objects.
+    ///
+    /// URLs stay the same, so clients don't need to know about the layout. Defaults to 0, for
+    /// a flat directory like a [`Store`](crate::store::Store).
+    #[must_use]
+    pub fn fanout(mut self, levels: usize) -> Self {
+        self.fanout = levels;
+        self
+    }
+
+    /// Where an object is kept on disk, or `None` if its hash is too short for the fanout.
+    ///
+    /// Only `{hash}` or `{hash}.{ext}` are allowed, so that requests can never escape the stream
+    /// directory.
+    fn object_path(&self, name: &str) -> Option<PathBuf> {
+        let hash = object_hash(name)?;
+        let mut path = self.stream_dir.clone();
+        for level in 0..self.fanout {
+            path.push(hash.get(level * 2..level * 2 + 2)?);
+        }
+        path.push(name);
+        Some(path)
+    }
+
+    /// Accept `PUT /streams/{hash}.{ext}` uploads into the stream directory, and
+    /// `PUT /trees/{name}` into the manifest directory if [serving one](Self::manifests).
+    ///
+    /// Existing objects are never overwritten, while manifests are replaced atomically.
+    ///
+    /// Streams, uncompressed or compressed, are only kept if their contents match the hash they
+    /// are uploaded as. Other objects, like outboards, block indexes and streams compressed with
+    /// a dictionary, are kept as they are, as clients check what they get from them against the
+    /// stream's hash anyway.
+    #[must_use]
+    pub fn allow_uploads(mut self, allow_uploads: bool) -> Self {
+        self.allow_uploads = allow_uploads;
+        self
+    }
+
+    /// Decrypts uploaded compressed streams with `key` to check their hash, for repositories
+    /// whose objects are [encrypted](crate::store::Store::with_encryption). Without it, their
+    /// uploads are rejected.
+    #[must_use]
+    pub fn encryption(mut self, key: EncryptionKey) -> Self {
+        self.encryption = Some(key);
+        self
+    }
+
+    /// Builds the `axum` router, for embedding into a larger application.
+    pub fn router(self) -> Router {
+        let allow_uploads = self.allow_uploads;
+        let state = Arc::new(self);
+
+        let route = if allow_uploads {
+            get(serve_object).put(upload_object)
+        } else {
+            get(serve_object)
+        };
+
+        let mut router = Router::new().route("/streams/{name}", route);
+        if state.manifest_dir.is_some() {
+            // Objects are compressed already, so only manifests are
+            let tree_route = if allow_uploads {
+                get(serve_tree).put(upload_tree)
+            } else {
+                get(serve_tree)
+            };
+            let manifests = Router::new()
+                .route("/trees/{*name}", tree_route)
+                .route("/index.json", get(serve_index))
+                .layer(CompressionLayer::new());
+            router = router.merge(manifests);
+        }
+
+        router.with_state(state)
+    }
+
+    /// Serves the stream directory until the listener fails.
+    ///
+    /// # Errors
+    ///
+    /// - Network errors (Typically the listener being closed)
+    pub async fn serve(self, listener: TcpListener) -> io::Result<()> {
+        axum::serve(listener, self.router()).await
+    }
+}
+
+async fn serve_object(
+    State(server): State<Arc<Server>>,
+    UrlPath(name): UrlPath<String>,
+    req: Request,
+) -> Response {
+    match server.object_path(&name) {
+        Some(file_path) => serve_file(file_path, req).await,
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn serve_tree(
+    State(server): State<Arc<Server>>,
+    UrlPath(name): UrlPath<String>,
+    req: Request,
+) -> Response {
+    match &server.manifest_dir {
+        Some(dir) if is_valid_ref(&name) => serve_file(dir.join("trees").join(name), req).await,
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn serve_index(State(server): State<Arc<Server>>, req: Request) -> Response {
+    match &server.manifest_dir {
+        Some(dir) => serve_file(dir.join("index.json"), req).await,
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn serve_file(path: PathBuf, mut req: Request) -> Response {
+    let metadata = tokio::fs::metadata(&path).await.ok();
+    let etag = metadata.as_ref().and_then(etag);
+
+    // A range of a file that changed since the client saw it can't be used, so it gets the whole
+    // file instead
+    let if_range = req.headers().get(header::IF_RANGE);
+    if if_range.is_some_and(|if_range| !is_current(if_range, etag.as_ref(), metadata.as_ref())) {
+        req.headers_mut().remove(header::RANGE);
+    }
+
+    // If-None-Match takes precedence over If-Modified-Since, which ServeFile handles
+    if let (Some(if_none_match), Some(etag)) = (req.headers().get(header::IF_NONE_MATCH), &etag) {
+        if matches_any(if_none_match, etag) {
+            return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag.clone())]).into_response();
+        }
+        req.headers_mut().remove(header::IF_MODIFIED_SINCE);
+    }
+
+    // ServeFile handles HEAD, Range and If-Modified-Since for us
+    match ServeFile::new(path).try_call(req).await {
+        Ok(res) => {
+            let mut res = res.map(Body::new);
+            if let Some(etag) = etag.filter(|_| res.status().is_success()) {
+                res.headers_mut().insert(header::ETAG, etag);
+            }
+            res
+        }
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+fn etag(metadata: &std::fs::Metadata) -> Option<HeaderValue> {
+    HeaderValue::from_str(&net::etag(metadata)?).ok()
+}
+
+/// Whether an `If-None-Match` header lists the `ETag`, comparing them weakly.
+fn matches_any(if_none_match: &HeaderValue, etag: &HeaderValue) -> bool {
+    let etag = etag.as_bytes();
+    if_none_match.as_bytes().split(|&b| b == b',').any(|tag| {
+        let tag = tag.trim_ascii();
+        tag == b"*" || tag.strip_prefix(b"W/").unwrap_or(tag) == etag
+    })
+}
+
+/// Whether an `If-Range` validator, an `ETag` or a `Last-Modified` date, matches the file.
+fn is_current(
+    if_range: &HeaderValue,
+    etag: Option<&HeaderValue>,
+    metadata: Option<&std::fs::Metadata>,
+) -> bool {
+    if etag == Some(if_range) {
+        return true;
+    }
+    let modified = metadata.and_then(|metadata| metadata.modified().ok());
+    modified
+        .is_some_and(|modified| if_range.as_bytes() == httpdate::fmt_http_date(modified).as_bytes())
+}
+
+async fn upload_object(
+    State(server): State<Arc<Server>>,
+    UrlPath(name): UrlPath<String>,
+    body: Body,
+) -> Response {
+    let Some(file_path) = server.object_path(&name) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    if file_path.exists() {
+        return StatusCode::OK.into_response();
+    }
+
+    let Ok(tmp_file_path) = write_upload(&file_path, body).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let res = store_upload(&server, &name, &tmp_file_path, &file_path).await;
+    let _ = fs::remove_file(&tmp_file_path).await;
+
+    match res {
+        Ok(status) => status.into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// Moves an uploaded object into place if its contents match its name.
+async fn store_upload(
+    server: &Server,
+    name: &str,
+    tmp_file_path: &Path,
+    file_path: &Path,
+) -> io::Result<StatusCode> {
+    let extension = name.split_once('.').map(|(_, extension)| extension);
+    if let (Some(hash), Some(compression)) = (
+        object_hash(name),
+        CompressionKind::from_extension(extension),
+    ) {
+        let reader = fs::open_buffered(tmp_file_path).await?;
+        let contents_hash = hash_object(reader, compression, server.encryption.as_ref()).await;
+        if contents_hash.ok().as_deref() != Some(hash) {
+            return Ok(StatusCode::UNPROCESSABLE_ENTITY);
+        }
+    }
+
+    // Unlike renaming, linking never replaces an object another upload put there first
+    match std::fs::hard_link(tmp_file_path, file_path) {
+        Ok(()) => Ok(StatusCode::CREATED),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(StatusCode::OK),
+        Err(e) => Err(e),
+    }
+}
+
+async fn upload_tree(
+    State(server): State<Arc<Server>>,
+    UrlPath(name): UrlPath<String>,
+    body: Body,
+) -> Response {
+    let Some(dir) = server.manifest_dir.as_ref().filter(|_| is_valid_ref(&name)) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    let file_path = dir.join("trees").join(name);
+    let res = match write_upload(&file_path, body).await {
+        Ok(tmp_file_path) => {
+            let res = fs::rename(tmp_file_path.as_path(), &file_path);
+            if res.is_err() {
+                let _ = fs::remove_file(&tmp_file_path).await;
+            }
+            res
+        }
+        Err(e) => Err(e),
+    };
+
+    match res {
+        Ok(()) => StatusCode::CREATED.into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// Writes an upload to a new temporary file next to `file_path`, returning its path.
+async fn write_upload(file_path: &Path, body: Body) -> io::Result<PathBuf> {
+    // Every upload gets its own, so concurrent uploads of the same file never share one
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let mut tmp_file_path = file_path.as_os_str().to_owned();
+    tmp_file_path.push(format!(".{}-{n}.upload.tmp", std::process::id()));
+    let tmp_file_path = PathBuf::from(tmp_file_path);
+
+    if let Some(parent) = file_path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    let mut file = fs::File::create_new(&tmp_file_path).await?;
+    let mut stream = body.into_data_stream();
+
+    let res = async {
+        while let Some(chunk) = stream.next().await {
+            file.write_all(&chunk.map_err(io::Error::other)?).await?;
+        }
+        file.shutdown().await
+    }
+    .await;
+
+    match res {
+        Ok(()) => Ok(tmp_file_path),
+        Err(e) => {
+            fs::remove_file(&tmp_file_path).await?;
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CompressionKind;
+    use crate::store::Store;
+    use crate::stream::Stream;
+    use crate::tree::Tree;
+    use temp_dir::TempDir;
+    use temp_file::TempFile;
+
+    async fn start(server: Server) -> io::Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(server.serve(listener));
+        Ok(format!("http://{addr}"))
+    }
+
+    #[test]
+    fn test_server_object_names() {
+        let server = Server::new("streams");
+        let is_valid_object_name = |name| server.object_path(name).is_some();
+        assert!(is_valid_object_name("abc123"));
+        assert!(is_valid_object_name("abc123.zstd"));
+        assert!(!is_valid_object_name(""));
+        assert!(!is_valid_object_name(".zstd"));
+        assert!(!is_valid_object_name("abc123."));
+        assert!(!is_valid_object_name("..%2fetc"));
+        assert!(!is_valid_object_name("abc123.zstd.tmp"));
+    }
+
+    #[tokio::test]
+    async fn test_server_download() -> crate::Result<()> {
+        let remote_stream_dir = TempDir::new()?;
+        let local_stream_dir = TempDir::new()?;
+        let test_data = b"This is some test data.";
+        let test_file = TempFile::new()?.with_contents(test_data)?;
+
+        let stream = Stream::create(
+            test_file.path(),
+            &Store::new(remote_stream_dir.path()),
+            CompressionKind::Zstd,
+        )
+        .await?;
+
+        let url = start(Server::new(remote_stream_dir.path())).await?;
+
+        let path = stream
+            .download(
+                &url,
+                &Store::new(local_stream_dir.path()),
+                CompressionKind::Zstd,
+            )
+            .await?;
+
+        assert_eq!(fs::read_to_end(path).await?, test_data);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_server_head_and_range() -> crate::Result<()> {
+        let stream_dir = TempDir::new()?;
+        let hash = blake3::hash(b"0123456789").to_hex().to_string();
+        fs::write(stream_dir.path().join(&hash), b"0123456789").await?;
+
+        let url = start(Server::new(stream_dir.path())).await?;
+        let client = reqwest::Client::new();
+
+        let res = client
+            .head(format!("{url}/streams/{hash}"))
+            .send()
+            .await?
+            .error_for_status()?;
+        assert_eq!(res.headers()["content-length"], "10");
+        let etag = res.headers()["etag"].to_str().unwrap().to_string();
+
+        let res = client
+            .get(format!("{url}/streams/{hash}"))
+            .header("Range", "bytes=2-5")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(&res.bytes().await?[..], b"2345");
+
+        // Ranges are only served for the file the client saw
+        for (if_range, status) in [
+            (etag.as_str(), StatusCode::PARTIAL_CONTENT),
+            ("\"stale\"", StatusCode::OK),
+            ("Thu, 01 Jan 1970 00:00:00 GMT", StatusCode::OK),
+        ] {
+            let res = client
+                .get(format!("{url}/streams/{hash}"))
+                .header("Range", "bytes=2-5")
+                .header("If-Range", if_range)
+                .send()
+                .await?;
+            assert_eq!(res.status(), status, "{if_range}");
+        }
+        let modified = std::fs::metadata(stream_dir.path().join(&hash))?.modified()?;
+        let res = client
+            .get(format!("{url}/streams/{hash}"))
+            .header("Range", "bytes=2-5")
+            .header("If-Range", httpdate::fmt_http_date(modified))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+
+        let res = client
+            .get(format!("{url}/streams/{}", "f".repeat(64)))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_server_upload() -> crate::Result<()> {
+        let stream_dir = TempDir::new()?;
+        let hash = blake3::hash(b"uploaded").to_hex().to_string();
+
+        let client = reqwest::Client::new();
+
+        // Uploads are rejected unless enabled
+        let url = start(Server::new(stream_dir.path())).await?;
+        let res = client
+            .put(format!("{url}/streams/{hash}"))
+            .body("uploaded")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        let url = start(Server::new(stream_dir.path()).allow_uploads(true)).await?;
+        client
+            .put(format!("{url}/streams/{hash}"))
+            .body("uploaded")
+            .send()
+            .await?
+            .error_for_status()?;
+
+        assert_eq!(
+            fs::read_to_end(stream_dir.path().join(&hash)).await?,
+            b"uploaded"
+        );
+
+        // Contents have to match the hash, compressed or not
+        let poisoned = blake3::hash(b"expected").to_hex().to_string();
+        let zstd = |data: &[u8]| zstd::encode_all(data, 0);
+        for (name, body) in [
+            (poisoned.clone(), b"poisoned".to_vec()),
+            (format!("{poisoned}.zstd"), zstd(b"poisoned")?),
+            (format!("{poisoned}.zstd"), b"not zstd".to_vec()),
+        ] {
+            let res = client
+                .put(format!("{url}/streams/{name}"))
+                .body(body)
+                .send()
+                .await?;
+            assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY, "{name}");
+            assert!(!stream_dir.path().join(name).exists());
+        }
+        client
+            .put(format!("{url}/streams/{poisoned}.zstd"))
+            .body(zstd(b"expected")?)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        // Concurrent uploads of the same object both succeed
+        let concurrent = blake3::hash(b"concurrent").to_hex().to_string();
+        let upload = || {
+            client
+                .put(format!("{url}/streams/{concurrent}"))
+                .body("concurrent")
+                .send()
+        };
+        let (a, b) = tokio::join!(upload(), upload());
+        a?.error_for_status()?;
+        b?.error_for_status()?;
+        assert_eq!(
+            fs::read_to_end(stream_dir.path().join(&concurrent)).await?,
+            b"concurrent"
+        );
+
+        // No temporary files are left behind
+        for entry in std::fs::read_dir(stream_dir.path())? {
+            let name = entry?.file_name();
+            assert!(!name.to_string_lossy().ends_with(".tmp"), "{name:?}");
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_server_encrypted_upload() -> crate::Result<()> {
+        let local_stream_dir = TempDir::new()?;
+        let test_file = TempFile::new()?.with_contents(b"encrypted")?;
+        let key = EncryptionKey::generate();
+        let store = Store::new(local_stream_dir.path()).with_encryption(key.clone());
+        let stream = Stream::create(test_file.path(), &store, CompressionKind::Zstd).await?;
+
+        // Can't be checked without the key
+        let repo_dir = TempDir::new()?;
+        std::fs::create_dir(repo_dir.path().join("streams"))?;
+        let server = Server::new(repo_dir.path().join("streams")).allow_uploads(true);
+        let url = start(server.clone()).await?;
+        assert!(
+            stream
+                .push(&url, &store, CompressionKind::Zstd)
+                .await
+                .is_err()
+        );
+
+        let url = start(server.encryption(key)).await?;
+        stream.push(&url, &store, CompressionKind::Zstd).await?;
+        assert!(
+            repo_dir
+                .path()
+                .join(format!("streams/{}.zstd", stream.hash))
+                .exists()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_server_fanout() -> crate::Result<()> {
+        let stream_dir = TempDir::new()?;
+        let served = blake3::hash(b"served").to_hex().to_string();
+        let uploaded = blake3::hash(b"uploaded").to_hex().to_string();
+        let sharded = |hash: &str| {
+            stream_dir
+                .path()
+                .join(&hash[..2])
+                .join(&hash[2..4])
+                .join(hash)
+        };
+
+        std::fs::create_dir_all(sharded(&served).parent().unwrap())?;
+        fs::write(sharded(&served), b"served").await?;
+
+        let url = start(Server::new(stream_dir.path()).fanout(2).allow_uploads(true)).await?;
+        let client = reqwest::Client::new();
+
+        let res = client
+            .get(format!("{url}/streams/{served}"))
+            .send()
+            .await?
+            .error_for_status()?;
+        assert_eq!(&res.bytes().await?[..], b"served");
+
+        client
+            .put(format!("{url}/streams/{uploaded}"))
+            .body("uploaded")
+            .send()
+            .await?
+            .error_for_status()?;
+        assert_eq!(fs::read_to_end(sharded(&uploaded)).await?, b"uploaded");
+
+        // Too short to be sharded
+        let res = client.get(format!("{url}/streams/abc")).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_server_manifests() -> crate::Result<()> {
+        let repo_dir = TempDir::new()?;
+        let stream_dir = repo_dir.path().join("streams");
+        let original_dir = TempDir::new()?;
+        let local_stream_dir = TempDir::new()?;
+        std::fs::create_dir_all(&stream_dir)?;
+        std::fs::create_dir_all(repo_dir.path().join("trees"))?;
+
+        for i in 0..20 {
+            fs::write(original_dir.path().join(format!("file{i}")), b"contents").await?;
+        }
+        let tree = Tree::create(
+            &Store::new(&stream_dir),
+            original_dir.path(),
+            CompressionKind::Zstd,
+        )
+        .await?;
+        let mut manifest = Vec::new();
+        tree.write_manifest(&mut manifest).await?;
+        fs::write(repo_dir.path().join("trees/latest"), &manifest).await?;
+        fs::write(
+            repo_dir.path().join("index.json"),
+            b"{\"trees\": [\"latest\"]}",
+        )
+        .await?;
+
+        let url = start(Server::new(&stream_dir).manifests(repo_dir.path())).await?;
+
+        // Compressed when asked for, and only then
+        let client = reqwest::Client::builder().no_zstd().build()?;
+        let compressed = client
+            .get(format!("{url}/trees/latest"))
+            .header("Accept-Encoding", "zstd")
+            .send()
+            .await?
+            .error_for_status()?;
+        assert_eq!(compressed.headers()["content-encoding"], "zstd");
+        assert!(compressed.bytes().await?.len() < manifest.len());
+        let plain = client.get(format!("{url}/trees/latest")).send().await?;
+        assert!(plain.headers().get("content-encoding").is_none());
+
+        // The client decodes it transparently
+        let fetched = Tree::fetch(
+            &format!("{url}/trees/latest"),
+            &url,
+            &Store::new(local_stream_dir.path()),
+            CompressionKind::Zstd,
+        )
+        .await?;
+        assert_eq!(fetched.hash(), tree.hash());
+
+        let res = reqwest::get(format!("{url}/index.json")).await?;
+        assert_eq!(&res.bytes().await?[..], b"{\"trees\": [\"latest\"]}");
+        let res = reqwest::get(format!("{url}/trees/..")).await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_server_refs() -> crate::Result<()> {
+        let repo_dir = TempDir::new()?;
+        let stream_dir = repo_dir.path().join("streams");
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let local_dir = TempDir::new()?;
+        let original_dir = TempDir::new()?;
+        std::fs::create_dir_all(&stream_dir)?;
+
+        fs::write(original_dir.path().join("app"), b"app").await?;
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::Zstd).await?;
+
+        let server = Server::new(&stream_dir)
+            .manifests(repo_dir.path())
+            .allow_uploads(true);
+        let url = start(server).await?;
+        tree.publish(&url, "myapp/stable", &store, CompressionKind::Zstd)
+            .await?;
+        assert!(repo_dir.path().join("trees/myapp/stable").is_file());
+
+        let fetched = Tree::fetch_ref(
+            &url,
+            "myapp/stable",
+            &Store::new(local_dir.path()),
+            CompressionKind::Zstd,
+        )
+        .await?;
+        assert_eq!(fetched.hash(), tree.hash());
+
+        // Polling only fetches the manifest once it changed
+        let mut validators = crate::repo::ManifestValidators::default();
+        let local_store = Store::new(local_dir.path());
+        let compression = CompressionKind::Zstd;
+        let polled = Tree::fetch_ref_if_modified(
+            &url,
+            "myapp/stable",
+            &local_store,
+            compression,
+            &mut validators,
+        )
+        .await?;
+        assert_eq!(polled.hash(), tree.hash());
+        let etag = validators.etag.clone().expect("the server sends an ETag");
+        assert!(matches!(
+            Tree::fetch_ref_if_modified(
+                &url,
+                "myapp/stable",
+                &local_store,
+                compression,
+                &mut validators
+            )
+            .await,
+            Err(crate::Error::NotModified)
+        ));
+        for (if_none_match, status) in [
+            (etag.as_str(), StatusCode::NOT_MODIFIED),
+            ("\"stale\", *", StatusCode::NOT_MODIFIED),
+            ("\"stale\"", StatusCode::OK),
+        ] {
+            let res = reqwest::Client::new()
+                .get(format!("{url}/trees/myapp/stable"))
+                .header("If-None-Match", if_none_match)
+                .send()
+                .await?;
+            assert_eq!(res.status(), status, "{if_none_match}");
+        }
+
+        fs::write(original_dir.path().join("app"), b"app v2").await?;
+        let v2 = Tree::create(&store, original_dir.path(), compression).await?;
+        v2.publish(&url, "myapp/stable", &store, compression)
+            .await?;
+        let update = Tree::check_for_update(&url, "myapp/stable", &tree).await?;
+        assert_eq!(update.map(|update| update.tree.hash()), Some(v2.hash()));
+        let checks = Tree::check_refs(&url, &["myapp/stable"], &store, compression, None).await?;
+        assert_eq!(checks[0].repair, None);
+        let polled = Tree::fetch_ref_if_modified(
+            &url,
+            "myapp/stable",
+            &local_store,
+            compression,
+            &mut validators,
+        )
+        .await?;
+        assert_eq!(polled.hash(), v2.hash());
+        assert_ne!(validators.etag, Some(etag));
+
+        // Read-only servers refuse to move references
+        let url = start(Server::new(&stream_dir).manifests(repo_dir.path())).await?;
+        let res = reqwest::Client::new()
+            .put(format!("{url}/trees/myapp/stable"))
+            .body("")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,306 @@
+//! Syncing one repository over and over, like a daemon following its latest tree.
+//!
+//! A [`SyncSession`] downloads only what the store is missing each time, retries streams that
+//! fail, and keeps [`SessionStats`] across every sync, which can be read while one is running.
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::metrics::Counters;
+use crate::store::Store;
+use crate::tree::plan::DownloadPlan;
+use crate::tree::report::TreeReport;
+use crate::tree::{Tree, all_streams};
+use crate::{CompressionKind, Mirrors};
+
+/// Repeated downloads and deploys from one repository, see the [module docs](self).
+#[derive(Debug)]
+pub struct SyncSession {
+    mirrors: Mirrors,
+    store: Store,
+    compression: Option<CompressionKind>,
+    retries: u32,
+    counters: Arc<Counters>,
+    totals: Totals,
+    busy: Mutex<Busy>,
+}
+
+#[derive(Debug, Default)]
+struct Totals {
+    syncs: AtomicU64,
+    deploys: AtomicU64,
+    streams_downloaded: AtomicU64,
+    streams_reused: AtomicU64,
+    streams_failed: AtomicU64,
+    retries: AtomicU64,
+}
+
+/// Time spent syncing, counted once however many syncs run at the same time.
+#[derive(Debug, Default)]
+struct Busy {
+    running: usize,
+    since: Option<Instant>,
+    total: Duration,
+}
+
+impl Busy {
+    fn elapsed(&self) -> Duration {
+        self.total + self.since.map_or(Duration::ZERO, |since| since.elapsed())
+    }
+}
+
+/// Ends a sync's busy time when dropped, even if the sync was cancelled.
+struct Running<'a>(&'a Mutex<Busy>);
+
+impl<'a> Running<'a> {
+    fn start(busy: &'a Mutex<Busy>) -> Self {
+        let mut guard = busy.lock().unwrap_or_else(PoisonError::into_inner);
+        if guard.running == 0 {
+            guard.since = Some(Instant::now());
+        }
+        guard.running += 1;
+        Self(busy)
+    }
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        let mut busy = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        busy.running -= 1;
+        if busy.running == 0 {
+            if let Some(since) = busy.since.take() {
+                busy.total += since.elapsed();
+            }
+        }
+    }
+}
+
+impl SyncSession {
+    /// Starts a session downloading from `mirrors` into `store`, with `compression` as the
+    /// fallback for streams that don't record their kind. Metrics the store already reports to
+    /// keep getting them.
+    #[must_use]
+    pub fn new<C: Into<Option<CompressionKind>>>(
+        mirrors: Mirrors,
+        store: Store,
+        compression: C,
+    ) -> Self {
+        let counters = Counters::new();
+        Self {
+            mirrors,
+            store: store.and_metrics(counters.clone()),
+            compression: compression.into(),
+            retries: 0,
+            counters,
+            totals: Totals::default(),
+            busy: Mutex::default(),
+        }
+    }
+
+    /// How many more times a sync tries streams that failed, before giving up on them until the
+    /// next sync. Defaults to 0.
+    #[must_use]
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    #[must_use]
+    pub fn mirrors(&self) -> &Mirrors {
+        &self.mirrors
+    }
+
+    #[must_use]
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    /// Downloads the streams of `tree` that the store is missing, carrying on past streams that
+    /// fail like [`Tree::download_with_report`]. The report only lists streams this sync
+    /// downloaded.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors cleaning up the store before downloading
+    pub async fn sync(&self, tree: &Tree) -> crate::Result<TreeReport> {
+        let _running = Running::start(&self.busy);
+        self.store.clean_before_download()?;
+
+        let plan = DownloadPlan::for_tree(tree, &self.store);
+        let reused = all_streams(tree).count() - plan.len();
+        let mut report = TreeReport::default();
+        report
+            .download(
+                plan.streams().iter().cloned(),
+                &self.mirrors,
+                &self.store,
+                self.compression,
+            )
+            .await;
+        for _ in 0..self.retries {
+            if report.is_complete() {
+                break;
+            }
+            add(&self.totals.retries, report.failed.len());
+            report
+                .retry(&self.mirrors, &self.store, self.compression)
+                .await?;
+        }
+
+        add(&self.totals.syncs, 1);
+        add(&self.totals.streams_downloaded, report.downloaded.len());
+        add(&self.totals.streams_reused, reused);
+        add(&self.totals.streams_failed, report.failed.len());
+        Ok(report)
+    }
+
+    /// Syncs `tree`, then deploys it to `deploy_path` if every stream was downloaded. Otherwise
+    /// nothing is deployed, and the report says what's missing.
+    ///
+    /// # Errors
+    ///
+    /// - See [`SyncSession::sync`] and [`Tree::deploy`]
+    pub async fn sync_and_deploy(
+        &self,
+        tree: &Tree,
+        deploy_path: &Path,
+    ) -> crate::Result<TreeReport> {
+        let report = self.sync(tree).await?;
+        if report.is_complete() {
+            tree.deploy(&self.store, deploy_path)?;
+            add(&self.totals.deploys, 1);
+        }
+        Ok(report)
+    }
+
+    /// The statistics so far. Bytes and time include syncs that are still running.
+    #[must_use]
+    pub fn stats(&self) -> SessionStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        SessionStats {
+            syncs: load(&self.totals.syncs),
+            deploys: load(&self.totals.deploys),
+            streams_downloaded: load(&self.totals.streams_downloaded),
+            streams_reused: load(&self.totals.streams_reused),
+            streams_failed: load(&self.totals.streams_failed),
+            retries: load(&self.totals.retries),
+            downloaded_bytes: self.counters.downloaded_bytes(),
+            written_bytes: self.counters.written_bytes(),
+            busy: self
+                .busy
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .elapsed(),
+        }
+    }
+}
+
+fn add(counter: &AtomicU64, n: usize) {
+    counter.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+/// What a [`SyncSession`] did across all its syncs.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SessionStats {
+    /// Finished syncs, including those with failed streams
+    pub syncs: u64,
+    pub deploys: u64,
+    pub streams_downloaded: u64,
+    /// Files that didn't need a download, as the store had their stream or another file in the
+    /// tree shares it
+    pub streams_reused: u64,
+    /// Streams that still failed after every retry
+    pub streams_failed: u64,
+    /// Streams that were tried again after failing
+    pub retries: u64,
+    /// Bytes received from mirrors, usually compressed
+    pub downloaded_bytes: u64,
+    /// Bytes written into the store, and copied into deployments
+    pub written_bytes: u64,
+    /// Time spent syncing
+    pub busy: Duration,
+}
+
+impl SessionStats {
+    /// Bytes received per second spent syncing, if any time was.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn throughput(&self) -> Option<f64> {
+        let secs = self.busy.as_secs_f64();
+        (secs > 0.0).then(|| self.downloaded_bytes as f64 / secs)
+    }
+
+    /// The share of files that didn't need a download, from 0 to 1, if there were any files.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn dedup_ratio(&self) -> Option<f64> {
+        let files = self.streams_downloaded + self.streams_reused;
+        (files > 0).then(|| self.streams_reused as f64 / files as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::fs;
+
+    #[tokio::test]
+    async fn test_sync_session() -> crate::Result<()> {
+        let compression = CompressionKind::Zstd;
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let repo_dir = TempDir::new()?;
+        let repo_url = repo_dir.path().to_str().unwrap();
+        let local_dir = TempDir::new()?;
+        let original_dir = TempDir::new()?;
+        let deploy_dir = TempDir::new()?;
+
+        fs::write(original_dir.path().join("a"), b"a").await?;
+        fs::write(original_dir.path().join("b"), b"b").await?;
+        fs::write(original_dir.path().join("copy of a"), b"a").await?;
+        let tree = Tree::create(&store, original_dir.path(), compression).await?;
+        let [a, b, _] = &tree.streams[..] else {
+            panic!("expected three streams");
+        };
+
+        let session = SyncSession::new(
+            Mirrors::from(repo_url),
+            Store::new(local_dir.path()),
+            compression,
+        )
+        .with_retries(2);
+        assert_eq!(session.stats(), SessionStats::default());
+        assert_eq!(session.stats().dedup_ratio(), None);
+
+        // A stream that never shows up is retried, and nothing is deployed
+        a.push(repo_url, &store, compression).await?;
+        let report = session.sync_and_deploy(&tree, deploy_dir.path()).await?;
+        assert_eq!(report.failed.len(), 1);
+        assert!(!deploy_dir.path().join("a").exists());
+        let stats = session.stats();
+        assert_eq!((stats.syncs, stats.deploys), (1, 0));
+        assert_eq!(stats.streams_downloaded, 1);
+        assert_eq!(stats.streams_reused, 1);
+        assert_eq!((stats.streams_failed, stats.retries), (1, 2));
+        assert!(stats.downloaded_bytes > 0);
+
+        // Only the missing stream is downloaded next time
+        b.push(repo_url, &store, compression).await?;
+        let report = session.sync_and_deploy(&tree, deploy_dir.path()).await?;
+        assert_eq!(report.downloaded, std::slice::from_ref(&b.hash));
+        assert_eq!(std::fs::read(deploy_dir.path().join("copy of a"))?, b"a");
+        let stats = session.stats();
+        assert_eq!((stats.syncs, stats.deploys), (2, 1));
+        assert_eq!(stats.streams_downloaded, 2);
+        assert_eq!(stats.streams_reused, 3);
+        assert_eq!(stats.dedup_ratio(), Some(0.6));
+        assert!(stats.written_bytes >= 2);
+        assert!(stats.busy > Duration::ZERO);
+        assert!(stats.throughput().is_some());
+
+        Ok(())
+    }
+}
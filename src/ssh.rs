@@ -0,0 +1,219 @@
+//! Repositories on remote machines, reached over `ssh://[user@]host[:port]/path` URLs.
+//!
+//! Commands are run through the system `ssh` client, so authentication, host keys and connection
+//! sharing all come from the user's usual SSH configuration. The remote machine only needs a
+//! POSIX shell, and stores objects in the same `streams/` layout as every other repository.
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::process::{ExitStatus, Stdio};
+
+use crate::async_types::{AsyncBufRead, BufReader};
+
+#[cfg(not(feature = "tokio"))]
+use futures_util::io::AllowStdIo;
+
+#[cfg(feature = "tokio")]
+type Command = tokio::process::Command;
+#[cfg(not(feature = "tokio"))]
+type Command = std::process::Command;
+
+#[cfg(feature = "tokio")]
+pub(crate) type Child = tokio::process::Child;
+#[cfg(not(feature = "tokio"))]
+pub(crate) type Child = std::process::Child;
+
+/// The exit status `ssh` uses for its own errors, as opposed to the remote command's.
+const SSH_FAILURE: i32 = 255;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Remote<'a> {
+    destination: &'a str,
+    port: Option<&'a str>,
+    path: &'a str,
+}
+
+/// Quotes a string for a POSIX shell.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+impl<'a> Remote<'a> {
+    pub(crate) fn parse(url: &'a str) -> Option<Self> {
+        let rest = url.strip_prefix("ssh://")?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "."),
+        };
+
+        let (destination, port) = match authority.rsplit_once(':') {
+            Some((destination, port)) if port.bytes().all(|b| b.is_ascii_digit()) => {
+                (destination, Some(port))
+            }
+            _ => (authority, None),
+        };
+
+        Some(Self {
+            destination,
+            port,
+            path,
+        })
+    }
+
+    fn object_path(&self, object: &str) -> String {
+        format!("{}/{object}", self.path.trim_end_matches('/'))
+    }
+
+    fn command(&self, script: &str) -> Command {
+        let mut command = Command::new("ssh");
+        // Never prompt for passwords, as there is nobody to answer
+        command.args(["-o", "BatchMode=yes"]);
+        if let Some(port) = self.port {
+            command.args(["-p", port]);
+        }
+        command
+            .args(["--", self.destination, script])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        command
+    }
+
+    /// Starts streaming an object from the remote. The child must be passed to [`finish`] once
+    /// the reader is exhausted.
+    pub(crate) fn read(
+        &self,
+        object: &str,
+    ) -> io::Result<(Pin<Box<dyn AsyncBufRead + Send>>, Child)> {
+        let script = format!("cat -- {}", quote(&self.object_path(object)));
+        let mut child = self.command(&script).stdout(Stdio::piped()).spawn()?;
+
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| io::Error::other("ssh stdout was not captured"))?;
+        #[cfg(not(feature = "tokio"))]
+        let stdout = AllowStdIo::new(stdout);
+
+        Ok((Box::pin(BufReader::new(stdout)), child))
+    }
+
+    /// Whether the remote has `object`, without reading it.
+    pub(crate) async fn exists(&self, object: &str) -> crate::Result<bool> {
+        let script = format!("test -e {}", quote(&self.object_path(object)));
+        let child = self.command(&script).spawn()?;
+        match finish(child, object).await {
+            Ok(()) => Ok(true),
+            Err(crate::Error::NotFound(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Atomically uploads a local file as `object`, creating directories as needed.
+    pub(crate) async fn write(&self, object: &str, source: &Path) -> crate::Result<()> {
+        let path = self.object_path(object);
+        let dir = path.rsplit_once('/').map_or(".", |(dir, _)| dir);
+        let tmp = format!("{path}.tmp");
+        let script = format!(
+            "mkdir -p -- {dir} && cat > {tmp} && mv -f -- {tmp} {path}",
+            dir = quote(dir),
+            tmp = quote(&tmp),
+            path = quote(&path),
+        );
+
+        let mut child = self.command(&script).stdin(Stdio::piped()).spawn()?;
+        let mut stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| io::Error::other("ssh stdin was not captured"))?;
+
+        #[cfg(feature = "tokio")]
+        {
+            let mut file = tokio::fs::File::open(source).await?;
+            tokio::io::copy(&mut file, &mut stdin).await?;
+        }
+        #[cfg(not(feature = "tokio"))]
+        {
+            let mut file = std::fs::File::open(source)?;
+            std::io::copy(&mut file, &mut stdin)?;
+        }
+        drop(stdin);
+
+        finish(child, object).await
+    }
+}
+
+/// Waits for a remote command, turning its exit status into an error.
+#[cfg_attr(not(feature = "tokio"), allow(clippy::unused_async))]
+pub(crate) async fn finish(mut child: Child, object: &str) -> crate::Result<()> {
+    #[cfg(feature = "tokio")]
+    let status = child.wait().await?;
+    #[cfg(not(feature = "tokio"))]
+    let status = child.wait()?;
+
+    check_status(status, object)
+}
+
+fn check_status(status: ExitStatus, object: &str) -> crate::Result<()> {
+    match status.code() {
+        Some(0) => Ok(()),
+        Some(SSH_FAILURE) | None => {
+            Err(crate::Error::SshError(format!("ssh exited with {status}")))
+        }
+        // The remote command itself failed, typically because the object doesn't exist
+        Some(_) => Err(crate::Error::NotFound(object.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ssh_parse() {
+        assert_eq!(
+            Remote::parse("ssh://user@example.com:2222/srv/repo"),
+            Some(Remote {
+                destination: "user@example.com",
+                port: Some("2222"),
+                path: "/srv/repo",
+            })
+        );
+        assert_eq!(
+            Remote::parse("ssh://example.com"),
+            Some(Remote {
+                destination: "example.com",
+                port: None,
+                path: ".",
+            })
+        );
+        assert_eq!(Remote::parse("https://example.com/repo"), None);
+        assert_eq!(Remote::parse("/srv/repo"), None);
+    }
+
+    #[test]
+    fn test_ssh_quote() {
+        assert_eq!(quote("/srv/repo"), "'/srv/repo'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn test_ssh_command() {
+        let remote = Remote::parse("ssh://user@host:22/srv/my repo/").expect("valid url");
+        assert_eq!(
+            remote.object_path("streams/abc"),
+            "/srv/my repo/streams/abc"
+        );
+
+        let command = remote.command("true");
+        #[cfg(feature = "tokio")]
+        let command = command.as_std();
+        let args: Vec<_> = command.get_args().collect();
+
+        assert_eq!(command.get_program(), "ssh");
+        assert_eq!(
+            args,
+            ["-o", "BatchMode=yes", "-p", "22", "--", "user@host", "true"]
+        );
+    }
+}
@@ -0,0 +1,2052 @@
+//! The local content-addressed store that streams are kept in.
+//!
+//! Every stream is stored uncompressed as `{hash}`, ready to be hardlinked into deployments, and
+//! may also be stored compressed as `{hash}.{ext}`, ready to be served or pushed to a repository.
+//! Publishers can [keep only the compressed copies](StoreLayout::CompressedOnly) instead.
+//! Nothing becomes visible under its final name until it has been fully written and verified.
+use blake3::Hasher;
+use nix::fcntl::{Flock, FlockArg};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+#[cfg(target_os = "linux")]
+use std::os::fd::OwnedFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::Mirrors;
+use crate::async_types::{
+    AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, StreamExt,
+};
+use crate::clock::{Clock, SharedClock};
+use crate::compression::CompressionKind;
+use crate::encryption::{EncryptionKey, decrypt};
+use crate::event::{Event, EventSink, SlowThresholds};
+use crate::fs;
+use crate::metrics::{Metrics, MetricsSink};
+use crate::pool::CpuPool;
+use crate::stream::{ObjectHash, Owner, Stream};
+use crate::tree::{Tree, all_streams};
+
+#[cfg(target_os = "linux")]
+mod watch;
+#[cfg(target_os = "linux")]
+pub use watch::{StoreEvent, StoreWatcher};
+
+/// When each stream was last used, as `{hash} {unix millis}` lines. Accesses are appended, so
+/// later lines override earlier ones, and the log is compacted while evicting. Starting with a dot
+/// means it can never be mistaken for an object.
+const ACCESS_INDEX: &str = ".access-index";
+/// Held shared while appending to the access index, and exclusively while rewriting it, so that
+/// processes sharing a store never lose each other's accesses.
+const ACCESS_INDEX_LOCK: &str = ".access-index.lock";
+/// About how long a line of the access index is.
+const ACCESS_LINE_LEN: u64 = 80;
+/// The running total of the objects' sizes and how many streams they belong to, as
+/// `{bytes} {streams}`, so that inserting into a store with a quota doesn't list it every time.
+/// Guarded by the access index lock, and removed wherever it may have gone wrong.
+const STORE_USAGE: &str = ".store-usage";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Store {
+    root: PathBuf,
+    quota: Option<u64>,
+    /// Streams referred to by the registered roots, which are never evicted
+    pinned: HashSet<String>,
+    /// Age after which temporary files are cleaned up before downloading
+    incomplete_max_age: Option<Duration>,
+    events: EventSink,
+    metrics: MetricsSink,
+    clock: SharedClock,
+    deploy_mode: DeployMode,
+    symlink_policy: SymlinkPolicy,
+    mode_mask: u32,
+    capture_mtimes: bool,
+    /// Tree hashes deploys accept, when restricted
+    allowed_trees: Option<HashSet<String>>,
+    keep_owners: bool,
+    owner_map: OwnerMap,
+    slow: SlowThresholds,
+    special_files: SpecialFilePolicy,
+    name_policy: NamePolicy,
+    cpu_pool: CpuPool,
+    encryption: Option<EncryptionKey>,
+    layout: StoreLayout,
+}
+
+/// Which symlink targets deploys accept, see [`Store::with_symlink_policy`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Any target, as trees are trusted
+    #[default]
+    Allow,
+    /// Only relative targets that stay inside the deployment, for trees from untrusted sources
+    Sandbox,
+}
+
+/// What [`Tree::create`] does with FIFOs, sockets and devices, see
+/// [`Store::with_special_files`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpecialFilePolicy {
+    /// Leaves them out of the tree
+    #[default]
+    Skip,
+    /// Fails on the first one
+    Error,
+    /// Records FIFOs as [special files](crate::tree::SpecialFile), so deploys recreate them.
+    /// Sockets and devices are still left out.
+    Record,
+}
+
+/// What [`Tree::create`] and deploys do with names Windows can't create or that aren't valid
+/// UTF-8, like `CON`, `a:b` or ones ending in a dot, see [`Store::with_name_policy`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NamePolicy {
+    /// Keeps them as they are, which works wherever the tree came from
+    #[default]
+    Preserve,
+    /// Fails on the first one
+    Error,
+    /// Replaces them with a portable name that's close, like `CON_` or `a_b`
+    Sanitize,
+}
+
+/// How streams are put into deployments, see [`Store::with_deploy_mode`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DeployMode {
+    /// Hardlinks, which take no space but share the store's copy of each file. Falls back onto
+    /// copying where hardlinks aren't possible, like across filesystems, and where a file needs a
+    /// different mode, mtime or owner than the store's copy has, as links share them.
+    #[default]
+    Hardlink,
+    /// Independent copies with the stream's mode
+    Copy,
+    /// Absolute symlinks to the store's copy of each file
+    Symlink,
+    /// Clones through `copy_file_range`, which filesystems like btrfs and XFS turn into
+    /// copy-on-write reflinks. Others may copy within the kernel, and where the kernel can't,
+    /// like across filesystems, files are copied like with [`Copy`](Self::Copy).
+    Reflink,
+}
+
+/// Which copies of a stream [`Stream::create`] keeps, see [`Store::with_layout`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StoreLayout {
+    /// The compressed object, and the uncompressed contents deploys link to
+    #[default]
+    Both,
+    /// Only the compressed object, for publishers that don't deploy what they create. Streams
+    /// created with [`CompressionKind::None`] are kept uncompressed, as that's their object.
+    CompressedOnly,
+}
+
+/// Maps the owners recorded in trees onto local ids, see [`Store::with_owner_map`]. Ids without
+/// a mapping are kept as they are.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OwnerMap {
+    uids: HashMap<u32, u32>,
+    gids: HashMap<u32, u32>,
+}
+
+impl OwnerMap {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Deploys files owned by user `from` as owned by `to`.
+    #[must_use]
+    pub fn uid(mut self, from: u32, to: u32) -> Self {
+        self.uids.insert(from, to);
+        self
+    }
+
+    /// Deploys files owned by group `from` as owned by `to`.
+    #[must_use]
+    pub fn gid(mut self, from: u32, to: u32) -> Self {
+        self.gids.insert(from, to);
+        self
+    }
+
+    #[must_use]
+    pub fn map(&self, owner: Owner) -> Owner {
+        Owner {
+            uid: self.uids.get(&owner.uid).copied().unwrap_or(owner.uid),
+            gid: self.gids.get(&owner.gid).copied().unwrap_or(owner.gid),
+        }
+    }
+}
+
+/// What [`Store::gc`] removed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// Objects deleted, counting each compressed copy separately
+    pub removed: usize,
+    /// Space actually reclaimed. Objects still hardlinked into a deployment don't free anything.
+    pub bytes_freed: u64,
+}
+
+/// What hosting a set of trees takes, see [`Store::required_objects`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RequiredObjects {
+    /// Every stream the trees refer to, sorted and only listed once
+    pub hashes: Vec<String>,
+    /// Total size of the objects that are in the store
+    pub bytes: u64,
+    /// Streams whose objects aren't in the store, and so aren't counted in `bytes`
+    pub missing: Vec<String>,
+}
+
+/// An object in the store: a stream's contents, or a compressed copy of them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoredObject {
+    pub hash: String,
+    pub compression: CompressionKind,
+}
+
+/// What [`Store::verify`] found.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Objects that were re-hashed
+    pub checked: usize,
+    /// Objects that don't match their hash, including truncated or undecompressable ones
+    pub corrupted: Vec<StoredObject>,
+    /// Streams that were downloaded again by [`Store::verify_and_repair`]
+    pub repaired: Vec<String>,
+}
+
+/// The hash of a `{hash}` or `{hash}.{ext}` object name. Temporary files never match.
+pub(crate) fn object_hash(name: &str) -> Option<&str> {
+    let (hash, extension) = match name.split_once('.') {
+        Some((hash, extension)) => (hash, Some(extension)),
+        None => (name, None),
+    };
+
+    let valid_hash = !hash.is_empty() && hash.bytes().all(|b| b.is_ascii_hexdigit());
+    let valid_extension =
+        extension.is_none_or(|e| !e.is_empty() && e.bytes().all(|b| b.is_ascii_alphanumeric()));
+
+    (valid_hash && valid_extension).then_some(hash)
+}
+
+impl Store {
+    /// Uses `root` as a store. The directory must already exist.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into(),
+            quota: None,
+            pinned: HashSet::new(),
+            incomplete_max_age: None,
+            events: EventSink::default(),
+            metrics: MetricsSink::default(),
+            clock: SharedClock::default(),
+            deploy_mode: DeployMode::default(),
+            symlink_policy: SymlinkPolicy::default(),
+            mode_mask: 0o7777,
+            capture_mtimes: false,
+            allowed_trees: None,
+            keep_owners: false,
+            owner_map: OwnerMap::default(),
+            slow: SlowThresholds::default(),
+            special_files: SpecialFilePolicy::default(),
+            name_policy: NamePolicy::default(),
+            cpu_pool: CpuPool::default(),
+            encryption: None,
+            layout: StoreLayout::default(),
+        }
+    }
+
+    /// How deploys from this store put streams into place. Defaults to
+    /// [hardlinks](DeployMode::Hardlink).
+    #[must_use]
+    pub fn with_deploy_mode(mut self, mode: DeployMode) -> Self {
+        self.deploy_mode = mode;
+        self
+    }
+
+    #[must_use]
+    pub fn deploy_mode(&self) -> DeployMode {
+        self.deploy_mode
+    }
+
+    /// Which symlink targets deploys from this store accept. Trees with other symlinks are
+    /// refused before anything is deployed. Defaults to [allowing](SymlinkPolicy::Allow) any.
+    #[must_use]
+    pub fn with_symlink_policy(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlink_policy = policy;
+        self
+    }
+
+    #[must_use]
+    pub fn symlink_policy(&self) -> SymlinkPolicy {
+        self.symlink_policy
+    }
+
+    /// What [`Tree::create`] does with files that are neither regular files, directories nor
+    /// symlinks. Defaults to [skipping](SpecialFilePolicy::Skip) them.
+    #[must_use]
+    pub fn with_special_files(mut self, policy: SpecialFilePolicy) -> Self {
+        self.special_files = policy;
+        self
+    }
+
+    #[must_use]
+    pub fn special_file_policy(&self) -> SpecialFilePolicy {
+        self.special_files
+    }
+
+    /// What [`Tree::create`] and deploys from this store do with names that aren't portable.
+    /// Deploys check every name before anything is deployed. Defaults to
+    /// [keeping](NamePolicy::Preserve) them.
+    #[must_use]
+    pub fn with_name_policy(mut self, policy: NamePolicy) -> Self {
+        self.name_policy = policy;
+        self
+    }
+
+    #[must_use]
+    pub fn name_policy(&self) -> NamePolicy {
+        self.name_policy
+    }
+
+    /// Where hashing and compression run. Defaults to the runtime's blocking threads.
+    #[must_use]
+    pub fn with_cpu_pool(mut self, pool: CpuPool) -> Self {
+        self.cpu_pool = pool;
+        self
+    }
+
+    #[must_use]
+    pub fn cpu_pool(&self) -> &CpuPool {
+        &self.cpu_pool
+    }
+
+    /// Encrypts the compressed objects [`Stream::create`] writes, for repositories pushed to
+    /// untrusted storage. Downloads need the same key on their [`Mirrors`].
+    ///
+    /// Uncompressed objects are the copies deploys link to, so they can't be encrypted, and
+    /// creating streams with [`CompressionKind::None`] fails.
+    #[must_use]
+    pub fn with_encryption(mut self, key: EncryptionKey) -> Self {
+        self.encryption = Some(key);
+        self
+    }
+
+    #[must_use]
+    pub fn encryption(&self) -> Option<&EncryptionKey> {
+        self.encryption.as_ref()
+    }
+
+    /// Which copies of a stream are kept when it's created. Defaults to
+    /// [both](StoreLayout::Both).
+    ///
+    /// Without the uncompressed contents, [`Self::open`] decompresses a compressed object
+    /// instead, and anything that needs a file, like deploying, needs the stream
+    /// [materialized](Self::materialize) first.
+    #[must_use]
+    pub fn with_layout(mut self, layout: StoreLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    #[must_use]
+    pub fn layout(&self) -> StoreLayout {
+        self.layout
+    }
+
+    /// Limits the mode bits deploys give files, like `0o777` to strip setuid, setgid and sticky
+    /// bits from untrusted trees. Keeps every bit by default.
+    #[must_use]
+    pub fn with_mode_mask(mut self, mask: u32) -> Self {
+        self.mode_mask = mask;
+        self
+    }
+
+    #[must_use]
+    pub fn mode_mask(&self) -> u32 {
+        self.mode_mask
+    }
+
+    /// Only deploys trees whose [hash](crate::tree::Tree::hash) is in `hashes`, refusing any
+    /// other before touching the deployment. Pinning the trees operators approved means a
+    /// compromised publisher can't push arbitrary trees to machines that update automatically.
+    #[must_use]
+    pub fn with_allowed_trees<I: IntoIterator<Item = S>, S: Into<String>>(
+        mut self,
+        hashes: I,
+    ) -> Self {
+        self.allowed_trees = Some(hashes.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Whether deploys from this store accept the tree with `hash`.
+    #[must_use]
+    pub fn is_tree_allowed(&self, hash: &str) -> bool {
+        self.allowed_trees
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(hash))
+    }
+
+    pub(crate) fn restricts_trees(&self) -> bool {
+        self.allowed_trees.is_some()
+    }
+
+    /// Records the owner of files added to the store with
+    /// [`Stream::create`](crate::stream::Stream::create), and restores owners on deploy through
+    /// the [owner map](Self::with_owner_map). Off by default, as only root can give files away.
+    #[must_use]
+    pub fn with_owners(mut self, keep: bool) -> Self {
+        self.keep_owners = keep;
+        self
+    }
+
+    #[must_use]
+    pub fn keeps_owners(&self) -> bool {
+        self.keep_owners
+    }
+
+    /// How the owners recorded in trees translate to this machine's users and groups.
+    #[must_use]
+    pub fn with_owner_map(mut self, map: OwnerMap) -> Self {
+        self.owner_map = map;
+        self
+    }
+
+    #[must_use]
+    pub fn owner_map(&self) -> &OwnerMap {
+        &self.owner_map
+    }
+
+    /// Records the modification time of files added to the store with
+    /// [`Stream::create`](crate::stream::Stream::create), for deploys to restore. Off by default,
+    /// as it makes otherwise identical trees hash differently.
+    #[must_use]
+    pub fn with_mtimes(mut self, capture: bool) -> Self {
+        self.capture_mtimes = capture;
+        self
+    }
+
+    #[must_use]
+    pub fn captures_mtimes(&self) -> bool {
+        self.capture_mtimes
+    }
+
+    /// Uses `clock` for access times and the age of temporary files, instead of the system
+    /// clock. Mostly useful for tests, see [`MockClock`](crate::clock::MockClock).
+    #[must_use]
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = SharedClock::new(clock);
+        self
+    }
+
+    pub(crate) fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
+
+    /// Calls `callback` for every [`Event`] while working with the store.
+    #[must_use]
+    pub fn with_events<F: Fn(&Event) + Send + Sync + 'static>(mut self, callback: F) -> Self {
+        self.events = EventSink::new(callback);
+        self
+    }
+
+    /// Emits [`Event::SlowDownload`], [`Event::SlowHashing`] and [`Event::SlowDeploy`] for
+    /// operations beyond `thresholds`. Nothing is reported by default.
+    #[must_use]
+    pub fn with_slow_thresholds(mut self, thresholds: SlowThresholds) -> Self {
+        self.slow = thresholds;
+        self
+    }
+
+    pub(crate) fn slow_thresholds(&self) -> &SlowThresholds {
+        &self.slow
+    }
+
+    pub(crate) fn emit(&self, event: &Event) {
+        self.events.emit(event);
+    }
+
+    /// Reports what downloads and deploys from this store do to `metrics`, like a shared
+    /// [`Counters`](crate::metrics::Counters). Nothing is recorded by default.
+    #[must_use]
+    pub fn with_metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = MetricsSink::new(metrics);
+        self
+    }
+
+    /// Reports to `metrics` as well as whatever the store already reports to.
+    #[must_use]
+    pub(crate) fn and_metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = self.metrics.and(metrics);
+        self
+    }
+
+    pub(crate) fn metrics(&self) -> &dyn Metrics {
+        self.metrics.get()
+    }
+
+    /// Whether a failed hardlink into the store should be replaced by a copy, reporting it if so.
+    pub(crate) fn link_fallback(&self, hash: &str, error: &io::Error) -> bool {
+        let copy = fs::should_copy(error, || nix::sys::statfs::statfs(&self.root));
+        if copy {
+            self.emit(&Event::LinkFallback {
+                hash: hash.to_string(),
+                error: error.kind(),
+            });
+        }
+        copy
+    }
+
+    /// Makes tree downloads start by [cleaning up](Self::clean_incomplete) temporary files
+    /// older than `max_age`.
+    #[must_use]
+    pub fn clean_incomplete_before_download(mut self, max_age: Duration) -> Self {
+        self.incomplete_max_age = Some(max_age);
+        self
+    }
+
+    /// Limits the store to `max_bytes`, counting every object. When an insert would go over the
+    /// limit, the least recently used streams that aren't [pinned](Self::set_roots) are evicted
+    /// first. The limit is best effort, and inserts still succeed when everything is pinned.
+    #[must_use]
+    pub fn with_quota(mut self, max_bytes: u64) -> Self {
+        self.quota = Some(max_bytes);
+        self
+    }
+
+    #[must_use]
+    pub fn quota(&self) -> Option<u64> {
+        self.quota
+    }
+
+    /// Pins every stream the trees refer to and their dictionaries, so that they are never
+    /// evicted to stay under the quota. Replaces any previously registered roots.
+    pub fn set_roots(&mut self, roots: &[Tree]) {
+        self.pinned = roots
+            .iter()
+            .flat_map(all_streams)
+            .flat_map(|stream| std::iter::once(&stream.hash).chain(&stream.dictionary))
+            .map(ToString::to_string)
+            .collect();
+    }
+
+    #[must_use]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where the uncompressed contents of a stream are kept, whether or not they exist yet.
+    #[must_use]
+    pub fn path_of(&self, hash: &str) -> PathBuf {
+        self.root.join(hash)
+    }
+
+    /// Where a compressed copy of a stream is kept. Uncompressed objects are the contents
+    /// themselves.
+    #[must_use]
+    pub fn object_path_of(&self, hash: &str, compression_kind: CompressionKind) -> PathBuf {
+        self.root.join(format!(
+            "{hash}{}",
+            compression_kind.get_extension_with_dot()
+        ))
+    }
+
+    /// Whether the uncompressed contents of a stream are in the store.
+    #[must_use]
+    pub fn contains(&self, hash: &str) -> bool {
+        self.path_of(hash).exists()
+    }
+
+    /// Opens the uncompressed contents of a stream for reading, decompressing a compressed
+    /// object of it if the store doesn't have them.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically the stream not being in the store)
+    pub async fn open(&self, hash: &str) -> io::Result<Pin<Box<dyn AsyncBufRead + Send>>> {
+        let path = self.path_of(hash);
+        let reader = match self.compressed_object(hash) {
+            Some((object, compression)) if !path.exists() => {
+                let reader = decrypt(fs::open_buffered(object).await?, self.encryption());
+                Box::pin(BufReader::new(compression.decompress(reader)))
+            }
+            _ => fs::open_buffered(path).await?,
+        };
+        self.record_access([hash])?;
+        Ok(reader)
+    }
+
+    /// Writes the uncompressed contents of a stream from a compressed object of it, unless the
+    /// store already has them, like for deploying from a
+    /// [compressed only](StoreLayout::CompressedOnly) store.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically the stream not being in the store)
+    /// - [`Error::HashError`](crate::Error::HashError) if the object is corrupted
+    pub async fn materialize(&self, hash: &str) -> crate::Result<PathBuf> {
+        if self.contains(hash) {
+            return Ok(self.path_of(hash));
+        }
+        let reader = self.open(hash).await?;
+        self.insert_from_reader(hash, reader).await
+    }
+
+    /// A compressed object of the stream, in the order of [`CompressionKind::ALL`].
+    fn compressed_object(&self, hash: &str) -> Option<(PathBuf, CompressionKind)> {
+        CompressionKind::ALL
+            .into_iter()
+            .filter(|&kind| kind != CompressionKind::None)
+            .map(|kind| (self.object_path_of(hash, kind), kind))
+            .find(|(path, _)| path.exists())
+    }
+
+    fn read_access_index(&self) -> HashMap<String, u64> {
+        // A lost index only makes eviction fall back onto modification times
+        let Ok(index) = std::fs::read_to_string(self.root.join(ACCESS_INDEX)) else {
+            return HashMap::new();
+        };
+
+        index
+            .lines()
+            .filter_map(|line| {
+                let (hash, time) = line.split_once(' ')?;
+                Some((hash.to_string(), time.parse().ok()?))
+            })
+            .collect()
+    }
+
+    /// Locks the access index, `exclusive` for rewriting it rather than appending to it.
+    fn lock_access_index(&self, exclusive: bool) -> io::Result<Flock<std::fs::File>> {
+        let file = std::fs::File::options()
+            .append(true)
+            .create(true)
+            .open(self.root.join(ACCESS_INDEX_LOCK))?;
+        let arg = if exclusive {
+            FlockArg::LockExclusive
+        } else {
+            FlockArg::LockShared
+        };
+        Flock::lock(file, arg).map_err(|(_, e)| e.into())
+    }
+
+    /// Whether the access index grew well past a line per stream, so it's worth compacting.
+    fn access_index_oversized(&self, streams: usize) -> bool {
+        let len = std::fs::metadata(self.root.join(ACCESS_INDEX)).map_or(0, |m| m.len());
+        len > 64 * 1024 && len > 4 * ACCESS_LINE_LEN * streams as u64
+    }
+
+    /// Replaces the access index. The caller must hold the [exclusive
+    /// lock](Self::lock_access_index) from reading it until here.
+    fn write_access_index(&self, index: &HashMap<String, u64>) -> io::Result<()> {
+        let mut contents = String::new();
+        for (hash, time) in index {
+            // Writing to a string can't fail
+            let _ = writeln!(contents, "{hash} {time}");
+        }
+
+        let tmp_file_path = self.temp_path("access-index");
+        std::fs::write(&tmp_file_path, contents)?;
+        fs::rename(tmp_file_path, self.root.join(ACCESS_INDEX))
+    }
+
+    /// Marks streams as just used. Only tracked when the store has a quota.
+    pub(crate) fn record_access<'a, I: IntoIterator<Item = &'a str>>(
+        &self,
+        hashes: I,
+    ) -> io::Result<()> {
+        if self.quota.is_none() {
+            return Ok(());
+        }
+
+        let now = unix_millis(self.now());
+        let mut lines = String::new();
+        for hash in hashes {
+            // Writing to a string can't fail
+            let _ = writeln!(lines, "{hash} {now}");
+        }
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        // A single appending write never interleaves with other processes' ones
+        let _lock = self.lock_access_index(false)?;
+        std::fs::File::options()
+            .append(true)
+            .create(true)
+            .open(self.root.join(ACCESS_INDEX))?
+            .write_all(lines.as_bytes())
+    }
+
+    /// Drops streams that are gone from the access index.
+    pub(crate) fn forget_access<'a, I: IntoIterator<Item = &'a str>>(
+        &self,
+        hashes: I,
+    ) -> io::Result<()> {
+        let mut hashes = hashes.into_iter().peekable();
+        if hashes.peek().is_none() {
+            return Ok(());
+        }
+
+        let _lock = self.lock_access_index(true)?;
+        let mut index = self.read_access_index();
+        let len = index.len();
+        for hash in hashes {
+            index.remove(hash);
+        }
+        self.forget_usage()?;
+
+        if index.len() == len {
+            return Ok(());
+        }
+        self.write_access_index(&index)
+    }
+
+    /// The running total of the store's usage, if it's known. The caller must hold the
+    /// [exclusive lock](Self::lock_access_index).
+    fn read_usage(&self) -> Option<(u64, usize)> {
+        let usage = std::fs::read_to_string(self.root.join(STORE_USAGE)).ok()?;
+        let (bytes, streams) = usage.trim_end().split_once(' ')?;
+        Some((bytes.parse().ok()?, streams.parse().ok()?))
+    }
+
+    fn write_usage(&self, bytes: u64, streams: usize) -> io::Result<()> {
+        let tmp_file_path = self.temp_path("store-usage");
+        std::fs::write(&tmp_file_path, format!("{bytes} {streams}\n"))?;
+        fs::rename(tmp_file_path, self.root.join(STORE_USAGE))
+    }
+
+    /// Makes the next insert list the store again, for when objects were removed outside of
+    /// [`Self::make_room`]. The caller must hold the [exclusive lock](Self::lock_access_index).
+    fn forget_usage(&self) -> io::Result<()> {
+        match std::fs::remove_file(self.root.join(STORE_USAGE)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+
+    /// Evicts least recently used streams until `incoming` more bytes fit within the quota.
+    /// `hash` is about to be inserted, so is never evicted.
+    pub(crate) fn make_room(&self, hash: &str, incoming: u64) -> io::Result<()> {
+        let Some(quota) = self.quota else {
+            return Ok(());
+        };
+
+        let _lock = self.lock_access_index(true)?;
+        if let Some((bytes, streams)) = self.read_usage() {
+            if bytes + incoming <= quota && !self.access_index_oversized(streams) {
+                // Counting a stream that is already there only makes the next eviction early
+                return self.write_usage(bytes + incoming, streams + 1);
+            }
+        }
+
+        // Every object of a stream is evicted together
+        let mut objects: HashMap<String, (u64, u64, Vec<PathBuf>)> = HashMap::new();
+        let mut total = 0;
+        for entry in std::fs::read_dir(&self.root)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(object_hash) = name.to_str().and_then(object_hash) else {
+                continue;
+            };
+
+            let metadata = entry.metadata()?;
+            let modified = unix_millis(metadata.modified()?);
+
+            total += metadata.len();
+            let object = objects.entry(object_hash.to_string()).or_default();
+            object.0 += metadata.len();
+            object.1 = object.1.max(modified);
+            object.2.push(entry.path());
+        }
+
+        if total + incoming <= quota && !self.access_index_oversized(objects.len()) {
+            return self.write_usage(total + incoming, objects.len() + 1);
+        }
+
+        let mut index = self.read_access_index();
+        index.retain(|hash, _| objects.contains_key(hash));
+
+        let mut candidates: Vec<_> = objects
+            .into_iter()
+            .filter(|(candidate, _)| candidate != hash && !self.pinned.contains(candidate))
+            .map(|(hash, (size, modified, paths))| {
+                let used = index.get(&hash).copied().unwrap_or(modified);
+                (used, hash, size, paths)
+            })
+            .collect();
+        candidates.sort_unstable();
+
+        for (_, hash, size, paths) in candidates {
+            if total + incoming <= quota {
+                break;
+            }
+
+            for path in paths {
+                std::fs::remove_file(path)?;
+            }
+            index.remove(&hash);
+            total -= size;
+        }
+
+        self.write_access_index(&index)?;
+        self.write_usage(total + incoming, index.len() + 1)
+    }
+
+    /// Lists the objects needed to serve every one of `trees` with `compression`, and their
+    /// total size. Streams shared between trees are only counted once. Useful for sizing a
+    /// mirror, whose `streams` directory is laid out like a store.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically permissions)
+    pub fn required_objects(
+        &self,
+        trees: &[Tree],
+        compression: CompressionKind,
+    ) -> io::Result<RequiredObjects> {
+        let mut hashes: Vec<String> = trees
+            .iter()
+            .flat_map(all_streams)
+            .map(|stream| stream.hash.to_string())
+            .collect();
+        hashes.sort_unstable();
+        hashes.dedup();
+
+        let mut required = RequiredObjects::default();
+        for hash in &hashes {
+            match std::fs::metadata(self.object_path_of(hash, compression)) {
+                Ok(metadata) => required.bytes += metadata.len(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    required.missing.push(hash.clone());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        required.hashes = hashes;
+
+        Ok(required)
+    }
+
+    /// Deletes every object that none of `roots` refer to, including compressed copies. The
+    /// dictionaries of streams that are kept are kept too.
+    ///
+    /// Temporary files are left alone, as they may belong to a download that is still running.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically permissions)
+    pub fn gc(&self, roots: &[Tree]) -> io::Result<GcReport> {
+        let live: HashSet<&str> = roots
+            .iter()
+            .flat_map(all_streams)
+            .flat_map(|stream| std::iter::once(&stream.hash).chain(&stream.dictionary))
+            .map(ObjectHash::as_str)
+            .collect();
+
+        let mut report = GcReport::default();
+
+        for entry in std::fs::read_dir(&self.root)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(hash) = name.to_str().and_then(object_hash) else {
+                continue;
+            };
+            if live.contains(hash) || !entry.file_type()?.is_file() {
+                continue;
+            }
+
+            let metadata = entry.metadata()?;
+            std::fs::remove_file(entry.path())?;
+
+            report.removed += 1;
+            if metadata.nlink() == 1 {
+                report.bytes_freed += metadata.len();
+            }
+        }
+
+        if report.removed > 0 && self.root.join(STORE_USAGE).exists() {
+            let _lock = self.lock_access_index(true)?;
+            self.forget_usage()?;
+        }
+        Ok(report)
+    }
+
+    /// Deletes temporary files left behind by crashed downloads, returning how many were
+    /// removed.
+    ///
+    /// Only files older than `max_age` are removed, as newer ones may belong to a download that is
+    /// still running, possibly in another process.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically permissions)
+    pub fn clean_incomplete(&self, max_age: Duration) -> io::Result<usize> {
+        let mut removed = 0;
+
+        for entry in std::fs::read_dir(&self.root)? {
+            let entry = entry?;
+            if !entry.file_name().as_encoded_bytes().ends_with(b".tmp") {
+                continue;
+            }
+
+            // Files that are already gone were cleaned up by their download
+            let age = match entry.metadata().and_then(|metadata| metadata.modified()) {
+                Ok(modified) => self.now().duration_since(modified).unwrap_or_default(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+
+            if age > max_age {
+                match std::fs::remove_file(entry.path()) {
+                    Ok(()) => removed += 1,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Runs [`Self::clean_incomplete`] if the store was configured to before downloading.
+    pub(crate) fn clean_before_download(&self) -> io::Result<()> {
+        if let Some(max_age) = self.incomplete_max_age {
+            self.clean_incomplete(max_age)?;
+        }
+        Ok(())
+    }
+
+    /// Re-hashes every object in the store, decompressing compressed copies first.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically permissions). Errors while reading an object's contents
+    ///   are reported as corruption instead.
+    pub async fn verify(&self) -> io::Result<VerifyReport> {
+        self.verify_where(|_| true).await
+    }
+
+    /// Re-hashes the objects whose metadata `filter` accepts.
+    async fn verify_where<F: Fn(&std::fs::Metadata) -> bool>(
+        &self,
+        filter: F,
+    ) -> io::Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+
+        for entry in std::fs::read_dir(&self.root)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            let Some(hash) = object_hash(name) else {
+                continue;
+            };
+            let extension = name.split_once('.').map(|(_, extension)| extension);
+            let Some(compression) = CompressionKind::from_extension(extension) else {
+                continue;
+            };
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() || !filter(&metadata) {
+                continue;
+            }
+
+            report.checked += 1;
+            let reader = fs::open_buffered(entry.path()).await?;
+            let contents_hash = hash_object(reader, compression, self.encryption()).await;
+            if contents_hash.ok().as_deref() != Some(hash) {
+                report.corrupted.push(StoredObject {
+                    hash: hash.to_string(),
+                    compression,
+                });
+            }
+        }
+
+        report.corrupted.sort_by(|a, b| a.hash.cmp(&b.hash));
+        Ok(report)
+    }
+
+    /// Verifies the store, deleting every corrupted object and downloading corrupted streams
+    /// again from `mirrors`. Compressed copies are only deleted, as downloads are stored
+    /// uncompressed.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically permissions)
+    /// - Network errors, if no mirror could serve a corrupted stream
+    pub async fn verify_and_repair(
+        &self,
+        mirrors: &Mirrors,
+        compression: CompressionKind,
+    ) -> crate::Result<VerifyReport> {
+        let mut report = self.verify().await?;
+
+        for object in &report.corrupted {
+            fs::remove_file(self.object_path_of(&object.hash, object.compression)).await?;
+            if object.compression != CompressionKind::None {
+                continue;
+            }
+
+            let stream = Stream {
+                hash: ObjectHash::new_unchecked(object.hash.clone()),
+                file_name: OsString::new(),
+                compression: None,
+                dictionary: None,
+                disk_size: None,
+                network_size: None,
+                #[cfg(unix)]
+                mode: None,
+                #[cfg(unix)]
+                mtime: None,
+                #[cfg(unix)]
+                owner: None,
+            };
+            stream.download_mirrored(mirrors, self, compression).await?;
+            report.repaired.push(object.hash.clone());
+        }
+
+        Ok(report)
+    }
+
+    /// A path to write to before atomically renaming into place. Each call returns a new path,
+    /// so that concurrent writers never share a temporary file.
+    pub(crate) fn temp_path(&self, name: &str) -> PathBuf {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+        self.root
+            .join(format!("{name}.{}-{n}.tmp", std::process::id()))
+    }
+
+    /// Creates a file to write to before persisting it, along with a writer for it.
+    ///
+    /// On Linux this is an anonymous `O_TMPFILE`, falling back onto a [named](Self::temp_path)
+    /// temporary file where the filesystem doesn't support them.
+    pub(crate) async fn create_temp(&self, name: &str) -> io::Result<(fs::File, TempFile)> {
+        #[cfg(target_os = "linux")]
+        if let Some(file) = self.create_anonymous() {
+            let fd = OwnedFd::from(file.try_clone()?);
+            return Ok((fs::File::from_std(file), TempFile::Anonymous(fd)));
+        }
+
+        let path = self.temp_path(name);
+        let file = fs::File::create_new(&path).await?;
+        Ok((file, TempFile::Named(path)))
+    }
+
+    /// Like [`Self::create_temp`], for writing from [pool](CpuPool) threads.
+    pub(crate) fn create_temp_blocking(&self, name: &str) -> io::Result<(std::fs::File, TempFile)> {
+        #[cfg(target_os = "linux")]
+        if let Some(file) = self.create_anonymous() {
+            let fd = OwnedFd::from(file.try_clone()?);
+            return Ok((file, TempFile::Anonymous(fd)));
+        }
+
+        let path = self.temp_path(name);
+        let file = std::fs::File::create_new(&path)?;
+        Ok((file, TempFile::Named(path)))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn create_anonymous(&self) -> Option<std::fs::File> {
+        use nix::fcntl::{OFlag, open};
+        use nix::sys::stat::Mode;
+
+        // Anonymous files can only be linked in through /proc
+        if !Path::new("/proc/self/fd").is_dir() {
+            return None;
+        }
+
+        let flags = OFlag::O_TMPFILE | OFlag::O_WRONLY | OFlag::O_CLOEXEC;
+        open(&self.root, flags, Mode::from_bits_truncate(0o666))
+            .ok()
+            .map(std::fs::File::from)
+    }
+
+    /// Gives a fully written temporary file its final name, replacing anything already there.
+    pub(crate) fn persist_temp(&self, temp: TempFile, path: &Path) -> io::Result<()> {
+        match temp {
+            #[cfg(target_os = "linux")]
+            TempFile::Anonymous(fd) => {
+                use nix::fcntl::{AT_FDCWD, AtFlags};
+                use nix::unistd::linkat;
+                use std::os::fd::AsRawFd;
+
+                let link = |path: &Path| {
+                    let proc_path = format!("/proc/self/fd/{}", fd.as_raw_fd());
+                    linkat(
+                        AT_FDCWD,
+                        proc_path.as_str(),
+                        AT_FDCWD,
+                        path,
+                        AtFlags::AT_SYMLINK_FOLLOW,
+                    )
+                };
+
+                match link(path) {
+                    Ok(()) => Ok(()),
+                    // Links never replace, so go through a name that can be renamed over it
+                    Err(nix::errno::Errno::EEXIST) => {
+                        let tmp_file_path = self.temp_path("persist");
+                        link(&tmp_file_path)?;
+                        fs::rename(tmp_file_path.as_path(), path)
+                    }
+                    Err(e) => Err(e.into()),
+                }
+            }
+            TempFile::Named(tmp_file_path) => fs::rename(tmp_file_path.as_path(), path),
+        }
+    }
+
+    /// Throws away a temporary file that won't be persisted.
+    pub(crate) async fn discard_temp(temp: TempFile) -> io::Result<()> {
+        match temp {
+            #[cfg(target_os = "linux")]
+            TempFile::Anonymous(_) => Ok(()),
+            TempFile::Named(tmp_file_path) => fs::remove_file(tmp_file_path).await,
+        }
+    }
+
+    /// Writes a stream's uncompressed contents into the store, only keeping them if they hash to
+    /// `hash`.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically out of space)
+    /// - Errors from the reader
+    /// - [`Error::HashError`](crate::Error::HashError) if the contents don't match the hash
+    pub async fn insert_from_reader<R: AsyncRead + Unpin>(
+        &self,
+        hash: &str,
+        mut reader: R,
+    ) -> crate::Result<PathBuf> {
+        let (mut file, temp) = self.create_temp(hash).await?;
+
+        let mut hasher = Hasher::new();
+        let mut hashing = Duration::ZERO;
+
+        let res: crate::Result<()> = async {
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = reader.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+
+                let chunk = &buf[..n];
+                file.write_all(chunk).await?;
+                let started = Instant::now();
+                hasher.write_all(chunk)?;
+                hashing += started.elapsed();
+            }
+            file.flush().await?;
+            Ok(())
+        }
+        .await;
+
+        // Never leave a partial download behind, so that it can be retried
+        if let Err(e) = res {
+            Self::discard_temp(temp).await?;
+            return Err(e);
+        }
+
+        let bytes = hasher.count();
+        self.metrics().hashed(bytes, hashing);
+        self.metrics().written(bytes);
+        self.finish_insert(hash, hasher, temp).await
+    }
+
+    /// Hardlinks a stream's uncompressed contents into the store, verifying them.
+    ///
+    /// Returns `None` if the file can't be linked (Typically due to being on another
+    /// filesystem), in which case it should be copied instead.
+    pub(crate) async fn insert_link(
+        &self,
+        hash: &str,
+        source: &Path,
+    ) -> crate::Result<Option<PathBuf>> {
+        let tmp_file_path = self.temp_path(hash);
+
+        if let Err(e) = std::fs::hard_link(source, &tmp_file_path) {
+            if self.link_fallback(hash, &e) {
+                return Ok(None);
+            }
+            return Err(e.into());
+        }
+
+        let mut hasher = Hasher::new();
+        let mut hashing = Duration::ZERO;
+        let res: io::Result<()> = async {
+            let mut stream = fs::read_chunked(&tmp_file_path).await?;
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk?;
+                let started = Instant::now();
+                hasher.write_all(&chunk)?;
+                hashing += started.elapsed();
+            }
+            Ok(())
+        }
+        .await;
+        self.metrics().hashed(hasher.count(), hashing);
+
+        if let Err(e) = res {
+            fs::remove_file(tmp_file_path).await?;
+            return Err(e.into());
+        }
+
+        self.finish_insert(hash, hasher, TempFile::Named(tmp_file_path))
+            .await
+            .map(Some)
+    }
+
+    pub(crate) async fn finish_insert(
+        &self,
+        hash: &str,
+        hasher: Hasher,
+        temp: TempFile,
+    ) -> crate::Result<PathBuf> {
+        let actual = hasher.finalize().to_hex().to_string();
+
+        if actual == hash {
+            let file_path = self.path_of(hash);
+            self.make_room(hash, temp.len()?)?;
+            self.persist_temp(temp, &file_path)?;
+            self.record_access([hash])?;
+            Ok(file_path)
+        } else {
+            Self::discard_temp(temp).await?;
+            Err(crate::Error::HashError(hash.to_string(), actual))
+        }
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |time| {
+        u64::try_from(time.as_millis()).unwrap_or(u64::MAX)
+    })
+}
+
+/// The layout of the store this version of the crate uses, see [`migrate`].
+pub const LAYOUT_VERSION: u32 = 1;
+
+/// Records the layout version. Stores from before it existed have no marker, and are version 0.
+const LAYOUT_MARKER: &str = ".layout-version";
+
+/// What [`migrate`] changed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MigrateReport {
+    pub from_version: u32,
+    pub to_version: u32,
+    /// Objects renamed to the current naming
+    pub renamed: usize,
+}
+
+/// Upgrades a store created by an older version of the crate in place, so that it doesn't have
+/// to be wiped. Stores that are already current are left alone.
+///
+/// - Version 1 names compressed objects with the canonical extensions (`.zstd`, not `.zst`),
+///   builds the [access index](Store::with_quota) from modification times, and adds the layout
+///   marker.
+///
+/// # Errors
+///
+/// - Filesystem errors (Typically permissions)
+/// - [`io::ErrorKind::Unsupported`] for stores created by a newer version of the crate
+pub fn migrate<P: AsRef<Path>>(stream_dir: P) -> io::Result<MigrateReport> {
+    let store = Store::new(stream_dir.as_ref());
+    let marker = store.root.join(LAYOUT_MARKER);
+
+    let from_version = match std::fs::read_to_string(&marker) {
+        Ok(version) => version
+            .trim()
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid layout marker"))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+
+    let mut report = MigrateReport {
+        from_version,
+        to_version: from_version,
+        renamed: 0,
+    };
+
+    if from_version > LAYOUT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("store layout version {from_version} is newer than {LAYOUT_VERSION}"),
+        ));
+    }
+
+    if from_version < 1 {
+        report.renamed = store.migrate_v1()?;
+    }
+
+    if from_version < LAYOUT_VERSION {
+        let tmp_file_path = store.temp_path("layout-version");
+        std::fs::write(&tmp_file_path, format!("{LAYOUT_VERSION}\n"))?;
+        fs::rename(tmp_file_path, marker)?;
+        report.to_version = LAYOUT_VERSION;
+    }
+
+    Ok(report)
+}
+
+impl Store {
+    fn migrate_v1(&self) -> io::Result<usize> {
+        let mut renamed = 0;
+        let _lock = self.lock_access_index(true)?;
+        let mut index = self.read_access_index();
+
+        for entry in std::fs::read_dir(&self.root)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            let Some(hash) = object_hash(name) else {
+                continue;
+            };
+
+            let extension = name.split_once('.').map(|(_, extension)| extension);
+            if let Some(kind) = CompressionKind::from_extension(extension) {
+                let path = self.object_path_of(hash, kind);
+                if path != entry.path() {
+                    // An object already under the canonical name is kept instead
+                    if path.exists() {
+                        std::fs::remove_file(entry.path())?;
+                    } else {
+                        std::fs::rename(entry.path(), path)?;
+                    }
+                    renamed += 1;
+                }
+            }
+
+            if !index.contains_key(hash) {
+                let modified = unix_millis(entry.metadata()?.modified()?);
+                index.insert(hash.to_string(), modified);
+            }
+        }
+
+        self.write_access_index(&index)?;
+        Ok(renamed)
+    }
+}
+
+/// A file being written into the store, which only appears under its final name once
+/// [persisted](Store::persist_temp).
+pub(crate) enum TempFile {
+    /// Unnamed (Linux `O_TMPFILE`), so nothing is left behind if the process dies while writing
+    #[cfg(target_os = "linux")]
+    Anonymous(OwnedFd),
+    Named(PathBuf),
+}
+
+impl TempFile {
+    pub(crate) fn len(&self) -> io::Result<u64> {
+        match self {
+            #[cfg(target_os = "linux")]
+            TempFile::Anonymous(fd) => Ok(std::fs::File::from(fd.try_clone()?).metadata()?.len()),
+            TempFile::Named(tmp_file_path) => Ok(tmp_file_path.metadata()?.len()),
+        }
+    }
+}
+
+/// Hashes everything a reader returns.
+async fn hash_reader<R: AsyncRead + Unpin>(mut reader: R) -> io::Result<String> {
+    let mut hasher = Hasher::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.write_all(&buf[..n])?;
+    }
+
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Hashes the contents of an object, decrypting and decompressing it first unless it's
+/// uncompressed.
+pub(crate) async fn hash_object(
+    reader: Pin<Box<dyn AsyncBufRead + Send>>,
+    compression: CompressionKind,
+    key: Option<&EncryptionKey>,
+) -> io::Result<String> {
+    let reader = if compression == CompressionKind::None {
+        reader
+    } else {
+        decrypt(reader, key)
+    };
+    hash_reader(compression.decompress(reader)).await
+}
+
+/// Temporary files at least this old are left behind, rather than still being written.
+const STALE_TEMP_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// What [`startup_maintenance`] did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MaintenanceReport {
+    /// Temporary files from interrupted downloads, see [`Store::clean_incomplete`]
+    pub removed_incomplete: usize,
+    /// Access index entries for streams that are no longer in the store
+    pub forgotten: usize,
+    /// Recently added objects that were re-hashed. The corrupted ones were deleted.
+    pub verified: VerifyReport,
+}
+
+/// Tidies up a store before a long-running process starts using it: temporary files older than
+/// an hour are removed, and the [access index](Store::with_quota) is brought back in line with
+/// the objects in the store. With `verify_recent`, objects modified within it are re-hashed too,
+/// as those are the ones a crash or power loss may have left corrupted, and corrupted ones are
+/// deleted so they get downloaded again.
+///
+/// Objects are read with the store's [encryption key](Store::with_encryption), so it should be
+/// set up the way the process will use it.
+///
+/// # Errors
+///
+/// - Filesystem errors (Typically permissions)
+pub async fn startup_maintenance(
+    store: &Store,
+    verify_recent: Option<Duration>,
+) -> io::Result<MaintenanceReport> {
+    let mut report = MaintenanceReport {
+        removed_incomplete: store.clean_incomplete(STALE_TEMP_AGE)?,
+        ..MaintenanceReport::default()
+    };
+
+    if let Some(max_age) = verify_recent {
+        let since = store.now().checked_sub(max_age).unwrap_or(UNIX_EPOCH);
+        report.verified = store
+            .verify_where(|metadata| metadata.modified().is_ok_and(|modified| modified >= since))
+            .await?;
+        for object in &report.verified.corrupted {
+            fs::remove_file(store.object_path_of(&object.hash, object.compression)).await?;
+        }
+    }
+
+    report.forgotten = store.prune_access_index()?;
+    Ok(report)
+}
+
+impl Store {
+    /// Drops access index entries for streams without any object left, returning how many.
+    fn prune_access_index(&self) -> io::Result<usize> {
+        let _lock = self.lock_access_index(true)?;
+        // Objects may have been removed while no process was running
+        self.forget_usage()?;
+        let mut index = self.read_access_index();
+        if index.is_empty() {
+            return Ok(0);
+        }
+
+        let mut stored = HashSet::new();
+        for entry in std::fs::read_dir(&self.root)? {
+            let name = entry?.file_name();
+            if let Some(hash) = name.to_str().and_then(object_hash) {
+                stored.insert(hash.to_string());
+            }
+        }
+
+        let len = index.len();
+        index.retain(|hash, _| stored.contains(hash));
+        let forgotten = len - index.len();
+        if forgotten > 0 {
+            self.write_access_index(&index)?;
+        }
+        Ok(forgotten)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[tokio::test]
+    async fn test_store_insert() -> crate::Result<()> {
+        let dir = TempDir::new()?;
+        let store = Store::new(dir.path());
+        let hash = blake3::hash(b"contents").to_hex().to_string();
+
+        assert!(!store.contains(&hash));
+        assert_ne!(store.temp_path(&hash), store.temp_path(&hash));
+
+        let res = store.insert_from_reader(&hash, &b"tampered"[..]).await;
+        assert!(matches!(res, Err(crate::Error::HashError(..))));
+        assert!(!store.contains(&hash));
+
+        let path = store.insert_from_reader(&hash, &b"contents"[..]).await?;
+        assert_eq!(path, store.path_of(&hash));
+        assert!(store.contains(&hash));
+
+        let mut contents = Vec::new();
+        store.open(&hash).await?.read_to_end(&mut contents).await?;
+        assert_eq!(contents, b"contents");
+
+        // Only the verified stream is left behind
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+        assert_eq!(
+            store.object_path_of(&hash, CompressionKind::Zstd),
+            dir.path().join(format!("{hash}.zstd"))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_temp_file() -> crate::Result<()> {
+        let dir = TempDir::new()?;
+        let store = Store::new(dir.path());
+        let path = dir.path().join("persisted");
+
+        for contents in [&b"first"[..], b"second"] {
+            let (mut file, temp) = store.create_temp("test").await?;
+            file.write_all(contents).await?;
+            file.flush().await?;
+
+            // Nothing is visible while writing, where anonymous files are supported
+            let visible = std::fs::read_dir(dir.path())?.count();
+            match temp {
+                #[cfg(target_os = "linux")]
+                TempFile::Anonymous(_) => assert_eq!(visible, usize::from(path.exists())),
+                TempFile::Named(_) => assert_eq!(visible, usize::from(path.exists()) + 1),
+            }
+
+            store.persist_temp(temp, &path)?;
+            assert_eq!(fs::read_to_end(&path).await?, contents);
+        }
+
+        // Discarded files leave nothing behind either
+        let (_, temp) = store.create_temp("test").await?;
+        Store::discard_temp(temp).await?;
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_quota() -> crate::Result<()> {
+        let dir = TempDir::new()?;
+        let clock = MockClock::new(SystemTime::now());
+        let mut store = Store::new(dir.path())
+            .with_quota(20)
+            .with_clock(clock.clone());
+
+        let insert = async |store: &Store, contents: &[u8]| {
+            clock.advance(Duration::from_secs(1));
+            let hash = blake3::hash(contents).to_hex().to_string();
+            store
+                .insert_from_reader(&hash, contents)
+                .await
+                .map(|_| hash)
+        };
+
+        let first = insert(&store, b"aaaaaaaaaa").await?;
+        let second = insert(&store, b"bbbbbbbbbb").await?;
+        let third = insert(&store, b"cccccccccc").await?;
+        assert!(!store.contains(&first));
+        assert!(store.contains(&second));
+
+        // Pinned streams are kept even when they are the least recently used
+        store.set_roots(&[Tree {
+            permissions: 0o755,
+            streams: vec![Stream {
+                hash: ObjectHash::new(second.clone())?,
+                file_name: "b".into(),
+                compression: None,
+                dictionary: None,
+                disk_size: None,
+                network_size: None,
+                mode: None,
+                mtime: None,
+                owner: None,
+            }],
+            subtrees: Vec::new(),
+            symlinks: Vec::new(),
+            specials: Vec::new(),
+            meta: None,
+        }]);
+        let fourth = insert(&store, b"dddddddddd").await?;
+        assert!(store.contains(&second));
+        assert!(!store.contains(&third));
+
+        // Access times survive reopening the store
+        let store = Store::new(dir.path())
+            .with_quota(20)
+            .with_clock(clock.clone());
+        clock.advance(Duration::from_secs(1));
+        store.open(&second).await?;
+        let fifth = insert(&store, b"eeeeeeeeee").await?;
+        assert!(store.contains(&second));
+        assert!(!store.contains(&fourth));
+        assert!(store.contains(&fifth));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_usage() -> crate::Result<()> {
+        let dir = TempDir::new()?;
+        let clock = MockClock::new(SystemTime::now());
+        let store = Store::new(dir.path())
+            .with_quota(30)
+            .with_clock(clock.clone());
+
+        let insert = async |store: &Store, contents: &[u8]| {
+            clock.advance(Duration::from_secs(1));
+            let hash = blake3::hash(contents).to_hex().to_string();
+            store
+                .insert_from_reader(&hash, contents)
+                .await
+                .map(|_| hash)
+        };
+
+        let first = insert(&store, b"aaaaaaaaaa").await?;
+        insert(&store, b"bbbbbbbbbb").await?;
+        assert_eq!(store.read_usage(), Some((20, 2)));
+
+        // Objects added behind the store's back aren't counted, as it isn't listed again
+        let unseen = blake3::hash(b"cccccccccc").to_hex().to_string();
+        std::fs::write(store.path_of(&unseen), b"cccccccccc")?;
+        insert(&store, b"dddddddddd").await?;
+        assert!(store.contains(&first));
+        assert!(store.contains(&unseen));
+        assert_eq!(store.read_usage(), Some((30, 3)));
+
+        // Until startup maintenance makes the next insert list it
+        startup_maintenance(&store, None).await?;
+        assert_eq!(store.read_usage(), None);
+        insert(&store, b"eeeeeeeeee").await?;
+        assert!(!store.contains(&first));
+        assert!(!store.contains(&unseen));
+        assert_eq!(store.read_usage(), Some((30, 3)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_quota_dictionary() -> crate::Result<()> {
+        let dir = TempDir::new()?;
+        let clock = MockClock::new(SystemTime::now());
+        let mut store = Store::new(dir.path())
+            .with_quota(30)
+            .with_clock(clock.clone());
+
+        let insert = async |store: &Store, contents: &[u8]| {
+            clock.advance(Duration::from_secs(1));
+            let hash = blake3::hash(contents).to_hex().to_string();
+            store
+                .insert_from_reader(&hash, contents)
+                .await
+                .map(|_| hash)
+        };
+
+        // The dictionary is the least recently used, but the root can't be read without it
+        let dictionary = crate::dictionary::Dictionary::from_bytes(b"dddddddddd".to_vec());
+        dictionary.insert(&store).await?;
+        let stream = insert(&store, b"ssssssssss").await?;
+        store.set_roots(&[Tree {
+            permissions: 0o755,
+            streams: vec![Stream {
+                hash: ObjectHash::new(stream.clone())?,
+                file_name: "s".into(),
+                compression: Some(CompressionKind::Zstd),
+                dictionary: Some(ObjectHash::new(dictionary.hash())?),
+                disk_size: None,
+                network_size: None,
+                mode: None,
+                mtime: None,
+                owner: None,
+            }],
+            subtrees: Vec::new(),
+            symlinks: Vec::new(),
+            specials: Vec::new(),
+            meta: None,
+        }]);
+
+        let first = insert(&store, b"aaaaaaaaaa").await?;
+        insert(&store, b"bbbbbbbbbb").await?;
+        assert!(store.contains(dictionary.hash()));
+        assert!(store.contains(&stream));
+        assert!(!store.contains(&first));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_shared_access_index() -> io::Result<()> {
+        let dir = TempDir::new()?;
+        let index_len = || std::fs::metadata(dir.path().join(ACCESS_INDEX)).map(|m| m.len());
+
+        // Like processes sharing a store, none of which loses another's accesses
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let store = Store::new(dir.path()).with_quota(1 << 20);
+                scope.spawn(move || {
+                    for i in 0..50 {
+                        store.record_access([format!("{thread:x}{i:x}").as_str()])?;
+                    }
+                    io::Result::Ok(())
+                });
+            }
+        });
+        let store = Store::new(dir.path()).with_quota(1 << 20);
+        assert_eq!(store.read_access_index().len(), 400);
+
+        // Accesses are appended until compacted
+        let hash = "a".repeat(64);
+        for _ in 0..1000 {
+            store.record_access([hash.as_str()])?;
+        }
+        let appended = index_len()?;
+        assert!(appended > 64 * 1024);
+        store.make_room("b", 0)?;
+        assert!(index_len()? < appended / 10);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_clean_incomplete() -> crate::Result<()> {
+        let dir = TempDir::new()?;
+        let clock = MockClock::new(SystemTime::now());
+        let store = Store::new(dir.path()).with_clock(clock.clone());
+        let hash = blake3::hash(b"contents").to_hex().to_string();
+        store.insert_from_reader(&hash, &b"contents"[..]).await?;
+
+        let stale = store.temp_path(&hash);
+        let running = store.temp_path(&hash);
+        fs::write(&stale, b"partial").await?;
+        clock.advance(Duration::from_secs(3600));
+        fs::write(&running, b"partial").await?;
+        std::fs::File::options()
+            .write(true)
+            .open(&running)?
+            .set_modified(clock.now())?;
+
+        assert_eq!(store.clean_incomplete(Duration::from_secs(60))?, 1);
+        assert!(!stale.exists());
+        assert!(running.exists());
+        assert!(store.contains(&hash));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_startup_maintenance() -> crate::Result<()> {
+        let dir = TempDir::new()?;
+        let store = Store::new(dir.path()).with_quota(1 << 20);
+        let set_modified = |path: &Path, ago: Duration| {
+            std::fs::File::options()
+                .write(true)
+                .open(path)?
+                .set_modified(SystemTime::now() - ago)
+        };
+        let day = Duration::from_secs(24 * 60 * 60);
+
+        let mut hashes = Vec::new();
+        for contents in [b"recent", b"evicts", b"oldest"] {
+            let hash = blake3::hash(contents).to_hex().to_string();
+            store.insert_from_reader(&hash, &contents[..]).await?;
+            hashes.push(hash);
+        }
+        let [recent, evicted, old] = &hashes[..] else {
+            unreachable!()
+        };
+
+        let stale = store.temp_path(recent);
+        fs::write(&stale, b"partial").await?;
+        set_modified(&stale, day)?;
+        std::fs::remove_file(store.path_of(evicted))?;
+        for hash in [recent, old] {
+            std::fs::remove_file(store.path_of(hash))?;
+            fs::write(store.path_of(hash), b"bit rot").await?;
+        }
+        set_modified(&store.path_of(old), 2 * day)?;
+
+        let report = startup_maintenance(&store, Some(day)).await?;
+        assert_eq!(report.removed_incomplete, 1);
+        // Only the recent object is re-hashed and removed
+        assert_eq!(report.verified.checked, 1);
+        assert_eq!(report.verified.corrupted[0].hash, *recent);
+        assert!(!store.contains(recent));
+        assert!(store.contains(old));
+        // Both gone streams are dropped from the access index
+        assert_eq!(report.forgotten, 2);
+        assert_eq!(
+            startup_maintenance(&store, None).await?,
+            MaintenanceReport::default()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_startup_maintenance_encrypted() -> crate::Result<()> {
+        let dir = TempDir::new()?;
+        let files = TempDir::new()?;
+        let day = Duration::from_secs(24 * 60 * 60);
+        std::fs::write(files.path().join("file"), b"contents")?;
+
+        let store = Store::new(dir.path()).with_encryption(EncryptionKey::generate());
+        let stream =
+            Stream::create(files.path().join("file"), &store, CompressionKind::Zstd).await?;
+        let object = store.object_path_of(&stream.hash, CompressionKind::Zstd);
+        // Unreadable without the key
+        assert_eq!(Store::new(dir.path()).verify().await?.corrupted.len(), 1);
+
+        let report = startup_maintenance(&store, Some(day)).await?;
+        // Both the compressed object and the uncompressed copy
+        assert_eq!(report.verified.checked, 2);
+        assert!(report.verified.corrupted.is_empty());
+        assert!(object.exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_migrate() -> crate::Result<()> {
+        let dir = TempDir::new()?;
+        let store = Store::new(dir.path());
+        let hash = blake3::hash(b"contents").to_hex().to_string();
+        store.insert_from_reader(&hash, &b"contents"[..]).await?;
+        fs::write(dir.path().join(format!("{hash}.zst")), b"compressed").await?;
+
+        let report = migrate(dir.path())?;
+        assert_eq!(
+            report,
+            MigrateReport {
+                from_version: 0,
+                to_version: LAYOUT_VERSION,
+                renamed: 1,
+            }
+        );
+        assert!(store.object_path_of(&hash, CompressionKind::Zstd).exists());
+        assert!(store.read_access_index().contains_key(&hash));
+
+        // Migrating is idempotent
+        let report = migrate(dir.path())?;
+        assert_eq!(report.from_version, LAYOUT_VERSION);
+        assert_eq!(report.renamed, 0);
+
+        fs::write(dir.path().join(LAYOUT_MARKER), b"999").await?;
+        let res = migrate(dir.path());
+        assert!(res.is_err_and(|e| e.kind() == io::ErrorKind::Unsupported));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_link_fallback() -> crate::Result<()> {
+        let dir = TempDir::new()?;
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+        let store = Store::new(dir.path())
+            .with_events(move |event| sink.lock().unwrap().push(event.clone()));
+
+        let cross_device = io::Error::from_raw_os_error(nix::libc::EXDEV);
+        assert!(store.link_fallback("abc", &cross_device));
+        // Permissions on a filesystem with hardlinks are a real problem
+        let denied = io::Error::from_raw_os_error(nix::libc::EPERM);
+        assert!(!store.link_fallback("abc", &denied));
+        let missing = io::Error::from_raw_os_error(nix::libc::ENOENT);
+        assert!(!store.link_fallback("abc", &missing));
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            [Event::LinkFallback {
+                hash: "abc".into(),
+                error: cross_device.kind(),
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_gc() -> crate::Result<()> {
+        let compression = CompressionKind::Zstd;
+        let dir = TempDir::new()?;
+        let store = Store::new(dir.path());
+        let original_dir = TempDir::new()?;
+        let deploy_dir = TempDir::new()?;
+
+        fs::write(original_dir.path().join("kept"), b"kept").await?;
+        let kept = Tree::create(&store, original_dir.path(), compression).await?;
+        fs::write(original_dir.path().join("dropped"), b"dropped").await?;
+        fs::write(original_dir.path().join("deployed"), b"deployed").await?;
+        let old = Tree::create(&store, original_dir.path(), compression).await?;
+
+        let dropped = blake3::hash(b"dropped").to_hex().to_string();
+        let deployed = blake3::hash(b"deployed").to_hex().to_string();
+        let size = |path: PathBuf| path.metadata().map(|metadata| metadata.len());
+        let expected_freed = size(store.path_of(&dropped))?
+            + size(store.object_path_of(&dropped, compression))?
+            + size(store.object_path_of(&deployed, compression))?;
+
+        // Only a deployment still holds a link to one of the old streams
+        drop(original_dir);
+        std::fs::hard_link(store.path_of(&deployed), deploy_dir.path().join("deployed"))?;
+        fs::write(store.temp_path("in-flight"), b"partial").await?;
+
+        assert_eq!(store.gc(&[old, kept.clone()])?, GcReport::default());
+
+        let report = store.gc(std::slice::from_ref(&kept))?;
+        assert_eq!(report.removed, 4);
+        assert_eq!(report.bytes_freed, expected_freed);
+        assert!(store.contains(&kept.streams[0].hash));
+        assert!(!store.contains(&dropped));
+        assert!(!store.contains(&deployed));
+
+        // Kept streams, in both forms, and temporary files survive
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_compressed_only() -> crate::Result<()> {
+        let dir = TempDir::new()?;
+        let store = Store::new(dir.path()).with_layout(StoreLayout::CompressedOnly);
+        let repo_dir = TempDir::new()?;
+        let repo_url = repo_dir.path().to_str().unwrap();
+        let local_dir = TempDir::new()?;
+        let local_store = Store::new(local_dir.path());
+        let original_dir = TempDir::new()?;
+        let deploy_dir = TempDir::new()?;
+
+        fs::write(original_dir.path().join("file"), b"compressed only").await?;
+        fs::write(original_dir.path().join("plain"), b"uncompressed").await?;
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::Zstd).await?;
+        let hash = blake3::hash(b"compressed only").to_hex().to_string();
+        assert!(!store.contains(&hash));
+        assert!(store.object_path_of(&hash, CompressionKind::Zstd).exists());
+        let plain = Stream::create(
+            original_dir.path().join("plain"),
+            &store,
+            CompressionKind::None,
+        )
+        .await?;
+        assert!(store.contains(&plain.hash));
+
+        // Reading decompresses on demand, and pushing needs nothing else
+        let mut contents = Vec::new();
+        store.open(&hash).await?.read_to_end(&mut contents).await?;
+        assert_eq!(contents, b"compressed only");
+        assert!(!store.contains(&hash));
+        tree.push(repo_url, &store, CompressionKind::Zstd).await?;
+        tree.download(repo_url, &local_store, CompressionKind::Zstd)
+            .await?;
+        assert!(local_store.contains(&hash));
+
+        // Deploying needs the contents written out first
+        assert_eq!(store.materialize(&hash).await?, store.path_of(&hash));
+        for stream in &tree.streams {
+            store.materialize(&stream.hash).await?;
+        }
+        tree.deploy(&store, deploy_dir.path())?;
+        assert_eq!(
+            fs::read_to_end(deploy_dir.path().join("file")).await?,
+            b"compressed only"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_required_objects() -> crate::Result<()> {
+        let compression = CompressionKind::Zstd;
+        let dir = TempDir::new()?;
+        let store = Store::new(dir.path());
+        let original_dir = TempDir::new()?;
+
+        fs::write(original_dir.path().join("shared"), b"shared").await?;
+        let first = Tree::create(&store, original_dir.path(), compression).await?;
+        fs::write(original_dir.path().join("only"), b"only").await?;
+        let second = Tree::create(&store, original_dir.path(), compression).await?;
+
+        let shared = blake3::hash(b"shared").to_hex().to_string();
+        let only = blake3::hash(b"only").to_hex().to_string();
+        let size = |hash: &str| store.object_path_of(hash, compression).metadata();
+
+        let required = store.required_objects(&[first, second.clone()], compression)?;
+        let mut expected = vec![shared.clone(), only.clone()];
+        expected.sort();
+        assert_eq!(required.hashes, expected);
+        assert_eq!(required.bytes, size(&shared)?.len() + size(&only)?.len());
+        assert!(required.missing.is_empty());
+
+        std::fs::remove_file(store.object_path_of(&only, compression))?;
+        let required = store.required_objects(&[second], compression)?;
+        assert_eq!(required.bytes, size(&shared)?.len());
+        assert_eq!(required.missing, [only]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_verify() -> crate::Result<()> {
+        let compression = CompressionKind::Zstd;
+        let dir = TempDir::new()?;
+        let store = Store::new(dir.path());
+        let repo_dir = TempDir::new()?;
+        let repo_url = repo_dir.path().display().to_string();
+        let original_dir = TempDir::new()?;
+
+        fs::write(original_dir.path().join("a"), b"a").await?;
+        fs::write(original_dir.path().join("b"), b"b").await?;
+        let tree = Tree::create(&store, original_dir.path(), compression).await?;
+        tree.push(&repo_url, &store, compression).await?;
+
+        let report = store.verify().await?;
+        assert_eq!(report.checked, 4);
+        assert!(report.corrupted.is_empty());
+
+        // Replace rather than overwrite, as objects are hardlinked into the repository
+        let a = blake3::hash(b"a").to_hex().to_string();
+        let b = blake3::hash(b"b").to_hex().to_string();
+        let corrupt = |path: PathBuf, contents: &[u8]| {
+            std::fs::remove_file(&path)?;
+            std::fs::write(&path, contents)
+        };
+        corrupt(store.path_of(&a), b"bit rot")?;
+        corrupt(store.object_path_of(&b, compression), b"\x28\xb5\x2f\xfd")?;
+
+        let mut expected = vec![
+            StoredObject {
+                hash: a.clone(),
+                compression: CompressionKind::None,
+            },
+            StoredObject {
+                hash: b.clone(),
+                compression,
+            },
+        ];
+        expected.sort_by(|x, y| x.hash.cmp(&y.hash));
+        assert_eq!(store.verify().await?.corrupted, expected);
+
+        let report = store
+            .verify_and_repair(&Mirrors::from(repo_url.as_str()), compression)
+            .await?;
+        assert_eq!(report.repaired, std::slice::from_ref(&a));
+
+        let report = store.verify().await?;
+        assert_eq!(report.checked, 3);
+        assert!(report.corrupted.is_empty());
+        assert!(store.contains(&a));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+//! Watching a store for changes made by other processes.
+//!
+//! Long-running agents keep state about the store, like which streams they have already
+//! downloaded. Another tool or a manual `rm` can change the store underneath them, so the watcher
+//! reports every object that was added, replaced or removed, and drops removed streams from the
+//! access index itself.
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use std::io;
+use std::os::fd::{AsFd, BorrowedFd};
+
+use super::{Store, StoredObject, object_hash};
+use crate::compression::CompressionKind;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StoreEvent {
+    /// Written, linked or renamed into place
+    Changed(StoredObject),
+    Removed(StoredObject),
+    /// The kernel dropped events, so anything may have changed
+    Overflowed,
+}
+
+/// Watches a store directory with inotify. Linux only.
+///
+/// The watcher never blocks, so [`Self::poll`] can be called whenever convenient, or when the
+/// [file descriptor](AsFd) becomes readable.
+pub struct StoreWatcher {
+    store: Store,
+    inotify: Inotify,
+}
+
+impl Store {
+    /// Starts watching the store, see [`StoreWatcher`].
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically the inotify watch limit being reached)
+    pub fn watch(&self) -> io::Result<StoreWatcher> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        inotify.add_watch(
+            &self.root,
+            AddWatchFlags::IN_CREATE
+                | AddWatchFlags::IN_CLOSE_WRITE
+                | AddWatchFlags::IN_MOVED_TO
+                | AddWatchFlags::IN_DELETE
+                | AddWatchFlags::IN_MOVED_FROM,
+        )?;
+
+        Ok(StoreWatcher {
+            store: self.clone(),
+            inotify,
+        })
+    }
+}
+
+impl StoreWatcher {
+    /// Returns the changes since the last poll, oldest first. Temporary files and the store's own
+    /// bookkeeping are never reported.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically while updating the access index)
+    pub fn poll(&self) -> io::Result<Vec<StoreEvent>> {
+        let mut events = Vec::new();
+
+        loop {
+            let batch = match self.inotify.read_events() {
+                Ok(batch) => batch,
+                Err(nix::errno::Errno::EAGAIN) => break,
+                Err(e) => return Err(e.into()),
+            };
+
+            for event in batch {
+                if event.mask.contains(AddWatchFlags::IN_Q_OVERFLOW) {
+                    events.push(StoreEvent::Overflowed);
+                    continue;
+                }
+
+                let Some(object) = event
+                    .name
+                    .as_deref()
+                    .and_then(|name| name.to_str())
+                    .and_then(stored_object)
+                else {
+                    continue;
+                };
+
+                if event
+                    .mask
+                    .intersects(AddWatchFlags::IN_DELETE | AddWatchFlags::IN_MOVED_FROM)
+                {
+                    events.push(StoreEvent::Removed(object));
+                } else {
+                    events.push(StoreEvent::Changed(object));
+                }
+            }
+        }
+
+        let removed = events.iter().filter_map(|event| match event {
+            StoreEvent::Removed(object) if object.compression == CompressionKind::None => {
+                Some(object.hash.as_str())
+            }
+            _ => None,
+        });
+        self.store.forget_access(removed)?;
+
+        Ok(events)
+    }
+}
+
+impl AsFd for StoreWatcher {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inotify.as_fd()
+    }
+}
+
+fn stored_object(name: &str) -> Option<StoredObject> {
+    let hash = object_hash(name)?;
+    let extension = name.split_once('.').map(|(_, extension)| extension);
+
+    Some(StoredObject {
+        hash: hash.to_string(),
+        compression: CompressionKind::from_extension(extension)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_store_watch() -> crate::Result<()> {
+        let dir = TempDir::new()?;
+        let store = Store::new(dir.path()).with_quota(1024);
+        let hash = blake3::hash(b"contents").to_hex().to_string();
+        let object = StoredObject {
+            hash: hash.clone(),
+            compression: CompressionKind::None,
+        };
+
+        let watcher = store.watch()?;
+        assert!(watcher.poll()?.is_empty());
+
+        store.insert_from_reader(&hash, &b"contents"[..]).await?;
+        assert!(
+            watcher
+                .poll()?
+                .contains(&StoreEvent::Changed(object.clone()))
+        );
+        assert!(store.read_access_index().contains_key(&hash));
+
+        // Removed behind the store's back
+        std::fs::remove_file(store.path_of(&hash))?;
+        assert_eq!(watcher.poll()?, [StoreEvent::Removed(object)]);
+        assert!(!store.read_access_index().contains_key(&hash));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,444 @@
+//! Downloading a stream that only changed a little by reusing what's already on disk, like
+//! zsync.
+//!
+//! A stream can be pushed with a [`BlockIndex`]: a weak rolling checksum and a strong hash of
+//! each block of its contents, kept as `{hash}.blocks` next to its objects. A client with an older
+//! version of the file slides a window over it to find the blocks it already has, wherever they
+//! moved to, and only fetches the rest as byte ranges of the stream's uncompressed object.
+use blake3::Hasher;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::async_types::{AsyncReadExt, AsyncWriteExt};
+use crate::mirrors::Mirrors;
+use crate::net::{self, Location};
+use crate::store::Store;
+use crate::stream::Stream;
+use crate::stream::range::{no_ranges, open_range};
+
+/// How much of the basis is read at a time while looking for blocks.
+const READ_LEN: usize = 256 * 1024;
+
+/// Checksums of every block of a stream's contents, see [`Stream::index_blocks`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockIndex {
+    pub block_size: u32,
+    /// The length of the whole stream, where the last block can be shorter
+    pub len: u64,
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Block {
+    /// The rsync rolling checksum
+    pub weak: u32,
+    /// The start of the block's blake3 hash
+    pub strong: u64,
+}
+
+/// What [`Stream::download_reusing`] did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReuseReport {
+    /// The stream's uncompressed contents in the store
+    pub path: PathBuf,
+    /// Bytes copied from the basis
+    pub reused: u64,
+    /// Bytes downloaded
+    pub fetched: u64,
+}
+
+/// The name of a stream's block index in the store or a repository's `streams` directory.
+pub(crate) fn object_name(hash: &str) -> String {
+    format!("{hash}.blocks")
+}
+
+/// The rsync checksum of a window, which can be moved along by a byte without reading the whole
+/// window again.
+#[derive(Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Self {
+        let (mut a, mut b) = (0u32, 0u32);
+        for &byte in window {
+            a = a.wrapping_add(u32::from(byte));
+            b = b.wrapping_add(a);
+        }
+        Self { a, b }
+    }
+
+    /// Moves a window of `len` bytes forward, dropping `out` and taking in `byte`.
+    fn roll(&mut self, out: u8, byte: u8, len: u32) {
+        self.a = self
+            .a
+            .wrapping_sub(u32::from(out))
+            .wrapping_add(u32::from(byte));
+        self.b = self
+            .b
+            .wrapping_sub(len.wrapping_mul(u32::from(out)))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong_hash(data: &[u8]) -> u64 {
+    let hash = blake3::hash(data);
+    u64::from_le_bytes(
+        hash.as_bytes()[..8]
+            .try_into()
+            .expect("hashes are 32 bytes"),
+    )
+}
+
+/// Reads until `buf` is full or the reader ends, returning how much was read.
+pub(super) fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+impl BlockIndex {
+    pub const DEFAULT_BLOCK_SIZE: u32 = 4096;
+
+    /// Indexes everything `reader` reads, in blocks of `block_size` bytes.
+    ///
+    /// # Errors
+    ///
+    /// - Errors from the reader
+    /// - [`io::ErrorKind::InvalidInput`] for a block size of 0
+    pub fn compute<R: Read>(mut reader: R, block_size: u32) -> io::Result<Self> {
+        if block_size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "blocks can't be empty",
+            ));
+        }
+
+        let mut index = Self {
+            block_size,
+            len: 0,
+            blocks: Vec::new(),
+        };
+        let mut buf = vec![0; block_size as usize];
+        loop {
+            let len = read_full(&mut reader, &mut buf)?;
+            if len == 0 {
+                break;
+            }
+            index.blocks.push(Block {
+                weak: Rolling::new(&buf[..len]).digest(),
+                strong: strong_hash(&buf[..len]),
+            });
+            index.len += len as u64;
+            if len < buf.len() {
+                break;
+            }
+        }
+
+        Ok(index)
+    }
+
+    /// The bytes of block `n` in the stream.
+    fn range(&self, n: usize) -> Range<u64> {
+        let start = n as u64 * u64::from(self.block_size);
+        start..(start + u64::from(self.block_size)).min(self.len)
+    }
+
+    /// Whether the blocks add up to the length, as indexes come from repositories.
+    fn is_consistent(&self) -> bool {
+        self.block_size > 0
+            && self.blocks.len() as u64 == self.len.div_ceil(u64::from(self.block_size))
+    }
+
+    /// Where every block that `basis` has starts in it, by block number. Only full blocks are
+    /// looked for, as a shorter last block is cheaper to fetch than to find.
+    pub(crate) fn find_in<R: Read>(&self, mut basis: R) -> io::Result<HashMap<usize, u64>> {
+        let block_size = self.block_size as usize;
+        let mut by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (n, block) in self.blocks.iter().enumerate() {
+            if self.range(n).end - self.range(n).start == u64::from(self.block_size) {
+                by_weak.entry(block.weak).or_default().push(n);
+            }
+        }
+
+        let mut found = HashMap::new();
+        let mut buf = Vec::new();
+        // Where `buf` starts in the basis, and where the window starts in `buf`
+        let (mut offset, mut pos) = (0u64, 0);
+        let mut eof = false;
+        let mut rolling: Option<Rolling> = None;
+
+        loop {
+            // The window and the byte after it, for rolling
+            if buf.len() <= pos + block_size && !eof {
+                buf.drain(..pos);
+                offset += pos as u64;
+                pos = 0;
+
+                let filled = buf.len();
+                buf.resize(filled + READ_LEN, 0);
+                let len = read_full(&mut basis, &mut buf[filled..])?;
+                buf.truncate(filled + len);
+                eof = len < READ_LEN;
+                continue;
+            }
+            if buf.len() < pos + block_size {
+                break;
+            }
+
+            let window = &buf[pos..pos + block_size];
+            let weak = *rolling.get_or_insert_with(|| Rolling::new(window));
+            if let Some(candidates) = by_weak.get(&weak.digest()) {
+                let strong = strong_hash(window);
+                let mut matched = false;
+                for &n in candidates {
+                    if self.blocks[n].strong == strong {
+                        found.entry(n).or_insert(offset + pos as u64);
+                        matched = true;
+                    }
+                }
+                if matched {
+                    pos += block_size;
+                    rolling = None;
+                    continue;
+                }
+            }
+
+            let Some(&byte) = buf.get(pos + block_size) else {
+                break;
+            };
+            if let Some(rolling) = &mut rolling {
+                rolling.roll(buf[pos], byte, self.block_size);
+            }
+            pos += 1;
+        }
+
+        Ok(found)
+    }
+}
+
+impl Stream {
+    /// Indexes the stream's contents in blocks of `block_size` bytes, so that clients can
+    /// [download it reusing](Self::download_reusing) an older version. The index is kept in the
+    /// store, and [pushed](Self::push) along with the stream from then on.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically the stream not being in the store)
+    /// - See [`BlockIndex::compute`]
+    pub fn index_blocks(&self, store: &Store, block_size: u32) -> io::Result<BlockIndex> {
+        let file = std::fs::File::open(store.path_of(&self.hash))?;
+        let index = BlockIndex::compute(io::BufReader::new(file), block_size)?;
+
+        let path = store.root().join(object_name(&self.hash));
+        let tmp = store.temp_path("blocks");
+        std::fs::write(&tmp, serde_json::to_vec(&index)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(index)
+    }
+
+    /// Downloads the stream, copying every block that `basis` already has, like an older version
+    /// of the same file, and fetching only the rest. The repository needs the stream's
+    /// [block index](Self::index_blocks) and its uncompressed object, pushed with
+    /// [`CompressionKind::None`](crate::CompressionKind::None), to take byte ranges from.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::NotFound`](crate::Error::NotFound) or a 404 if the repository doesn't have
+    ///   the block index or uncompressed object, in which case [`Self::download`] still works
+    /// - [`io::ErrorKind::Unsupported`] for SSH repositories, and HTTP servers that don't serve
+    ///   byte ranges
+    /// - [`Error::HashError`](crate::Error::HashError) if the result doesn't match
+    /// - Filesystem and network errors
+    pub async fn download_reusing(
+        &self,
+        url: &str,
+        store: &Store,
+        basis: &Path,
+    ) -> crate::Result<ReuseReport> {
+        let mirrors = Mirrors::from(url);
+        let index = fetch_index(url, &self.hash).await?;
+        if !index.is_consistent() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed block index").into());
+        }
+        let found = index.find_in(std::fs::File::open(basis)?)?;
+
+        let mut basis = std::fs::File::open(basis)?;
+        let (mut file, temp) = store.create_temp(&self.hash).await?;
+        let mut hasher = Hasher::new();
+        let mut report = ReuseReport::default();
+
+        let res: crate::Result<()> = async {
+            let mut n = 0;
+            while n < index.blocks.len() {
+                if let Some(&offset) = found.get(&n) {
+                    let range = index.range(n);
+                    let mut block = Vec::new();
+                    basis.seek(SeekFrom::Start(offset))?;
+                    Read::take(&mut basis, range.end - range.start).read_to_end(&mut block)?;
+
+                    file.write_all(&block).await?;
+                    hasher.update(&block);
+                    report.reused += block.len() as u64;
+                    n += 1;
+                    continue;
+                }
+
+                // Everything up to the next block the basis has, in one request
+                let end = (n..index.blocks.len())
+                    .find(|m| found.contains_key(m))
+                    .unwrap_or(index.blocks.len());
+                let range = index.range(n).start..index.range(end - 1).end;
+                let mut reader = open_range(&mirrors, url, &self.hash, range).await?;
+                let mut buf = vec![0; 64 * 1024];
+                loop {
+                    let len = reader.read(&mut buf).await?;
+                    if len == 0 {
+                        break;
+                    }
+                    file.write_all(&buf[..len]).await?;
+                    hasher.update(&buf[..len]);
+                    report.fetched += len as u64;
+                }
+                n = end;
+            }
+            file.flush().await?;
+            Ok(())
+        }
+        .await;
+
+        if let Err(e) = res {
+            Store::discard_temp(temp).await?;
+            return Err(e);
+        }
+        report.path = store.finish_insert(&self.hash, hasher, temp).await?;
+        Ok(report)
+    }
+}
+
+async fn fetch_index(url: &str, hash: &str) -> crate::Result<BlockIndex> {
+    let object = format!("streams/{}", object_name(hash));
+
+    let data = match Location::parse(url) {
+        Location::Local(root) => {
+            let path = root.join(&object);
+            if !path.exists() {
+                return Err(crate::Error::NotFound(path.display().to_string()));
+            }
+            std::fs::read(path)?
+        }
+        Location::Ssh(_) => return Err(no_ranges("SSH repositories")),
+        Location::Http(url) => net::default_client()
+            .get(format!("{url}/{object}"))
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?
+            .to_vec(),
+    };
+
+    Ok(serde_json::from_slice(&data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::CompressionKind;
+
+    fn random(seed: &str, len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        blake3::Hasher::new()
+            .update(seed.as_bytes())
+            .finalize_xof()
+            .fill(&mut data);
+        data
+    }
+
+    #[test]
+    fn test_rolling_checksum() {
+        let data = random("rolling", 1000);
+        let mut rolling = Rolling::new(&data[..100]);
+        for i in 0..900 {
+            rolling.roll(data[i], data[i + 100], 100);
+            assert_eq!(
+                rolling.digest(),
+                Rolling::new(&data[i + 1..i + 101]).digest()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_reusing() -> crate::Result<()> {
+        let repo_dir = TempDir::new()?;
+        let repo_url = repo_dir.path().to_str().unwrap();
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let local_dir = TempDir::new()?;
+        let local_store = Store::new(local_dir.path());
+        let files = TempDir::new()?;
+
+        // Bytes inserted near the start shift every block after them
+        let old = random("old", 64 * 1024 + 100);
+        let mut new = old[..5000].to_vec();
+        new.extend_from_slice(b"inserted");
+        new.extend_from_slice(&old[5000..40_000]);
+        new.extend_from_slice(&random("changed", 3000));
+        new.extend_from_slice(&old[43_000..]);
+        std::fs::write(files.path().join("old"), &old)?;
+        std::fs::write(files.path().join("new"), &new)?;
+
+        let stream =
+            Stream::create(files.path().join("new"), &store, CompressionKind::None).await?;
+        let index = stream.index_blocks(&store, 1024)?;
+        assert_eq!(index.len, new.len() as u64);
+        assert!(index.is_consistent());
+
+        // Without an index, there's nothing to reuse against
+        assert!(matches!(
+            stream
+                .download_reusing(repo_url, &local_store, &files.path().join("old"))
+                .await,
+            Err(crate::Error::NotFound(_))
+        ));
+
+        stream.push(repo_url, &store, CompressionKind::None).await?;
+        let report = stream
+            .download_reusing(repo_url, &local_store, &files.path().join("old"))
+            .await?;
+        assert_eq!(std::fs::read(&report.path)?, new);
+        assert_eq!(report.reused + report.fetched, new.len() as u64);
+        // The blocks around the insertion and the change, and the short last one
+        assert!(report.fetched <= 8 * 1024, "fetched {}", report.fetched);
+
+        // A basis with nothing in common fetches everything
+        let other_dir = TempDir::new()?;
+        let other_store = Store::new(other_dir.path());
+        std::fs::write(files.path().join("other"), random("other", 10_000))?;
+        let report = stream
+            .download_reusing(repo_url, &other_store, &files.path().join("other"))
+            .await?;
+        assert_eq!(report.reused, 0);
+        assert_eq!(report.fetched, new.len() as u64);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+//! The hashes streams are named by.
+//!
+//! Manifests come from remote repositories, and a stream's hash is used as a file name in stores
+//! and repositories, so it has to name nothing but the stream's objects. An [`ObjectHash`] can only
+//! be built from a BLAKE3 hash in lowercase hex, which is checked once when it is constructed or
+//! deserialized.
+use serde::{Deserialize, Deserializer, Serialize, de::Error as _};
+use std::borrow::Borrow;
+use std::fmt;
+use std::ops::Deref;
+use std::path::Path;
+use std::str::FromStr;
+
+/// The BLAKE3 hash of a stream's contents, as lowercase hex.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(transparent)]
+pub struct ObjectHash(String);
+
+impl ObjectHash {
+    /// Characters in a hash.
+    pub const LEN: usize = 64;
+
+    /// Validates a hash.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidHash`](crate::Error::InvalidHash) for anything but 64 lowercase hex
+    ///   characters
+    pub fn new<S: Into<String>>(hash: S) -> crate::Result<Self> {
+        let hash = hash.into();
+        if is_valid_hash(&hash) {
+            Ok(Self(hash))
+        } else {
+            Err(crate::Error::InvalidHash(hash))
+        }
+    }
+
+    /// The hash of `data`.
+    #[must_use]
+    pub fn of(data: &[u8]) -> Self {
+        blake3::hash(data).into()
+    }
+
+    /// Wraps a hash that is already known to be valid, such as one just computed.
+    pub(crate) fn new_unchecked<S: Into<String>>(hash: S) -> Self {
+        Self(hash.into())
+    }
+
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == ObjectHash::LEN
+        && hash
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+impl From<blake3::Hash> for ObjectHash {
+    fn from(hash: blake3::Hash) -> Self {
+        Self(hash.to_hex().to_string())
+    }
+}
+
+impl Deref for ObjectHash {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for ObjectHash {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+// Hashes are the file names of objects
+impl AsRef<Path> for ObjectHash {
+    fn as_ref(&self) -> &Path {
+        Path::new(&self.0)
+    }
+}
+
+impl Borrow<str> for ObjectHash {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<ObjectHash> for String {
+    fn from(hash: ObjectHash) -> Self {
+        hash.0
+    }
+}
+
+impl TryFrom<String> for ObjectHash {
+    type Error = crate::Error;
+
+    fn try_from(hash: String) -> crate::Result<Self> {
+        Self::new(hash)
+    }
+}
+
+impl TryFrom<&str> for ObjectHash {
+    type Error = crate::Error;
+
+    fn try_from(hash: &str) -> crate::Result<Self> {
+        Self::new(hash)
+    }
+}
+
+impl FromStr for ObjectHash {
+    type Err = crate::Error;
+
+    fn from_str(hash: &str) -> crate::Result<Self> {
+        Self::new(hash)
+    }
+}
+
+impl PartialEq<str> for ObjectHash {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for ObjectHash {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+impl PartialEq<String> for ObjectHash {
+    fn eq(&self, other: &String) -> bool {
+        self.0 == *other
+    }
+}
+
+impl PartialEq<ObjectHash> for String {
+    fn eq(&self, other: &ObjectHash) -> bool {
+        *self == other.0
+    }
+}
+
+impl PartialEq<ObjectHash> for &str {
+    fn eq(&self, other: &ObjectHash) -> bool {
+        *self == other.0
+    }
+}
+
+impl fmt::Display for ObjectHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<'de> Deserialize<'de> for ObjectHash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hash = String::deserialize(deserializer)?;
+        Self::new(hash).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object_hash() -> crate::Result<()> {
+        let hash = ObjectHash::of(b"contents");
+        assert_eq!(ObjectHash::new(hash.as_str())?, hash);
+        assert_eq!(hash, blake3::hash(b"contents").to_hex().as_str());
+
+        for invalid in [
+            String::new(),
+            "abc".to_string(),
+            hash.to_uppercase(),
+            format!("{hash}0"),
+            format!("../{}", &hash[3..]),
+        ] {
+            assert!(
+                matches!(
+                    ObjectHash::new(invalid.clone()),
+                    Err(crate::Error::InvalidHash(_))
+                ),
+                "{invalid}"
+            );
+        }
+
+        assert!(serde_json::from_str::<ObjectHash>("\"../trees/latest\"").is_err());
+        assert_eq!(serde_json::to_string(&hash)?, format!("\"{hash}\""));
+
+        Ok(())
+    }
+}
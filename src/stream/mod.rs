@@ -1,84 +1,510 @@
-use crate::async_types::{AsyncReadExt, AsyncWriteExt, BufReader, StreamExt, TryStreamExt};
+use crate::async_types::{
+    AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, TryStreamExt,
+};
 use blake3::Hasher;
+use serde::{Deserialize, Serialize};
 use std::ffi::OsString;
 use std::io;
-use std::io::Write;
+use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
 
 #[cfg(unix)]
 use std::os::unix::fs::MetadataExt;
 
+mod blocks;
+mod hash;
+mod outboard;
+mod patch;
+mod probe;
+mod range;
+pub use blocks::{Block, BlockIndex, ReuseReport};
+pub use hash::ObjectHash;
+pub use outboard::{BLOCK_LEN, Outboard};
+
 use crate::compression::CompressionKind;
-use crate::fs;
+use crate::dictionary::{self, Dictionary};
+use crate::encryption::{EncryptingWriter, EncryptionKey, decrypt};
+use crate::event::Event;
+use crate::fs::{self, BlockingWriter};
+use crate::metrics::{Metrics, NoMetrics};
+use crate::mirrors::{Fallthrough, Mirrors};
+use crate::net::{self, Counted, Location};
+use crate::pool::block_on;
+use crate::ssh;
+use crate::store::{Store, StoreLayout};
 
-#[derive(Hash, Clone, Debug)]
+#[derive(Hash, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Stream {
-    pub hash: String,
+    pub hash: ObjectHash,
+    #[serde(with = "crate::tree::manifest::os_string")]
     pub file_name: OsString,
+    /// The compression kind the stream was created with, which downloads and pushes use instead
+    /// of the one they're given. Manifests written before it was recorded don't have it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<CompressionKind>,
+    /// The hash of the [`Dictionary`] the stream was compressed with, which is downloaded along
+    /// with it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dictionary: Option<ObjectHash>,
+    /// The size of the file, recorded when the stream is created
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disk_size: Option<u64>,
+    /// The size of the object it was created with, as downloaded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network_size: Option<u64>,
     #[cfg(unix)]
+    #[serde(default)]
     pub mode: Option<u32>,
+    /// Modification time in seconds since the Unix epoch, if the store
+    /// [captured it](Store::with_mtimes)
+    #[cfg(unix)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+    /// The file's owner, if the store [captured it](Store::with_owners)
+    #[cfg(unix)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<Owner>,
+}
+
+/// Numeric user and group ids.
+#[derive(Hash, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Turns a HTTP response body into a buffered async reader.
+pub(crate) fn response_reader(res: reqwest::Response) -> impl AsyncBufRead + Send + Unpin {
+    #[cfg(feature = "tokio")]
+    let stream = tokio_util::io::StreamReader::new(res.bytes_stream().map_err(io::Error::other));
+    #[cfg(not(feature = "tokio"))]
+    let stream = res
+        .bytes_stream()
+        .map_err(io::Error::other)
+        .into_async_read();
+
+    BufReader::new(stream)
 }
 
 impl Stream {
-    /// Downloads this stream using reqwest
+    /// Downloads this stream using reqwest, or copies it when `url` is a `file://` URL or a
+    /// plain path to a local repository. The kind the stream was [recorded](Self::compression)
+    /// with is tried first, so `compression_kind` is only a fallback, needed for streams from
+    /// older manifests that don't record one.
     ///
     /// # Errors
     ///
     /// - Filesystem errors (Typically out of space)
     /// - Network errors (Non-2xx codes, etc)
-    pub async fn download<P: AsRef<Path>, S: AsRef<str>>(
+    pub async fn download<S: AsRef<str>, C: Into<Option<CompressionKind>>>(
         &self,
         url: S,
-        stream_dir: P,
+        store: &Store,
+        compression_kind: C,
+    ) -> crate::Result<PathBuf> {
+        self.download_mirrored(&Mirrors::from(url.as_ref()), store, compression_kind)
+            .await
+    }
+
+    /// Downloads from a single mirror, using the mirrors' settings.
+    async fn download_with(
+        &self,
+        mirrors: &Mirrors,
+        url: &str,
+        store: &Store,
         compression_kind: CompressionKind,
     ) -> crate::Result<PathBuf> {
-        let res = reqwest::get(format!(
-            "{}/streams/{}{}",
-            url.as_ref(),
+        let dictionary = match &self.dictionary {
+            Some(hash) => Some(dictionary_from(hash, mirrors, url, store).await?),
+            None => None,
+        };
+        let object = self.object_name(mirrors, compression_kind);
+        let transfer = mirrors.transfer();
+        let downloaded = &transfer.downloaded;
+        let started = Instant::now();
+
+        // Uncompressed objects can be shared with a local source store, and only need verifying
+        let linked = match Location::parse(url) {
+            Location::Local(root)
+                if compression_kind == CompressionKind::None
+                    && mirrors.encryption_key().is_none()
+                    && root.join(&object).exists() =>
+            {
+                let source = root.join(&object);
+                let linked = store.insert_link(&self.hash, &source).await?;
+                if linked.is_some() {
+                    downloaded.fetch_add(source.metadata()?.len(), Ordering::Relaxed);
+                }
+                linked
+            }
+            _ => None,
+        };
+
+        let path = if let Some(path) = linked {
+            path
+        } else {
+            let outboard = self.outboard(mirrors, url).await?;
+            let (reader, child) = open_object(
+                mirrors,
+                url,
+                &object,
+                compression_kind,
+                dictionary.as_ref(),
+                outboard,
+                store.metrics(),
+            )
+            .await?;
+            let res = store.insert_from_reader(&self.hash, reader).await;
+
+            // A failed remote command explains any error from reading its output
+            if let Some(child) = child {
+                ssh::finish(child, &object).await?;
+            }
+            res?
+        };
+
+        let bytes = path.metadata()?.len();
+        transfer.decompressed.fetch_add(bytes, Ordering::Relaxed);
+
+        let elapsed = started.elapsed();
+        if store.slow_thresholds().is_slow_download(bytes, elapsed) {
+            store.emit(&Event::SlowDownload {
+                hash: self.hash.to_string(),
+                bytes,
+                elapsed,
+            });
+        }
+        Ok(path)
+    }
+
+    /// Uploads this stream's object from the store to a repository. Objects the repository
+    /// already has are skipped, see [`Mirrors::has_stream`]. If the store doesn't have the
+    /// object compressed with `compression_kind`, it's uploaded compressed the way the stream
+    /// was created instead.
+    ///
+    /// HTTP repositories must accept `PUT` uploads, like the built-in server.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically the object missing from the store)
+    /// - Network errors (Non-2xx codes, etc)
+    pub async fn push<S: AsRef<str>>(
+        &self,
+        url: S,
+        store: &Store,
+        compression_kind: CompressionKind,
+    ) -> crate::Result<()> {
+        if let Some(dictionary) = &self.dictionary {
+            Box::pin(dictionary_stream(dictionary).push(
+                url.as_ref(),
+                store,
+                CompressionKind::None,
+            ))
+            .await?;
+        }
+        let (source, object) = self.push_source(store, compression_kind);
+        self.push_object(url.as_ref(), store, &source, &object)
+            .await?;
+
+        for name in [
+            blocks::object_name(&self.hash),
+            outboard::object_name(&self.hash),
+        ] {
+            let index = store.root().join(&name);
+            if index.exists() {
+                self.push_object(url.as_ref(), store, &index, &format!("streams/{name}"))
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The object [`Self::push`] uploads from the store, and its name in the repository.
+    pub(crate) fn push_source(
+        &self,
+        store: &Store,
+        compression_kind: CompressionKind,
+    ) -> (PathBuf, String) {
+        if let Some(dictionary) = &self.dictionary {
+            let name = dictionary::object_name(&self.hash, dictionary);
+            return (store.root().join(&name), format!("streams/{name}"));
+        }
+
+        let mut source = store.object_path_of(&self.hash, compression_kind);
+        let compression_kind = match self.compression {
+            Some(recorded) if !source.exists() => {
+                source = store.object_path_of(&self.hash, recorded);
+                recorded
+            }
+            _ => compression_kind,
+        };
+        let object = format!(
+            "streams/{}{}",
             self.hash,
             compression_kind.get_extension_with_dot()
-        ))
-        .await?;
-        let res = res.error_for_status()?;
+        );
+        (source, object)
+    }
 
-        let file_path = stream_dir.as_ref().join(&self.hash);
-        let mut tmp_file_path = file_path.clone();
-        tmp_file_path.set_extension("tmp");
-        let mut file = fs::File::create_new(&tmp_file_path).await?;
+    /// Uploads `source` from the store as `object`, unless the repository already has it.
+    async fn push_object(
+        &self,
+        url: &str,
+        store: &Store,
+        source: &Path,
+        object: &str,
+    ) -> crate::Result<()> {
+        // Every push gets its own temporary file, so concurrent pushes of an object never share
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
 
-        let mut hasher = Hasher::new();
+        // Objects are named after their contents, so one that's there already is the same. If
+        // the repository can't say, uploading is still right
+        let exists = probe::has_object(&Mirrors::from(url), url, object).await;
+        if exists.unwrap_or(false) {
+            return Ok(());
+        }
 
-        #[cfg(feature = "tokio")]
-        let stream =
-            tokio_util::io::StreamReader::new(res.bytes_stream().map_err(io::Error::other));
-        #[cfg(not(feature = "tokio"))]
-        let stream = res
-            .bytes_stream()
-            .map_err(io::Error::other)
-            .into_async_read();
+        match Location::parse(url) {
+            Location::Local(root) => {
+                let target = root.join(object);
+                let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+                let tmp = root.join(format!("{object}.{}-{n}.tmp", std::process::id()));
+                if let Some(dir) = target.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                let res = match std::fs::hard_link(source, &tmp) {
+                    Err(e) if store.link_fallback(&self.hash, &e) => {
+                        std::fs::copy(source, &tmp).map(|_| ())
+                    }
+                    res => res,
+                }
+                .and_then(|()| fs::rename(&tmp, &target));
+                if res.is_err() {
+                    let _ = std::fs::remove_file(&tmp);
+                }
+                res?;
+            }
+            Location::Ssh(remote) => remote.write(object, source).await?,
+            Location::Http(url) => {
+                let body =
+                    reqwest::Body::wrap_stream(fs::read_chunked(source.to_path_buf()).await?);
+                net::default_client()
+                    .put(format!("{url}/{object}"))
+                    .body(body)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+        }
 
-        let mut reader = compression_kind.decompress(BufReader::new(stream));
+        Ok(())
+    }
 
-        let mut buf = [0u8; 4096];
-        loop {
-            let n = reader.read(&mut buf).await?;
-            if n == 0 {
-                break;
+    /// Downloads this stream from the first mirror that can serve it, trying each of the
+    /// mirrors' compression fallbacks before moving on to the next mirror. Compression kinds are
+    /// tried like by [`Self::download`].
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically out of space)
+    /// - Network errors from the last mirror tried, if none could serve the stream
+    /// - [`Error::BudgetExceeded`](crate::Error::BudgetExceeded) if the mirrors'
+    ///   [budget](Mirrors::budget) is used up
+    pub async fn download_mirrored<C: Into<Option<CompressionKind>>>(
+        &self,
+        mirrors: &Mirrors,
+        store: &Store,
+        compression_kind: C,
+    ) -> crate::Result<PathBuf> {
+        mirrors.check_budget()?;
+        let compression_kind = compression_kind.into();
+        let mut last_error = None;
+
+        for url in mirrors.candidates() {
+            for kind in self.compression_kinds(mirrors, url, compression_kind) {
+                let res = self.download_with(mirrors, url, store, kind).await;
+                match res {
+                    Ok(path) => {
+                        mirrors.record_success(url);
+                        if self.dictionary.is_none() {
+                            mirrors.record_compression(url, kind);
+                        }
+                        return Ok(path);
+                    }
+                    Err(e) => match Fallthrough::classify(&e) {
+                        Fallthrough::Missing => last_error = Some(e),
+                        Fallthrough::Unhealthy => {
+                            mirrors.record_failure(url);
+                            last_error = Some(e);
+                            break;
+                        }
+                        Fallthrough::Fatal => return Err(e),
+                    },
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| io::Error::other("no mirrors configured").into()))
+    }
+
+    /// Downloads this stream into `writer` instead of the store, returning its size. Mirrors are
+    /// tried like by [`Self::download_mirrored`] until one has the stream, but once it's being
+    /// written, errors are returned as is.
+    ///
+    /// The stream is hashed as it's written, so a corrupted one only fails with
+    /// [`Error::HashError`](crate::Error::HashError) after `writer` received it. Mirrors that
+    /// [verify blocks](Mirrors::verify_blocks) fail before the first bad block instead, when
+    /// they have the stream's [outboard](Self::create_outboard).
+    ///
+    /// # Errors
+    ///
+    /// - Errors from the writer
+    /// - Network errors from the last mirror tried, if none could serve the stream
+    /// - [`Error::BudgetExceeded`](crate::Error::BudgetExceeded) if the mirrors'
+    ///   [budget](Mirrors::budget) is used up
+    pub async fn download_to<W: AsyncWrite + Unpin, C: Into<Option<CompressionKind>>>(
+        &self,
+        mirrors: &Mirrors,
+        writer: &mut W,
+        compression_kind: C,
+    ) -> crate::Result<u64> {
+        mirrors.check_budget()?;
+        let compression_kind = compression_kind.into();
+        let mut last_error = None;
+
+        for url in mirrors.candidates() {
+            for kind in self.compression_kinds(mirrors, url, compression_kind) {
+                let object = self.object_name(mirrors, kind);
+                match self.open_to(mirrors, url, &object, kind).await {
+                    Ok((reader, child)) => {
+                        let len = self
+                            .write_verified(reader, child, &object, writer, mirrors)
+                            .await?;
+                        mirrors.record_success(url);
+                        if self.dictionary.is_none() {
+                            mirrors.record_compression(url, kind);
+                        }
+                        return Ok(len);
+                    }
+                    Err(e) => match Fallthrough::classify(&e) {
+                        Fallthrough::Missing => last_error = Some(e),
+                        Fallthrough::Unhealthy => {
+                            mirrors.record_failure(url);
+                            last_error = Some(e);
+                            break;
+                        }
+                        Fallthrough::Fatal => return Err(e),
+                    },
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| io::Error::other("no mirrors configured").into()))
+    }
+
+    /// Opens the stream on a single mirror for [`Self::download_to`], which has no store to keep
+    /// its dictionary in.
+    async fn open_to<'a>(
+        &self,
+        mirrors: &'a Mirrors,
+        url: &str,
+        object: &str,
+        compression_kind: CompressionKind,
+    ) -> crate::Result<(Pin<Box<dyn AsyncRead + Send + 'a>>, Option<ssh::Child>)> {
+        let dictionary = match &self.dictionary {
+            Some(hash) => Some(fetch_dictionary(mirrors, url, hash).await?),
+            None => None,
+        };
+        let outboard = self.outboard(mirrors, url).await?;
+        open_object(
+            mirrors,
+            url,
+            object,
+            compression_kind,
+            dictionary.as_ref(),
+            outboard,
+            &NoMetrics,
+        )
+        .await
+    }
+
+    async fn write_verified<W: AsyncWrite + Unpin>(
+        &self,
+        mut reader: Pin<Box<dyn AsyncRead + Send + '_>>,
+        child: Option<ssh::Child>,
+        object: &str,
+        writer: &mut W,
+        mirrors: &Mirrors,
+    ) -> crate::Result<u64> {
+        let mut hasher = Hasher::new();
+        let mut len = 0;
+
+        let res: crate::Result<()> = async {
+            let mut buf = vec![0; 64 * 1024];
+            loop {
+                let read = reader.read(&mut buf).await?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buf[..read]);
+                writer.write_all(&buf[..read]).await?;
+                len += read as u64;
             }
+            writer.flush().await?;
+            Ok(())
+        }
+        .await;
 
-            let chunk = &buf[..n];
-            file.write_all(chunk).await?;
-            hasher.write_all(chunk)?;
+        // A failed remote command explains any error from reading its output
+        if let Some(child) = child {
+            ssh::finish(child, object).await?;
         }
+        res?;
 
-        let hash = hasher.finalize().to_hex().to_string();
+        let actual = hasher.finalize().to_hex().to_string();
+        if actual != self.hash {
+            return Err(crate::Error::HashError(self.hash.to_string(), actual));
+        }
+        mirrors
+            .transfer()
+            .decompressed
+            .fetch_add(len, Ordering::Relaxed);
+        Ok(len)
+    }
 
-        if hash == self.hash {
-            fs::rename(&tmp_file_path, &file_path)?;
-            Ok(file_path)
+    /// The kinds to try on the mirror at `url`, in order.
+    fn compression_kinds(
+        &self,
+        mirrors: &Mirrors,
+        url: &str,
+        compression_kind: Option<CompressionKind>,
+    ) -> Vec<CompressionKind> {
+        match self.dictionary {
+            // Objects compressed with a dictionary are always Zstd, and named after it
+            Some(_) => vec![CompressionKind::Zstd],
+            None => mirrors.compression_kinds(url, compression_kind, self.compression),
+        }
+    }
+
+    /// The path of the stream's object in a repository.
+    fn object_name(&self, mirrors: &Mirrors, compression_kind: CompressionKind) -> String {
+        let name = match &self.dictionary {
+            Some(hash) => dictionary::object_name(&self.hash, hash),
+            None => mirrors.naming().object_name(&self.hash, compression_kind),
+        };
+        format!("streams/{name}")
+    }
+
+    /// The stream's outboard on the mirror at `url`, if the mirrors verify blocks.
+    async fn outboard(&self, mirrors: &Mirrors, url: &str) -> crate::Result<Option<Outboard>> {
+        if mirrors.verifies_blocks() {
+            outboard::fetch(mirrors, url, &self.hash).await
         } else {
-            fs::remove_file(tmp_file_path).await?;
-            Err(crate::Error::HashError(self.hash.clone(), hash))
+            Ok(None)
         }
     }
 
@@ -87,11 +513,39 @@ impl Stream {
     /// # Errors
     ///
     /// - Out of storage/Permissions Errors
-    pub async fn create<F: AsRef<Path>, S: AsRef<Path>>(
+    pub async fn create<F: AsRef<Path>>(
         file: F,
-        stream_dir: S,
+        store: &Store,
         compression_kind: CompressionKind,
     ) -> Result<Self, std::io::Error> {
+        Self::create_with(file, store, compression_kind, None).await
+    }
+
+    /// Creates a Stream from a raw on-disk File, compressed with Zstd and `dictionary`, which is
+    /// added to the store too.
+    ///
+    /// # Errors
+    ///
+    /// - Out of storage/Permissions Errors
+    /// - [`io::ErrorKind::InvalidInput`] for encrypted stores, as dictionaries are pushed
+    ///   unencrypted
+    pub async fn create_with_dictionary<F: AsRef<Path>>(
+        file: F,
+        store: &Store,
+        dictionary: &Dictionary,
+    ) -> crate::Result<Self> {
+        let stream =
+            Self::create_with(file, store, CompressionKind::Zstd, Some(dictionary)).await?;
+        dictionary.insert(store).await?;
+        Ok(stream)
+    }
+
+    async fn create_with<F: AsRef<Path>>(
+        file: F,
+        store: &Store,
+        compression_kind: CompressionKind,
+        dictionary: Option<&Dictionary>,
+    ) -> io::Result<Self> {
         let file_name = file
             .as_ref()
             .file_name()
@@ -100,56 +554,301 @@ impl Stream {
 
         // Get Permissions/Mode
         #[cfg(unix)]
-        let mode = file.as_ref().metadata()?.mode();
-
-        let mut hasher = Hasher::new();
-
-        let mut output_temp_path = stream_dir.as_ref().join(&file_name);
-        output_temp_path.set_file_name("tmp");
-
-        let output_file = fs::File::create_new(&output_temp_path).await?;
+        let metadata = file.as_ref().metadata()?;
+        #[cfg(unix)]
+        let mtime = store.captures_mtimes().then(|| metadata.mtime());
+        #[cfg(unix)]
+        let owner = store.keeps_owners().then(|| Owner {
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+        });
 
-        let mut writer = compression_kind.compress(output_file);
+        let key = store.encryption().cloned();
+        if key.is_some() && compression_kind == CompressionKind::None {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "encrypted stores need compressed objects",
+            ));
+        }
+        if key.is_some() && dictionary.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "encrypted stores can't use dictionaries",
+            ));
+        }
+        let owned_dictionary = dictionary.cloned();
+        let (output_file, output_temp) = store.create_temp_blocking("create")?;
 
         // Hash and compress
-        let mut stream = fs::read_chunked(&file).await?;
-        while let Some(chunk) = stream.next().await {
-            let chunk = chunk?;
-            hasher.write_all(&chunk)?;
-            writer.write_all(&chunk).await?;
+        let started = Instant::now();
+        let input = std::fs::File::open(&file)?;
+        let (hash, bytes) = store
+            .cpu_pool()
+            .run(move || {
+                hash_and_compress(
+                    input,
+                    output_file,
+                    compression_kind,
+                    owned_dictionary.as_ref(),
+                    key.as_ref(),
+                )
+            })
+            .await??;
+        let elapsed = started.elapsed();
+        if store.slow_thresholds().is_slow_hashing(bytes, elapsed) {
+            store.emit(&Event::SlowHashing {
+                hash: hash.clone(),
+                bytes,
+                elapsed,
+            });
         }
-
-        let hash = hasher.finalize().to_hex().to_string();
-        #[cfg(feature = "tokio")]
-        writer.shutdown().await?;
-        #[cfg(not(feature = "tokio"))]
-        writer.close().await?;
-
         // Final paths
-        let uncompressed_path = stream_dir.as_ref().join(&hash);
-        let mut compressed_path = uncompressed_path.clone();
-        if let Some(extension) = compression_kind.try_get_extension() {
-            compressed_path.set_extension(extension);
-        }
+        let uncompressed_path = store.path_of(&hash);
+        let compressed_path = match dictionary {
+            Some(dictionary) => store
+                .root()
+                .join(dictionary::object_name(&hash, dictionary.hash())),
+            None => store.object_path_of(&hash, compression_kind),
+        };
 
         // Move/Copy to final path
-        fs::rename(output_temp_path, compressed_path)?;
-        if std::fs::hard_link(&file, &uncompressed_path).is_err() {
-            std::fs::copy(&file, &uncompressed_path)?;
+        let network_size = output_temp.len()?;
+        let keep_uncompressed =
+            store.layout() == StoreLayout::Both || compression_kind == CompressionKind::None;
+        // Uncompressed objects are the contents themselves, so there's nothing to link
+        let link_uncompressed = keep_uncompressed && uncompressed_path != compressed_path;
+        store.make_room(
+            &hash,
+            network_size + if link_uncompressed { bytes } else { 0 },
+        )?;
+        // Already in the store, from another file with the same contents
+        let existed = if keep_uncompressed {
+            uncompressed_path.exists()
+        } else {
+            compressed_path.exists()
+        };
+        store.persist_temp(output_temp, &compressed_path)?;
+        if link_uncompressed {
+            link_contents(store, &hash, file.as_ref(), &uncompressed_path)?;
+        }
+        if existed {
+            store.metrics().deduped();
         }
+        store.record_access([hash.as_str()])?;
 
         Ok(Self {
-            hash,
+            hash: ObjectHash::new_unchecked(hash),
             file_name,
+            compression: Some(compression_kind),
+            dictionary: dictionary.map(|dictionary| ObjectHash::new_unchecked(dictionary.hash())),
+            disk_size: Some(bytes),
+            network_size: Some(network_size),
             #[cfg(unix)]
-            mode: Some(mode),
+            mode: Some(metadata.mode()),
+            #[cfg(unix)]
+            mtime,
+            #[cfg(unix)]
+            owner,
         })
     }
 }
 
+/// Links the contents of a stream being created into the store, unless another file with the
+/// same contents already put them there.
+fn link_contents(store: &Store, hash: &str, file: &Path, path: &Path) -> io::Result<()> {
+    match std::fs::hard_link(file, path) {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        Err(e) if store.link_fallback(hash, &e) => std::fs::copy(file, path).map(|_| ()),
+        res => res,
+    }
+}
+
+/// The object of a [`Dictionary`], which is stored uncompressed.
+fn dictionary_stream(hash: &str) -> Stream {
+    Stream {
+        hash: ObjectHash::new_unchecked(hash),
+        file_name: OsString::new(),
+        compression: Some(CompressionKind::None),
+        dictionary: None,
+        disk_size: None,
+        network_size: None,
+        #[cfg(unix)]
+        mode: None,
+        #[cfg(unix)]
+        mtime: None,
+        #[cfg(unix)]
+        owner: None,
+    }
+}
+
+/// The dictionary called `hash`, downloaded from the mirror at `url` first if the store doesn't
+/// have it.
+async fn dictionary_from(
+    hash: &str,
+    mirrors: &Mirrors,
+    url: &str,
+    store: &Store,
+) -> crate::Result<Dictionary> {
+    if !store.contains(hash) {
+        Box::pin(dictionary_stream(hash).download_with(mirrors, url, store, CompressionKind::None))
+            .await?;
+    }
+    Ok(Dictionary::load(store, hash).await?)
+}
+
+/// The dictionary called `hash` from the mirror at `url`, without a store to keep it in.
+async fn fetch_dictionary(mirrors: &Mirrors, url: &str, hash: &str) -> crate::Result<Dictionary> {
+    let object = format!("streams/{hash}");
+    let (mut reader, child) = open_object(
+        mirrors,
+        url,
+        &object,
+        CompressionKind::None,
+        None,
+        None,
+        &NoMetrics,
+    )
+    .await?;
+    let mut bytes = Vec::new();
+    let res = reader.read_to_end(&mut bytes).await;
+    if let Some(child) = child {
+        ssh::finish(child, &object).await?;
+    }
+    res?;
+
+    let dictionary = Dictionary::from_bytes(bytes);
+    if dictionary.hash() != hash {
+        return Err(crate::Error::HashError(
+            hash.to_string(),
+            dictionary.hash().to_string(),
+        ));
+    }
+    Ok(dictionary)
+}
+
+/// Opens `object` on the mirror at `url`, decrypted, decompressed and verified against
+/// `outboard`. SSH repositories also return the remote command, to be
+/// [finished](ssh::finish) once the object was read.
+async fn open_object<'a>(
+    mirrors: &'a Mirrors,
+    url: &str,
+    object: &str,
+    compression_kind: CompressionKind,
+    dictionary: Option<&Dictionary>,
+    outboard: Option<Outboard>,
+    metrics: &'a dyn Metrics,
+) -> crate::Result<(Pin<Box<dyn AsyncRead + Send + 'a>>, Option<ssh::Child>)> {
+    let downloaded = &mirrors.transfer().downloaded;
+
+    match Location::parse(url) {
+        Location::Local(root) => {
+            let source = root.join(object);
+            if !source.exists() {
+                return Err(crate::Error::NotFound(source.display().to_string()));
+            }
+            let reader = Counted::new(fs::open_buffered(&source).await?, downloaded, metrics);
+            let reader = unpack(reader, compression_kind, dictionary, mirrors).await?;
+            Ok((verify(reader, outboard), None))
+        }
+        Location::Ssh(remote) => {
+            let (reader, child) = remote.read(object)?;
+            let reader = Counted::new(reader, downloaded, metrics);
+            match unpack(reader, compression_kind, dictionary, mirrors).await {
+                Ok(reader) => Ok((verify(reader, outboard), Some(child))),
+                Err(e) => {
+                    // A failed remote command explains any error from reading its output
+                    ssh::finish(child, object).await?;
+                    Err(e.into())
+                }
+            }
+        }
+        Location::Http(url) => {
+            let reader = range::open_resumable(mirrors, format!("{url}/{object}")).await?;
+            let reader = Counted::new(reader, downloaded, metrics);
+            let reader = unpack(reader, compression_kind, dictionary, mirrors).await?;
+            Ok((verify(reader, outboard), None))
+        }
+    }
+}
+
+/// Decrypts and decompresses a downloaded object.
+async fn unpack<'a, R: AsyncBufRead + Send + Unpin + 'a>(
+    reader: R,
+    compression_kind: CompressionKind,
+    dictionary: Option<&Dictionary>,
+    mirrors: &Mirrors,
+) -> io::Result<Pin<Box<dyn AsyncRead + Send + 'a>>> {
+    let reader = decrypt(reader, mirrors.encryption_key());
+    match dictionary {
+        Some(dictionary) => dictionary.decompress(reader),
+        None => compression_kind.decompress_detected(reader).await,
+    }
+}
+
+/// Verifies each block of a decompressed object against `outboard`, if the mirror has one.
+fn verify<'a>(
+    reader: Pin<Box<dyn AsyncRead + Send + 'a>>,
+    outboard: Option<Outboard>,
+) -> Pin<Box<dyn AsyncRead + Send + 'a>> {
+    match outboard {
+        Some(outboard) => outboard::verify(BufReader::new(reader), outboard),
+        None => reader,
+    }
+}
+
+/// Hashes `input` while compressing it into `output`, returning the hash and its size. Runs on
+/// a [pool](crate::pool::CpuPool) thread.
+fn hash_and_compress(
+    mut input: std::fs::File,
+    output: std::fs::File,
+    compression_kind: CompressionKind,
+    dictionary: Option<&Dictionary>,
+    key: Option<&EncryptionKey>,
+) -> io::Result<(String, u64)> {
+    let mut hasher = Hasher::new();
+    let mut output = EncryptingWriter::new(output, key)?;
+    let mut writer = match dictionary {
+        Some(dictionary) => dictionary.compress(BlockingWriter(&mut output))?,
+        None => compression_kind.compress(BlockingWriter(&mut output)),
+    };
+    let mut buf = vec![0; 64 * 1024];
+    let mut bytes = 0;
+
+    block_on(async {
+        loop {
+            let len = input.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+            hasher.update(&buf[..len]);
+            writer.write_all(&buf[..len]).await?;
+            bytes += len as u64;
+        }
+
+        #[cfg(feature = "tokio")]
+        writer.shutdown().await?;
+        #[cfg(not(feature = "tokio"))]
+        writer.close().await?;
+
+        Ok::<_, io::Error>(())
+    })?;
+    drop(writer);
+    output.finish()?;
+
+    Ok((hasher.finalize().to_hex().to_string(), bytes))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::compression::ObjectNaming;
+    use crate::event::SlowThresholds;
+    use crate::mirrors::TransferTotals;
+    use crate::net::Timeouts;
+    use crate::tree::Tree;
+    use crate::tree::builder::TreeBuilder;
+    use crate::tree::compact::CompactTree;
+    use crate::tree::view::TreeRef;
     use httpmock::prelude::*;
     use temp_dir::TempDir;
     use temp_file::TempFile;
@@ -161,9 +860,10 @@ mod tests {
         let test_data = b"This is some test data.";
 
         let stream_dir = TempDir::new()?;
+        let store = Store::new(stream_dir.path());
         let test_file = TempFile::new()?.with_contents(test_data)?;
 
-        let stream = Stream::create(test_file.path(), stream_dir.path(), compression_kind).await?;
+        let stream = Stream::create(test_file.path(), &store, compression_kind).await?;
 
         assert_eq!(stream.file_name, test_file.path().file_name().unwrap());
         assert_eq!(stream.hash, expected_hash);
@@ -185,13 +885,13 @@ mod tests {
     #[tokio::test]
     async fn test_create_chunk_large() -> io::Result<()> {
         let stream_dir = TempDir::new()?;
+        let store = Store::new(stream_dir.path());
 
         for input in [&[][..], &[0u8; 1024][..], &[0u8; 16384][..]] {
             let compression_kind = CompressionKind::None;
             let test_file = TempFile::new()?.with_contents(input)?;
 
-            let stream =
-                Stream::create(test_file.path(), stream_dir.path(), compression_kind).await?;
+            let stream = Stream::create(test_file.path(), &store, compression_kind).await?;
 
             assert_eq!(stream.file_name, test_file.path().file_name().unwrap());
         }
@@ -202,16 +902,13 @@ mod tests {
     #[tokio::test]
     async fn test_download_basic() -> crate::Result<()> {
         let remote_stream_dir = TempDir::new()?;
+        let remote_store = Store::new(remote_stream_dir.path());
         let local_stream_dir = TempDir::new()?;
+        let local_store = Store::new(local_stream_dir.path());
         let test_data = b"This is some test data.";
         let test_file = TempFile::new()?.with_contents(test_data)?;
 
-        let stream = Stream::create(
-            test_file.path(),
-            remote_stream_dir.path(),
-            CompressionKind::Zstd,
-        )
-        .await?;
+        let stream = Stream::create(test_file.path(), &remote_store, CompressionKind::Zstd).await?;
 
         let server = MockServer::start();
         let stream_mock = server.mock(|when, then| {
@@ -227,11 +924,7 @@ mod tests {
         });
 
         stream
-            .download(
-                &server.base_url(),
-                local_stream_dir.path(),
-                CompressionKind::Zstd,
-            )
+            .download(&server.base_url(), &local_store, CompressionKind::Zstd)
             .await?;
 
         let local_stream_file = local_stream_dir.path().join(stream.hash);
@@ -244,19 +937,427 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_download_recorded_kind() -> crate::Result<()> {
+        let remote_stream_dir = TempDir::new()?;
+        let remote_store = Store::new(remote_stream_dir.path());
+        let local_stream_dir = TempDir::new()?;
+        let local_store = Store::new(local_stream_dir.path());
+        let test_file = TempFile::new()?.with_contents(b"This is some test data.")?;
+        let stream = Stream::create(test_file.path(), &remote_store, CompressionKind::Xz).await?;
+
+        let server = MockServer::start();
+        let xz_mock = server.mock(|when, then| {
+            when.method(GET)
+                .path(format!("/streams/{}.xz", &stream.hash));
+            then.status(200).body_from_file(
+                remote_store
+                    .object_path_of(&stream.hash, CompressionKind::Xz)
+                    .to_str()
+                    .unwrap(),
+            );
+        });
+        let zstd_mock = server.mock(|when, then| {
+            when.method(GET)
+                .path(format!("/streams/{}.zstd", &stream.hash));
+            then.status(404);
+        });
+
+        // The requested kind isn't asked for when the recorded one is there
+        stream
+            .download(&server.base_url(), &local_store, CompressionKind::Zstd)
+            .await?;
+        xz_mock.assert();
+        zstd_mock.assert_calls(0);
+
+        // Streams from older manifests don't record a kind, and without a requested one every
+        // kind is tried
+        let older = Stream {
+            compression: None,
+            ..stream.clone()
+        };
+        let older_dir = TempDir::new()?;
+        let older_store = Store::new(older_dir.path());
+        older
+            .download(&server.base_url(), &older_store, None)
+            .await?;
+        assert!(older_store.contains(&older.hash));
+        xz_mock.assert_calls(2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_to() -> crate::Result<()> {
+        let remote_stream_dir = TempDir::new()?;
+        let remote_store = Store::new(remote_stream_dir.path());
+        let repo_dir = TempDir::new()?;
+        let test_data = b"This is some test data.";
+        let test_file = TempFile::new()?.with_contents(test_data)?;
+        let stream = Stream::create(test_file.path(), &remote_store, CompressionKind::Xz).await?;
+
+        let server = MockServer::start();
+        let stream_mock = server.mock(|when, then| {
+            when.method(GET)
+                .path(format!("/streams/{}.xz", &stream.hash));
+            then.status(200).body_from_file(
+                remote_store
+                    .object_path_of(&stream.hash, CompressionKind::Xz)
+                    .to_str()
+                    .unwrap(),
+            );
+        });
+
+        // Tries the kind the stream was created with first, and never touches a store
+        let mirrors = Mirrors::new([server.base_url()]);
+        let mut written = Vec::new();
+        let len = stream
+            .download_to(&mirrors, &mut written, CompressionKind::Zstd)
+            .await?;
+        assert_eq!(len, test_data.len() as u64);
+        assert_eq!(written, test_data);
+        assert_eq!(mirrors.transfer_totals().decompressed, len);
+        stream_mock.assert();
+
+        // Contents that don't match are still reported
+        let repo_url = repo_dir.path().to_str().unwrap();
+        stream
+            .push(repo_url, &remote_store, CompressionKind::Xz)
+            .await?;
+        let mut wrong = stream.clone();
+        wrong.hash = ObjectHash::of(b"other");
+        std::fs::copy(
+            repo_dir.path().join(format!("streams/{}.xz", stream.hash)),
+            repo_dir.path().join(format!("streams/{}.xz", wrong.hash)),
+        )?;
+        let res = wrong
+            .download_to(
+                &Mirrors::new([repo_url]),
+                &mut Vec::new(),
+                CompressionKind::Xz,
+            )
+            .await;
+        assert!(matches!(res, Err(crate::Error::HashError(..))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_push_concurrent() -> crate::Result<()> {
+        let repo_dir = TempDir::new()?;
+        let repo_url = repo_dir.path().to_str().unwrap();
+        let stream_dir = TempDir::new()?;
+        let store = Store::new(stream_dir.path());
+        let test_file = TempFile::new()?.with_contents(b"This is some test data.")?;
+        let stream = Stream::create(test_file.path(), &store, CompressionKind::Zstd).await?;
+
+        // Pushes of the same object never trip over each other's temporary files
+        let barrier = std::sync::Barrier::new(16);
+        std::thread::scope(|scope| {
+            let pushes: Vec<_> = (0..16)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+                        barrier.wait();
+                        runtime.block_on(stream.push(repo_url, &store, CompressionKind::Zstd))
+                    })
+                })
+                .collect();
+            pushes
+                .into_iter()
+                .try_for_each(|push| push.join().expect("push panicked"))
+        })?;
+
+        let names: Vec<_> = std::fs::read_dir(repo_dir.path().join("streams"))?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<io::Result<_>>()?;
+        assert_eq!(names, [OsString::from(format!("{}.zstd", stream.hash))]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_recorded_compression() -> crate::Result<()> {
+        let repo_dir = TempDir::new()?;
+        let repo_url = repo_dir.path().to_str().unwrap();
+        let stream_dir = TempDir::new()?;
+        let store = Store::new(stream_dir.path());
+        let local_stream_dir = TempDir::new()?;
+        let local_store = Store::new(local_stream_dir.path());
+        let test_file = TempFile::new()?.with_contents(b"This is some test data.")?;
+
+        let stream = Stream::create(test_file.path(), &store, CompressionKind::Xz).await?;
+        assert_eq!(stream.compression, Some(CompressionKind::Xz));
+
+        // Asking for the wrong kind works on both ends
+        stream.push(repo_url, &store, CompressionKind::Zstd).await?;
+        assert!(
+            repo_dir
+                .path()
+                .join(format!("streams/{}.xz", stream.hash))
+                .exists()
+        );
+        stream
+            .download(repo_url, &local_store, CompressionKind::Zstd)
+            .await?;
+        assert!(local_store.contains(&stream.hash));
+
+        // The kind is kept by manifests, but isn't part of what a tree is
+        let tree = TreeBuilder::new().add_file("data", stream.clone())?.build();
+        let mut manifest = Vec::new();
+        tree.write_manifest(&mut manifest).await?;
+        let text = String::from_utf8(manifest.clone()).expect("manifests are UTF-8");
+        assert!(text.contains("\"compression\":\"xz\""));
+        for read in [
+            Tree::read_manifest(&manifest[..]).await?,
+            TreeRef::parse(&text)?.to_tree()?,
+            CompactTree::from(&tree).to_tree(),
+        ] {
+            assert_eq!(read.streams, tree.streams);
+        }
+
+        let mut unrecorded = tree.clone();
+        unrecorded.streams[0].compression = None;
+        assert_eq!(unrecorded.hash(), tree.hash());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_object_naming() -> crate::Result<()> {
+        let remote_stream_dir = TempDir::new()?;
+        let remote_store = Store::new(remote_stream_dir.path());
+        let local_stream_dir = TempDir::new()?;
+        let local_store = Store::new(local_stream_dir.path());
+        let test_file = TempFile::new()?.with_contents(b"This is some test data.")?;
+
+        let stream = Stream::create(test_file.path(), &remote_store, CompressionKind::Zstd).await?;
+
+        let server = MockServer::start();
+        let stream_mock = server.mock(|when, then| {
+            when.method(GET)
+                .path(format!("/streams/{}.zst", &stream.hash));
+            then.status(200).body_from_file(
+                remote_store
+                    .object_path_of(&stream.hash, CompressionKind::Zstd)
+                    .to_str()
+                    .unwrap(),
+            );
+        });
+
+        let mirrors = Mirrors::from(server.base_url().as_str()).object_naming(ObjectNaming::zst());
+        stream
+            .download_mirrored(&mirrors, &local_store, CompressionKind::Zstd)
+            .await?;
+
+        // The local store keeps its own naming
+        assert!(local_store.contains(&stream.hash));
+        stream_mock.assert();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_detects_compression() -> crate::Result<()> {
+        let local_stream_dir = TempDir::new()?;
+        let local_store = Store::new(local_stream_dir.path());
+        let test_data = b"This is some test data.";
+        let stream = Stream {
+            hash: ObjectHash::of(test_data),
+            file_name: "test".into(),
+            compression: None,
+            dictionary: None,
+            disk_size: None,
+            network_size: None,
+            #[cfg(unix)]
+            mode: None,
+            #[cfg(unix)]
+            mtime: None,
+            #[cfg(unix)]
+            owner: None,
+        };
+
+        // Asked for zstd, but served the object as is
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET)
+                .path(format!("/streams/{}.zstd", &stream.hash));
+            then.status(200).body(test_data);
+        });
+
+        let path = stream
+            .download(&server.base_url(), &local_store, CompressionKind::Zstd)
+            .await?;
+        assert_eq!(fs::read_to_end(path).await?, test_data);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_objects() -> crate::Result<()> {
+        let repo_dir = TempDir::new()?;
+        let repo_streams = repo_dir.path().join("streams");
+        std::fs::create_dir(&repo_streams)?;
+        let local_stream_dir = TempDir::new()?;
+        let local_store = Store::new(local_stream_dir.path());
+        let test_data = b"Nobody but the key holders should read this.".repeat(2000);
+        let test_file = TempFile::new()?.with_contents(&test_data)?;
+
+        let key = EncryptionKey::generate();
+        let repo_store = Store::new(&repo_streams).with_encryption(key.clone());
+        let stream = Stream::create(test_file.path(), &repo_store, CompressionKind::Zstd).await?;
+
+        // The object can't be decompressed, or read, without the key
+        let object = std::fs::read(repo_store.object_path_of(&stream.hash, CompressionKind::Zstd))?;
+        assert_ne!(object[..4], [0x28, 0xb5, 0x2f, 0xfd]);
+        let url = repo_dir.path().to_str().unwrap();
+        assert!(
+            stream
+                .download(url, &local_store, CompressionKind::Zstd)
+                .await
+                .is_err()
+        );
+        assert!(!local_store.contains(&stream.hash));
+
+        let mirrors = Mirrors::from(url).encryption(key);
+        let path = stream
+            .download_mirrored(&mirrors, &local_store, CompressionKind::Zstd)
+            .await?;
+        assert_eq!(fs::read_to_end(path).await?, test_data);
+        assert!(repo_store.verify().await?.corrupted.is_empty());
+
+        // Uncompressed objects are the deployable copies
+        assert!(
+            Stream::create(test_file.path(), &repo_store, CompressionKind::None)
+                .await
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_url_resolver() -> crate::Result<()> {
+        let remote_stream_dir = TempDir::new()?;
+        let remote_store = Store::new(remote_stream_dir.path());
+        let local_stream_dir = TempDir::new()?;
+        let local_store = Store::new(local_stream_dir.path());
+        let test_file = TempFile::new()?.with_contents(b"This is some test data.")?;
+
+        let stream = Stream::create(test_file.path(), &remote_store, CompressionKind::None).await?;
+        let object_path = format!("/streams/{}", &stream.hash);
+
+        let server = MockServer::start();
+        let expired_mock = server.mock(|when, then| {
+            when.method(GET)
+                .path(&object_path)
+                .query_param("signature", "1");
+            then.status(403);
+        });
+        let fresh_mock = server.mock(|when, then| {
+            when.method(GET)
+                .path(&object_path)
+                .query_param("signature", "2");
+            then.status(200)
+                .body_from_file(remote_store.path_of(&stream.hash).to_str().unwrap());
+        });
+
+        let signed = std::sync::atomic::AtomicU32::new(0);
+        let mirrors = Mirrors::from(server.base_url().as_str()).url_resolver(move |url| {
+            let signature = signed.fetch_add(1, Ordering::Relaxed) + 1;
+            format!("{url}?signature={signature}")
+        });
+        stream
+            .download_mirrored(&mirrors, &local_store, CompressionKind::None)
+            .await?;
+
+        assert!(local_store.contains(&stream.hash));
+        expired_mock.assert();
+        fresh_mock.assert();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transfer_totals() -> crate::Result<()> {
+        let repo_dir = TempDir::new()?;
+        std::fs::create_dir(repo_dir.child("streams"))?;
+        let remote_store = Store::new(repo_dir.child("streams"));
+        let local_stream_dir = TempDir::new()?;
+        let local_store = Store::new(local_stream_dir.path());
+        let test_data = b"This is some test data.";
+        let test_file = TempFile::new()?.with_contents(test_data)?;
+
+        let stream = Stream::create(test_file.path(), &remote_store, CompressionKind::Zstd).await?;
+        let compressed = remote_store
+            .object_path_of(&stream.hash, CompressionKind::Zstd)
+            .metadata()?
+            .len();
+
+        let mirrors = Mirrors::from(repo_dir.path().to_str().unwrap());
+        assert_eq!(mirrors.transfer_totals(), TransferTotals::default());
+
+        stream
+            .download_mirrored(&mirrors, &local_store, CompressionKind::Zstd)
+            .await?;
+        assert_eq!(
+            mirrors.transfer_totals(),
+            TransferTotals {
+                downloaded: compressed,
+                decompressed: test_data.len() as u64,
+            }
+        );
+
+        mirrors.reset_transfer_totals();
+        assert_eq!(mirrors.transfer_totals(), TransferTotals::default());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transfer_budget() -> crate::Result<()> {
+        let repo_dir = TempDir::new()?;
+        std::fs::create_dir(repo_dir.child("streams"))?;
+        let remote_store = Store::new(repo_dir.child("streams"));
+        let local_stream_dir = TempDir::new()?;
+        let local_store = Store::new(local_stream_dir.path());
+        let first_file = TempFile::new()?.with_contents(b"first")?;
+        let second_file = TempFile::new()?.with_contents(b"second")?;
+
+        let first = Stream::create(first_file.path(), &remote_store, CompressionKind::None).await?;
+        let second =
+            Stream::create(second_file.path(), &remote_store, CompressionKind::None).await?;
+
+        // The download that crosses the budget still finishes
+        let mirrors = Mirrors::from(repo_dir.path().to_str().unwrap()).budget(1);
+        first
+            .download_mirrored(&mirrors, &local_store, CompressionKind::None)
+            .await?;
+        let res = second
+            .download_mirrored(&mirrors, &local_store, CompressionKind::None)
+            .await;
+        assert!(matches!(res, Err(crate::Error::BudgetExceeded(1))));
+        assert!(!local_store.contains(&second.hash));
+
+        // A new period
+        mirrors.reset_transfer_totals();
+        second
+            .download_mirrored(&mirrors, &local_store, CompressionKind::None)
+            .await?;
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_download_invalid_hash() -> crate::Result<()> {
         let remote_stream_dir = TempDir::new()?;
+        let remote_store = Store::new(remote_stream_dir.path());
         let local_stream_dir = TempDir::new()?;
+        let local_store = Store::new(local_stream_dir.path());
         let test_data = b"This is some test data.";
         let test_file = TempFile::new()?.with_contents(test_data)?;
 
-        let stream = Stream::create(
-            test_file.path(),
-            remote_stream_dir.path(),
-            CompressionKind::None,
-        )
-        .await?;
+        let stream = Stream::create(test_file.path(), &remote_store, CompressionKind::None).await?;
 
         fs::write(&remote_stream_dir.child(&stream.hash), "a").await?;
 
@@ -273,14 +1374,211 @@ mod tests {
         });
 
         let res = stream
-            .download(
-                &server.base_url(),
-                local_stream_dir.path(),
+            .download(&server.base_url(), &local_store, CompressionKind::Zstd)
+            .await;
+
+        assert!(res.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_timeout() -> crate::Result<()> {
+        let local_stream_dir = TempDir::new()?;
+        let local_store = Store::new(local_stream_dir.path());
+        let stream = Stream {
+            hash: ObjectHash::of(b"slow"),
+            file_name: "slow".into(),
+            compression: None,
+            dictionary: None,
+            disk_size: None,
+            network_size: None,
+            #[cfg(unix)]
+            mode: None,
+            #[cfg(unix)]
+            mtime: None,
+            #[cfg(unix)]
+            owner: None,
+        };
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path(format!("/streams/{}", &stream.hash));
+            then.status(200)
+                .body("slow")
+                .delay(std::time::Duration::from_secs(5));
+        });
+
+        let mirrors = Mirrors::new([server.base_url()]).timeouts(Timeouts {
+            stream: Some(std::time::Duration::from_millis(100)),
+            ..Timeouts::default()
+        });
+        let res = stream
+            .download_mirrored(&mirrors, &local_store, CompressionKind::None)
+            .await;
+
+        assert!(matches!(res, Err(crate::Error::Timeout)), "{res:?}");
+        assert!(!local_stream_dir.path().join(&stream.hash).exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_slow_download_event() -> crate::Result<()> {
+        let local_stream_dir = TempDir::new()?;
+        let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let local_store = Store::new(local_stream_dir.path())
+            .with_events(move |event| sink.lock().unwrap().push(event.clone()))
+            .with_slow_thresholds(SlowThresholds::new().download_rate(1024));
+        let hash = ObjectHash::of(b"slow");
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path(format!("/streams/{hash}"));
+            then.status(200)
+                .body("slow")
+                .delay(std::time::Duration::from_millis(1100));
+        });
+
+        let stream = Stream {
+            hash: hash.clone(),
+            file_name: "slow".into(),
+            compression: None,
+            dictionary: None,
+            disk_size: None,
+            network_size: None,
+            #[cfg(unix)]
+            mode: None,
+            #[cfg(unix)]
+            mtime: None,
+            #[cfg(unix)]
+            owner: None,
+        };
+        let path = stream
+            .download(server.base_url(), &local_store, CompressionKind::None)
+            .await?;
+        assert_eq!(fs::read_to_end(path).await?, b"slow");
+
+        let events = events.lock().unwrap();
+        assert!(matches!(
+            &events[..],
+            [Event::SlowDownload { hash: slow, bytes: 4, .. }] if *slow == hash
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_compression_fallback() -> crate::Result<()> {
+        let remote_stream_dir = TempDir::new()?;
+        let remote_store = Store::new(remote_stream_dir.path());
+        let local_stream_dir = TempDir::new()?;
+        let local_store = Store::new(local_stream_dir.path());
+        let test_file = TempFile::new()?.with_contents(b"This is some test data.")?;
+
+        let mut stream =
+            Stream::create(test_file.path(), &remote_store, CompressionKind::Xz).await?;
+        // As listed by a manifest that predates recorded compression kinds
+        stream.compression = None;
+
+        let server = MockServer::start();
+        let zstd_mock = server.mock(|when, then| {
+            when.method(GET)
+                .path(format!("/streams/{}.zstd", &stream.hash));
+            then.status(404);
+        });
+        let xz_mock = server.mock(|when, then| {
+            when.method(GET)
+                .path(format!("/streams/{}.xz", &stream.hash));
+            then.status(200).body_from_file(
+                remote_stream_dir
+                    .path()
+                    .join(format!("{}.xz", &stream.hash))
+                    .to_str()
+                    .unwrap(),
+            );
+        });
+
+        // Without fallbacks, only the requested kind is tried
+        let res = stream
+            .download_mirrored(
+                &Mirrors::new([server.base_url()]),
+                &local_store,
                 CompressionKind::Zstd,
             )
             .await;
-
         assert!(res.is_err());
+        xz_mock.assert_calls(0);
+
+        let mirrors = Mirrors::new([server.base_url()])
+            .compression_fallbacks([CompressionKind::Zstd, CompressionKind::Xz]);
+        stream
+            .download_mirrored(&mirrors, &local_store, CompressionKind::Zstd)
+            .await?;
+
+        zstd_mock.assert_calls(2);
+        xz_mock.assert_calls(1);
+        assert!(local_stream_dir.path().join(&stream.hash).exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_compression_negotiation() -> crate::Result<()> {
+        let remote_stream_dir = TempDir::new()?;
+        let remote_store = Store::new(remote_stream_dir.path());
+        let local_stream_dir = TempDir::new()?;
+        let local_store = Store::new(local_stream_dir.path());
+        let server = MockServer::start();
+
+        let mut streams = Vec::new();
+        let mut mocks = Vec::new();
+        for contents in [&b"first"[..], b"second"] {
+            let file = TempFile::new()?.with_contents(contents)?;
+            let mut stream =
+                Stream::create(file.path(), &remote_store, CompressionKind::Lz4).await?;
+            stream.compression = None;
+            let object = remote_stream_dir
+                .path()
+                .join(format!("{}.lz4", stream.hash));
+
+            let missing = [CompressionKind::Zstd, CompressionKind::Xz].map(|kind| {
+                server.mock(|when, then| {
+                    when.method(GET).path(format!(
+                        "/streams/{}{}",
+                        stream.hash,
+                        kind.get_extension_with_dot()
+                    ));
+                    then.status(404);
+                })
+            });
+            let found = server.mock(|when, then| {
+                when.method(GET)
+                    .path(format!("/streams/{}.lz4", stream.hash));
+                then.status(200).body_from_file(object.to_str().unwrap());
+            });
+            streams.push(stream);
+            mocks.push((missing, found));
+        }
+
+        let mirrors = Mirrors::new([server.base_url()]).negotiate_compression();
+        for stream in &streams {
+            stream
+                .download_mirrored(&mirrors, &local_store, CompressionKind::Zstd)
+                .await?;
+            assert!(local_store.contains(&stream.hash));
+        }
+
+        // The first stream walks the priority list, the second goes straight to lz4
+        let ([zstd, xz], lz4) = &mocks[0];
+        zstd.assert_calls(1);
+        xz.assert_calls(1);
+        lz4.assert_calls(1);
+        let ([zstd, xz], lz4) = &mocks[1];
+        zstd.assert_calls(0);
+        xz.assert_calls(0);
+        lz4.assert_calls(1);
 
         Ok(())
     }
@@ -0,0 +1,547 @@
+//! Authenticating a stream block by block while it's downloaded, like bao.
+//!
+//! A BLAKE3 hash is the root of a tree over a stream's contents. An [`Outboard`] keeps the
+//! chaining value of every [`BLOCK_LEN`] block of it, as `{hash}.bao` next to the stream's
+//! objects. Clients that [verify blocks](crate::Mirrors::verify_blocks) check that the chaining
+//! values add up to the stream's hash before downloading it, and then each block against its
+//! chaining value before it's written, so corrupted data is caught where it starts rather than
+//! once the whole stream is on disk.
+use blake3::Hasher;
+use blake3::hazmat::{
+    ChainingValue, HasherExt, Mode, merge_subtrees_non_root, merge_subtrees_root,
+};
+use std::fmt;
+use std::io::{self, Read};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use crate::async_types::{AsyncBufRead, AsyncRead, AsyncReadExt};
+use crate::mirrors::{Fallthrough, Mirrors};
+use crate::net::Location;
+use crate::ssh;
+use crate::store::Store;
+use crate::stream::{Stream, blocks::read_full, response_reader};
+
+/// Bytes per verified block, a power of two number of BLAKE3 chunks so that blocks are subtrees.
+pub const BLOCK_LEN: usize = 16 * 1024;
+const CV_LEN: usize = 32;
+
+/// The chaining values of a stream's blocks, see [`Stream::create_outboard`].
+#[derive(Clone, PartialEq, Eq)]
+pub struct Outboard {
+    hash: blake3::Hash,
+    len: u64,
+    /// Empty for streams of a single block, which are checked against the hash itself
+    cvs: Vec<ChainingValue>,
+}
+
+impl fmt::Debug for Outboard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Outboard")
+            .field("hash", &self.hash.to_hex())
+            .field("len", &self.len)
+            .field("blocks", &self.blocks())
+            .finish_non_exhaustive()
+    }
+}
+
+/// The name of a stream's outboard in the store or a repository's `streams` directory.
+pub(crate) fn object_name(hash: &str) -> String {
+    format!("{hash}.bao")
+}
+
+fn block_cv(offset: u64, data: &[u8]) -> ChainingValue {
+    let mut hasher = Hasher::new();
+    hasher.set_input_offset(offset);
+    hasher.update(data);
+    hasher.finalize_non_root()
+}
+
+/// The blocks in the left subtree of `blocks` blocks, the largest power of two below it.
+fn left_blocks(blocks: usize) -> usize {
+    1 << (usize::BITS - 1 - (blocks - 1).leading_zeros())
+}
+
+fn subtree(cvs: &[ChainingValue]) -> ChainingValue {
+    if let [cv] = cvs {
+        return *cv;
+    }
+    let (left, right) = cvs.split_at(left_blocks(cvs.len()));
+    merge_subtrees_non_root(&subtree(left), &subtree(right), Mode::Hash)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl Outboard {
+    /// Hashes `reader` block by block.
+    ///
+    /// # Errors
+    ///
+    /// - Errors from the reader
+    pub fn compute<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut hasher = Hasher::new();
+        let mut cvs = Vec::new();
+        let mut len = 0;
+        let mut buf = vec![0; BLOCK_LEN];
+
+        loop {
+            let read = read_full(&mut reader, &mut buf)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buf[..read]);
+            cvs.push(block_cv(len, &buf[..read]));
+            len += read as u64;
+            if read < BLOCK_LEN {
+                break;
+            }
+        }
+        if cvs.len() <= 1 {
+            cvs.clear();
+        }
+
+        Ok(Self {
+            hash: hasher.finalize(),
+            len,
+            cvs,
+        })
+    }
+
+    /// Reads an outboard written by [`Self::to_bytes`], checking that it belongs to the stream
+    /// `hash`.
+    ///
+    /// # Errors
+    ///
+    /// - [`io::ErrorKind::InvalidData`] if it's malformed, or doesn't add up to `hash`
+    pub fn from_bytes(hash: &str, bytes: &[u8]) -> io::Result<Self> {
+        let hash = blake3::Hash::from_hex(hash).map_err(|_| invalid("invalid stream hash"))?;
+        let (len, cvs) = bytes
+            .split_first_chunk::<8>()
+            .ok_or_else(|| invalid("truncated outboard"))?;
+        let len = u64::from_le_bytes(*len);
+        let chunks = cvs.chunks_exact(CV_LEN);
+        let rest = chunks.remainder();
+
+        let outboard = Self {
+            hash,
+            len,
+            cvs: chunks
+                .map(|chunk| {
+                    let mut cv = [0; CV_LEN];
+                    cv.copy_from_slice(chunk);
+                    cv
+                })
+                .collect(),
+        };
+        let expected = if len > BLOCK_LEN as u64 {
+            len.div_ceil(BLOCK_LEN as u64)
+        } else {
+            0
+        };
+        if !rest.is_empty() || outboard.cvs.len() as u64 != expected {
+            return Err(invalid("outboard doesn't match the stream's length"));
+        }
+        if !outboard.cvs.is_empty() && outboard.root() != hash {
+            return Err(invalid("outboard doesn't match the stream's hash"));
+        }
+        Ok(outboard)
+    }
+
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.len.to_le_bytes().to_vec();
+        bytes.extend(self.cvs.iter().flatten());
+        bytes
+    }
+
+    #[must_use]
+    pub fn hash(&self) -> blake3::Hash {
+        self.hash
+    }
+
+    /// The length of the whole stream, where the last block can be shorter.
+    #[must_use]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Streams of up to one block, including empty ones, still have one to verify.
+    fn blocks(&self) -> usize {
+        self.cvs.len().max(1)
+    }
+
+    fn block_len(&self, n: usize) -> usize {
+        let start = n as u64 * BLOCK_LEN as u64;
+        usize::try_from(self.len.saturating_sub(start))
+            .map_or(BLOCK_LEN, |rest| rest.min(BLOCK_LEN))
+    }
+
+    fn root(&self) -> blake3::Hash {
+        let (left, right) = self.cvs.split_at(left_blocks(self.cvs.len()));
+        merge_subtrees_root(&subtree(left), &subtree(right), Mode::Hash)
+    }
+
+    fn verify_block(&self, n: usize, data: &[u8]) -> io::Result<()> {
+        let (expected, actual) = if self.cvs.is_empty() {
+            (*self.hash.as_bytes(), *blake3::hash(data).as_bytes())
+        } else {
+            (self.cvs[n], block_cv(n as u64 * BLOCK_LEN as u64, data))
+        };
+
+        if expected == actual {
+            Ok(())
+        } else {
+            let hex = |cv| blake3::Hash::from_bytes(cv).to_hex().to_string();
+            let error = crate::Error::HashError(hex(expected), hex(actual));
+            Err(io::Error::new(io::ErrorKind::InvalidData, error))
+        }
+    }
+}
+
+impl Stream {
+    /// Computes the stream's [`Outboard`], so that clients can verify each block of it as it's
+    /// downloaded. It's kept in the store, and [pushed](Self::push) along with the stream from
+    /// then on.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically the stream not being in the store)
+    pub fn create_outboard(&self, store: &Store) -> io::Result<Outboard> {
+        let file = std::fs::File::open(store.path_of(&self.hash))?;
+        let outboard = Outboard::compute(io::BufReader::new(file))?;
+
+        let path = store.root().join(object_name(&self.hash));
+        let tmp = store.temp_path("bao");
+        std::fs::write(&tmp, outboard.to_bytes())?;
+        std::fs::rename(&tmp, path)?;
+        Ok(outboard)
+    }
+}
+
+/// The outboard of the stream `hash` from the mirror at `url`, or `None` if it doesn't have one.
+pub(crate) async fn fetch(
+    mirrors: &Mirrors,
+    url: &str,
+    hash: &str,
+) -> crate::Result<Option<Outboard>> {
+    let object = format!("streams/{}", object_name(hash));
+
+    let res = match Location::parse(url) {
+        Location::Local(root) => {
+            let path = root.join(&object);
+            if path.exists() {
+                std::fs::read(path).map_err(Into::into)
+            } else {
+                Err(crate::Error::NotFound(path.display().to_string()))
+            }
+        }
+        Location::Ssh(remote) => {
+            let (mut reader, child) = remote.read(&object)?;
+            let mut data = Vec::new();
+            let res = reader.read_to_end(&mut data).await;
+            // A failed remote command explains any error from reading its output
+            match ssh::finish(child, &object).await {
+                Ok(()) => res.map(|_| data).map_err(Into::into),
+                Err(e) => Err(e),
+            }
+        }
+        Location::Http(url) => {
+            let (client, timeouts) = mirrors.client();
+            let res = timeouts
+                .apply(client.get(mirrors.resolve(&format!("{url}/{object}"))))
+                .headers(mirrors.request_headers().clone())
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status);
+            match res {
+                Ok(res) => {
+                    let mut data = Vec::new();
+                    response_reader(res).read_to_end(&mut data).await?;
+                    Ok(data)
+                }
+                Err(e) => Err(e.into()),
+            }
+        }
+    };
+
+    match res {
+        Ok(data) => Ok(Some(Outboard::from_bytes(hash, &data)?)),
+        Err(e) if matches!(Fallthrough::classify(&e), Fallthrough::Missing) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Only passes on blocks of `reader` once they're verified against `outboard`.
+pub(crate) fn verify<'a, R: AsyncBufRead + Send + Unpin + 'a>(
+    reader: R,
+    outboard: Outboard,
+) -> Pin<Box<dyn AsyncRead + Send + 'a>> {
+    Box::pin(VerifyingReader {
+        inner: reader,
+        outboard,
+        block: 0,
+        input: Vec::new(),
+        output: Vec::new(),
+        pos: 0,
+        done: false,
+    })
+}
+
+struct VerifyingReader<R> {
+    inner: R,
+    outboard: Outboard,
+    /// The next block to verify
+    block: usize,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R> VerifyingReader<R> {
+    /// The rest of the next block.
+    fn needed(&self) -> usize {
+        if self.block < self.outboard.blocks() {
+            self.outboard.block_len(self.block) - self.input.len()
+        } else {
+            0
+        }
+    }
+
+    /// Verifies every complete block in the input.
+    fn verify_blocks(&mut self) -> io::Result<()> {
+        while self.block < self.outboard.blocks() {
+            let len = self.outboard.block_len(self.block);
+            if self.input.len() < len {
+                return Ok(());
+            }
+            self.outboard.verify_block(self.block, &self.input[..len])?;
+            self.output.extend_from_slice(&self.input[..len]);
+            self.input.drain(..len);
+            self.block += 1;
+        }
+
+        if self.input.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid("stream is longer than its outboard"))
+        }
+    }
+
+    fn verify_last(&mut self) -> io::Result<()> {
+        self.done = true;
+        self.verify_blocks()?;
+        if self.block < self.outboard.blocks() {
+            return Err(invalid("stream is shorter than its outboard"));
+        }
+        Ok(())
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncBufRead for VerifyingReader<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+
+        while this.pos == this.output.len() && !this.done {
+            this.output.clear();
+            this.pos = 0;
+
+            let needed = this.needed();
+            let data = ready!(Pin::new(&mut this.inner).poll_fill_buf(cx))?;
+            if data.is_empty() {
+                this.verify_last()?;
+            } else {
+                // A block at a time, so that earlier blocks are passed on before a bad one
+                let len = data.len().min(needed.max(1));
+                this.input.extend_from_slice(&data[..len]);
+                Pin::new(&mut this.inner).consume(len);
+                this.verify_blocks()?;
+            }
+        }
+
+        Poll::Ready(Ok(&this.output[this.pos..]))
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<R: AsyncBufRead + Unpin> AsyncRead for VerifyingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let data = ready!(self.as_mut().poll_fill_buf(cx))?;
+        let len = data.len().min(buf.remaining());
+        buf.put_slice(&data[..len]);
+        self.consume(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(not(feature = "tokio"))]
+impl<R: AsyncBufRead + Unpin> AsyncRead for VerifyingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let data = ready!(self.as_mut().poll_fill_buf(cx))?;
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        self.consume(len);
+        Poll::Ready(Ok(len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::{CompressionKind, fs};
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251).to_le_bytes()[0]).collect()
+    }
+
+    /// Reads as much as the reader passes on before failing.
+    async fn read_verified(data: &[u8], outboard: Outboard) -> (Vec<u8>, io::Result<()>) {
+        let mut reader = verify(data, outboard);
+        let mut read = Vec::new();
+        let mut buf = vec![0; 4096];
+        loop {
+            match reader.read(&mut buf).await {
+                Ok(0) => return (read, Ok(())),
+                Ok(len) => read.extend_from_slice(&buf[..len]),
+                Err(e) => return (read, Err(e)),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outboard() -> io::Result<()> {
+        for len in [
+            0,
+            1,
+            BLOCK_LEN,
+            BLOCK_LEN + 1,
+            5 * BLOCK_LEN + 7,
+            64 * BLOCK_LEN,
+        ] {
+            let data = data(len);
+            let outboard = Outboard::compute(&data[..])?;
+            assert_eq!(outboard.hash(), blake3::hash(&data), "{len} bytes");
+            let hash = outboard.hash().to_hex();
+            assert_eq!(Outboard::from_bytes(&hash, &outboard.to_bytes())?, outboard);
+
+            let (read, res) = read_verified(&data, outboard.clone()).await;
+            res?;
+            assert_eq!(read, data);
+
+            // Truncated and extended streams are caught
+            if len > 0 {
+                assert!(
+                    read_verified(&data[..len - 1], outboard.clone())
+                        .await
+                        .1
+                        .is_err()
+                );
+            }
+            let longer = [&data[..], b"!"].concat();
+            assert!(read_verified(&longer, outboard).await.1.is_err());
+        }
+
+        // Nothing from a corrupted block on is passed on
+        let mut data = data(5 * BLOCK_LEN + 7);
+        let outboard = Outboard::compute(&data[..])?;
+        data[3 * BLOCK_LEN + 10] ^= 1;
+        let (read, res) = read_verified(&data, outboard.clone()).await;
+        assert_eq!(read.len(), 3 * BLOCK_LEN);
+        assert!(matches!(
+            res.map_err(crate::Error::from),
+            Err(crate::Error::HashError(..))
+        ));
+
+        // Outboards of other streams, or tampered ones, are refused
+        let hash = outboard.hash().to_hex();
+        let other = blake3::hash(b"other").to_hex();
+        assert!(Outboard::from_bytes(&other, &outboard.to_bytes()).is_err());
+        let mut tampered = outboard.to_bytes();
+        tampered[20] ^= 1;
+        assert!(Outboard::from_bytes(&hash, &tampered).is_err());
+        assert!(Outboard::from_bytes(&hash, &tampered[..40]).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_verified() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let repo_dir = TempDir::new()?;
+        let repo_url = repo_dir.path().to_str().unwrap();
+        let local_dir = TempDir::new()?;
+        let local_store = Store::new(local_dir.path());
+        let original_dir = TempDir::new()?;
+
+        let mut contents = data(10 * BLOCK_LEN);
+        fs::write(original_dir.path().join("file"), &contents).await?;
+        let stream = Stream::create(
+            original_dir.path().join("file"),
+            &store,
+            CompressionKind::Zstd,
+        )
+        .await?;
+        stream.create_outboard(&store)?;
+        stream.push(repo_url, &store, CompressionKind::Zstd).await?;
+        assert!(
+            repo_dir
+                .path()
+                .join(format!("streams/{}", object_name(&stream.hash)))
+                .exists()
+        );
+
+        let mirrors = Mirrors::new([repo_url]).verify_blocks();
+        stream
+            .download_mirrored(&mirrors, &local_store, CompressionKind::Zstd)
+            .await?;
+        assert!(local_store.contains(&stream.hash));
+
+        // A repository serving other contents under the stream's name
+        contents[7 * BLOCK_LEN] ^= 1;
+        fs::write(original_dir.path().join("other"), &contents).await?;
+        let other = Stream::create(
+            original_dir.path().join("other"),
+            &store,
+            CompressionKind::Zstd,
+        )
+        .await?;
+        std::fs::copy(
+            store.object_path_of(&other.hash, CompressionKind::Zstd),
+            repo_dir
+                .path()
+                .join(format!("streams/{}.zstd", stream.hash)),
+        )?;
+
+        let other_dir = TempDir::new()?;
+        let other_store = Store::new(other_dir.path());
+        let res = stream
+            .download_mirrored(&mirrors, &other_store, CompressionKind::Zstd)
+            .await;
+        assert!(matches!(res, Err(crate::Error::HashError(..))));
+        assert!(!other_store.contains(&stream.hash));
+
+        Ok(())
+    }
+}
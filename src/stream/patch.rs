@@ -0,0 +1,246 @@
+//! Binary patches between two versions of a stream, for releases where most of a large file
+//! stays the same.
+//!
+//! A patch is the new stream compressed with Zstd using the old one as a reference, like
+//! `zstd --patch-from`, so that it only holds what changed. Repositories keep it as
+//! `patches/{old}-{new}`, and stores as `{new}.from{old}`, so that it's kept for as long as the
+//! new stream is. Both versions are read into memory while a patch is created or applied.
+use blake3::Hasher;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::PathBuf;
+
+use crate::async_types::AsyncReadExt;
+use crate::encryption::{EncryptingWriter, decrypt};
+use crate::net::{self, Location};
+use crate::ssh;
+use crate::store::Store;
+use crate::stream::Stream;
+
+/// The level patches are compressed at, which is higher than for objects as they're created once
+/// and downloaded by every client that has the old version.
+const LEVEL: i32 = 19;
+/// The largest window patches use, 1 GiB, which is as far as 32-bit Zstd can reach back. Only the
+/// last GiB of an older version is used for larger streams.
+const MAX_WINDOW_LOG: u32 = 30;
+const MIN_WINDOW_LOG: u32 = 10;
+
+/// The name of the patch from `old` to `new` in a repository.
+pub(crate) fn object_name(old: &str, new: &str) -> String {
+    format!("patches/{old}-{new}")
+}
+
+/// The path of the patch from `old` to `new` in the store.
+fn store_path(store: &Store, old: &str, new: &str) -> PathBuf {
+    store.root().join(format!("{new}.from{old}"))
+}
+
+/// A window that reaches from the end of `new` back to the start of `old`.
+fn window_log(old: u64, new: u64) -> u32 {
+    let len = old.saturating_add(new);
+    (u64::BITS - len.saturating_sub(1).leading_zeros()).clamp(MIN_WINDOW_LOG, MAX_WINDOW_LOG)
+}
+
+impl Stream {
+    /// Creates a patch from `old` to `new`, which must both be in the store, so that clients
+    /// with `old` can [download `new`](Self::download_patched) by fetching only what changed.
+    /// The patch is kept in the store, and uploaded by [`Self::push_patch`].
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically either stream not being in the store)
+    pub async fn create_patch(old: &Stream, new: &Stream, store: &Store) -> crate::Result<PathBuf> {
+        let reference = std::fs::read(store.path_of(&old.hash))?;
+        let input = std::fs::File::open(store.path_of(&new.hash))?;
+        let window_log = window_log(reference.len() as u64, input.metadata()?.len());
+        let (output, temp) = store.create_temp_blocking("patch")?;
+        let key = store.encryption().cloned();
+
+        let res = store
+            .cpu_pool()
+            .run(move || {
+                let mut output = EncryptingWriter::new(output, key.as_ref())?;
+                let mut encoder =
+                    zstd::stream::write::Encoder::with_ref_prefix(&mut output, LEVEL, &reference)?;
+                encoder.window_log(window_log)?;
+                encoder.long_distance_matching(true)?;
+                io::copy(&mut io::BufReader::new(input), &mut encoder)?;
+                encoder.finish()?;
+                output.finish().map(drop)
+            })
+            .await?;
+        if let Err(e) = res {
+            Store::discard_temp(temp).await?;
+            return Err(e.into());
+        }
+
+        let path = store_path(store, &old.hash, &new.hash);
+        store.persist_temp(temp, &path)?;
+        Ok(path)
+    }
+
+    /// Uploads the patch from `old` to this stream, made by [`Self::create_patch`], to a
+    /// repository. The stream itself should still be [pushed](Self::push) for clients that don't
+    /// have `old`.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically the patch missing from the store)
+    /// - Network errors (Non-2xx codes, etc). The built-in server only accepts uploads of
+    ///   streams and manifests.
+    pub async fn push_patch(&self, old: &Stream, url: &str, store: &Store) -> crate::Result<()> {
+        let source = store_path(store, &old.hash, &self.hash);
+        if !source.exists() {
+            return Err(crate::Error::NotFound(source.display().to_string()));
+        }
+        self.push_object(url, store, &source, &object_name(&old.hash, &self.hash))
+            .await
+    }
+
+    /// Downloads this stream by applying the repository's patch from `old`, which must be in the
+    /// store, instead of fetching all of it.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::NotFound`](crate::Error::NotFound) or a 404 if the repository doesn't have
+    ///   the patch, in which case [`Self::download`] still works
+    /// - Filesystem errors (Typically `old` not being in the store)
+    /// - [`Error::HashError`](crate::Error::HashError) if the result doesn't match, like when
+    ///   `old` isn't the version the patch was made from
+    pub async fn download_patched(
+        &self,
+        old: &Stream,
+        url: &str,
+        store: &Store,
+    ) -> crate::Result<PathBuf> {
+        let reference = std::fs::read(store.path_of(&old.hash))?;
+        let encrypted = fetch_patch(url, &object_name(&old.hash, &self.hash)).await?;
+        let mut patch = Vec::new();
+        decrypt(&encrypted[..], store.encryption())
+            .read_to_end(&mut patch)
+            .await?;
+        let (output, temp) = store.create_temp_blocking(&self.hash)?;
+
+        let res = store
+            .cpu_pool()
+            .run(move || {
+                let mut decoder =
+                    zstd::stream::read::Decoder::with_ref_prefix(&patch[..], &reference)?;
+                decoder.window_log_max(MAX_WINDOW_LOG)?;
+                let mut output = BufWriter::new(output);
+                let mut hasher = Hasher::new();
+                let mut buf = vec![0; 64 * 1024];
+                loop {
+                    let len = decoder.read(&mut buf)?;
+                    if len == 0 {
+                        break;
+                    }
+                    hasher.update(&buf[..len]);
+                    output.write_all(&buf[..len])?;
+                }
+                output.flush()?;
+                Ok::<_, io::Error>(hasher)
+            })
+            .await?;
+
+        match res {
+            Ok(hasher) => store.finish_insert(&self.hash, hasher, temp).await,
+            Err(e) => {
+                Store::discard_temp(temp).await?;
+                Err(e.into())
+            }
+        }
+    }
+}
+
+async fn fetch_patch(url: &str, object: &str) -> crate::Result<Vec<u8>> {
+    match Location::parse(url) {
+        Location::Local(root) => {
+            let path = root.join(object);
+            if !path.exists() {
+                return Err(crate::Error::NotFound(path.display().to_string()));
+            }
+            Ok(std::fs::read(path)?)
+        }
+        Location::Ssh(remote) => {
+            let (mut reader, child) = remote.read(object)?;
+            let mut data = Vec::new();
+            let res = reader.read_to_end(&mut data).await;
+            // A failed remote command explains any error from reading its output
+            ssh::finish(child, object).await?;
+            res?;
+            Ok(data)
+        }
+        Location::Http(url) => Ok(net::default_client()
+            .get(format!("{url}/{object}"))
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?
+            .to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::{CompressionKind, fs};
+
+    #[tokio::test]
+    async fn test_patch() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let repo_dir = TempDir::new()?;
+        let repo_url = repo_dir.path().to_str().unwrap();
+        let local_dir = TempDir::new()?;
+        let local_store = Store::new(local_dir.path());
+        let original_dir = TempDir::new()?;
+
+        // A release where a little changed in the middle of a file that doesn't compress
+        let old_data: Vec<u8> = (0..256 * 1024u32)
+            .flat_map(|i| blake3::hash(&i.to_le_bytes()).as_bytes()[..4].to_vec())
+            .collect();
+        let mut new_data = old_data.clone();
+        new_data.splice(300_000..300_100, b"patched".repeat(50));
+        fs::write(original_dir.path().join("old"), &old_data).await?;
+        fs::write(original_dir.path().join("new"), &new_data).await?;
+        let old = Stream::create(
+            original_dir.path().join("old"),
+            &store,
+            CompressionKind::Zstd,
+        )
+        .await?;
+        let new = Stream::create(
+            original_dir.path().join("new"),
+            &store,
+            CompressionKind::Zstd,
+        )
+        .await?;
+
+        let patch_path = Stream::create_patch(&old, &new, &store).await?;
+        assert!(patch_path.metadata()?.len() < 4096, "{patch_path:?}");
+        old.push(repo_url, &store, CompressionKind::Zstd).await?;
+        new.push_patch(&old, repo_url, &store).await?;
+        assert!(
+            repo_dir
+                .path()
+                .join(object_name(&old.hash, &new.hash))
+                .exists()
+        );
+
+        // Patches apply to the old version only
+        let missing = new.download_patched(&old, repo_url, &local_store).await;
+        assert!(missing.is_err());
+        old.download(repo_url, &local_store, CompressionKind::Zstd)
+            .await?;
+        let new_path = new.download_patched(&old, repo_url, &local_store).await?;
+        assert_eq!(std::fs::read(new_path)?, new_data);
+        assert!(matches!(
+            old.download_patched(&new, repo_url, &local_store).await,
+            Err(crate::Error::NotFound(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+//! Checking whether a repository has an object, without downloading it.
+//!
+//! HTTP repositories are asked with `HEAD`. Servers that refuse it, like ones behind pre-signed
+//! URLs only valid for `GET`, are asked for the first byte instead, and the connection is
+//! dropped without reading the rest.
+use reqwest::StatusCode;
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use std::io;
+
+use crate::CompressionKind;
+use crate::mirrors::{Fallthrough, Mirrors};
+use crate::net::Location;
+use crate::stream::Stream;
+use crate::stream::range::send;
+
+impl Mirrors {
+    /// Whether any of the mirrors has the stream, in any of the kinds a download would try, see
+    /// [`Stream::download`]. Nothing is downloaded, so this is cheap enough to check a whole tree
+    /// before a long download.
+    ///
+    /// # Errors
+    ///
+    /// - Network and SSH errors, if no mirror could be asked
+    pub async fn has_stream<C: Into<Option<CompressionKind>>>(
+        &self,
+        stream: &Stream,
+        compression_kind: C,
+    ) -> crate::Result<bool> {
+        let compression_kind = compression_kind.into();
+        let mut last_error = None;
+        let mut answered = false;
+
+        for url in self.candidates() {
+            for kind in stream.compression_kinds(self, url, compression_kind) {
+                let object = stream.object_name(self, kind);
+                match has_object(self, url, &object).await {
+                    Ok(true) => return Ok(true),
+                    Ok(false) => answered = true,
+                    Err(e) => match Fallthrough::classify(&e) {
+                        Fallthrough::Missing => answered = true,
+                        Fallthrough::Unhealthy => {
+                            self.record_failure(url);
+                            last_error = Some(e);
+                            break;
+                        }
+                        Fallthrough::Fatal => return Err(e),
+                    },
+                }
+            }
+        }
+
+        match last_error {
+            Some(e) if !answered => Err(e),
+            _ => Ok(false),
+        }
+    }
+}
+
+/// Whether the repository at `url` has `object`, like `streams/{hash}.zstd`.
+pub(crate) async fn has_object(mirrors: &Mirrors, url: &str, object: &str) -> crate::Result<bool> {
+    match Location::parse(url) {
+        Location::Local(root) => Ok(root.join(object).exists()),
+        Location::Ssh(remote) => remote.exists(object).await,
+        Location::Http(url) => {
+            let object_url = format!("{url}/{object}");
+            let (client, timeouts) = mirrors.client();
+            let head = || {
+                timeouts
+                    .apply(client.head(mirrors.resolve(&object_url)))
+                    .headers(mirrors.request_headers().clone())
+                    .send()
+            };
+
+            let mut res = head().await?;
+            if res.status() == StatusCode::FORBIDDEN && mirrors.has_resolver() {
+                res = head().await?;
+            }
+            match res.status() {
+                status if status.is_success() => return Ok(true),
+                StatusCode::NOT_FOUND => return Ok(false),
+                StatusCode::FORBIDDEN
+                | StatusCode::METHOD_NOT_ALLOWED
+                | StatusCode::NOT_IMPLEMENTED => {}
+                _ => {
+                    res.error_for_status()?;
+                }
+            }
+
+            let mut headers = HeaderMap::new();
+            headers.insert(header::RANGE, HeaderValue::from_static("bytes=0-0"));
+            let res = send(mirrors, &object_url, headers).await?;
+            match res.status() {
+                status if status.is_success() => Ok(true),
+                // Only empty objects have no first byte
+                StatusCode::RANGE_NOT_SATISFIABLE => Ok(true),
+                StatusCode::NOT_FOUND => Ok(false),
+                _ => {
+                    res.error_for_status()?;
+                    Err(io::Error::other("unexpected response to a ranged request").into())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use httpmock::prelude::*;
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::store::Store;
+
+    #[tokio::test]
+    async fn test_has_stream() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let repo_dir = TempDir::new()?;
+        let repo_url = repo_dir.path().to_str().unwrap();
+        let files = TempDir::new()?;
+        std::fs::write(files.path().join("file"), b"contents")?;
+        let stream =
+            Stream::create(files.path().join("file"), &store, CompressionKind::Zstd).await?;
+        let object = format!("/streams/{}.zstd", stream.hash);
+
+        let mirrors = Mirrors::from(repo_url);
+        assert!(!mirrors.has_stream(&stream, CompressionKind::Zstd).await?);
+        stream.push(repo_url, &store, CompressionKind::Zstd).await?;
+        assert!(mirrors.has_stream(&stream, CompressionKind::Zstd).await?);
+
+        let server = MockServer::start();
+        let head = server.mock(|when, then| {
+            when.method(Method::HEAD).path(&object);
+            then.status(200);
+        });
+        let put = server.mock(|when, then| {
+            when.method(PUT).path(&object);
+            then.status(201);
+        });
+        let mirrors = Mirrors::new([server.base_url()]);
+        assert!(mirrors.has_stream(&stream, CompressionKind::Zstd).await?);
+        // Pushes skip what's there already
+        stream
+            .push(server.base_url(), &store, CompressionKind::Zstd)
+            .await?;
+        head.assert_calls(2);
+        put.assert_calls(0);
+
+        // Servers refusing HEAD are asked for the first byte
+        let get_only = MockServer::start();
+        get_only.mock(|when, then| {
+            when.method(Method::HEAD);
+            then.status(405);
+        });
+        let ranged = get_only.mock(|when, then| {
+            when.method(GET).path(&object).header("range", "bytes=0-0");
+            then.status(206).body("x");
+        });
+        let mirrors = Mirrors::new([get_only.base_url()]);
+        assert!(mirrors.has_stream(&stream, CompressionKind::Zstd).await?);
+        ranged.assert();
+
+        // Missing everywhere, unlike unreachable
+        let empty = MockServer::start();
+        let mirrors = Mirrors::new([empty.base_url()]);
+        assert!(!mirrors.has_stream(&stream, CompressionKind::Zstd).await?);
+        let unreachable = "http://127.0.0.1:1";
+        let mirrors = Mirrors::new([unreachable.to_string(), empty.base_url()]);
+        assert!(!mirrors.has_stream(&stream, CompressionKind::Zstd).await?);
+        let mirrors = Mirrors::new([unreachable]);
+        assert!(
+            mirrors
+                .has_stream(&stream, CompressionKind::Zstd)
+                .await
+                .is_err()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,407 @@
+//! Fetching parts of objects with HTTP `Range` requests.
+//!
+//! [`Stream::download_range`] reads a slice of a stream's uncompressed object, for putting a file
+//! together out of pieces of others. Downloads of whole objects over HTTP also pick up where they
+//! left off when the connection drops partway: the rest is requested as a range, guarded by
+//! `If-Range` so that an object replaced in the meantime is never spliced onto the old one.
+use reqwest::StatusCode;
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use std::io;
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+
+use crate::async_types::{AsyncBufRead, AsyncReadExt, BufReader, StreamExt, unfold};
+use crate::fs;
+use crate::mirrors::{Fallthrough, Mirrors};
+use crate::net::Location;
+use crate::stream::{Stream, response_reader};
+
+/// How many times a single download is resumed before its error is returned.
+const MAX_RESUMES: u32 = 3;
+
+impl Stream {
+    /// Downloads the bytes `range` of the stream's contents from the first mirror that has its
+    /// uncompressed object, pushed with [`CompressionKind::None`](crate::CompressionKind::None).
+    /// Fewer bytes are returned if the range goes past the end of the stream.
+    ///
+    /// Nothing is verified, as only the whole stream has a hash.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::NotFound`](crate::Error::NotFound) or a 404 if no mirror has the uncompressed
+    ///   object
+    /// - [`io::ErrorKind::Unsupported`] for encrypted mirrors, and if no mirror can serve byte
+    ///   ranges, like SSH repositories
+    /// - [`Error::BudgetExceeded`](crate::Error::BudgetExceeded) if the mirrors'
+    ///   [budget](Mirrors::budget) is used up
+    /// - Filesystem and network errors
+    pub async fn download_range(
+        &self,
+        mirrors: &Mirrors,
+        range: Range<u64>,
+    ) -> crate::Result<Vec<u8>> {
+        if mirrors.encryption_key().is_some() {
+            return Err(no_ranges("encrypted repositories"));
+        }
+        mirrors.check_budget()?;
+        let mut last_error = None;
+
+        for url in mirrors.candidates() {
+            let res = async {
+                let mut bytes = Vec::new();
+                open_range(mirrors, url, &self.hash, range.clone())
+                    .await?
+                    .read_to_end(&mut bytes)
+                    .await?;
+                Ok::<_, crate::Error>(bytes)
+            }
+            .await;
+
+            match res {
+                Ok(bytes) => {
+                    mirrors.record_success(url);
+                    (mirrors.transfer().downloaded)
+                        .fetch_add(bytes.len() as u64, Ordering::Relaxed);
+                    return Ok(bytes);
+                }
+                Err(crate::Error::IoError(e)) if e.kind() == io::ErrorKind::Unsupported => {
+                    last_error = Some(e.into());
+                }
+                Err(e) => match Fallthrough::classify(&e) {
+                    Fallthrough::Missing => last_error = Some(e),
+                    Fallthrough::Unhealthy => {
+                        mirrors.record_failure(url);
+                        last_error = Some(e);
+                    }
+                    Fallthrough::Fatal => return Err(e),
+                },
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| io::Error::other("no mirrors configured").into()))
+    }
+}
+
+/// The bytes `range` of a stream's uncompressed object in the repository at `url`.
+pub(crate) async fn open_range(
+    mirrors: &Mirrors,
+    url: &str,
+    hash: &str,
+    range: Range<u64>,
+) -> crate::Result<Pin<Box<dyn AsyncBufRead + Send>>> {
+    let object = format!("streams/{hash}");
+
+    match Location::parse(url) {
+        Location::Local(root) => {
+            let path = root.join(object);
+            if !path.exists() {
+                return Err(crate::Error::NotFound(path.display().to_string()));
+            }
+            let len = range.end.saturating_sub(range.start);
+            Ok(fs::open_range(path, range.start, len).await?)
+        }
+        Location::Ssh(_) => Err(no_ranges("SSH repositories")),
+        Location::Http(url) => {
+            if range.is_empty() {
+                return Ok(Box::pin(&b""[..]));
+            }
+            let mut headers = HeaderMap::new();
+            headers.insert(header::RANGE, range_header(range.start, Some(range.end)));
+            let res = send(mirrors, &format!("{url}/{object}"), headers)
+                .await?
+                .error_for_status()?;
+            match res.status() {
+                StatusCode::PARTIAL_CONTENT => Ok(Box::pin(response_reader(res))),
+                // Ranges starting past the end
+                StatusCode::RANGE_NOT_SATISFIABLE => Ok(Box::pin(&b""[..])),
+                _ => Err(no_ranges("this server")),
+            }
+        }
+    }
+}
+
+/// Requests `object_url` with the mirrors' client, timeouts and headers, plus `headers`. A
+/// request refused with 403 is sent once more, in case a pre-signed URL expired since it was
+/// [resolved](Mirrors::url_resolver).
+///
+/// Objects are compressed already, and ranges only line up with the bytes received when they
+/// aren't encoded again on the way, so `identity` is the only encoding accepted.
+pub(crate) async fn send(
+    mirrors: &Mirrors,
+    object_url: &str,
+    headers: HeaderMap,
+) -> reqwest::Result<reqwest::Response> {
+    let (client, timeouts) = mirrors.client();
+    let request = || {
+        timeouts
+            .apply(client.get(mirrors.resolve(object_url)))
+            .header(header::ACCEPT_ENCODING, "identity")
+            .headers(mirrors.request_headers().clone())
+            .headers(headers.clone())
+            .send()
+    };
+
+    let res = request().await?;
+    if res.status() == StatusCode::FORBIDDEN && mirrors.has_resolver() {
+        return request().await;
+    }
+    Ok(res)
+}
+
+/// A whole object over HTTP, being downloaded.
+struct Download<'a> {
+    mirrors: &'a Mirrors,
+    object_url: String,
+    res: reqwest::Response,
+    /// The object's `ETag`, or `Last-Modified` date, to resume with
+    validator: Option<HeaderValue>,
+    received: u64,
+    resumes: u32,
+}
+
+impl Download<'_> {
+    /// Requests the rest of the object after a failed read, or `None` if the download can't be
+    /// resumed.
+    async fn resume(&mut self, error: &reqwest::Error) -> Option<reqwest::Response> {
+        // A whole stream timeout is meant to end the download
+        if error.is_timeout() || self.resumes >= MAX_RESUMES {
+            return None;
+        }
+        self.resumes += 1;
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, range_header(self.received, None));
+        headers.insert(header::IF_RANGE, self.validator.clone()?);
+        let res = send(self.mirrors, &self.object_url, headers).await.ok()?;
+
+        // Anything else, like the whole object again because it changed, can't be appended
+        let resumed = res.status() == StatusCode::PARTIAL_CONTENT
+            && content_range_start(res.headers()) == Some(self.received);
+        resumed.then_some(res)
+    }
+}
+
+/// Opens a whole object over HTTP, resuming the download with a range when reading it fails.
+pub(crate) async fn open_resumable(
+    mirrors: &Mirrors,
+    object_url: String,
+) -> crate::Result<impl AsyncBufRead + Send + Unpin + '_> {
+    let res = send(mirrors, &object_url, HeaderMap::new())
+        .await?
+        .error_for_status()?;
+    let download = Download {
+        mirrors,
+        object_url,
+        validator: validator(res.headers()),
+        res,
+        received: 0,
+        resumes: 0,
+    };
+
+    let chunks = unfold(Some(download), |download| async move {
+        let mut download = download?;
+        loop {
+            match download.res.chunk().await {
+                Ok(Some(chunk)) => {
+                    download.received += chunk.len() as u64;
+                    return Some((Ok(chunk), Some(download)));
+                }
+                Ok(None) => return None,
+                Err(e) => match download.resume(&e).await {
+                    Some(res) => download.res = res,
+                    None => return Some((Err(io::Error::other(e)), None)),
+                },
+            }
+        }
+    })
+    // Readers may ask for more after the end
+    .fuse();
+
+    #[cfg(feature = "tokio")]
+    let reader = tokio_util::io::StreamReader::new(Box::pin(chunks));
+    #[cfg(not(feature = "tokio"))]
+    let reader = crate::async_types::TryStreamExt::into_async_read(Box::pin(chunks));
+    Ok(BufReader::new(reader))
+}
+
+/// A strong `ETag`, or else the `Last-Modified` date, which `If-Range` can compare.
+fn validator(headers: &HeaderMap) -> Option<HeaderValue> {
+    let etag = headers
+        .get(header::ETAG)
+        .filter(|etag| !etag.as_bytes().starts_with(b"W/"));
+    etag.or_else(|| headers.get(header::LAST_MODIFIED)).cloned()
+}
+
+/// A `Range` header for the bytes from `start` up to `end`, or the end of the object.
+fn range_header(start: u64, end: Option<u64>) -> HeaderValue {
+    let value = match end {
+        Some(end) => format!("bytes={start}-{}", end - 1),
+        None => format!("bytes={start}-"),
+    };
+    HeaderValue::from_str(&value).expect("range headers are valid")
+}
+
+/// Where the body of a `206 Partial Content` response starts in the object.
+fn content_range_start(headers: &HeaderMap) -> Option<u64> {
+    let range = headers.get(header::CONTENT_RANGE)?.to_str().ok()?;
+    let (start, _) = range.strip_prefix("bytes ")?.split_once('-')?;
+    start.parse().ok()
+}
+
+pub(crate) fn no_ranges(what: &str) -> crate::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("byte ranges can't be downloaded from {what}"),
+    )
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use httpmock::prelude::*;
+    use std::io::{BufRead, BufReader as StdBufReader, Write};
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::CompressionKind;
+    use crate::store::Store;
+
+    /// Answers each connection with the next of `responses` and closes it, returning the
+    /// requests' headers.
+    fn serve(responses: Vec<Vec<u8>>) -> io::Result<(String, JoinHandle<Vec<String>>)> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let handle = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut conn, _) = listener.accept().expect("accepting a connection");
+                let mut request = String::new();
+                let mut reader = StdBufReader::new(&mut conn);
+                while reader.read_line(&mut request).is_ok_and(|n| n > 2) {}
+                conn.write_all(&response).expect("writing the response");
+                requests.push(request.to_ascii_lowercase());
+            }
+            requests
+        });
+        Ok((url, handle))
+    }
+
+    fn response(head: &str, body: &[u8]) -> Vec<u8> {
+        let mut response = format!("{head}\r\n\r\n").into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    #[tokio::test]
+    async fn test_download_range() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let repo_dir = TempDir::new()?;
+        let repo_url = repo_dir.path().to_str().unwrap();
+        let files = TempDir::new()?;
+        std::fs::write(files.path().join("file"), b"0123456789")?;
+
+        let stream =
+            Stream::create(files.path().join("file"), &store, CompressionKind::None).await?;
+        let mirrors = Mirrors::from(repo_url);
+        assert!(matches!(
+            stream.download_range(&mirrors, 2..6).await,
+            Err(crate::Error::NotFound(_))
+        ));
+        stream.push(repo_url, &store, CompressionKind::None).await?;
+        assert_eq!(stream.download_range(&mirrors, 2..6).await?, b"2345");
+        assert_eq!(stream.download_range(&mirrors, 8..20).await?, b"89");
+
+        // Servers ignoring ranges are skipped for the next mirror
+        let ignoring = MockServer::start();
+        let ignored = ignoring.mock(|when, then| {
+            when.method(GET).path(format!("/streams/{}", stream.hash));
+            then.status(200).body("0123456789");
+        });
+        let ranged = MockServer::start();
+        let served = ranged.mock(|when, then| {
+            when.method(GET)
+                .path(format!("/streams/{}", stream.hash))
+                .header("range", "bytes=2-5");
+            then.status(206)
+                .header("content-range", "bytes 2-5/10")
+                .body("2345");
+        });
+        let mirrors = Mirrors::new([ignoring.base_url(), ranged.base_url()]);
+        assert_eq!(stream.download_range(&mirrors, 2..6).await?, b"2345");
+        ignored.assert();
+        served.assert();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_resumes() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let files = TempDir::new()?;
+        let data: Vec<u8> = (0..100_000u32).flat_map(u32::to_le_bytes).collect();
+        std::fs::write(files.path().join("file"), &data)?;
+        let stream = Stream::create(
+            files.path().join("file"),
+            &Store::new(store_dir.path()),
+            CompressionKind::None,
+        )
+        .await?;
+        let (half, len) = (data.len() / 2, data.len());
+
+        // The connection drops halfway, and the rest is requested as long as it's unchanged
+        let (url, server) = serve(vec![
+            response(
+                &format!("HTTP/1.1 200 OK\r\ncontent-length: {len}\r\netag: \"v1\""),
+                &data[..half],
+            ),
+            response(
+                &format!(
+                    "HTTP/1.1 206 Partial Content\r\ncontent-length: {}\r\ncontent-range: bytes {half}-{}/{len}",
+                    len - half,
+                    len - 1
+                ),
+                &data[half..],
+            ),
+        ])?;
+        let local_dir = TempDir::new()?;
+        let path = stream
+            .download(&url, &Store::new(local_dir.path()), CompressionKind::None)
+            .await?;
+        assert_eq!(std::fs::read(path)?, data);
+        let requests = server.join().expect("the server thread");
+        assert!(requests[1].contains(&format!("range: bytes={half}-\r\n")));
+        assert!(requests[1].contains("if-range: \"v1\"\r\n"));
+
+        // An object that changed comes back whole, which can't be appended
+        let (url, server) = serve(vec![
+            response(
+                &format!("HTTP/1.1 200 OK\r\ncontent-length: {len}\r\netag: \"v1\""),
+                &data[..half],
+            ),
+            response(
+                &format!("HTTP/1.1 200 OK\r\ncontent-length: {len}\r\netag: \"v2\""),
+                &data,
+            ),
+        ])?;
+        let local_dir = TempDir::new()?;
+        let mirrors = Mirrors::new([url]).max_failures(1);
+        assert!(
+            stream
+                .download_mirrored(
+                    &mirrors,
+                    &Store::new(local_dir.path()),
+                    CompressionKind::None
+                )
+                .await
+                .is_err()
+        );
+        server.join().expect("the server thread");
+        assert!(!local_dir.path().join(&stream.hash).exists());
+
+        Ok(())
+    }
+}
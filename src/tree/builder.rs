@@ -0,0 +1,247 @@
+//! Assembling trees in memory.
+//!
+//! A [`TreeBuilder`] makes a [`Tree`] out of paths and streams that were never laid out in a real
+//! directory, like the outputs of a build. The streams have to be in the store already, or be
+//! fetched before the tree is deployed.
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use crate::stream::Stream;
+use crate::tree::{Symlink, Tree, TreePath};
+
+/// The mode of directories that weren't [given one](TreeBuilder::add_dir_with_mode), as
+/// [`Tree::create`] records a typical directory: `drwxr-xr-x`.
+const DEFAULT_DIR_MODE: u32 = 0o40_755;
+
+/// Builds a tree from individual entries. Missing parent directories are added along the way,
+/// and an entry replaces anything that was already at its path, except that adding a directory
+/// again only changes its mode.
+///
+/// Entries are sorted when the tree is built, so the order they're added in doesn't matter.
+#[derive(Clone, Debug)]
+pub struct TreeBuilder {
+    root: Tree,
+}
+
+impl Default for TreeBuilder {
+    fn default() -> Self {
+        Self {
+            root: empty_dir(DEFAULT_DIR_MODE),
+        }
+    }
+}
+
+impl TreeBuilder {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file with the stream's contents and metadata, named after the last component of
+    /// `path` rather than the stream's own name.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidPath`](crate::Error::InvalidPath) for paths that escape the tree, or
+    ///   the root
+    pub fn add_file<P: AsRef<Path>>(mut self, path: P, mut stream: Stream) -> crate::Result<Self> {
+        let (parent, name) = split(path.as_ref())?;
+        let dir = self.dir_mut(&parent);
+        remove_name(dir, &name);
+
+        stream.file_name = name.as_os_str().to_os_string();
+        dir.streams.push(stream);
+        Ok(self)
+    }
+
+    /// Adds a symlink to `target`, which is kept as is.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidPath`](crate::Error::InvalidPath) for paths that escape the tree, or
+    ///   the root
+    pub fn add_symlink<P: AsRef<Path>, T: Into<PathBuf>>(
+        mut self,
+        path: P,
+        target: T,
+    ) -> crate::Result<Self> {
+        let (parent, name) = split(path.as_ref())?;
+        let dir = self.dir_mut(&parent);
+        remove_name(dir, &name);
+
+        dir.symlinks.push(Symlink {
+            file_name: name.as_os_str().to_os_string(),
+            target: target.into(),
+        });
+        Ok(self)
+    }
+
+    /// Adds a directory with the default mode, `0o755`.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidPath`](crate::Error::InvalidPath) for paths that escape the tree
+    pub fn add_dir<P: AsRef<Path>>(self, path: P) -> crate::Result<Self> {
+        self.add_dir_with_mode(path, DEFAULT_DIR_MODE)
+    }
+
+    /// Adds a directory, or changes the mode of one that was already added. The empty path is
+    /// the root. `mode` is stored like [`Tree::permissions`], where the file type bits are
+    /// optional.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidPath`](crate::Error::InvalidPath) for paths that escape the tree
+    pub fn add_dir_with_mode<P: AsRef<Path>>(mut self, path: P, mode: u32) -> crate::Result<Self> {
+        let path = TreePath::new(path)?;
+        self.dir_mut(path.as_path()).permissions = mode;
+        Ok(self)
+    }
+
+    /// The tree, with every directory's entries sorted by name.
+    #[must_use]
+    pub fn build(mut self) -> Tree {
+        sort(&mut self.root);
+        self.root
+    }
+
+    /// The directory at `path`, replacing anything else in the way.
+    fn dir_mut(&mut self, path: &Path) -> &mut Tree {
+        let mut dir = &mut self.root;
+
+        for name in path {
+            let name = TreePath::new_unchecked(name);
+            let existing = dir
+                .subtrees
+                .iter()
+                .position(|(existing, _)| *existing == name);
+            let index = if let Some(index) = existing {
+                index
+            } else {
+                remove_name(dir, &name);
+                dir.subtrees.push((name, empty_dir(DEFAULT_DIR_MODE)));
+                dir.subtrees.len() - 1
+            };
+            dir = &mut dir.subtrees[index].1;
+        }
+
+        dir
+    }
+}
+
+fn empty_dir(permissions: u32) -> Tree {
+    Tree {
+        permissions,
+        streams: Vec::new(),
+        subtrees: Vec::new(),
+        symlinks: Vec::new(),
+        specials: Vec::new(),
+        meta: None,
+    }
+}
+
+/// Splits a path into its parent directory and name, refusing the root.
+fn split(path: &Path) -> crate::Result<(PathBuf, TreePath)> {
+    let path = TreePath::new(path)?;
+    if path.is_root() {
+        return Err(crate::Error::InvalidPath(PathBuf::new()));
+    }
+
+    let parent = path
+        .as_path()
+        .parent()
+        .unwrap_or(Path::new(""))
+        .to_path_buf();
+    Ok((parent, path.name()))
+}
+
+/// Removes every entry called `name` from `dir`.
+pub(crate) fn remove_name(dir: &mut Tree, name: &TreePath) {
+    let name: &OsStr = name.as_os_str();
+    dir.streams.retain(|stream| stream.file_name != name);
+    dir.symlinks.retain(|symlink| symlink.file_name != name);
+    dir.specials.retain(|special| special.file_name != name);
+    dir.subtrees
+        .retain(|(existing, _)| existing.as_os_str() != name);
+}
+
+pub(crate) fn sort(tree: &mut Tree) {
+    tree.streams.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+    tree.symlinks.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+    tree.specials.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+    tree.subtrees.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    for (_, subtree) in &mut tree.subtrees {
+        sort(subtree);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{PermissionsExt, symlink};
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::CompressionKind;
+    use crate::fs;
+    use crate::store::Store;
+    use crate::tree::all_streams;
+
+    #[tokio::test]
+    async fn test_tree_builder() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let original_dir = TempDir::new()?;
+        let original = |path: &str| original_dir.path().join(path);
+
+        std::fs::create_dir_all(original("bin"))?;
+        std::fs::create_dir_all(original("share/doc"))?;
+        for (dir, mode) in [
+            ("", 0o755),
+            ("bin", 0o700),
+            ("share", 0o755),
+            ("share/doc", 0o755),
+        ] {
+            std::fs::set_permissions(original(dir), std::fs::Permissions::from_mode(mode))?;
+        }
+        fs::write(original("bin/app"), b"app").await?;
+        fs::write(original("share/doc/README"), b"readme").await?;
+        symlink("bin/app", original("app"))?;
+        let created = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+
+        let stream = |name: &str| {
+            all_streams(&created)
+                .find(|stream| stream.file_name == name)
+                .cloned()
+                .expect("stream is in the tree")
+        };
+        let manifest = |tree: Tree| async move {
+            let mut manifest = Vec::new();
+            tree.write_manifest(&mut manifest).await.map(|()| manifest)
+        };
+
+        // The same layout from streams already in the store, added in another order
+        let built = TreeBuilder::new()
+            .add_file("share/doc/README", stream("README"))?
+            .add_symlink("app", "bin/app")?
+            // Replaced by a directory, and its name taken from the path
+            .add_file("bin", stream("README"))?
+            .add_file("./bin//app", stream("README"))?
+            .add_file("bin/app", stream("app"))?
+            .add_dir_with_mode("bin", 0o40_700)?
+            .build();
+        assert_eq!(built.hash(), created.hash());
+        assert_eq!(manifest(built).await?, manifest(created.clone()).await?);
+
+        assert!(matches!(
+            TreeBuilder::new().add_file("", stream("app")),
+            Err(crate::Error::InvalidPath(_))
+        ));
+        assert!(matches!(
+            TreeBuilder::new().add_symlink("../escape", "target"),
+            Err(crate::Error::InvalidPath(_))
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,583 @@
+//! A compact representation of a [`Tree`], for very large manifests.
+//!
+//! Rather than nesting `Tree`s and allocating every name and hash separately, a [`CompactTree`]
+//! keeps each kind of entry in a flat table that refers to its parent directory by index, with
+//! all names, symlink targets and hashes interned into one shared buffer.
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::async_types::AsyncBufRead;
+use crate::store::Store;
+use crate::stream::{ObjectHash, Owner, Stream};
+use crate::tree::deploy::{Dir, FileMeta};
+use crate::tree::deployment::Deployment;
+use crate::tree::manifest::{Entry, ManifestReader, invalid};
+use crate::tree::meta::TreeMeta;
+use crate::tree::{SpecialFile, SpecialKind, Symlink, Tree, TreePath, check_deployable};
+use crate::{CompressionKind, Mirrors};
+
+/// A string in the shared buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Name {
+    offset: u32,
+    len: u32,
+}
+
+#[derive(Clone, Debug)]
+struct DirEntry {
+    /// The root directory is its own parent
+    parent: u32,
+    name: Name,
+    permissions: u32,
+}
+
+#[derive(Clone, Debug)]
+struct StreamEntry {
+    dir: u32,
+    name: Name,
+    hash: Name,
+    compression: Option<CompressionKind>,
+    dictionary: Option<Name>,
+    disk_size: Option<u64>,
+    network_size: Option<u64>,
+    #[cfg(unix)]
+    mode: Option<u32>,
+    #[cfg(unix)]
+    mtime: Option<i64>,
+    #[cfg(unix)]
+    owner: Option<Owner>,
+}
+
+#[derive(Clone, Debug)]
+struct SymlinkEntry {
+    dir: u32,
+    name: Name,
+    target: Name,
+}
+
+#[derive(Clone, Debug)]
+struct SpecialEntry {
+    dir: u32,
+    name: Name,
+    kind: SpecialKind,
+    mode: u32,
+}
+
+/// A flat, interned [`Tree`].
+///
+/// Directories always come after their parent, so the structure can be rebuilt in one pass.
+#[derive(Clone, Debug)]
+pub struct CompactTree {
+    strings: Vec<u8>,
+    dirs: Vec<DirEntry>,
+    streams: Vec<StreamEntry>,
+    symlinks: Vec<SymlinkEntry>,
+    specials: Vec<SpecialEntry>,
+    meta: Option<TreeMeta>,
+}
+
+fn index(len: usize) -> u32 {
+    u32::try_from(len).expect("compact trees are limited to u32::MAX entries")
+}
+
+/// Builds a `CompactTree`, deduplicating strings as it goes.
+#[derive(Default)]
+struct Builder {
+    strings: Vec<u8>,
+    interned: HashMap<Box<[u8]>, Name>,
+    dirs: Vec<DirEntry>,
+    streams: Vec<StreamEntry>,
+    symlinks: Vec<SymlinkEntry>,
+    specials: Vec<SpecialEntry>,
+    meta: Option<TreeMeta>,
+}
+
+impl Builder {
+    fn intern(&mut self, bytes: &[u8]) -> Name {
+        if let Some(name) = self.interned.get(bytes) {
+            return *name;
+        }
+
+        let name = Name {
+            offset: index(self.strings.len()),
+            len: index(bytes.len()),
+        };
+        self.strings.extend_from_slice(bytes);
+        self.interned.insert(bytes.into(), name);
+        name
+    }
+
+    fn push_dir(&mut self, parent: Option<u32>, name: &OsStr, permissions: u32) -> u32 {
+        let id = index(self.dirs.len());
+        let name = self.intern(name.as_bytes());
+        self.dirs.push(DirEntry {
+            parent: parent.unwrap_or(id),
+            name,
+            permissions,
+        });
+        id
+    }
+
+    fn push_stream(&mut self, dir: u32, stream: &Stream) {
+        let name = self.intern(stream.file_name.as_bytes());
+        let hash = self.intern(stream.hash.as_bytes());
+        let dictionary = stream
+            .dictionary
+            .as_ref()
+            .map(|dictionary| self.intern(dictionary.as_bytes()));
+        self.streams.push(StreamEntry {
+            dir,
+            name,
+            hash,
+            compression: stream.compression,
+            dictionary,
+            disk_size: stream.disk_size,
+            network_size: stream.network_size,
+            #[cfg(unix)]
+            mode: stream.mode,
+            #[cfg(unix)]
+            mtime: stream.mtime,
+            #[cfg(unix)]
+            owner: stream.owner,
+        });
+    }
+
+    fn push_symlink(&mut self, dir: u32, symlink: &Symlink) {
+        let name = self.intern(symlink.file_name.as_bytes());
+        let target = self.intern(symlink.target.as_os_str().as_bytes());
+        self.symlinks.push(SymlinkEntry { dir, name, target });
+    }
+
+    fn push_special(&mut self, dir: u32, special: &SpecialFile) {
+        let name = self.intern(special.file_name.as_bytes());
+        self.specials.push(SpecialEntry {
+            dir,
+            name,
+            kind: special.kind,
+            mode: special.mode,
+        });
+    }
+
+    fn finish(mut self) -> CompactTree {
+        self.strings.shrink_to_fit();
+        self.dirs.shrink_to_fit();
+        self.streams.shrink_to_fit();
+        self.symlinks.shrink_to_fit();
+        self.specials.shrink_to_fit();
+
+        CompactTree {
+            strings: self.strings,
+            dirs: self.dirs,
+            streams: self.streams,
+            symlinks: self.symlinks,
+            specials: self.specials,
+            meta: self.meta,
+        }
+    }
+}
+
+impl CompactTree {
+    fn str(&self, name: Name) -> &OsStr {
+        let start = name.offset as usize;
+        OsStr::from_bytes(&self.strings[start..start + name.len as usize])
+    }
+
+    fn stream(&self, entry: &StreamEntry) -> Stream {
+        Stream {
+            hash: ObjectHash::new_unchecked(self.str(entry.hash).to_string_lossy()),
+            file_name: self.str(entry.name).to_owned(),
+            compression: entry.compression,
+            dictionary: entry.dictionary.map(|dictionary| {
+                ObjectHash::new_unchecked(self.str(dictionary).to_string_lossy())
+            }),
+            disk_size: entry.disk_size,
+            network_size: entry.network_size,
+            #[cfg(unix)]
+            mode: entry.mode,
+            #[cfg(unix)]
+            mtime: entry.mtime,
+            #[cfg(unix)]
+            owner: entry.owner,
+        }
+    }
+
+    /// The path of every directory, relative to the root, by index.
+    fn dir_paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = Vec::with_capacity(self.dirs.len());
+        for (id, dir) in self.dirs.iter().enumerate() {
+            let path = if dir.parent as usize == id {
+                PathBuf::new()
+            } else {
+                paths[dir.parent as usize].join(self.str(dir.name))
+            };
+            paths.push(path);
+        }
+        paths
+    }
+
+    /// The number of streams in the whole tree.
+    #[must_use]
+    pub fn stream_count(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// Reads a streaming manifest directly into a `CompactTree`, without building a [`Tree`].
+    ///
+    /// # Errors
+    ///
+    /// - IO errors from the reader
+    /// - Malformed manifests
+    pub async fn read_manifest<R: AsyncBufRead + Unpin>(reader: R) -> crate::Result<Self> {
+        let mut reader = ManifestReader::new(reader);
+        let mut builder = Builder::default();
+        let mut dirs_by_path: HashMap<PathBuf, u32> = HashMap::new();
+
+        let lookup = |dirs_by_path: &HashMap<PathBuf, u32>, path: &Path| {
+            dirs_by_path.get(path).copied().ok_or_else(|| {
+                invalid(&format!(
+                    "entry refers to unknown directory {}",
+                    path.display()
+                ))
+            })
+        };
+
+        while let Some(entry) = reader.next_entry().await? {
+            match entry {
+                Entry::Tree {
+                    path,
+                    permissions,
+                    meta,
+                } => {
+                    let parent = match (path.parent(), builder.dirs.is_empty()) {
+                        (None, true) => None,
+                        (Some(parent), false) => Some(lookup(&dirs_by_path, parent)?),
+                        (None, false) => return Err(invalid("a second root tree was found")),
+                        (Some(_), true) => {
+                            return Err(invalid("the first entry must be the root tree"));
+                        }
+                    };
+
+                    let name = path.file_name().unwrap_or_default();
+                    let id = builder.push_dir(parent, name, permissions);
+                    dirs_by_path.insert(path.into(), id);
+                    if parent.is_none() {
+                        builder.meta = meta;
+                    }
+                }
+                Entry::Stream { parent, stream } => {
+                    let dir = lookup(&dirs_by_path, &parent)?;
+                    builder.push_stream(dir, &stream);
+                }
+                Entry::Symlink { parent, symlink } => {
+                    let dir = lookup(&dirs_by_path, &parent)?;
+                    builder.push_symlink(dir, &symlink);
+                }
+                Entry::Special { parent, special } => {
+                    let dir = lookup(&dirs_by_path, &parent)?;
+                    builder.push_special(dir, &special);
+                }
+            }
+        }
+
+        if builder.dirs.is_empty() {
+            return Err(invalid("the manifest is empty"));
+        }
+
+        Ok(builder.finish())
+    }
+
+    /// Rebuilds the nested [`Tree`].
+    #[must_use]
+    pub fn to_tree(&self) -> Tree {
+        let mut trees: Vec<Tree> = self
+            .dirs
+            .iter()
+            .map(|dir| Tree {
+                permissions: dir.permissions,
+                streams: Vec::new(),
+                subtrees: Vec::new(),
+                symlinks: Vec::new(),
+                specials: Vec::new(),
+                meta: None,
+            })
+            .collect();
+
+        for entry in &self.streams {
+            trees[entry.dir as usize].streams.push(self.stream(entry));
+        }
+        for entry in &self.symlinks {
+            trees[entry.dir as usize].symlinks.push(Symlink {
+                file_name: self.str(entry.name).to_owned(),
+                target: self.str(entry.target).into(),
+            });
+        }
+        for entry in &self.specials {
+            trees[entry.dir as usize].specials.push(SpecialFile {
+                file_name: self.str(entry.name).to_owned(),
+                kind: entry.kind,
+                mode: entry.mode,
+            });
+        }
+
+        // Children always come after their parent, so attach them from the back. This leaves
+        // every list of subtrees reversed, which is fixed up afterwards.
+        for id in (1..trees.len()).rev() {
+            let dir = &self.dirs[id];
+            let tree = std::mem::replace(
+                &mut trees[id],
+                Tree {
+                    permissions: 0,
+                    streams: Vec::new(),
+                    subtrees: Vec::new(),
+                    symlinks: Vec::new(),
+                    specials: Vec::new(),
+                    meta: None,
+                },
+            );
+            trees[dir.parent as usize]
+                .subtrees
+                .push((TreePath::new_unchecked(self.str(dir.name)), tree));
+        }
+
+        let mut root = trees.swap_remove(0);
+        root.meta.clone_from(&self.meta);
+        let mut pending = vec![&mut root];
+        while let Some(tree) = pending.pop() {
+            tree.subtrees.reverse();
+            pending.extend(tree.subtrees.iter_mut().map(|(_, subtree)| subtree));
+        }
+
+        root
+    }
+
+    /// Downloads all streams required to build the tree. Streams shared between several files
+    /// are only downloaded once.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically out of space)
+    /// - Network errors (Non-2xx codes, etc)
+    pub async fn download<C: Into<Option<CompressionKind>>>(
+        &self,
+        repo_url: &str,
+        store: &Store,
+        compression: C,
+    ) -> crate::Result<()> {
+        self.download_mirrored(&Mirrors::from(repo_url), store, compression)
+            .await
+    }
+
+    /// Downloads all streams required to build the tree, see [`Tree::download_mirrored`].
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically out of space)
+    /// - Network errors, if no mirror could serve a stream
+    pub async fn download_mirrored<C: Into<Option<CompressionKind>>>(
+        &self,
+        mirrors: &Mirrors,
+        store: &Store,
+        compression: C,
+    ) -> crate::Result<()> {
+        store.clean_before_download()?;
+        let compression = compression.into();
+        let mut downloaded = HashSet::new();
+
+        for entry in &self.streams {
+            if downloaded.insert(entry.hash) {
+                self.stream(entry)
+                    .download_mirrored(mirrors, store, compression)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deploys the tree, see [`Tree::deploy`].
+    ///
+    /// # Errors
+    ///
+    /// - Out of storage/Permissions Errors
+    /// - [`Error::TreeNotAllowed`](crate::Error::TreeNotAllowed) if the store's
+    ///   [allowlist](Store::with_allowed_trees) doesn't include the tree
+    /// - [`Error::UnsafeSymlink`](crate::Error::UnsafeSymlink) if the store's
+    ///   [`SymlinkPolicy`](crate::store::SymlinkPolicy) refuses a symlink
+    /// - [`Error::InvalidPath`](crate::Error::InvalidPath) for names that could escape their
+    ///   directory, like `../a`, before anything is deployed
+    /// - [`Error::NonPortableName`](crate::Error::NonPortableName) if the store's
+    ///   [`NamePolicy`](crate::store::NamePolicy) refuses a name
+    pub fn deploy(&self, store: &Store, deploy_path: &Path) -> crate::Result<()> {
+        let tree = self.to_tree();
+        if let Cow::Owned(sanitized) = check_deployable(&tree, store)? {
+            return sanitized.deploy_as(tree.hash(), store, deploy_path);
+        }
+        let store_dir = Dir::open(store.root())?;
+        let root = Dir::open(deploy_path)?;
+        let paths = self.dir_paths();
+
+        for path in &paths[1..] {
+            root.create_path(path)?;
+        }
+
+        let mut dirs = OpenDir {
+            root: &root,
+            paths: &paths,
+            open: None,
+        };
+
+        for entry in &self.streams {
+            dirs.get(entry.dir)?.deploy_stream(
+                store,
+                &store_dir,
+                &self.str(entry.hash).to_string_lossy(),
+                self.str(entry.name),
+                FileMeta {
+                    mode: entry.mode,
+                    mtime: entry.mtime,
+                    owner: entry.owner,
+                },
+            )?;
+        }
+
+        for entry in &self.symlinks {
+            dirs.get(entry.dir)?
+                .symlink(Path::new(self.str(entry.target)), self.str(entry.name))?;
+        }
+
+        for entry in &self.specials {
+            dirs.get(entry.dir)?
+                .create_special(entry.kind, self.str(entry.name), entry.mode)?;
+        }
+
+        // Children before their parents, in case a parent is read-only
+        for (path, entry) in paths.iter().zip(&self.dirs).skip(1).rev() {
+            root.open_path(path)?.set_permissions(entry.permissions)?;
+        }
+
+        Deployment::new(tree.hash(), store).write(&root)?;
+
+        let hashes: Vec<_> = self
+            .streams
+            .iter()
+            .map(|entry| self.str(entry.hash).to_string_lossy())
+            .collect();
+        store.record_access(hashes.iter().map(AsRef::as_ref))?;
+
+        Ok(())
+    }
+}
+
+/// Keeps the most recently used directory open while deploying, as entries are usually grouped
+/// by directory.
+struct OpenDir<'a> {
+    root: &'a Dir,
+    paths: &'a [PathBuf],
+    open: Option<(u32, Dir)>,
+}
+
+impl OpenDir<'_> {
+    fn get(&mut self, id: u32) -> io::Result<&Dir> {
+        if self.open.as_ref().is_none_or(|(open, _)| *open != id) {
+            self.open = Some((id, self.root.open_path(&self.paths[id as usize])?));
+        }
+        Ok(&self.open.as_ref().expect("opened above").1)
+    }
+}
+
+impl From<&Tree> for CompactTree {
+    fn from(tree: &Tree) -> Self {
+        let mut builder = Builder {
+            meta: tree.meta.clone(),
+            ..Builder::default()
+        };
+        let mut pending = vec![(None, OsString::new(), tree)];
+
+        while let Some((parent, name, tree)) = pending.pop() {
+            let id = builder.push_dir(parent, &name, tree.permissions);
+
+            for stream in &tree.streams {
+                builder.push_stream(id, stream);
+            }
+            for symlink in &tree.symlinks {
+                builder.push_symlink(id, symlink);
+            }
+            for special in &tree.specials {
+                builder.push_special(id, special);
+            }
+
+            // Reversed, so that subtrees keep their original order
+            for (name, subtree) in tree.subtrees.iter().rev() {
+                pending.push((Some(id), name.as_os_str().to_owned(), subtree));
+            }
+        }
+
+        builder.finish()
+    }
+}
+
+impl From<&CompactTree> for Tree {
+    fn from(tree: &CompactTree) -> Self {
+        tree.to_tree()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::async_types::BufReader;
+    use crate::fs;
+
+    async fn manifest(tree: &Tree) -> crate::Result<Vec<u8>> {
+        let mut manifest = Vec::new();
+        tree.write_manifest(&mut manifest).await?;
+        Ok(manifest)
+    }
+
+    #[tokio::test]
+    async fn test_compact_roundtrip() -> crate::Result<()> {
+        let remote_stream_dir = TempDir::new()?;
+        let remote_store = Store::new(remote_stream_dir.path());
+        let original_dir = TempDir::new()?;
+        let original_path = original_dir.path();
+        let deploy_dir = TempDir::new()?;
+
+        std::fs::create_dir_all(original_path.join("a/b"))?;
+        std::fs::create_dir_all(original_path.join("c"))?;
+        fs::write(original_path.join("file"), b"same").await?;
+        fs::write(original_path.join("a/file"), b"same").await?;
+        fs::write(original_path.join("a/b/other"), b"other").await?;
+        fs::write(original_path.join("c/file"), b"third").await?;
+
+        let tree = Tree::create(&remote_store, original_path, CompressionKind::None).await?;
+        let expected = manifest(&tree).await?;
+
+        let compact = CompactTree::from(&tree);
+        assert_eq!(compact.stream_count(), 4);
+        assert_eq!(manifest(&compact.to_tree()).await?, expected);
+
+        let from_manifest = CompactTree::read_manifest(BufReader::new(&expected[..])).await?;
+        assert_eq!(manifest(&from_manifest.to_tree()).await?, expected);
+
+        // Identical names and hashes are only stored once
+        assert_eq!(from_manifest.strings.len(), 3 * 64 + "fileabotherc".len());
+
+        compact.deploy(&remote_store, deploy_dir.path())?;
+        assert_eq!(
+            fs::read_to_end(deploy_dir.path().join("a/b/other")).await?,
+            b"other"
+        );
+        assert_eq!(
+            fs::read_to_end(deploy_dir.path().join("c/file")).await?,
+            b"third"
+        );
+
+        Ok(())
+    }
+}
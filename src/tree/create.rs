@@ -0,0 +1,81 @@
+//! Options for [`Tree::create_with`](crate::tree::Tree::create_with), which only matter while
+//! a tree is being created rather than to the store it's created in.
+use crate::tree::filter::Filter;
+
+/// How [`Tree::create_with`](crate::tree::Tree::create_with) walks the directory it creates a
+/// tree from. The defaults are what [`Tree::create`](crate::tree::Tree::create) uses.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CreateOptions {
+    filter: Filter,
+    follow_symlinks: FollowSymlinks,
+    concurrency: usize,
+}
+
+impl Default for CreateOptions {
+    fn default() -> Self {
+        Self {
+            filter: Filter::default(),
+            follow_symlinks: FollowSymlinks::default(),
+            concurrency: 1,
+        }
+    }
+}
+
+/// Which symlinks [`Tree::create_with`](crate::tree::Tree::create_with) replaces with what they
+/// point to, see [`CreateOptions::with_follow_symlinks`]. Symlinks that dangle, point at special
+/// files or lead back into a directory being walked are always recorded as symlinks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FollowSymlinks {
+    /// Records every symlink as a symlink
+    #[default]
+    Never,
+    /// Stores the contents of symlinked directories, and records other symlinks
+    Directories,
+    /// Stores the contents of symlinked directories and files
+    Always,
+}
+
+impl CreateOptions {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// What's left out of the tree. Keeps everything by default.
+    #[must_use]
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    #[must_use]
+    pub fn filter(&self) -> &Filter {
+        &self.filter
+    }
+
+    /// Which symlinks are followed. Defaults to [none](FollowSymlinks::Never).
+    #[must_use]
+    pub fn with_follow_symlinks(mut self, follow: FollowSymlinks) -> Self {
+        self.follow_symlinks = follow;
+        self
+    }
+
+    #[must_use]
+    pub fn follow_symlinks(&self) -> FollowSymlinks {
+        self.follow_symlinks
+    }
+
+    /// How many files are hashed and compressed at once, at least one. Defaults to one at a
+    /// time. The work itself runs on the store's [CPU pool](crate::store::Store::with_cpu_pool),
+    /// which should have at least as many threads.
+    #[must_use]
+    pub fn with_concurrency(mut self, files: usize) -> Self {
+        self.concurrency = files.max(1);
+        self
+    }
+
+    #[must_use]
+    pub fn concurrency(&self) -> usize {
+        self.concurrency
+    }
+}
@@ -0,0 +1,206 @@
+//! Deltas between trees, for channels that are updated often.
+//!
+//! A [`TreeDelta`] only holds the manifest entries that changed since a base tree, so clients that
+//! already have the base can rebuild the new tree without fetching its whole manifest. Both tree
+//! hashes are checked when applying it, so a delta against the wrong base, or a tampered one,
+//! can't silently produce some other tree.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::tree::manifest::{Entry, TreeAssembler};
+use crate::tree::{Tree, TreePath};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TreeDelta {
+    /// The [hash](Tree::hash) of the tree the delta applies to
+    pub base: String,
+    /// The hash of the tree it produces
+    pub target: String,
+    /// Directories, files, symlinks and special files only in the base tree. Removing a directory
+    /// removes everything inside it, which isn't listed separately.
+    pub removed: Vec<TreePath>,
+    /// New and changed entries, as they appear in a manifest
+    pub changed: Vec<Entry>,
+}
+
+impl TreeDelta {
+    /// Whether the delta changes nothing.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// The path an entry describes.
+fn entry_path(entry: &Entry) -> TreePath {
+    match entry {
+        Entry::Tree { path, .. } => path.clone(),
+        Entry::Stream { parent, stream } => {
+            parent.join(&TreePath::new_unchecked(&stream.file_name))
+        }
+        Entry::Symlink { parent, symlink } => {
+            parent.join(&TreePath::new_unchecked(&symlink.file_name))
+        }
+        Entry::Special { parent, special } => {
+            parent.join(&TreePath::new_unchecked(&special.file_name))
+        }
+    }
+}
+
+/// Every entry in a tree by path. Paths sort depth-first, with every directory right before its
+/// contents, which is the order manifests are assembled in.
+fn entries(tree: &Tree) -> BTreeMap<TreePath, Entry> {
+    let mut entries = BTreeMap::new();
+    let mut pending = vec![(TreePath::root(), tree)];
+
+    while let Some((path, tree)) = pending.pop() {
+        for stream in &tree.streams {
+            let entry = Entry::Stream {
+                parent: path.clone(),
+                stream: stream.clone(),
+            };
+            entries.insert(entry_path(&entry), entry);
+        }
+        for symlink in &tree.symlinks {
+            let entry = Entry::Symlink {
+                parent: path.clone(),
+                symlink: symlink.clone(),
+            };
+            entries.insert(entry_path(&entry), entry);
+        }
+        for special in &tree.specials {
+            let entry = Entry::Special {
+                parent: path.clone(),
+                special: special.clone(),
+            };
+            entries.insert(entry_path(&entry), entry);
+        }
+        for (name, subtree) in &tree.subtrees {
+            pending.push((path.join(name), subtree));
+        }
+        entries.insert(
+            path.clone(),
+            Entry::Tree {
+                meta: tree.meta.clone().filter(|_| path.is_root()),
+                path,
+                permissions: tree.permissions,
+            },
+        );
+    }
+
+    entries
+}
+
+impl Tree {
+    /// The delta that turns `base` into this tree.
+    #[must_use]
+    pub fn delta_from(&self, base: &Tree) -> TreeDelta {
+        let old = entries(base);
+        let new = entries(self);
+
+        let mut removed: Vec<TreePath> = Vec::new();
+        for path in old.keys().filter(|path| !new.contains_key(*path)) {
+            // Already covered by a removed directory, which sorts right before its contents
+            if removed.last().is_none_or(|dir| !path.starts_with(dir)) {
+                removed.push(path.clone());
+            }
+        }
+
+        let changed = new
+            .into_iter()
+            .filter(|(path, entry)| old.get(path) != Some(entry))
+            .map(|(_, entry)| entry)
+            .collect();
+
+        TreeDelta {
+            base: base.hash(),
+            target: self.hash(),
+            removed,
+            changed,
+        }
+    }
+
+    /// Applies a delta to this tree, which must be its base.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::HashError`](crate::Error::HashError) if this tree isn't the delta's base, or
+    ///   the result isn't its target
+    /// - Malformed entries in the delta
+    pub fn apply_delta(&self, delta: &TreeDelta) -> crate::Result<Tree> {
+        let base = self.hash();
+        if base != delta.base {
+            return Err(crate::Error::HashError(delta.base.clone(), base));
+        }
+
+        let mut entries = entries(self);
+        for path in &delta.removed {
+            entries.retain(|entry, _| !entry.starts_with(path));
+        }
+        for entry in &delta.changed {
+            entry.validate()?;
+            entries.insert(entry_path(entry), entry.clone());
+        }
+
+        let mut assembler = TreeAssembler::default();
+        for entry in entries.into_values() {
+            assembler.push(entry)?;
+        }
+        let tree = assembler.finish()?;
+
+        let target = tree.hash();
+        if target != delta.target {
+            return Err(crate::Error::HashError(delta.target.clone(), target));
+        }
+        Ok(tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use crate::CompressionKind;
+    use crate::fs;
+    use crate::store::Store;
+    use crate::tree::Tree;
+
+    #[tokio::test]
+    async fn test_tree_delta() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let original_dir = TempDir::new()?;
+        let original = |path: &str| original_dir.path().join(path);
+
+        std::fs::create_dir_all(original("old/nested"))?;
+        std::fs::create_dir(original("kept"))?;
+        fs::write(original("old/nested/file"), b"old").await?;
+        fs::write(original("kept/same"), b"same").await?;
+        fs::write(original("kept/changed"), b"before").await?;
+        let base = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+
+        std::fs::remove_dir_all(original("old"))?;
+        std::fs::remove_file(original("kept/changed"))?;
+        fs::write(original("kept/changed"), b"after").await?;
+        fs::write(original("added"), b"added").await?;
+        let target = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+
+        let delta = target.delta_from(&base);
+        // Only the removed directory itself, and the new and changed files
+        assert_eq!(delta.removed, ["old"].map(|path| path.try_into().unwrap()));
+        assert_eq!(delta.changed.len(), 2);
+        assert!(base.delta_from(&base).is_empty());
+
+        // Round trips through JSON, as it would be published
+        let delta = serde_json::from_slice(&serde_json::to_vec(&delta)?)?;
+        assert_eq!(base.apply_delta(&delta)?.hash(), target.hash());
+
+        // Only applies to its base
+        assert!(matches!(
+            target.apply_delta(&delta),
+            Err(crate::Error::HashError(..))
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,862 @@
+//! Deploying through directory file descriptors.
+//!
+//! Every operation is relative to an already open directory and never follows symlinks, so
+//! another process can't redirect a deployment elsewhere by swapping a directory for a symlink
+//! while it is running.
+use nix::dir::Type;
+use nix::errno::Errno;
+use nix::fcntl::{AT_FDCWD, AtFlags, OFlag, copy_file_range, openat, readlinkat, renameat};
+use nix::sys::stat::{
+    FchmodatFlags, FileStat, Mode, SFlag, UtimensatFlags, fchmod, fchmodat, fstat, fstatat,
+    mkdirat, utimensat,
+};
+use nix::sys::time::TimeSpec;
+use nix::unistd::{Gid, Uid, UnlinkatFlags, fchown, linkat, mkfifoat, symlinkat, unlinkat};
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::os::fd::OwnedFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::Instant;
+
+use crate::event::Event;
+use crate::store::{DeployMode, Store};
+use crate::stream::{Owner, Stream};
+use crate::tree::{SpecialKind, TreePath};
+
+fn dir_flags() -> OFlag {
+    OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC
+}
+
+/// What a deployed file gets besides its contents.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct FileMeta {
+    pub(crate) mode: Option<u32>,
+    pub(crate) mtime: Option<i64>,
+    pub(crate) owner: Option<Owner>,
+}
+
+impl From<&Stream> for FileMeta {
+    fn from(stream: &Stream) -> Self {
+        Self {
+            mode: stream.mode,
+            mtime: stream.mtime,
+            owner: stream.owner,
+        }
+    }
+}
+
+/// An open directory.
+pub(crate) struct Dir {
+    fd: OwnedFd,
+}
+
+impl Dir {
+    /// Opens an existing directory by path. This is the only lookup that may follow symlinks.
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC;
+        let fd = openat(AT_FDCWD, path, flags, Mode::empty())?;
+        Ok(Self { fd })
+    }
+
+    /// Opens a directory below this one, one component at a time.
+    pub(crate) fn open_path(&self, path: &Path) -> io::Result<Self> {
+        let mut dir = Self {
+            fd: self.fd.try_clone()?,
+        };
+        for name in normal_components(path) {
+            dir = Self {
+                fd: openat(&dir.fd, name, dir_flags(), Mode::empty())?,
+            };
+        }
+        Ok(dir)
+    }
+
+    /// Creates any missing directories along `path` below this one, and opens the last.
+    pub(crate) fn create_path(&self, path: &Path) -> io::Result<Self> {
+        let mut dir = Self {
+            fd: self.fd.try_clone()?,
+        };
+        for name in normal_components(path) {
+            match mkdirat(&dir.fd, name, Mode::from_bits_truncate(0o755)) {
+                Ok(()) | Err(Errno::EEXIST) => {}
+                Err(e) => return Err(e.into()),
+            }
+            dir = Self {
+                fd: openat(&dir.fd, name, dir_flags(), Mode::empty())?,
+            };
+        }
+        Ok(dir)
+    }
+
+    /// Puts a stream from the store at `name` the way the store's [`DeployMode`] says, replacing
+    /// any file already there. Hardlinks fall back onto copying where [the store
+    /// allows](Store::link_fallback).
+    ///
+    /// Files get the mode in `meta`, limited by the store's [mode mask](Store::with_mode_mask),
+    /// its mtime, and its owner if the store [restores owners](Store::with_owners). Hardlinks
+    /// share all of them with the store's copy, and so with every other link to it, so files are
+    /// only linked when the store's copy already has what was asked for, and copied otherwise.
+    pub(crate) fn deploy_stream(
+        &self,
+        store: &Store,
+        store_dir: &Dir,
+        hash: &str,
+        name: &OsStr,
+        meta: FileMeta,
+    ) -> io::Result<()> {
+        let mode = meta.mode.map(|mode| mode & store.mode_mask());
+        let owner = meta
+            .owner
+            .filter(|_| store.keeps_owners())
+            .map(|owner| store.owner_map().map(owner));
+
+        let started = Instant::now();
+        match store.deploy_mode() {
+            DeployMode::Symlink => {
+                return self.symlink(&std::path::absolute(store.path_of(hash))?, name);
+            }
+            DeployMode::Hardlink
+                if store_dir.has_meta(OsStr::new(hash), mode, meta.mtime, owner)?
+                    && self.link_stream(store, store_dir, hash, name)? => {}
+            _ => {
+                self.copy_stream(store, store_dir, hash, name, mode, owner)?;
+                if let Some(mtime) = meta.mtime {
+                    utimensat(
+                        &self.fd,
+                        name,
+                        &TimeSpec::UTIME_OMIT,
+                        &TimeSpec::new(mtime, 0),
+                        UtimensatFlags::NoFollowSymlink,
+                    )?;
+                }
+            }
+        }
+
+        let elapsed = started.elapsed();
+        if store.slow_thresholds().is_slow_deploy(elapsed) {
+            store.emit(&Event::SlowDeploy {
+                hash: hash.to_string(),
+                elapsed,
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether the file at `name` already has `mode`, `mtime` and `owner`, where there are any.
+    fn has_meta(
+        &self,
+        name: &OsStr,
+        mode: Option<u32>,
+        mtime: Option<i64>,
+        owner: Option<Owner>,
+    ) -> io::Result<bool> {
+        let current = fstatat(&self.fd, name, AtFlags::AT_SYMLINK_NOFOLLOW)?;
+        Ok(
+            mode.is_none_or(|mode| current.st_mode & 0o7777 == mode & 0o7777)
+                && mtime.is_none_or(|mtime| current.st_mtime == mtime)
+                && owner
+                    .is_none_or(|owner| (current.st_uid, current.st_gid) == (owner.uid, owner.gid)),
+        )
+    }
+
+    /// Hardlinks a stream out of the store, returning `false` if it should be copied instead.
+    fn link_stream(
+        &self,
+        store: &Store,
+        store_dir: &Dir,
+        hash: &str,
+        name: &OsStr,
+    ) -> io::Result<bool> {
+        let link = || linkat(&store_dir.fd, hash, &self.fd, name, AtFlags::empty());
+        let res = match link() {
+            Err(Errno::EEXIST) => {
+                unlinkat(&self.fd, name, UnlinkatFlags::NoRemoveDir)?;
+                link()
+            }
+            res => res,
+        };
+
+        match res {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                let e = io::Error::from(e);
+                if store.link_fallback(hash, &e) {
+                    Ok(false)
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    fn copy_stream(
+        &self,
+        store: &Store,
+        store_dir: &Dir,
+        hash: &str,
+        name: &OsStr,
+        mode: Option<u32>,
+        owner: Option<Owner>,
+    ) -> io::Result<()> {
+        let source = openat(
+            &store_dir.fd,
+            hash,
+            OFlag::O_RDONLY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?;
+        // Never truncate what is there, as it may be hardlinked to the store
+        self.remove_file(name)?;
+        let mut target = std::fs::File::from(openat(
+            &self.fd,
+            name,
+            OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_CLOEXEC,
+            Mode::from_bits_truncate(0o644),
+        )?);
+
+        let res = write_copy(store, source, &mut target, mode, owner);
+        if res.is_err() {
+            // Don't leave a partial copy behind for the next deploy to trust
+            let _ = unlinkat(&self.fd, name, UnlinkatFlags::NoRemoveDir);
+        }
+        res
+    }
+
+    /// Creates a symlink in this directory, replacing any file already at `name`.
+    pub(crate) fn symlink(&self, target: &Path, name: &OsStr) -> io::Result<()> {
+        match symlinkat(target, &self.fd, name) {
+            Err(Errno::EEXIST) => {
+                unlinkat(&self.fd, name, UnlinkatFlags::NoRemoveDir)?;
+                symlinkat(target, &self.fd, name)?;
+            }
+            res => res?,
+        }
+        Ok(())
+    }
+
+    /// Creates a special file in this directory, replacing any file already at `name`.
+    pub(crate) fn create_special(
+        &self,
+        kind: SpecialKind,
+        name: &OsStr,
+        mode: u32,
+    ) -> io::Result<()> {
+        self.remove_file(name)?;
+        match kind {
+            SpecialKind::Fifo => mkfifoat(&self.fd, name, Mode::from_bits_truncate(mode))?,
+        }
+        // Not limited by the umask
+        self.set_file_mode(name, mode)
+    }
+
+    /// Writes a file in this directory, only replacing any file already at `name` once it has
+    /// been fully written.
+    pub(crate) fn write_atomic(&self, name: &OsStr, contents: &[u8]) -> io::Result<()> {
+        let mut tmp_name = name.to_os_string();
+        tmp_name.push(format!(".{}.tmp", std::process::id()));
+
+        let file = openat(
+            &self.fd,
+            tmp_name.as_os_str(),
+            OFlag::O_WRONLY
+                | OFlag::O_CREAT
+                | OFlag::O_TRUNC
+                | OFlag::O_NOFOLLOW
+                | OFlag::O_CLOEXEC,
+            Mode::from_bits_truncate(0o644),
+        )?;
+        io::Write::write_all(&mut std::fs::File::from(file), contents)?;
+        self.rename(&tmp_name, self, name)
+    }
+
+    /// Removes a file or symlink, if it is still there.
+    pub(crate) fn remove_file(&self, name: &OsStr) -> io::Result<()> {
+        match unlinkat(&self.fd, name, UnlinkatFlags::NoRemoveDir) {
+            Ok(()) | Err(Errno::ENOENT) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Removes a directory if it is empty, returning whether it was.
+    pub(crate) fn remove_empty_dir(&self, name: &OsStr) -> io::Result<bool> {
+        match unlinkat(&self.fd, name, UnlinkatFlags::RemoveDir) {
+            Ok(()) | Err(Errno::ENOENT) => Ok(true),
+            Err(Errno::ENOTEMPTY | Errno::EEXIST) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Moves `name` to `new_name` in `new_dir`, replacing any file already there.
+    pub(crate) fn rename(&self, name: &OsStr, new_dir: &Dir, new_name: &OsStr) -> io::Result<()> {
+        renameat(&self.fd, name, &new_dir.fd, new_name)?;
+        Ok(())
+    }
+
+    /// Every entry in this directory, and whether it is a directory itself. Symlinks to
+    /// directories aren't.
+    pub(crate) fn entries(&self) -> io::Result<Vec<(OsString, bool)>> {
+        // A fresh open, as reading entries moves the offset shared with duplicates
+        let fd = openat(&self.fd, ".", dir_flags(), Mode::empty())?;
+        let mut dir = nix::dir::Dir::from_fd(fd)?;
+
+        let mut entries = Vec::new();
+        for entry in dir.iter() {
+            let entry = entry?;
+            let name = OsStr::from_bytes(entry.file_name().to_bytes());
+            if name == "." || name == ".." {
+                continue;
+            }
+
+            let is_dir = if let Some(file_type) = entry.file_type() {
+                file_type == Type::Directory
+            } else {
+                // Not every filesystem reports types while listing
+                let stat = fstatat(&self.fd, name, AtFlags::AT_SYMLINK_NOFOLLOW)?;
+                SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT == SFlag::S_IFDIR
+            };
+            entries.push((name.to_os_string(), is_dir));
+        }
+
+        Ok(entries)
+    }
+
+    /// Removes an entry, along with everything below it if it is a directory.
+    pub(crate) fn remove_all(&self, name: &OsStr, is_dir: bool) -> io::Result<()> {
+        if !is_dir {
+            return self.remove_file(name);
+        }
+
+        let dir = self.open_path(Path::new(name))?;
+        for (child, is_dir) in dir.entries()? {
+            dir.remove_all(&child, is_dir)?;
+        }
+        match unlinkat(&self.fd, name, UnlinkatFlags::RemoveDir) {
+            Ok(()) | Err(Errno::ENOENT) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// What is at `name`, without following symlinks, or `None` if nothing is.
+    pub(crate) fn stat(&self, name: &OsStr) -> io::Result<Option<FileStat>> {
+        match fstatat(&self.fd, name, AtFlags::AT_SYMLINK_NOFOLLOW) {
+            Ok(stat) => Ok(Some(stat)),
+            Err(Errno::ENOENT) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Opens a file in this directory for reading, without following symlinks.
+    pub(crate) fn open_file(&self, name: &OsStr) -> io::Result<std::fs::File> {
+        let fd = openat(
+            &self.fd,
+            name,
+            OFlag::O_RDONLY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?;
+        Ok(fd.into())
+    }
+
+    /// Opens a file in this directory for appending, creating it if needed, without following
+    /// symlinks.
+    pub(crate) fn append_file(&self, name: &OsStr) -> io::Result<std::fs::File> {
+        let fd = openat(
+            &self.fd,
+            name,
+            OFlag::O_WRONLY
+                | OFlag::O_APPEND
+                | OFlag::O_CREAT
+                | OFlag::O_NOFOLLOW
+                | OFlag::O_CLOEXEC,
+            Mode::from_bits_truncate(0o644),
+        )?;
+        Ok(fd.into())
+    }
+
+    pub(crate) fn read_link(&self, name: &OsStr) -> io::Result<PathBuf> {
+        Ok(readlinkat(&self.fd, name)?.into())
+    }
+
+    pub(crate) fn metadata(&self) -> io::Result<FileStat> {
+        Ok(fstat(&self.fd)?)
+    }
+
+    /// Changes the mode of a file in this directory, without following symlinks.
+    pub(crate) fn set_file_mode(&self, name: &OsStr, mode: u32) -> io::Result<()> {
+        fchmodat(
+            &self.fd,
+            name,
+            Mode::from_bits_truncate(mode),
+            FchmodatFlags::NoFollowSymlink,
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn set_permissions(&self, mode: u32) -> io::Result<()> {
+        fchmod(&self.fd, Mode::from_bits_truncate(mode))?;
+        Ok(())
+    }
+}
+
+/// The directory containing `path`, relative to the root of a deployment.
+pub(crate) fn parent(path: &TreePath) -> &Path {
+    path.parent().unwrap_or(Path::new(""))
+}
+
+pub(crate) fn name(path: &TreePath) -> &OsStr {
+    path.file_name().unwrap_or_default()
+}
+
+/// Tree paths only ever contain normal components.
+fn normal_components(path: &Path) -> impl Iterator<Item = &OsStr> {
+    path.components().filter_map(|component| match component {
+        Component::Normal(name) => Some(name),
+        _ => None,
+    })
+}
+
+/// Copies `source` into `target`, then gives it its owner and mode.
+fn write_copy(
+    store: &Store,
+    source: OwnedFd,
+    target: &mut std::fs::File,
+    mode: Option<u32>,
+    owner: Option<Owner>,
+) -> io::Result<()> {
+    let mut source = std::fs::File::from(source);
+    let mut copied = 0;
+    if store.deploy_mode() == DeployMode::Reflink {
+        loop {
+            match copy_file_range(&source, None, &*target, None, 1 << 30) {
+                Ok(0) => break,
+                Ok(n) => copied += n as u64,
+                // Across filesystems, or where copy_file_range isn't supported. The offsets
+                // of both files are where the kernel stopped, so the copy carries on from there.
+                Err(Errno::EXDEV | Errno::EOPNOTSUPP | Errno::ENOSYS | Errno::EINVAL) => {
+                    copied += io::copy(&mut source, target)?;
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    } else {
+        copied = io::copy(&mut source, target)?;
+    }
+    store.metrics().written(copied);
+
+    // Writing clears setuid and setgid bits, and so does changing the owner
+    if let Some(owner) = owner {
+        fchown(
+            &*target,
+            Some(Uid::from_raw(owner.uid)),
+            Some(Gid::from_raw(owner.gid)),
+        )?;
+    }
+    if let Some(mode) = mode {
+        fchmod(&*target, Mode::from_bits_truncate(mode))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt, symlink};
+    use temp_dir::TempDir;
+
+    use crate::CompressionKind;
+    use crate::fs;
+    use crate::store::{DeployMode, OwnerMap, SpecialFilePolicy, Store, SymlinkPolicy};
+    use crate::stream::Owner;
+    use crate::tree::{SpecialKind, Tree};
+
+    #[tokio::test]
+    async fn test_deploy_dirfd() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let original_dir = TempDir::new()?;
+        let deploy_dir = TempDir::new()?;
+        let outside_dir = TempDir::new()?;
+
+        std::fs::create_dir(original_dir.path().join("sub"))?;
+        fs::write(original_dir.path().join("sub/file"), b"contents").await?;
+        symlink("sub/file", original_dir.path().join("link"))?;
+        std::fs::set_permissions(
+            original_dir.path().join("sub"),
+            std::fs::Permissions::from_mode(0o750),
+        )?;
+
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+
+        // Symlinks are created inside the deployment, and deploying again replaces everything
+        tree.deploy(&store, deploy_dir.path())?;
+        tree.deploy(&store, deploy_dir.path())?;
+        assert_eq!(
+            std::fs::read_link(deploy_dir.path().join("link"))?,
+            std::path::Path::new("sub/file")
+        );
+        assert_eq!(
+            fs::read_to_end(deploy_dir.path().join("link")).await?,
+            b"contents"
+        );
+        let mode = deploy_dir
+            .path()
+            .join("sub")
+            .metadata()?
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o750);
+
+        // A directory swapped for a symlink is never followed
+        let swapped_dir = TempDir::new()?;
+        symlink(outside_dir.path(), swapped_dir.path().join("sub"))?;
+        assert!(tree.deploy(&store, swapped_dir.path()).is_err());
+        assert!(!outside_dir.path().join("file").exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deploy_modes() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let original_dir = TempDir::new()?;
+        let deploy_dir = TempDir::new()?;
+        let deployed = deploy_dir.path().join("file");
+
+        let store = Store::new(store_dir.path());
+        fs::write(original_dir.path().join("file"), b"contents").await?;
+        std::fs::set_permissions(
+            original_dir.path().join("file"),
+            std::fs::Permissions::from_mode(0o640),
+        )?;
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+        let object = store.path_of(&tree.streams[0].hash);
+
+        // Only linked when the store's copy has the same mode
+        tree.deploy(&store, deploy_dir.path())?;
+        assert_ne!(deployed.metadata()?.ino(), object.metadata()?.ino());
+        std::fs::set_permissions(&object, std::fs::Permissions::from_mode(0o640))?;
+        tree.deploy(&store, deploy_dir.path())?;
+        assert_eq!(deployed.metadata()?.ino(), object.metadata()?.ino());
+
+        // Copying over a hardlink leaves the store alone
+        for mode in [DeployMode::Copy, DeployMode::Reflink] {
+            let store = Store::new(store_dir.path()).with_deploy_mode(mode);
+            tree.deploy(&store, deploy_dir.path())?;
+            assert_ne!(deployed.metadata()?.ino(), object.metadata()?.ino());
+            assert_eq!(deployed.metadata()?.permissions().mode() & 0o777, 0o640);
+            assert_eq!(fs::read_to_end(&deployed).await?, b"contents");
+
+            std::fs::write(&deployed, b"changed")?;
+            assert_eq!(fs::read_to_end(&object).await?, b"contents");
+        }
+
+        let store = Store::new(store_dir.path()).with_deploy_mode(DeployMode::Symlink);
+        tree.deploy(&store, deploy_dir.path())?;
+        assert_eq!(
+            std::fs::read_link(&deployed)?,
+            std::path::absolute(&object)?
+        );
+        assert_eq!(fs::read_to_end(&deployed).await?, b"contents");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_symlink_policy() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let original_dir = TempDir::new()?;
+        let deploy_dir = TempDir::new()?;
+
+        let store = Store::new(store_dir.path()).with_symlink_policy(SymlinkPolicy::Sandbox);
+        std::fs::create_dir(original_dir.path().join("dir"))?;
+        fs::write(original_dir.path().join("file"), b"contents").await?;
+        symlink("../file", original_dir.path().join("dir/inside"))?;
+        let mut tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+        tree.deploy(&store, deploy_dir.path())?;
+
+        symlink("../../etc/passwd", original_dir.path().join("dir/outside"))?;
+        tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+        std::fs::remove_file(deploy_dir.path().join("file"))?;
+        let res = tree.deploy(&store, deploy_dir.path());
+        assert!(matches!(res, Err(crate::Error::UnsafeSymlink(..))));
+        // Refused before deploying anything
+        assert!(!deploy_dir.path().join("file").exists());
+
+        tree.deploy(&Store::new(store_dir.path()), deploy_dir.path())?;
+        assert!(deploy_dir.path().join("dir/outside").is_symlink());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deploy_file_modes() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let original_dir = TempDir::new()?;
+        let deploy_dir = TempDir::new()?;
+        let deployed = deploy_dir.path().join("file");
+        let mode =
+            |path: &std::path::Path| path.metadata().map(|m| m.permissions().mode() & 0o7777);
+
+        let store = Store::new(store_dir.path());
+        fs::write(original_dir.path().join("file"), b"contents").await?;
+        std::fs::set_permissions(
+            original_dir.path().join("file"),
+            std::fs::Permissions::from_mode(0o4755),
+        )?;
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+        let object = store.path_of(&tree.streams[0].hash);
+
+        // Like a downloaded stream, which doesn't keep the original's mode
+        std::fs::set_permissions(&object, std::fs::Permissions::from_mode(0o600))?;
+        tree.deploy(&store, deploy_dir.path())?;
+        assert_eq!(mode(&deployed)?, 0o4755);
+
+        for deploy_mode in [DeployMode::Hardlink, DeployMode::Copy] {
+            let store = Store::new(store_dir.path())
+                .with_deploy_mode(deploy_mode)
+                .with_mode_mask(0o777);
+            tree.deploy(&store, deploy_dir.path())?;
+            assert_eq!(mode(&deployed)?, 0o755);
+        }
+        // Not changed through a hardlink
+        assert_eq!(mode(&object)?, 0o600);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deploy_copied_modes() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let original_dir = TempDir::new()?;
+        let deploy_dir = TempDir::new()?;
+        let deployed = deploy_dir.path().join("file");
+
+        let store = Store::new(store_dir.path()).with_owners(true);
+        fs::write(original_dir.path().join("file"), b"contents").await?;
+        std::fs::set_permissions(
+            original_dir.path().join("file"),
+            std::fs::Permissions::from_mode(0o6755),
+        )?;
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+        let object = store.path_of(&tree.streams[0].hash);
+
+        // Setuid and setgid survive both the write and the change of owner
+        for deploy_mode in [DeployMode::Copy, DeployMode::Reflink] {
+            let store = Store::new(store_dir.path())
+                .with_deploy_mode(deploy_mode)
+                .with_owners(true);
+            tree.deploy(&store, deploy_dir.path())?;
+            assert_ne!(deployed.metadata()?.ino(), object.metadata()?.ino());
+            assert_eq!(deployed.metadata()?.permissions().mode() & 0o7777, 0o6755);
+            assert_eq!(fs::read_to_end(&deployed).await?, b"contents");
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deploy_shared_modes() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let original_dir = TempDir::new()?;
+        let deploy_dir = TempDir::new()?;
+        let mode =
+            |path: &std::path::Path| path.metadata().map(|m| m.permissions().mode() & 0o7777);
+
+        let store = Store::new(store_dir.path()).with_deploy_mode(DeployMode::Hardlink);
+        for (name, file_mode) in [("plain", 0o644), ("script", 0o755)] {
+            let path = original_dir.path().join(name);
+            fs::write(&path, b"contents").await?;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(file_mode))?;
+        }
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+        let object = store.path_of(&tree.streams[0].hash);
+        std::fs::set_permissions(&object, std::fs::Permissions::from_mode(0o644))?;
+
+        tree.deploy(&store, deploy_dir.path())?;
+        let plain = deploy_dir.path().join("plain");
+        let script = deploy_dir.path().join("script");
+        assert_eq!(mode(&plain)?, 0o644);
+        assert_eq!(mode(&script)?, 0o755);
+        assert_eq!(mode(&object)?, 0o644);
+        // Only the file matching the store's copy is linked
+        assert_eq!(plain.metadata()?.ino(), object.metadata()?.ino());
+        assert_ne!(script.metadata()?.ino(), object.metadata()?.ino());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deploy_mtimes() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let original_dir = TempDir::new()?;
+        let deploy_dir = TempDir::new()?;
+        let original = original_dir.path().join("file");
+        let deployed = deploy_dir.path().join("file");
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+
+        fs::write(&original, b"contents").await?;
+        std::fs::File::options()
+            .write(true)
+            .open(&original)?
+            .set_modified(mtime)?;
+
+        // Not captured by default, which keeps tree hashes as they were
+        let store = Store::new(store_dir.path());
+        let plain = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+        assert_eq!(plain.streams[0].mtime, None);
+
+        let store = store.with_mtimes(true);
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+        assert_eq!(tree.streams[0].mtime, Some(1_000_000_000));
+        assert_ne!(tree.hash(), plain.hash());
+
+        let object = store.path_of(&tree.streams[0].hash);
+        for deploy_mode in [DeployMode::Hardlink, DeployMode::Copy] {
+            let store = Store::new(store_dir.path()).with_deploy_mode(deploy_mode);
+            // Like a downloaded stream, which was written just now
+            std::fs::File::options()
+                .write(true)
+                .open(&object)?
+                .set_modified(std::time::SystemTime::now())?;
+
+            tree.deploy(&store, deploy_dir.path())?;
+            assert_eq!(deployed.metadata()?.mtime(), 1_000_000_000);
+            // Not changed through a hardlink
+            assert_ne!(object.metadata()?.mtime(), 1_000_000_000);
+        }
+
+        // Linked once the store's copy has the same mtime
+        std::fs::File::options()
+            .write(true)
+            .open(&object)?
+            .set_modified(mtime)?;
+        tree.deploy(&store, deploy_dir.path())?;
+        assert_eq!(deployed.metadata()?.ino(), object.metadata()?.ino());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_allowed_trees() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let original_dir = TempDir::new()?;
+        let deploy_dir = TempDir::new()?;
+
+        fs::write(original_dir.path().join("file"), b"contents").await?;
+        let store = Store::new(store_dir.path());
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+        assert!(store.is_tree_allowed(&tree.hash()));
+
+        let store = Store::new(store_dir.path()).with_allowed_trees(["0".repeat(64)]);
+        assert!(matches!(
+            tree.deploy(&store, deploy_dir.path()),
+            Err(crate::Error::TreeNotAllowed(hash)) if hash == tree.hash()
+        ));
+        assert!(tree.plan_deploy(&store, deploy_dir.path()).is_err());
+        assert!(!deploy_dir.path().join("file").exists());
+
+        let store = Store::new(store_dir.path()).with_allowed_trees([tree.hash()]);
+        tree.deploy(&store, deploy_dir.path())?;
+        assert!(deploy_dir.path().join("file").exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deploy_owners() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let original_dir = TempDir::new()?;
+        let deploy_dir = TempDir::new()?;
+        let deployed = deploy_dir.path().join("file");
+        let (uid, gid) = (
+            nix::unistd::getuid().as_raw(),
+            nix::unistd::getgid().as_raw(),
+        );
+
+        fs::write(original_dir.path().join("file"), b"contents").await?;
+        let store = Store::new(store_dir.path()).with_owners(true);
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+        assert_eq!(tree.streams[0].owner, Some(Owner { uid, gid }));
+
+        let map = OwnerMap::new().uid(uid, 1001).gid(gid, 1002);
+        assert_eq!(
+            map.map(Owner { uid, gid }),
+            Owner {
+                uid: 1001,
+                gid: 1002
+            }
+        );
+        assert_eq!(map.map(Owner { uid: 7, gid: 8 }), Owner { uid: 7, gid: 8 });
+
+        // Giving files away takes root
+        if !nix::unistd::geteuid().is_root() {
+            return Ok(());
+        }
+        for deploy_mode in [DeployMode::Hardlink, DeployMode::Copy] {
+            let store = Store::new(store_dir.path())
+                .with_deploy_mode(deploy_mode)
+                .with_owners(true)
+                .with_owner_map(map.clone());
+            tree.deploy(&store, deploy_dir.path())?;
+            let metadata = deployed.metadata()?;
+            assert_eq!((metadata.uid(), metadata.gid()), (1001, 1002));
+            // Not changed through a hardlink
+            let metadata = store.path_of(&tree.streams[0].hash).metadata()?;
+            assert_eq!((metadata.uid(), metadata.gid()), (uid, gid));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_special_files() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let original_dir = TempDir::new()?;
+        let deploy_dir = TempDir::new()?;
+        let fifo = original_dir.path().join("fifo");
+
+        nix::unistd::mkfifo(&fifo, nix::sys::stat::Mode::from_bits_truncate(0o600))
+            .map_err(std::io::Error::from)?;
+        std::fs::set_permissions(&fifo, std::fs::Permissions::from_mode(0o640))?;
+
+        // Skipped by default
+        let store = Store::new(store_dir.path());
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+        assert!(tree.specials.is_empty());
+
+        let store = store.with_special_files(SpecialFilePolicy::Error);
+        assert!(
+            Tree::create(&store, original_dir.path(), CompressionKind::None)
+                .await
+                .is_err()
+        );
+
+        let store = store.with_special_files(SpecialFilePolicy::Record);
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+        assert_eq!(tree.specials.len(), 1);
+        assert_eq!(tree.specials[0].kind, SpecialKind::Fifo);
+        assert_ne!(
+            tree.hash(),
+            Tree::create(
+                &Store::new(store_dir.path()),
+                original_dir.path(),
+                CompressionKind::None
+            )
+            .await?
+            .hash()
+        );
+
+        let mut manifest = Vec::new();
+        tree.write_manifest(&mut manifest).await?;
+        assert_eq!(
+            Tree::read_manifest(&manifest[..]).await?.hash(),
+            tree.hash()
+        );
+
+        tree.deploy(&store, deploy_dir.path())?;
+        let metadata = std::fs::symlink_metadata(deploy_dir.path().join("fifo"))?;
+        assert!(metadata.file_type().is_fifo());
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o640);
+
+        Ok(())
+    }
+}
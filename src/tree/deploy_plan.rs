@@ -0,0 +1,434 @@
+//! Planning a deploy without touching anything.
+//!
+//! [`Tree::plan_deploy`] lists what [`Tree::deploy`] would do to a directory as it is now, so
+//! operators can review it first. [`DeployPlan::execute`] then carries out exactly those actions,
+//! which is also how [`Tree::deploy`] works, keeping a [journal](crate::tree::journal) as it goes.
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::store::{DeployMode, Store};
+use crate::stream::Owner;
+use crate::tree::deploy::{Dir, FileMeta, name, parent};
+use crate::tree::deployment::Deployment;
+use crate::tree::journal::Journal;
+use crate::tree::{SpecialKind, Tree, TreePath, check_deployable};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum DeployAction {
+    /// A missing directory is created
+    CreateDir {
+        path: TreePath,
+    },
+    /// A stream is hardlinked out of the store, restoring its mode, mtime and owner if they were
+    /// captured. The store's copy shares them.
+    Link {
+        path: TreePath,
+        hash: String,
+        mode: Option<u32>,
+        mtime: Option<i64>,
+        owner: Option<Owner>,
+    },
+    /// A stream is copied out of the store, as the deployment is on another filesystem or the
+    /// store [deploys](crate::store::DeployMode) copies
+    Copy {
+        path: TreePath,
+        hash: String,
+        mode: Option<u32>,
+        mtime: Option<i64>,
+        owner: Option<Owner>,
+    },
+    Symlink {
+        path: TreePath,
+        target: PathBuf,
+    },
+    /// A special file, like a FIFO, is created
+    Special {
+        path: TreePath,
+        kind: SpecialKind,
+        mode: u32,
+    },
+    /// A file or symlink in the way is removed
+    Remove {
+        path: TreePath,
+    },
+    /// A directory is restricted, once everything inside it is deployed
+    SetPermissions {
+        path: TreePath,
+        permissions: u32,
+    },
+}
+
+impl DeployAction {
+    /// The path affected, relative to the root of the deployment.
+    #[must_use]
+    pub fn path(&self) -> &TreePath {
+        match self {
+            Self::CreateDir { path }
+            | Self::Link { path, .. }
+            | Self::Copy { path, .. }
+            | Self::Symlink { path, .. }
+            | Self::Special { path, .. }
+            | Self::Remove { path }
+            | Self::SetPermissions { path, .. } => path,
+        }
+    }
+}
+
+/// Everything a deploy would do, in order.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeployPlan {
+    tree_hash: String,
+    actions: Vec<DeployAction>,
+}
+
+impl DeployPlan {
+    #[must_use]
+    pub fn actions(&self) -> &[DeployAction] {
+        &self.actions
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    #[must_use]
+    pub fn tree_hash(&self) -> &str {
+        &self.tree_hash
+    }
+
+    /// Carries out the plan, then records the tree as deployed like [`Tree::deploy`].
+    ///
+    /// # Errors
+    ///
+    /// - Out of storage/Permissions Errors
+    /// - [`Error::InterruptedDeploy`](crate::Error::InterruptedDeploy) if an earlier deploy into
+    ///   `deploy_path` has to be resumed or undone first
+    pub fn execute(&self, store: &Store, deploy_path: &Path) -> crate::Result<()> {
+        let root = Dir::open(deploy_path)?;
+        let journal = Journal::begin(&root, deploy_path, self)?;
+        self.run(0, journal, store, &root)
+    }
+
+    /// Carries out the actions from `from` on, recording each in the journal, which is removed
+    /// once the tree is recorded as deployed.
+    pub(crate) fn run(
+        &self,
+        from: usize,
+        journal: Journal,
+        store: &Store,
+        root: &Dir,
+    ) -> crate::Result<()> {
+        let store_dir = Dir::open(store.root())?;
+
+        for (index, action) in self.actions.iter().enumerate().skip(from) {
+            let mut previous_permissions = None;
+            match action {
+                DeployAction::CreateDir { path } => {
+                    root.create_path(path)?;
+                }
+                DeployAction::Link {
+                    path,
+                    hash,
+                    mode,
+                    mtime,
+                    owner,
+                }
+                | DeployAction::Copy {
+                    path,
+                    hash,
+                    mode,
+                    mtime,
+                    owner,
+                } => root.open_path(parent(path))?.deploy_stream(
+                    store,
+                    &store_dir,
+                    hash,
+                    name(path),
+                    FileMeta {
+                        mode: *mode,
+                        mtime: *mtime,
+                        owner: *owner,
+                    },
+                )?,
+                DeployAction::Symlink { path, target } => {
+                    root.open_path(parent(path))?.symlink(target, name(path))?;
+                }
+                DeployAction::Special { path, kind, mode } => {
+                    root.open_path(parent(path))?
+                        .create_special(*kind, name(path), *mode)?;
+                }
+                DeployAction::Remove { path } => {
+                    journal.back_up(index, &root.open_path(parent(path))?, name(path))?;
+                }
+                DeployAction::SetPermissions { path, permissions } => {
+                    let dir = root.open_path(path)?;
+                    previous_permissions = Some(dir.metadata()?.st_mode & 0o7777);
+                    dir.set_permissions(*permissions)?;
+                }
+            }
+            journal.performed(index, previous_permissions)?;
+        }
+
+        Deployment::new(self.tree_hash.clone(), store).write(root)?;
+        store.record_access(self.actions.iter().filter_map(|action| match action {
+            DeployAction::Link { hash, .. } | DeployAction::Copy { hash, .. } => {
+                Some(hash.as_str())
+            }
+            _ => None,
+        }))?;
+        journal.finish(root)?;
+
+        Ok(())
+    }
+}
+
+/// How streams end up in the deployment.
+enum Placement {
+    Link,
+    Copy,
+    /// Symlinks into the store at this absolute path
+    Symlink(PathBuf),
+}
+
+impl Tree {
+    /// Lists everything [`Tree::deploy`] would do to `deploy_path`, without changing anything.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically permissions)
+    /// - A directory in the tree being something else in `deploy_path`, which deploying would
+    ///   fail on too
+    /// - [`Error::TreeNotAllowed`](crate::Error::TreeNotAllowed) if the store's
+    ///   [allowlist](Store::with_allowed_trees) doesn't include the tree
+    /// - [`Error::UnsafeSymlink`](crate::Error::UnsafeSymlink) if the store's
+    ///   [`SymlinkPolicy`](crate::store::SymlinkPolicy) refuses a symlink
+    /// - [`Error::InvalidPath`](crate::Error::InvalidPath) for names that could escape their
+    ///   directory, like `../a`, before anything is deployed
+    /// - [`Error::NonPortableName`](crate::Error::NonPortableName) if the store's
+    ///   [`NamePolicy`](crate::store::NamePolicy) refuses a name
+    pub fn plan_deploy(&self, store: &Store, deploy_path: &Path) -> crate::Result<DeployPlan> {
+        let tree = check_deployable(self, store)?;
+        let root = Dir::open(deploy_path)?;
+        Ok(tree.plan_as(self.hash(), store, &root)?)
+    }
+
+    /// Plans deploying the tree without any checks, as the tree with `hash`, like
+    /// [`Tree::deploy_as`].
+    pub(crate) fn plan_as(
+        &self,
+        tree_hash: String,
+        store: &Store,
+        root: &Dir,
+    ) -> io::Result<DeployPlan> {
+        let placement = match store.deploy_mode() {
+            // Hardlinks can't cross filesystems, so deploying would copy everything
+            DeployMode::Hardlink
+                if Dir::open(store.root())?.metadata()?.st_dev == root.metadata()?.st_dev =>
+            {
+                Placement::Link
+            }
+            DeployMode::Symlink => Placement::Symlink(std::path::absolute(store.root())?),
+            DeployMode::Hardlink | DeployMode::Copy | DeployMode::Reflink => Placement::Copy,
+        };
+
+        let mut actions = Vec::new();
+        self.plan_into(Some(root), &TreePath::root(), &placement, &mut actions)?;
+
+        Ok(DeployPlan { tree_hash, actions })
+    }
+
+    /// Plans deploying into `dir`, which is `None` when it doesn't exist yet.
+    fn plan_into(
+        &self,
+        dir: Option<&Dir>,
+        path: &TreePath,
+        placement: &Placement,
+        actions: &mut Vec<DeployAction>,
+    ) -> io::Result<()> {
+        for (name, subtree) in &self.subtrees {
+            let sub_path = path.join(name);
+            let subdir = match dir.map(|dir| dir.open_path(name)).transpose() {
+                Ok(subdir) => subdir,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            };
+            if subdir.is_none() {
+                actions.push(DeployAction::CreateDir {
+                    path: sub_path.clone(),
+                });
+            }
+
+            subtree.plan_into(subdir.as_ref(), &sub_path, placement, actions)?;
+
+            let current = subdir
+                .map(|subdir| subdir.metadata())
+                .transpose()?
+                .map(|stat| stat.st_mode & 0o7777);
+            if current != Some(subtree.permissions & 0o7777) {
+                actions.push(DeployAction::SetPermissions {
+                    path: sub_path,
+                    permissions: subtree.permissions,
+                });
+            }
+        }
+
+        let exists = |name| -> io::Result<bool> {
+            Ok(match dir {
+                Some(dir) => dir.stat(name)?.is_some(),
+                None => false,
+            })
+        };
+
+        for stream in &self.streams {
+            let stream_path = path.join(&TreePath::new_unchecked(&stream.file_name));
+            if exists(&stream.file_name)? {
+                actions.push(DeployAction::Remove {
+                    path: stream_path.clone(),
+                });
+            }
+            actions.push(match placement {
+                Placement::Link => DeployAction::Link {
+                    path: stream_path,
+                    hash: stream.hash.to_string(),
+                    mode: stream.mode,
+                    mtime: stream.mtime,
+                    owner: stream.owner,
+                },
+                Placement::Copy => DeployAction::Copy {
+                    path: stream_path,
+                    hash: stream.hash.to_string(),
+                    mode: stream.mode,
+                    mtime: stream.mtime,
+                    owner: stream.owner,
+                },
+                Placement::Symlink(store_root) => DeployAction::Symlink {
+                    path: stream_path,
+                    target: store_root.join(&stream.hash),
+                },
+            });
+        }
+
+        for link in &self.symlinks {
+            let link_path = path.join(&TreePath::new_unchecked(&link.file_name));
+            if exists(&link.file_name)? {
+                actions.push(DeployAction::Remove {
+                    path: link_path.clone(),
+                });
+            }
+            actions.push(DeployAction::Symlink {
+                path: link_path,
+                target: link.target.clone(),
+            });
+        }
+
+        for special in &self.specials {
+            let special_path = path.join(&TreePath::new_unchecked(&special.file_name));
+            if exists(&special.file_name)? {
+                actions.push(DeployAction::Remove {
+                    path: special_path.clone(),
+                });
+            }
+            actions.push(DeployAction::Special {
+                path: special_path,
+                kind: special.kind,
+                mode: special.mode,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{PermissionsExt, symlink};
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::CompressionKind;
+    use crate::fs;
+
+    #[tokio::test]
+    async fn test_plan_deploy() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let original_dir = TempDir::new()?;
+        let deploy_dir = TempDir::new()?;
+        let path = |path: &str| TreePath::new(path).unwrap();
+
+        std::fs::create_dir(original_dir.path().join("dir"))?;
+        fs::write(original_dir.path().join("dir/file"), b"contents").await?;
+        symlink("dir/file", original_dir.path().join("link"))?;
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+        let hash = tree.subtrees[0].1.streams[0].hash.to_string();
+
+        fs::write(deploy_dir.path().join("link"), b"in the way").await?;
+        let plan = tree.plan_deploy(&store, deploy_dir.path())?;
+        assert_eq!(
+            plan.actions(),
+            [
+                DeployAction::CreateDir { path: path("dir") },
+                DeployAction::Link {
+                    path: path("dir/file"),
+                    hash,
+                    mode: tree.subtrees[0].1.streams[0].mode,
+                    mtime: None,
+                    owner: None,
+                },
+                DeployAction::SetPermissions {
+                    path: path("dir"),
+                    permissions: tree.subtrees[0].1.permissions,
+                },
+                DeployAction::Remove { path: path("link") },
+                DeployAction::Symlink {
+                    path: path("link"),
+                    target: "dir/file".into(),
+                },
+            ]
+        );
+
+        // Planning doesn't change anything
+        assert!(!deploy_dir.path().join("dir").exists());
+        assert_eq!(
+            fs::read_to_end(deploy_dir.path().join("link")).await?,
+            b"in the way"
+        );
+
+        plan.execute(&store, deploy_dir.path())?;
+        assert_eq!(
+            fs::read_to_end(deploy_dir.path().join("link")).await?,
+            b"contents"
+        );
+        assert_eq!(
+            Deployment::load(deploy_dir.path())?.map(|record| record.tree_hash),
+            Some(tree.hash())
+        );
+
+        // Directories with the right permissions are left alone
+        let plan = tree.plan_deploy(&store, deploy_dir.path())?;
+        assert!(!plan.actions().iter().any(|action| matches!(
+            action,
+            DeployAction::CreateDir { .. } | DeployAction::SetPermissions { .. }
+        )));
+        std::fs::set_permissions(
+            deploy_dir.path().join("dir"),
+            std::fs::Permissions::from_mode(0o700),
+        )?;
+        let plan = tree.plan_deploy(&store, deploy_dir.path())?;
+        assert!(
+            plan.actions()
+                .iter()
+                .any(|action| matches!(action, DeployAction::SetPermissions { .. }))
+        );
+
+        Ok(())
+    }
+}
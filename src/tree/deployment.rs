@@ -0,0 +1,98 @@
+//! The record of what is deployed in a directory.
+//!
+//! Every successful deploy writes [`RECORD_NAME`] into the root of the deployment, so that later
+//! updates and verification know which tree is currently installed without having to trust the
+//! files themselves.
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::store::Store;
+use crate::tree::deploy::Dir;
+
+/// The name of the record, in the root of the deployment.
+pub const RECORD_NAME: &str = ".syncstream-deployed.json";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Deployment {
+    /// See [`Tree::hash`](crate::tree::Tree::hash)
+    pub tree_hash: String,
+    /// Seconds since the Unix epoch
+    pub deployed_at: u64,
+    /// The store the tree was deployed from, which files may be hardlinked to
+    pub store: PathBuf,
+}
+
+impl Deployment {
+    pub(crate) fn new(tree_hash: String, store: &Store) -> Self {
+        Self {
+            tree_hash,
+            deployed_at: store
+                .now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs()),
+            store: store.root().to_path_buf(),
+        }
+    }
+
+    /// Reads the record of what is deployed in `deploy_path`, or `None` if nothing was deployed
+    /// there yet.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically permissions)
+    /// - [`Error::ManifestError`](crate::Error::ManifestError) if the record is corrupted
+    pub fn load(deploy_path: &Path) -> crate::Result<Option<Self>> {
+        let record = match std::fs::read(deploy_path.join(RECORD_NAME)) {
+            Ok(record) => record,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Some(serde_json::from_slice(&record)?))
+    }
+
+    /// Replaces the record in an open deployment.
+    pub(crate) fn write(&self, dir: &Dir) -> crate::Result<()> {
+        dir.write_atomic(RECORD_NAME.as_ref(), &serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::CompressionKind;
+    use crate::fs;
+    use crate::tree::Tree;
+    use crate::tree::compact::CompactTree;
+
+    #[tokio::test]
+    async fn test_deployment_record() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let original_dir = TempDir::new()?;
+        let deploy_dir = TempDir::new()?;
+
+        fs::write(original_dir.path().join("file"), b"contents").await?;
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+
+        assert_eq!(Deployment::load(deploy_dir.path())?, None);
+
+        tree.deploy(&store, deploy_dir.path())?;
+        let deployment = Deployment::load(deploy_dir.path())?.expect("written by deploy");
+        assert_eq!(deployment.tree_hash, tree.hash());
+        assert_eq!(deployment.store, store_dir.path());
+        assert!(deployment.deployed_at > 0);
+
+        // Compact trees record the same hash
+        CompactTree::from(&tree).deploy(&store, deploy_dir.path())?;
+        let deployment = Deployment::load(deploy_dir.path())?.expect("written by deploy");
+        assert_eq!(deployment.tree_hash, tree.hash());
+
+        Ok(())
+    }
+}
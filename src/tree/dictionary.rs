@@ -0,0 +1,178 @@
+//! Compressing a tree's small files with a shared [`Dictionary`], which they're trained on.
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::async_types::AsyncReadExt;
+use crate::dictionary::Dictionary;
+use crate::store::Store;
+use crate::stream::{ObjectHash, Stream};
+use crate::tree::{Tree, all_streams};
+
+impl Tree {
+    /// Trains a dictionary of at most `max_size` bytes on every file in the tree of at most
+    /// `max_file_size` bytes, and adds it to the store.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically a stream not being in the store)
+    /// - See [`Dictionary::train`]
+    pub async fn train_dictionary(
+        &self,
+        store: &Store,
+        max_file_size: u64,
+        max_size: usize,
+    ) -> crate::Result<Dictionary> {
+        let mut samples = Vec::new();
+        for hash in small_streams(self, store, max_file_size)? {
+            let mut sample = Vec::new();
+            store.open(&hash).await?.read_to_end(&mut sample).await?;
+            samples.push(sample);
+        }
+
+        let dictionary = Dictionary::train(&samples, max_size)?;
+        dictionary.insert(store).await?;
+        Ok(dictionary)
+    }
+
+    /// Compresses every file in the tree of at most `max_file_size` bytes again with
+    /// `dictionary`, so that they're pushed and downloaded that way. Larger files are left
+    /// alone, and the tree's [hash](Tree::hash) doesn't change.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically a stream not being in the store)
+    /// - See [`Stream::create_with_dictionary`]
+    pub async fn compress_with_dictionary(
+        &mut self,
+        store: &Store,
+        dictionary: &Dictionary,
+        max_file_size: u64,
+    ) -> crate::Result<()> {
+        let mut compressed = BTreeMap::new();
+        for hash in small_streams(self, store, max_file_size)? {
+            let stream =
+                Stream::create_with_dictionary(store.path_of(&hash), store, dictionary).await?;
+            compressed.insert(hash, stream);
+        }
+
+        set_dictionary(self, &compressed);
+        Ok(())
+    }
+}
+
+/// The distinct streams in the tree of at most `max_file_size` bytes.
+fn small_streams(
+    tree: &Tree,
+    store: &Store,
+    max_file_size: u64,
+) -> crate::Result<BTreeSet<ObjectHash>> {
+    let mut hashes = BTreeSet::new();
+    for stream in all_streams(tree) {
+        if !hashes.contains(&stream.hash)
+            && std::fs::metadata(store.path_of(&stream.hash))?.len() <= max_file_size
+        {
+            hashes.insert(stream.hash.clone());
+        }
+    }
+    Ok(hashes)
+}
+
+fn set_dictionary(tree: &mut Tree, compressed: &BTreeMap<ObjectHash, Stream>) {
+    for stream in &mut tree.streams {
+        if let Some(created) = compressed.get(&stream.hash) {
+            stream.compression = created.compression;
+            stream.dictionary.clone_from(&created.dictionary);
+            stream.network_size = created.network_size;
+        }
+    }
+    for (_, subtree) in &mut tree.subtrees {
+        set_dictionary(subtree, compressed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::{CompressionKind, fs};
+
+    #[tokio::test]
+    async fn test_tree_dictionary() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let repo_dir = TempDir::new()?;
+        let repo_url = repo_dir.path().to_str().unwrap();
+        let local_dir = TempDir::new()?;
+        let local_store = Store::new(local_dir.path());
+        let original_dir = TempDir::new()?;
+
+        // Many small configs that only differ a little, and one large file
+        std::fs::create_dir(original_dir.path().join("configs"))?;
+        for i in 0..200 {
+            let config = format!(
+                "{{\"name\": \"service-{i}\", \"enabled\": {}, \"port\": {}, \
+                 \"log_level\": \"info\", \"restart_policy\": \"on-failure\", \
+                 \"healthcheck\": {{\"interval\": {}, \"timeout\": 5}}}}",
+                i % 2 == 0,
+                8000 + i,
+                i % 7 + 10,
+            );
+            fs::write(
+                original_dir.path().join(format!("configs/{i}.json")),
+                config,
+            )
+            .await?;
+        }
+        fs::write(original_dir.path().join("large"), vec![1; 64 * 1024]).await?;
+
+        let mut tree = Tree::create(&store, original_dir.path(), CompressionKind::Zstd).await?;
+        let hash = tree.hash();
+        let dictionary = tree.train_dictionary(&store, 4096, 4096).await?;
+        assert!(store.contains(dictionary.hash()));
+        tree.compress_with_dictionary(&store, &dictionary, 4096)
+            .await?;
+        assert_eq!(tree.hash(), hash);
+
+        let large = tree.streams.iter().find(|s| s.file_name == "large");
+        assert!(large.is_some_and(|stream| stream.dictionary.is_none()));
+        let configs = &tree.subtrees[0].1.streams;
+        assert!(
+            configs
+                .iter()
+                .all(|stream| stream.dictionary.as_deref() == Some(dictionary.hash()))
+        );
+
+        // Dictionaries make small files much smaller
+        let size = |name: String| std::fs::metadata(store.root().join(name)).map(|m| m.len());
+        let (mut plain, mut with_dictionary) = (0, 0);
+        for stream in configs {
+            plain += size(format!("{}.zstd", stream.hash))?;
+            with_dictionary += size(crate::dictionary::object_name(
+                &stream.hash,
+                dictionary.hash(),
+            ))?;
+        }
+        assert!(with_dictionary * 2 < plain, "{with_dictionary} vs {plain}");
+
+        // The dictionary is pushed and downloaded along with the streams
+        tree.push(repo_url, &store, CompressionKind::Zstd).await?;
+        assert!(
+            repo_dir
+                .path()
+                .join(format!("streams/{}", dictionary.hash()))
+                .exists()
+        );
+        tree.download(repo_url, &local_store, CompressionKind::Zstd)
+            .await?;
+        assert!(local_store.contains(dictionary.hash()));
+        for stream in configs {
+            assert!(local_store.contains(&stream.hash));
+        }
+
+        // and kept by garbage collection
+        local_store.gc(std::slice::from_ref(&tree))?;
+        assert!(local_store.contains(dictionary.hash()));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,395 @@
+//! Differences between two trees.
+//!
+//! Files are compared by path, and a file that was removed from one path and added at another
+//! with the same hash is reported as a single [`Change::Renamed`], so that restructuring
+//! directories doesn't look like deleting and re-adding all of their contents. Changes to only a
+//! file's mode or a symlink's target have their own kinds, as they don't touch any contents.
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+use crate::tree::{SpecialKind, Tree, TreePath};
+
+/// Something at a path in a tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Node {
+    File { hash: String },
+    Symlink { target: PathBuf },
+    Special { kind: SpecialKind, mode: u32 },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// Only in the new tree
+    Added { path: TreePath, node: Node },
+    /// Only in the old tree
+    Removed { path: TreePath, node: Node },
+    /// At the same path in both trees, but with different contents, or a file replaced by a
+    /// symlink or the other way around
+    Modified {
+        path: TreePath,
+        old: Node,
+        new: Node,
+    },
+    /// A file with the same contents, but another mode. Also reported for renamed files whose
+    /// mode changed, at their new path.
+    ModeChanged {
+        path: TreePath,
+        old: Option<u32>,
+        new: Option<u32>,
+    },
+    /// A symlink pointing somewhere else
+    Retargeted {
+        path: TreePath,
+        old: PathBuf,
+        new: PathBuf,
+    },
+    /// A file that moved without its contents changing
+    Renamed {
+        from: TreePath,
+        to: TreePath,
+        hash: String,
+    },
+}
+
+impl Change {
+    /// The path in the new tree, or the old one for removals.
+    #[must_use]
+    pub fn path(&self) -> &TreePath {
+        match self {
+            Change::Added { path, .. }
+            | Change::Removed { path, .. }
+            | Change::Modified { path, .. }
+            | Change::ModeChanged { path, .. }
+            | Change::Retargeted { path, .. } => path,
+            Change::Renamed { to, .. } => to,
+        }
+    }
+}
+
+/// How many changes of each kind a [`TreeDiff`] has.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DiffStats {
+    pub added: usize,
+    pub removed: usize,
+    pub modified: usize,
+    pub renamed: usize,
+    pub mode_changed: usize,
+    pub retargeted: usize,
+}
+
+/// The changes from one tree to another, ordered by path.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TreeDiff {
+    changes: Vec<Change>,
+}
+
+impl TreeDiff {
+    #[must_use]
+    pub fn changes(&self) -> &[Change] {
+        &self.changes
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// A summary of the changes, for showing users before committing to an update.
+    #[must_use]
+    pub fn stats(&self) -> DiffStats {
+        let mut stats = DiffStats::default();
+        for change in &self.changes {
+            match change {
+                Change::Added { .. } => stats.added += 1,
+                Change::Removed { .. } => stats.removed += 1,
+                Change::Modified { .. } => stats.modified += 1,
+                Change::Renamed { .. } => stats.renamed += 1,
+                Change::ModeChanged { .. } => stats.mode_changed += 1,
+                Change::Retargeted { .. } => stats.retargeted += 1,
+            }
+        }
+        stats
+    }
+}
+
+impl IntoIterator for TreeDiff {
+    type Item = Change;
+    type IntoIter = std::vec::IntoIter<Change>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.changes.into_iter()
+    }
+}
+
+/// Every file, symlink and special file in a tree by path, along with the modes of files.
+fn nodes(tree: &Tree) -> BTreeMap<TreePath, (Node, Option<u32>)> {
+    let mut nodes = BTreeMap::new();
+    let mut pending = vec![(TreePath::root(), tree)];
+
+    while let Some((path, tree)) = pending.pop() {
+        for stream in &tree.streams {
+            nodes.insert(
+                path.join(&TreePath::new_unchecked(&stream.file_name)),
+                (
+                    Node::File {
+                        hash: stream.hash.to_string(),
+                    },
+                    stream.mode,
+                ),
+            );
+        }
+        for symlink in &tree.symlinks {
+            nodes.insert(
+                path.join(&TreePath::new_unchecked(&symlink.file_name)),
+                (
+                    Node::Symlink {
+                        target: symlink.target.clone(),
+                    },
+                    None,
+                ),
+            );
+        }
+        for special in &tree.specials {
+            nodes.insert(
+                path.join(&TreePath::new_unchecked(&special.file_name)),
+                (
+                    Node::Special {
+                        kind: special.kind,
+                        mode: special.mode,
+                    },
+                    None,
+                ),
+            );
+        }
+        for (name, subtree) in &tree.subtrees {
+            pending.push((path.join(name), subtree));
+        }
+    }
+
+    nodes
+}
+
+impl Tree {
+    /// Lists the changes needed to turn this tree into `new`.
+    ///
+    /// Removed and added files with the same hash are paired up into renames, in path order.
+    #[must_use]
+    pub fn diff(&self, new: &Tree) -> TreeDiff {
+        let mut old_nodes = nodes(self);
+        let new_nodes = nodes(new);
+
+        let mut changes = Vec::new();
+        let mut added = Vec::new();
+
+        for (path, (node, mode)) in new_nodes {
+            let Some((old, old_mode)) = old_nodes.remove(&path) else {
+                added.push((path, node, mode));
+                continue;
+            };
+
+            match (old, node) {
+                (old, node) if old == node => {
+                    if old_mode != mode {
+                        changes.push(Change::ModeChanged {
+                            path,
+                            old: old_mode,
+                            new: mode,
+                        });
+                    }
+                }
+                (Node::Symlink { target: old }, Node::Symlink { target: new }) => {
+                    changes.push(Change::Retargeted { path, old, new });
+                }
+                (old, new) => changes.push(Change::Modified { path, old, new }),
+            }
+        }
+
+        // Whatever is left of the old tree was removed, unless it moved
+        let mut removed_by_hash: HashMap<String, Vec<(TreePath, Option<u32>)>> = HashMap::new();
+        for (path, (node, mode)) in old_nodes.into_iter().rev() {
+            match node {
+                Node::File { hash } => removed_by_hash.entry(hash).or_default().push((path, mode)),
+                node @ (Node::Symlink { .. } | Node::Special { .. }) => {
+                    changes.push(Change::Removed { path, node });
+                }
+            }
+        }
+
+        for (path, node, mode) in added {
+            let from = match &node {
+                Node::File { hash } => removed_by_hash.get_mut(hash).and_then(Vec::pop),
+                Node::Symlink { .. } | Node::Special { .. } => None,
+            };
+
+            match (from, node) {
+                (Some((from, old_mode)), Node::File { hash }) => {
+                    changes.push(Change::Renamed {
+                        from,
+                        to: path.clone(),
+                        hash,
+                    });
+                    if old_mode != mode {
+                        changes.push(Change::ModeChanged {
+                            path,
+                            old: old_mode,
+                            new: mode,
+                        });
+                    }
+                }
+                (_, node) => changes.push(Change::Added { path, node }),
+            }
+        }
+
+        for (hash, paths) in removed_by_hash {
+            changes.extend(paths.into_iter().map(|(path, _)| Change::Removed {
+                path,
+                node: Node::File { hash: hash.clone() },
+            }));
+        }
+
+        // Stable, so that renames come before the mode changes of their new paths
+        changes.sort_by(|a, b| a.path().cmp(b.path()));
+        TreeDiff { changes }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::{ObjectHash, Stream};
+    use crate::tree::Symlink;
+
+    fn file(name: &str, contents: &str) -> Stream {
+        Stream {
+            hash: ObjectHash::new_unchecked(contents),
+            file_name: name.into(),
+            compression: None,
+            dictionary: None,
+            disk_size: None,
+            network_size: None,
+            #[cfg(unix)]
+            mode: None,
+            #[cfg(unix)]
+            mtime: None,
+            #[cfg(unix)]
+            owner: None,
+        }
+    }
+
+    fn tree(streams: Vec<Stream>, subtrees: Vec<(&str, Tree)>) -> Tree {
+        Tree {
+            permissions: 0o755,
+            streams,
+            subtrees: subtrees
+                .into_iter()
+                .map(|(name, tree)| (TreePath::new_unchecked(name), tree))
+                .collect(),
+            symlinks: Vec::new(),
+            specials: Vec::new(),
+            meta: None,
+        }
+    }
+
+    fn path(path: &str) -> TreePath {
+        TreePath::new(path).expect("valid test path")
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut old = tree(
+            vec![file("same", "1"), file("changed", "2"), file("gone", "3")],
+            vec![(
+                "dir",
+                tree(
+                    vec![file("a", "4"), file("b", "5"), file("dup", "6")],
+                    vec![],
+                ),
+            )],
+        );
+        old.streams.push(file("dup", "6"));
+        old.symlinks.push(Symlink {
+            file_name: "link".into(),
+            target: "same".into(),
+        });
+
+        let mut new = tree(
+            vec![file("same", "1"), file("changed", "7"), file("fresh", "8")],
+            vec![("moved", tree(vec![file("a", "4"), file("b", "5")], vec![]))],
+        );
+        new.streams.push(file("dup", "6"));
+        new.subtrees[0].1.streams[0].mode = Some(0o100_755);
+        new.streams[0].mode = Some(0o100_600);
+        new.symlinks.push(Symlink {
+            file_name: "link".into(),
+            target: "changed".into(),
+        });
+
+        assert!(old.diff(&old).is_empty());
+
+        let diff = old.diff(&new);
+        assert_eq!(
+            diff.stats(),
+            DiffStats {
+                added: 1,
+                removed: 2,
+                modified: 1,
+                renamed: 2,
+                mode_changed: 2,
+                retargeted: 1,
+            }
+        );
+        assert_eq!(
+            diff.changes(),
+            [
+                Change::Modified {
+                    path: path("changed"),
+                    old: Node::File { hash: "2".into() },
+                    new: Node::File { hash: "7".into() },
+                },
+                // Only one of the two copies of "dup" was removed
+                Change::Removed {
+                    path: path("dir/dup"),
+                    node: Node::File { hash: "6".into() },
+                },
+                Change::Added {
+                    path: path("fresh"),
+                    node: Node::File { hash: "8".into() },
+                },
+                Change::Removed {
+                    path: path("gone"),
+                    node: Node::File { hash: "3".into() },
+                },
+                Change::Retargeted {
+                    path: path("link"),
+                    old: "same".into(),
+                    new: "changed".into(),
+                },
+                Change::Renamed {
+                    from: path("dir/a"),
+                    to: path("moved/a"),
+                    hash: "4".into(),
+                },
+                Change::ModeChanged {
+                    path: path("moved/a"),
+                    old: None,
+                    new: Some(0o100_755),
+                },
+                Change::Renamed {
+                    from: path("dir/b"),
+                    to: path("moved/b"),
+                    hash: "5".into(),
+                },
+                Change::ModeChanged {
+                    path: path("same"),
+                    old: None,
+                    new: Some(0o100_600),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,162 @@
+//! Writing trees out as tar archives, for systems that only take tarballs.
+//!
+//! Archives hold the tree the way [`Tree::deploy`] would lay it out, from the root directory
+//! (`./`) down, with every directory right before its contents. Modes, symlinks, FIFOs, and the
+//! mtimes and owners the store captured are kept. Anything not captured is written as 0, so the
+//! same tree always gives the same archive.
+use std::io::{self, Write};
+
+use crate::store::Store;
+use crate::tree::walk::TreeEntry;
+use crate::tree::{SpecialKind, Tree, check_deployable};
+
+/// The mode of files whose stream didn't record one.
+const DEFAULT_FILE_MODE: u32 = 0o644;
+
+impl Tree {
+    /// Streams the tree into `writer` as a tar archive, reading every file's contents from the
+    /// store. Names are checked and sanitized as they would be for a deploy.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically a stream missing from the store)
+    /// - Errors writing to `writer`
+    /// - See [`Tree::deploy`] for the checks on names and symlinks
+    pub fn export_tar<W: Write>(&self, writer: W, store: &Store) -> crate::Result<()> {
+        let tree = check_deployable(self, store)?;
+        let mut archive = tar::Builder::new(writer);
+
+        for (path, entry) in tree.walk() {
+            let mut header = tar::Header::new_gnu();
+            header.set_mtime(0);
+            let path = if path.is_root() {
+                "./".as_ref()
+            } else {
+                path.as_path()
+            };
+
+            match entry {
+                TreeEntry::Dir(tree) => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_mode(tree.permissions & 0o7777);
+                    header.set_size(0);
+                    archive.append_data(&mut header, path, io::empty())?;
+                }
+                TreeEntry::File(stream) => {
+                    let file = std::fs::File::open(store.path_of(&stream.hash))?;
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_mode(stream.mode.unwrap_or(DEFAULT_FILE_MODE) & store.mode_mask());
+                    header.set_size(file.metadata()?.len());
+                    if let Some(mtime) = stream.mtime {
+                        header.set_mtime(u64::try_from(mtime).unwrap_or(0));
+                    }
+                    if let Some(owner) = stream.owner {
+                        header.set_uid(owner.uid.into());
+                        header.set_gid(owner.gid.into());
+                    }
+                    archive.append_data(&mut header, path, file)?;
+                }
+                TreeEntry::Symlink(symlink) => {
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    header.set_mode(0o777);
+                    header.set_size(0);
+                    archive.append_link(&mut header, path, &symlink.target)?;
+                }
+                TreeEntry::Special(special) => {
+                    header.set_entry_type(match special.kind {
+                        SpecialKind::Fifo => tar::EntryType::Fifo,
+                    });
+                    header.set_mode(special.mode & 0o7777);
+                    header.set_size(0);
+                    archive.append_data(&mut header, path, io::empty())?;
+                }
+            }
+        }
+
+        archive.into_inner()?.flush()?;
+        Ok(())
+    }
+
+    /// Like [`Tree::export_tar`], compressing the archive with zstd at `level` (`.tar.zst`).
+    ///
+    /// # Errors
+    ///
+    /// - See [`Tree::export_tar`]
+    pub fn export_tar_zst<W: Write>(
+        &self,
+        writer: W,
+        store: &Store,
+        level: i32,
+    ) -> crate::Result<()> {
+        let mut encoder = zstd::stream::write::Encoder::new(writer, level)?;
+        self.export_tar(&mut encoder, store)?;
+        encoder.finish()?.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::CompressionKind;
+    use crate::fs;
+
+    #[tokio::test]
+    async fn test_export_tar() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let original_dir = TempDir::new()?;
+        let unpack_dir = TempDir::new()?;
+
+        std::fs::create_dir(original_dir.path().join("bin"))?;
+        fs::write(original_dir.path().join("bin/tool"), b"#!/bin/sh").await?;
+        std::fs::set_permissions(
+            original_dir.path().join("bin/tool"),
+            std::fs::Permissions::from_mode(0o755),
+        )?;
+        fs::write(original_dir.path().join("readme"), b"hello").await?;
+        std::os::unix::fs::symlink("bin/tool", original_dir.path().join("tool"))?;
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+
+        let mut archive = Vec::new();
+        tree.export_tar(&mut archive, &store)?;
+        let mut compressed = Vec::new();
+        tree.export_tar_zst(&mut compressed, &store, 3)?;
+        assert!(compressed.len() < archive.len());
+
+        // The same tree gives the same archive, whichever way it's compressed
+        let mut decompressed = Vec::new();
+        zstd::stream::read::Decoder::new(&compressed[..])?.read_to_end(&mut decompressed)?;
+        assert_eq!(decompressed, archive);
+
+        let mut entries = Vec::new();
+        for entry in tar::Archive::new(&archive[..]).entries()? {
+            let entry = entry?;
+            entries.push(entry.path()?.into_owned());
+        }
+        assert_eq!(
+            entries,
+            ["./", "readme", "tool", "bin/", "bin/tool"].map(Path::new)
+        );
+
+        tar::Archive::new(&archive[..]).unpack(unpack_dir.path())?;
+        let tool = unpack_dir.path().join("bin/tool");
+        assert_eq!(std::fs::read(&tool)?, b"#!/bin/sh");
+        assert_eq!(
+            std::fs::metadata(&tool)?.permissions().mode() & 0o777,
+            0o755
+        );
+        assert_eq!(
+            std::fs::read_link(unpack_dir.path().join("tool"))?,
+            Path::new("bin/tool")
+        );
+        assert_eq!(std::fs::read(unpack_dir.path().join("readme"))?, b"hello");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+//! Leaving files out of trees.
+//!
+//! A [`Filter`] takes gitignore-style patterns, so
+//! [`Tree::create_with`](crate::tree::Tree::create_with) can skip build output, VCS metadata and caches instead of storing them. Patterns can also be
+//! read from `.syncstreamignore` files in the directories being stored.
+use ignore::Match;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::io;
+use std::path::Path;
+
+/// The name of ignore files read when [enabled](Filter::ignore_files).
+pub const IGNORE_FILE: &str = ".syncstreamignore";
+
+/// What [`Tree::create_with`](crate::tree::Tree::create_with) leaves out, see
+/// [`CreateOptions::with_filter`](crate::tree::create::CreateOptions::with_filter). Excludes
+/// nothing by default.
+///
+/// Patterns follow `.gitignore` syntax, relative to the directory the tree is created from:
+/// `target/` only matches directories, `/build` only at the top, and `!keep.log` includes
+/// something an earlier pattern excluded. Later patterns win, and patterns from ignore files win
+/// over those given here, deeper ones first.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Filter {
+    patterns: Vec<String>,
+    ignore_files: bool,
+}
+
+impl Filter {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Leaves out everything matching the pattern.
+    #[must_use]
+    pub fn exclude(mut self, pattern: &str) -> Self {
+        self.patterns.push(pattern.to_string());
+        self
+    }
+
+    /// Keeps everything matching the pattern, even if an earlier one excluded it. Like in git,
+    /// nothing inside an excluded directory can be included again.
+    #[must_use]
+    pub fn include(mut self, pattern: &str) -> Self {
+        self.patterns.push(format!("!{pattern}"));
+        self
+    }
+
+    /// Reads more patterns from [`.syncstreamignore`](IGNORE_FILE) files, which apply to the
+    /// directory they're in and everything below it.
+    #[must_use]
+    pub fn ignore_files(mut self, enabled: bool) -> Self {
+        self.ignore_files = enabled;
+        self
+    }
+
+    /// The patterns given here, rooted at `root`.
+    pub(crate) fn build(&self, root: &Path) -> io::Result<Ignores> {
+        let mut builder = GitignoreBuilder::new(root);
+        for pattern in &self.patterns {
+            builder.add_line(None, pattern).map_err(invalid)?;
+        }
+        Ok(Ignores {
+            ignores: vec![builder.build().map_err(invalid)?],
+            ignore_files: self.ignore_files,
+        })
+    }
+}
+
+fn invalid(err: ignore::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err)
+}
+
+/// Compiled patterns for one directory, including those from ignore files above it.
+#[derive(Clone, Debug)]
+pub(crate) struct Ignores {
+    /// Shallowest first
+    ignores: Vec<Gitignore>,
+    ignore_files: bool,
+}
+
+impl Ignores {
+    /// The patterns for `dir`, a directory inside the one these apply to.
+    pub(crate) fn enter(&self, dir: &Path) -> io::Result<Ignores> {
+        let file = dir.join(IGNORE_FILE);
+        if !self.ignore_files || !file.is_file() {
+            return Ok(self.clone());
+        }
+
+        let mut builder = GitignoreBuilder::new(dir);
+        if let Some(err) = builder.add(&file) {
+            return Err(invalid(err));
+        }
+        let mut ignores = self.clone();
+        ignores.ignores.push(builder.build().map_err(invalid)?);
+        Ok(ignores)
+    }
+
+    /// Whether `path` is left out.
+    pub(crate) fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        for ignore in self.ignores.iter().rev() {
+            match ignore.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use super::{Filter, IGNORE_FILE};
+    use crate::CompressionKind;
+    use crate::fs;
+    use crate::store::Store;
+    use crate::tree::create::CreateOptions;
+    use crate::tree::{Tree, TreePath};
+
+    /// Every file and directory in the tree.
+    fn paths(tree: &Tree) -> Vec<String> {
+        let mut paths = Vec::new();
+        let mut pending = vec![(TreePath::root(), tree)];
+        while let Some((path, tree)) = pending.pop() {
+            for stream in &tree.streams {
+                let name = TreePath::new_unchecked(&stream.file_name);
+                paths.push(path.join(&name).to_string());
+            }
+            for (name, subtree) in &tree.subtrees {
+                paths.push(path.join(name).to_string());
+                pending.push((path.join(name), subtree));
+            }
+        }
+        paths.sort();
+        paths
+    }
+
+    #[tokio::test]
+    async fn test_filter() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let original_dir = TempDir::new()?;
+        let original = |path: &str| original_dir.path().join(path);
+
+        std::fs::create_dir_all(original("target/debug"))?;
+        std::fs::create_dir_all(original("src/target"))?;
+        std::fs::create_dir_all(original("logs"))?;
+        fs::write(original("target/debug/app"), b"binary").await?;
+        fs::write(original("src/main.rs"), b"fn main() {}").await?;
+        fs::write(original("src/cache.tmp"), b"cache").await?;
+        fs::write(original("logs/old.log"), b"old").await?;
+        fs::write(original("logs/keep.log"), b"keep").await?;
+
+        // Anchored and directory-only patterns, and including a file again
+        let filter = Filter::new()
+            .exclude("/target/")
+            .exclude("*.log")
+            .include("keep.log");
+        let store = Store::new(store_dir.path());
+        let options = CreateOptions::new().with_filter(filter.clone());
+        let tree =
+            Tree::create_with(&store, original_dir.path(), CompressionKind::None, &options).await?;
+        // Only the top `target` is left out
+        assert_eq!(
+            paths(&tree),
+            [
+                "logs",
+                "logs/keep.log",
+                "src",
+                "src/cache.tmp",
+                "src/main.rs",
+                "src/target"
+            ]
+        );
+
+        // Ignore files are only read when enabled, and apply below their directory
+        fs::write(original(&format!("src/{IGNORE_FILE}")), b"*.tmp\n").await?;
+        let tree =
+            Tree::create_with(&store, original_dir.path(), CompressionKind::None, &options).await?;
+        assert!(paths(&tree).contains(&"src/cache.tmp".to_string()));
+
+        let options = options.with_filter(filter.ignore_files(true));
+        let tree =
+            Tree::create_with(&store, original_dir.path(), CompressionKind::None, &options).await?;
+        assert_eq!(
+            paths(&tree),
+            [
+                "logs",
+                "logs/keep.log",
+                "src",
+                &format!("src/{IGNORE_FILE}"),
+                "src/main.rs",
+                "src/target"
+            ]
+        );
+
+        let options = options.with_filter(Filter::new().exclude("[z-a]"));
+        assert!(
+            Tree::create_with(&store, original_dir.path(), CompressionKind::None, &options)
+                .await
+                .is_err()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+//! Building trees straight from git commits.
+//!
+//! [`Tree::from_git`] reads a commit's tree out of the repository's object database, without a
+//! checkout, and stores each blob as a stream. The result is what a fresh checkout would hold,
+//! minus the `.git` directory, so releases can be published from a bare repository or a CI
+//! cache. Like `git archive`, submodules become empty directories.
+use git2::{ObjectType, Oid, Repository, TreeWalkMode, TreeWalkResult};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::CompressionKind;
+use crate::store::Store;
+use crate::stream::Stream;
+use crate::tree::Tree;
+use crate::tree::builder::TreeBuilder;
+use crate::tree::meta::TreeMeta;
+
+const EXECUTABLE: i32 = 0o100_755;
+const SYMLINK: i32 = 0o120_000;
+const COMMIT: i32 = 0o160_000;
+
+impl Tree {
+    /// Stores the tree of the commit `rev` resolves to in the git repository at `repo_path`,
+    /// like `HEAD`, a tag or a commit id. The tree's [metadata](Tree::meta) records the commit
+    /// as the `git.commit` annotation, and its time as when the tree was created.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::NotFound`](crate::Error::NotFound) if `rev` doesn't resolve to a commit
+    /// - [`Error::GitError`](crate::Error::GitError) if the repository can't be read
+    /// - Out of storage/Permissions Errors
+    pub async fn from_git(
+        store: &Store,
+        repo_path: &Path,
+        rev: &str,
+        compression: CompressionKind,
+    ) -> crate::Result<Tree> {
+        let repo = Repository::open(repo_path).map_err(|e| git_error(&e))?;
+        let commit = repo
+            .revparse_single(rev)
+            .and_then(|object| object.peel_to_commit())
+            .map_err(|e| match e.code() {
+                git2::ErrorCode::NotFound | git2::ErrorCode::Peel => {
+                    crate::Error::NotFound(format!("git revision {rev}"))
+                }
+                _ => git_error(&e),
+            })?;
+
+        let mut entries = Vec::new();
+        commit
+            .tree()
+            .and_then(|tree| {
+                tree.walk(TreeWalkMode::PreOrder, |parent, entry| {
+                    let path = Path::new(OsStr::from_bytes(parent.as_bytes()))
+                        .join(OsStr::from_bytes(entry.name_bytes()));
+                    entries.push((path, entry.id(), entry.filemode(), entry.kind()));
+                    TreeWalkResult::Ok
+                })
+            })
+            .map_err(|e| git_error(&e))?;
+
+        let mut builder = TreeBuilder::new();
+        let mut stored: HashMap<Oid, Stream> = HashMap::new();
+        for (path, id, mode, kind) in entries {
+            builder = match (kind, mode) {
+                (Some(ObjectType::Tree), _) | (_, COMMIT) => builder.add_dir(path)?,
+                (_, SYMLINK) => {
+                    let blob = repo.find_blob(id).map_err(|e| git_error(&e))?;
+                    let target = PathBuf::from(OsStr::from_bytes(blob.content()));
+                    builder.add_symlink(path, target)?
+                }
+                _ => {
+                    let mut stream = if let Some(stream) = stored.get(&id) {
+                        stream.clone()
+                    } else {
+                        let stream = store_blob(&repo, id, store, compression).await?;
+                        stored.insert(id, stream.clone());
+                        stream
+                    };
+                    stream.mode = Some(if mode == EXECUTABLE {
+                        0o100_755
+                    } else {
+                        0o100_644
+                    });
+                    builder.add_file(path, stream)?
+                }
+            };
+        }
+
+        let mut tree = builder.build();
+        tree.meta = Some(TreeMeta {
+            created_at: u64::try_from(commit.time().seconds()).ok(),
+            ..TreeMeta::default().annotation("git.commit", commit.id().to_string())
+        });
+        Ok(tree)
+    }
+}
+
+/// Stores a blob's contents as a stream, by way of a temporary file in the store.
+async fn store_blob(
+    repo: &Repository,
+    id: Oid,
+    store: &Store,
+    compression: CompressionKind,
+) -> crate::Result<Stream> {
+    let tmp = store.temp_path("git");
+    std::fs::write(
+        &tmp,
+        repo.find_blob(id).map_err(|e| git_error(&e))?.content(),
+    )?;
+    let res = Stream::create(&tmp, store, compression).await;
+    std::fs::remove_file(&tmp)?;
+
+    // Nothing about the temporary file says anything about the blob
+    let mut stream = res?;
+    stream.mtime = None;
+    stream.owner = None;
+    Ok(stream)
+}
+
+fn git_error(e: &git2::Error) -> crate::Error {
+    crate::Error::GitError(e.message().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::fs;
+    use crate::tree::walk::TreeEntry;
+
+    fn commit(repo: &Repository, message: &str) -> Result<Oid, git2::Error> {
+        let mut index = repo.index()?;
+        index.add_all(["*"], git2::IndexAddOption::DEFAULT, None)?;
+        index.write()?;
+        let tree = repo.find_tree(index.write_tree()?)?;
+        let signature = git2::Signature::new("Test", "test@example.com", &git2::Time::new(1, 0))?;
+        let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            parent.as_slice().iter().collect::<Vec<_>>().as_slice(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_from_git() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let repo_dir = TempDir::new()?;
+        let repo = Repository::init(repo_dir.path()).map_err(|e| git_error(&e))?;
+
+        std::fs::create_dir(repo_dir.path().join("bin"))?;
+        fs::write(repo_dir.path().join("bin/tool"), b"#!/bin/sh").await?;
+        std::fs::set_permissions(
+            repo_dir.path().join("bin/tool"),
+            std::fs::Permissions::from_mode(0o755),
+        )?;
+        fs::write(repo_dir.path().join("readme"), b"hello").await?;
+        fs::write(repo_dir.path().join("copy"), b"hello").await?;
+        std::os::unix::fs::symlink("bin/tool", repo_dir.path().join("tool"))?;
+        let first = commit(&repo, "first").map_err(|e| git_error(&e))?;
+
+        // Later commits and uncommitted changes don't matter
+        fs::write(repo_dir.path().join("readme"), b"changed").await?;
+        commit(&repo, "second").map_err(|e| git_error(&e))?;
+        fs::write(repo_dir.path().join("untracked"), b"untracked").await?;
+
+        let tree = Tree::from_git(
+            &store,
+            repo_dir.path(),
+            &first.to_string(),
+            CompressionKind::Zstd,
+        )
+        .await?;
+        let meta = tree.meta.as_ref().expect("trees from git have metadata");
+        assert_eq!(meta.annotations["git.commit"], first.to_string());
+        assert_eq!(meta.created_at, Some(1));
+        assert!(tree.get("untracked").is_none());
+        let Some(TreeEntry::Symlink(link)) = tree.get("tool") else {
+            panic!("expected tool to be a symlink");
+        };
+        assert_eq!(link.target, Path::new("bin/tool"));
+        let Some(TreeEntry::File(tool)) = tree.get("bin/tool") else {
+            panic!("expected bin/tool to be a file");
+        };
+        assert_eq!(tool.mode, Some(0o100_755));
+
+        let deploy_dir = TempDir::new()?;
+        tree.deploy(&store, deploy_dir.path())?;
+        assert_eq!(std::fs::read(deploy_dir.path().join("readme"))?, b"hello");
+        assert_eq!(std::fs::read(deploy_dir.path().join("copy"))?, b"hello");
+
+        let head = Tree::from_git(&store, repo_dir.path(), "HEAD", CompressionKind::Zstd).await?;
+        assert_ne!(head.hash(), tree.hash());
+        assert!(matches!(
+            Tree::from_git(&store, repo_dir.path(), "missing", CompressionKind::Zstd).await,
+            Err(crate::Error::NotFound(_))
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,229 @@
+//! Canonical tree hashes.
+//!
+//! Every directory is hashed on its own, and its parent includes that hash rather than its
+//! contents, so the hash of the root is a Merkle root over the whole tree. Entries are sorted by
+//! name and every field is length-prefixed, so neither the order trees were built in nor
+//! unusual file names can make two different trees hash the same.
+use blake3::Hasher;
+use std::ffi::OsStr;
+
+use crate::stream::Owner;
+use crate::tree::{SpecialKind, Tree};
+
+/// Bumped whenever the encoding changes, so old and new hashes never collide.
+const VERSION: &[u8] = b"syncstream-tree-v1";
+
+enum Item<'a> {
+    File {
+        hash: &'a str,
+        mode: Option<u32>,
+        mtime: Option<i64>,
+        owner: Option<Owner>,
+    },
+    Symlink {
+        target: &'a OsStr,
+    },
+    Special {
+        kind: SpecialKind,
+        mode: u32,
+    },
+    Dir {
+        hash: blake3::Hash,
+    },
+}
+
+fn write_bytes(hasher: &mut Hasher, bytes: &[u8]) {
+    hasher.update(&(bytes.len() as u64).to_le_bytes());
+    hasher.update(bytes);
+}
+
+impl Tree {
+    /// A stable digest of everything the tree describes: names, stream hashes, modes, captured
+    /// mtimes and owners, symlinks, special files, directory permissions and subtrees.
+    ///
+    /// Two trees with the same hash deploy identically, so this can be used to compare trees or
+    /// check that a manifest wasn't changed in transport.
+    #[must_use]
+    pub fn hash(&self) -> String {
+        self.merkle_hash().to_hex().to_string()
+    }
+
+    fn merkle_hash(&self) -> blake3::Hash {
+        let mut items: Vec<(&OsStr, Item)> = Vec::new();
+        for stream in &self.streams {
+            #[cfg(unix)]
+            let (mode, mtime, owner) = (stream.mode, stream.mtime, stream.owner);
+            #[cfg(not(unix))]
+            let (mode, mtime, owner) = (None, None, None);
+
+            items.push((
+                &stream.file_name,
+                Item::File {
+                    hash: &stream.hash,
+                    mode,
+                    mtime,
+                    owner,
+                },
+            ));
+        }
+        for symlink in &self.symlinks {
+            items.push((
+                &symlink.file_name,
+                Item::Symlink {
+                    target: symlink.target.as_os_str(),
+                },
+            ));
+        }
+        for special in &self.specials {
+            items.push((
+                &special.file_name,
+                Item::Special {
+                    kind: special.kind,
+                    mode: special.mode,
+                },
+            ));
+        }
+        for (name, subtree) in &self.subtrees {
+            items.push((
+                name.as_os_str(),
+                Item::Dir {
+                    hash: subtree.merkle_hash(),
+                },
+            ));
+        }
+        items.sort_by_key(|(name, _)| *name);
+
+        let mut hasher = Hasher::new();
+        write_bytes(&mut hasher, VERSION);
+        hasher.update(&self.permissions.to_le_bytes());
+        hasher.update(&(items.len() as u64).to_le_bytes());
+
+        for (name, item) in items {
+            match item {
+                Item::File {
+                    hash,
+                    mode,
+                    mtime,
+                    owner,
+                } => {
+                    hasher.update(b"f");
+                    write_bytes(&mut hasher, name.as_encoded_bytes());
+                    write_bytes(&mut hasher, hash.as_bytes());
+                    match mode {
+                        Some(mode) => hasher.update(b"m").update(&mode.to_le_bytes()),
+                        None => hasher.update(b"-"),
+                    };
+                    // Only when captured, so trees without mtimes keep their hashes
+                    if let Some(mtime) = mtime {
+                        hasher.update(b"t").update(&mtime.to_le_bytes());
+                    }
+                    if let Some(owner) = owner {
+                        hasher.update(b"o");
+                        hasher.update(&owner.uid.to_le_bytes());
+                        hasher.update(&owner.gid.to_le_bytes());
+                    }
+                }
+                Item::Symlink { target } => {
+                    hasher.update(b"l");
+                    write_bytes(&mut hasher, name.as_encoded_bytes());
+                    write_bytes(&mut hasher, target.as_encoded_bytes());
+                }
+                Item::Special { kind, mode } => {
+                    hasher.update(b"s");
+                    write_bytes(&mut hasher, name.as_encoded_bytes());
+                    hasher.update(match kind {
+                        SpecialKind::Fifo => b"p",
+                    });
+                    hasher.update(&mode.to_le_bytes());
+                }
+                Item::Dir { hash } => {
+                    hasher.update(b"d");
+                    write_bytes(&mut hasher, name.as_encoded_bytes());
+                    hasher.update(hash.as_bytes());
+                }
+            }
+        }
+
+        hasher.finalize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stream::{ObjectHash, Stream};
+    use crate::tree::{Symlink, Tree, TreePath};
+
+    fn file(name: &str, hash: &str, mode: Option<u32>) -> Stream {
+        Stream {
+            hash: ObjectHash::new_unchecked(hash),
+            file_name: name.into(),
+            compression: None,
+            dictionary: None,
+            disk_size: None,
+            network_size: None,
+            #[cfg(unix)]
+            mode,
+            #[cfg(unix)]
+            mtime: None,
+            #[cfg(unix)]
+            owner: None,
+        }
+    }
+
+    fn tree(streams: Vec<Stream>, subtrees: Vec<(&str, Tree)>) -> Tree {
+        Tree {
+            permissions: 0o755,
+            streams,
+            subtrees: subtrees
+                .into_iter()
+                .map(|(name, tree)| (TreePath::new_unchecked(name), tree))
+                .collect(),
+            symlinks: Vec::new(),
+            specials: Vec::new(),
+            meta: None,
+        }
+    }
+
+    #[test]
+    fn test_tree_hash() {
+        let sub = || tree(vec![file("c", "3", None)], vec![]);
+        let base = tree(
+            vec![file("a", "1", None), file("b", "2", Some(0o644))],
+            vec![("sub", sub())],
+        );
+        let hash = base.hash();
+        assert_eq!(hash.len(), 64);
+
+        // Order doesn't matter
+        let reordered = tree(
+            vec![file("b", "2", Some(0o644)), file("a", "1", None)],
+            vec![("sub", sub())],
+        );
+        assert_eq!(reordered.hash(), hash);
+
+        // Every field does
+        let mut changed = Vec::new();
+        let mut mode = base.clone();
+        mode.streams[1].mode = Some(0o755);
+        changed.push(mode);
+        let mut permissions = base.clone();
+        permissions.permissions = 0o700;
+        changed.push(permissions);
+        let mut nested = base.clone();
+        nested.subtrees[0].1.streams[0].hash = ObjectHash::new_unchecked("4");
+        changed.push(nested);
+        let mut renamed = base.clone();
+        renamed.streams[0].file_name = "z".into();
+        changed.push(renamed);
+        let mut linked = base.clone();
+        linked.symlinks.push(Symlink {
+            file_name: "link".into(),
+            target: "a".into(),
+        });
+        changed.push(linked);
+
+        for tree in changed {
+            assert_ne!(tree.hash(), hash, "{tree:?}");
+        }
+    }
+}
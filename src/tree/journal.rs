@@ -0,0 +1,330 @@
+//! Recovering deploys that were interrupted halfway.
+//!
+//! Before changing anything, [`Tree::deploy`] writes its [plan](DeployPlan) into
+//! [`JOURNAL_NAME`] in the root of the deployment, and appends every action to it once done.
+//! Files in the way are moved into the journal instead of being deleted. The journal is removed
+//! once the tree is recorded as deployed, so finding one means a deploy never finished:
+//! [`Tree::resume_deploy`] carries out the remaining actions, and [`Tree::undo_deploy`] reverts
+//! the ones that were done.
+//!
+//! ```text
+//! {deploy_path}/.syncstream-journal/plan.json
+//! {deploy_path}/.syncstream-journal/done
+//! {deploy_path}/.syncstream-journal/backup/{action}
+//! ```
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use crate::store::Store;
+use crate::tree::deploy::{Dir, name, parent};
+use crate::tree::deploy_plan::{DeployAction, DeployPlan};
+use crate::tree::deployment::{Deployment, RECORD_NAME};
+use crate::tree::{Tree, TreePath};
+
+/// The name of the journal, in the root of the deployment.
+pub const JOURNAL_NAME: &str = ".syncstream-journal";
+
+const PLAN_NAME: &str = "plan.json";
+const DONE_NAME: &str = "done";
+const BACKUP_NAME: &str = "backup";
+
+#[derive(Serialize, Deserialize)]
+struct Header<'a> {
+    plan: Cow<'a, DeployPlan>,
+    /// The record of what was deployed before, restored by undoing
+    previous: Option<Deployment>,
+}
+
+/// A line of the journal, written once an action is done.
+#[derive(Serialize, Deserialize)]
+struct Done {
+    action: usize,
+    /// What a directory's permissions were before [`DeployAction::SetPermissions`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    permissions: Option<u32>,
+}
+
+/// An open journal of a deploy in progress.
+pub(crate) struct Journal {
+    dir: Dir,
+    done: std::fs::File,
+}
+
+/// A journal left behind by a deploy that never finished.
+struct Interrupted {
+    journal: Journal,
+    plan: DeployPlan,
+    previous: Option<Deployment>,
+    done: Vec<Done>,
+}
+
+impl Journal {
+    /// Starts the journal of deploying `plan` into `root`, which is at `deploy_path`.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InterruptedDeploy`](crate::Error::InterruptedDeploy) if a journal is already
+    ///   there
+    pub(crate) fn begin(root: &Dir, deploy_path: &Path, plan: &DeployPlan) -> crate::Result<Self> {
+        match root.open_path(Path::new(JOURNAL_NAME)) {
+            Ok(dir) if dir.stat(OsStr::new(PLAN_NAME))?.is_some() => {
+                return Err(crate::Error::InterruptedDeploy(deploy_path.to_path_buf()));
+            }
+            // Crashed before anything was deployed
+            Ok(_) => root.remove_all(OsStr::new(JOURNAL_NAME), true)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let header = Header {
+            plan: Cow::Borrowed(plan),
+            previous: Deployment::load(deploy_path)?,
+        };
+        let dir = root.create_path(Path::new(JOURNAL_NAME))?;
+        let done = dir.append_file(OsStr::new(DONE_NAME))?;
+        // Written last, as it's what makes the journal count
+        dir.write_atomic(OsStr::new(PLAN_NAME), &serde_json::to_vec(&header)?)?;
+        Ok(Self { dir, done })
+    }
+
+    /// Records that an action is done.
+    pub(crate) fn performed(&self, action: usize, permissions: Option<u32>) -> crate::Result<()> {
+        let mut line = serde_json::to_vec(&Done {
+            action,
+            permissions,
+        })?;
+        line.push(b'\n');
+        (&self.done).write_all(&line)?;
+        Ok(())
+    }
+
+    /// Moves `name` out of the way into the journal, where undoing the deploy can restore it
+    /// from. Files on another filesystem than the journal are removed instead.
+    pub(crate) fn back_up(&self, action: usize, dir: &Dir, name: &OsStr) -> io::Result<()> {
+        let backup = self.dir.create_path(Path::new(BACKUP_NAME))?;
+        match dir.rename(name, &backup, action.to_string().as_ref()) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => dir.remove_file(name),
+            res => res,
+        }
+    }
+
+    /// Removes the journal, along with everything it backed up.
+    pub(crate) fn finish(self, root: &Dir) -> io::Result<()> {
+        drop(self.done);
+        root.remove_all(OsStr::new(JOURNAL_NAME), true)
+    }
+}
+
+impl Interrupted {
+    fn open(root: &Dir) -> crate::Result<Option<Self>> {
+        let dir = match root.open_path(Path::new(JOURNAL_NAME)) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let plan = match dir.open_file(OsStr::new(PLAN_NAME)) {
+            Ok(plan) => plan,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let header: Header = serde_json::from_reader(io::BufReader::new(plan))?;
+
+        let mut done = Vec::new();
+        for line in io::BufReader::new(dir.open_file(OsStr::new(DONE_NAME))?).lines() {
+            // The last line may be cut off by the crash
+            match serde_json::from_str(&line?) {
+                Ok(line) => done.push(line),
+                Err(_) => break,
+            }
+        }
+
+        Ok(Some(Self {
+            journal: Journal {
+                done: dir.append_file(OsStr::new(DONE_NAME))?,
+                dir,
+            },
+            plan: header.plan.into_owned(),
+            previous: header.previous,
+            done,
+        }))
+    }
+
+    /// The first action that wasn't recorded as done. It may have been partly carried out.
+    fn next(&self) -> usize {
+        self.done.last().map_or(0, |done| done.action + 1)
+    }
+}
+
+impl Tree {
+    /// Finishes a deploy of this tree into `deploy_path` that was interrupted, carrying out the
+    /// actions its [journal](crate::tree::journal) says weren't done.
+    ///
+    /// # Errors
+    ///
+    /// - Out of storage/Permissions Errors
+    /// - [`Error::NotFound`](crate::Error::NotFound) if no deploy was interrupted there
+    /// - [`Error::DeploymentMismatch`](crate::Error::DeploymentMismatch) if the interrupted deploy
+    ///   was of another tree
+    /// - [`Error::ManifestError`](crate::Error::ManifestError) if the journal is corrupted
+    pub fn resume_deploy(&self, store: &Store, deploy_path: &Path) -> crate::Result<()> {
+        let root = Dir::open(deploy_path)?;
+        let interrupted = Interrupted::open(&root)?.ok_or_else(|| {
+            crate::Error::NotFound(format!("journal in {}", deploy_path.display()))
+        })?;
+        if interrupted.plan.tree_hash() != self.hash() {
+            return Err(crate::Error::DeploymentMismatch(
+                self.hash(),
+                interrupted.plan.tree_hash().to_string(),
+            ));
+        }
+
+        let next = interrupted.next();
+        interrupted
+            .plan
+            .run(next, interrupted.journal, store, &root)
+    }
+
+    /// Reverts a deploy into `deploy_path` that was interrupted, whichever tree it was of. Files
+    /// it replaced are restored, unless they were on another filesystem than the deployment's
+    /// root, and so was the record of what was deployed before. A directory whose permissions
+    /// were being changed when the deploy stopped may keep its new ones.
+    ///
+    /// # Errors
+    ///
+    /// - Permissions Errors
+    /// - [`Error::NotFound`](crate::Error::NotFound) if no deploy was interrupted there
+    /// - [`Error::ManifestError`](crate::Error::ManifestError) if the journal is corrupted
+    pub fn undo_deploy(deploy_path: &Path) -> crate::Result<()> {
+        let root = Dir::open(deploy_path)?;
+        let interrupted = Interrupted::open(&root)?.ok_or_else(|| {
+            crate::Error::NotFound(format!("journal in {}", deploy_path.display()))
+        })?;
+        let permissions: HashMap<usize, u32> = interrupted
+            .done
+            .iter()
+            .filter_map(|done| Some((done.action, done.permissions?)))
+            .collect();
+        let backup = match interrupted.journal.dir.open_path(Path::new(BACKUP_NAME)) {
+            Ok(backup) => Some(backup),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        // Including the action that was in progress
+        let actions = interrupted.plan.actions();
+        let started = (interrupted.next() + 1).min(actions.len());
+        for (index, action) in actions[..started].iter().enumerate().rev() {
+            let path = action.path();
+            let Some(dir) = open_parent(&root, path)? else {
+                continue;
+            };
+            match action {
+                DeployAction::CreateDir { .. } => {
+                    dir.remove_empty_dir(name(path))?;
+                }
+                DeployAction::Link { .. }
+                | DeployAction::Copy { .. }
+                | DeployAction::Symlink { .. }
+                | DeployAction::Special { .. } => dir.remove_file(name(path))?,
+                DeployAction::Remove { .. } => {
+                    let backup_name = index.to_string();
+                    if let Some(backup) = &backup {
+                        if backup.stat(backup_name.as_ref())?.is_some() {
+                            backup.rename(backup_name.as_ref(), &dir, name(path))?;
+                        }
+                    }
+                }
+                DeployAction::SetPermissions { .. } => {
+                    if let Some(&permissions) = permissions.get(&index) {
+                        root.open_path(path)?.set_permissions(permissions)?;
+                    }
+                }
+            }
+        }
+
+        match &interrupted.previous {
+            Some(previous) => previous.write(&root)?,
+            None => root.remove_file(OsStr::new(RECORD_NAME))?,
+        }
+        interrupted.journal.finish(&root)?;
+        Ok(())
+    }
+}
+
+/// The directory containing `path`, if it exists.
+fn open_parent(root: &Dir, path: &TreePath) -> io::Result<Option<Dir>> {
+    match root.open_path(parent(path)) {
+        Ok(dir) => Ok(Some(dir)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::CompressionKind;
+    use crate::fs;
+
+    #[tokio::test]
+    async fn test_interrupted_deploy() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let original_dir = TempDir::new()?;
+        let deploy_dir = TempDir::new()?;
+        let deployed = |path: &str| std::fs::read(deploy_dir.path().join(path));
+
+        std::fs::create_dir(original_dir.path().join("sub"))?;
+        fs::write(original_dir.path().join("sub/c"), b"c").await?;
+        fs::write(original_dir.path().join("a"), b"a").await?;
+        fs::write(original_dir.path().join("b"), b"b").await?;
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+        fs::write(deploy_dir.path().join("a"), b"old").await?;
+
+        // The deploy stops at the stream missing from the store
+        std::fs::remove_file(store.path_of(&tree.streams[1].hash))?;
+        assert!(tree.deploy(&store, deploy_dir.path()).is_err());
+        assert_eq!(deployed("a")?, b"a");
+        assert!(deploy_dir.path().join(JOURNAL_NAME).exists());
+        assert!(matches!(
+            tree.deploy(&store, deploy_dir.path()),
+            Err(crate::Error::InterruptedDeploy(_))
+        ));
+
+        Tree::undo_deploy(deploy_dir.path())?;
+        assert_eq!(deployed("a")?, b"old");
+        assert!(!deploy_dir.path().join("sub").exists());
+        assert!(!deploy_dir.path().join(JOURNAL_NAME).exists());
+        assert_eq!(Deployment::load(deploy_dir.path())?, None);
+        assert!(matches!(
+            Tree::undo_deploy(deploy_dir.path()),
+            Err(crate::Error::NotFound(_))
+        ));
+
+        // Once the stream is back, the deploy can be finished instead
+        assert!(tree.deploy(&store, deploy_dir.path()).is_err());
+        Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+        let other = Tree::create(&store, deploy_dir.path(), CompressionKind::None).await?;
+        assert!(matches!(
+            other.resume_deploy(&store, deploy_dir.path()),
+            Err(crate::Error::DeploymentMismatch(..))
+        ));
+        tree.resume_deploy(&store, deploy_dir.path())?;
+        assert_eq!(deployed("b")?, b"b");
+        assert_eq!(deployed("sub/c")?, b"c");
+        assert!(!deploy_dir.path().join(JOURNAL_NAME).exists());
+        assert_eq!(
+            Deployment::load(deploy_dir.path())?.map(|record| record.tree_hash),
+            Some(tree.hash())
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,518 @@
+//! Streaming manifest format.
+//!
+//! A manifest is a list of newline-delimited JSON [`Entry`]s, written depth-first so that every
+//! directory appears before its contents. This allows very large trees to be written and read
+//! one entry at a time, and [`Tree::fetch`] to start downloading streams before the manifest has
+//! finished arriving.
+use reqwest::StatusCode;
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::CompressionKind;
+use crate::async_types::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+use crate::net;
+use crate::store::Store;
+use crate::stream::{Stream, response_reader};
+use crate::tree::meta::TreeMeta;
+use crate::tree::path::is_valid_name;
+use crate::tree::{SpecialFile, Symlink, Tree, TreePath};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Entry {
+    /// A directory, with its path relative to the root of the tree (empty for the root itself).
+    /// Only the root has [metadata](TreeMeta).
+    Tree {
+        path: TreePath,
+        permissions: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        meta: Option<TreeMeta>,
+    },
+    /// A stream inside the directory at `parent`
+    Stream { parent: TreePath, stream: Stream },
+    /// A symlink inside the directory at `parent`
+    Symlink { parent: TreePath, symlink: Symlink },
+    /// A special file inside the directory at `parent`
+    Special {
+        parent: TreePath,
+        special: SpecialFile,
+    },
+}
+
+/// What identifies the version of a fetched manifest, so that it's only fetched again once it
+/// changed, see [`Tree::fetch_if_modified`]. Pollers keep them between fetches, and can persist
+/// them to survive restarts.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestValidators {
+    /// Sent back as `If-None-Match`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    /// Sent back as `If-Modified-Since`, when there's no `ETag`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+impl ManifestValidators {
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Self {
+        let get = |name| {
+            headers
+                .get(name)
+                .and_then(|value: &HeaderValue| value.to_str().ok())
+                .map(str::to_string)
+        };
+        Self {
+            etag: get(header::ETAG),
+            last_modified: get(header::LAST_MODIFIED),
+        }
+    }
+
+    /// The headers of a request for the manifest, if it changed.
+    fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let value = |s: &str| HeaderValue::from_str(s).ok();
+        if let Some(etag) = self.etag.as_deref().and_then(value) {
+            headers.insert(header::IF_NONE_MATCH, etag);
+        } else if let Some(date) = self.last_modified.as_deref().and_then(value) {
+            headers.insert(header::IF_MODIFIED_SINCE, date);
+        }
+        headers
+    }
+}
+
+impl Entry {
+    /// Checks the file name of streams, symlinks and special files, and that only the root has
+    /// metadata. Directory paths are already checked when deserializing them.
+    pub(crate) fn validate(&self) -> crate::Result<()> {
+        let name = match self {
+            Entry::Tree { path, meta, .. } if meta.is_some() && !path.is_root() => {
+                return Err(invalid("only the root tree can have metadata"));
+            }
+            Entry::Tree { .. } => return Ok(()),
+            Entry::Stream { stream, .. } => &stream.file_name,
+            Entry::Symlink { symlink, .. } => &symlink.file_name,
+            Entry::Special { special, .. } => &special.file_name,
+        };
+
+        if is_valid_name(name) {
+            Ok(())
+        } else {
+            Err(crate::Error::InvalidPath(name.into()))
+        }
+    }
+}
+
+/// Reads [`Entry`]s one at a time from a manifest.
+pub struct ManifestReader<R> {
+    reader: R,
+    line: String,
+}
+
+impl<R: AsyncBufRead + Unpin> ManifestReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line: String::new(),
+        }
+    }
+
+    /// Reads the next entry, returning `None` at the end of the manifest.
+    ///
+    /// # Errors
+    ///
+    /// - IO/Network errors from the underlying reader
+    /// - Malformed entries
+    pub async fn next_entry(&mut self) -> crate::Result<Option<Entry>> {
+        loop {
+            self.line.clear();
+            if self.reader.read_line(&mut self.line).await? == 0 {
+                return Ok(None);
+            }
+
+            if !self.line.trim().is_empty() {
+                let entry: Entry = serde_json::from_str(&self.line)?;
+                entry.validate()?;
+                return Ok(Some(entry));
+            }
+        }
+    }
+}
+
+/// Incrementally assembles a [`Tree`] from depth-first manifest entries.
+#[derive(Default)]
+pub(crate) struct TreeAssembler {
+    /// Directories that are still open, from the root down to the most recent one
+    stack: Vec<(TreePath, Tree)>,
+}
+
+impl TreeAssembler {
+    pub(crate) fn push(&mut self, entry: Entry) -> crate::Result<()> {
+        match entry {
+            Entry::Tree {
+                path,
+                permissions,
+                meta,
+            } => {
+                let tree = Tree {
+                    permissions,
+                    streams: Vec::new(),
+                    subtrees: Vec::new(),
+                    symlinks: Vec::new(),
+                    specials: Vec::new(),
+                    meta,
+                };
+
+                if self.stack.is_empty() {
+                    if !path.is_root() {
+                        return Err(invalid("the first entry must be the root tree"));
+                    }
+                } else {
+                    let parent = path
+                        .parent()
+                        .ok_or_else(|| invalid("a second root tree was found"))?;
+                    self.close_until(parent)?;
+                }
+
+                self.stack.push((path, tree));
+            }
+            Entry::Stream { parent, stream } => {
+                self.close_until(&parent)?.streams.push(stream);
+            }
+            Entry::Symlink { parent, symlink } => {
+                self.close_until(&parent)?.symlinks.push(symlink);
+            }
+            Entry::Special { parent, special } => {
+                self.close_until(&parent)?.specials.push(special);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Closes directories until `path` is the innermost open one, and returns it.
+    fn close_until(&mut self, path: &Path) -> crate::Result<&mut Tree> {
+        if !self.stack.iter().any(|(p, _)| p.as_path() == path) {
+            return Err(invalid(&format!(
+                "entry refers to unknown directory {}",
+                path.display()
+            )));
+        }
+
+        while self.stack.last().is_some_and(|(p, _)| p.as_path() != path) {
+            self.close_last();
+        }
+
+        Ok(&mut self.stack.last_mut().expect("stack checked above").1)
+    }
+
+    fn close_last(&mut self) {
+        if let Some((path, tree)) = self.stack.pop() {
+            if let Some((_, parent)) = self.stack.last_mut() {
+                parent.subtrees.push((path.name(), tree));
+            }
+        }
+    }
+
+    pub(crate) fn finish(mut self) -> crate::Result<Tree> {
+        while self.stack.len() > 1 {
+            self.close_last();
+        }
+
+        self.stack
+            .pop()
+            .map(|(_, tree)| tree)
+            .ok_or_else(|| invalid("the manifest is empty"))
+    }
+}
+
+pub(crate) fn invalid(reason: &str) -> crate::Error {
+    crate::Error::InvalidManifest(reason.to_string())
+}
+
+impl Tree {
+    /// Writes this tree as a streaming manifest.
+    ///
+    /// # Errors
+    ///
+    /// - IO errors from the writer
+    pub async fn write_manifest<W: AsyncWrite + Unpin>(&self, mut writer: W) -> crate::Result<()> {
+        let mut pending = vec![(TreePath::root(), self)];
+
+        while let Some((path, tree)) = pending.pop() {
+            let mut entries = vec![Entry::Tree {
+                path: path.clone(),
+                permissions: tree.permissions,
+                meta: tree.meta.clone().filter(|_| path.is_root()),
+            }];
+            entries.extend(tree.streams.iter().map(|stream| Entry::Stream {
+                parent: path.clone(),
+                stream: stream.clone(),
+            }));
+            entries.extend(tree.symlinks.iter().map(|symlink| Entry::Symlink {
+                parent: path.clone(),
+                symlink: symlink.clone(),
+            }));
+            entries.extend(tree.specials.iter().map(|special| Entry::Special {
+                parent: path.clone(),
+                special: special.clone(),
+            }));
+
+            for entry in entries {
+                let mut line = serde_json::to_vec(&entry)?;
+                line.push(b'\n');
+                writer.write_all(&line).await?;
+            }
+
+            // Reversed, so that subtrees are written in their original order
+            for (name, subtree) in tree.subtrees.iter().rev() {
+                pending.push((path.join(name), subtree));
+            }
+        }
+
+        writer.flush().await?;
+        Ok(())
+    }
+
+    /// Reads a whole streaming manifest into a `Tree`.
+    ///
+    /// # Errors
+    ///
+    /// - IO errors from the reader
+    /// - Malformed manifests
+    pub async fn read_manifest<R: AsyncBufRead + Unpin>(reader: R) -> crate::Result<Tree> {
+        let mut reader = ManifestReader::new(reader);
+        let mut assembler = TreeAssembler::default();
+
+        while let Some(entry) = reader.next_entry().await? {
+            assembler.push(entry)?;
+        }
+
+        assembler.finish()
+    }
+
+    /// Fetches a manifest from `manifest_url`, downloading each stream as soon as its entry
+    /// arrives rather than waiting for the whole manifest.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically out of space)
+    /// - Network errors (Non-2xx codes, etc)
+    /// - Malformed manifests
+    pub async fn fetch<C: Into<Option<CompressionKind>>>(
+        manifest_url: &str,
+        repo_url: &str,
+        store: &Store,
+        compression: C,
+    ) -> crate::Result<Tree> {
+        let mut validators = ManifestValidators::default();
+        Self::fetch_if_modified(manifest_url, repo_url, store, compression, &mut validators).await
+    }
+
+    /// Fetches a manifest like [`Tree::fetch`], unless it's the version `validators` describe,
+    /// from an earlier fetch. Once the tree and its streams are fetched, `validators` describe
+    /// the new version, so that polling for changes mostly costs a request.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::NotModified`](crate::Error::NotModified) if the manifest didn't change
+    /// - See [`Tree::fetch`]
+    pub async fn fetch_if_modified<C: Into<Option<CompressionKind>>>(
+        manifest_url: &str,
+        repo_url: &str,
+        store: &Store,
+        compression: C,
+        validators: &mut ManifestValidators,
+    ) -> crate::Result<Tree> {
+        let compression = compression.into();
+        let res = net::default_client()
+            .get(manifest_url)
+            .headers(validators.headers())
+            .send()
+            .await?;
+        if res.status() == StatusCode::NOT_MODIFIED {
+            return Err(crate::Error::NotModified);
+        }
+        let res = res.error_for_status()?;
+        let fetched = ManifestValidators::from_headers(res.headers());
+        let mut reader = ManifestReader::new(response_reader(res));
+        let mut assembler = TreeAssembler::default();
+
+        while let Some(entry) = reader.next_entry().await? {
+            if let Entry::Stream { stream, .. } = &entry {
+                stream.download(repo_url, store, compression).await?;
+            }
+            assembler.push(entry)?;
+        }
+
+        let tree = assembler.finish()?;
+        *validators = fetched;
+        Ok(tree)
+    }
+}
+
+/// (De)serializes names as strings, or their bytes when they aren't UTF-8.
+pub(crate) mod os_string {
+    use serde::{Deserializer, Serializer};
+    use std::ffi::OsString;
+
+    use crate::tree::name;
+
+    pub fn serialize<S: Serializer>(name: &OsString, serializer: S) -> Result<S::Ok, S::Error> {
+        name::serialize(name, serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<OsString, D::Error> {
+        name::deserialize(deserializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use httpmock::prelude::*;
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::async_types::BufReader;
+    use crate::fs;
+    use crate::stream::ObjectHash;
+
+    fn stream(name: &str) -> Stream {
+        Stream {
+            hash: ObjectHash::of(name.as_bytes()),
+            file_name: name.into(),
+            compression: None,
+            dictionary: None,
+            disk_size: None,
+            network_size: None,
+            #[cfg(unix)]
+            mode: Some(0o644),
+            #[cfg(unix)]
+            mtime: None,
+            #[cfg(unix)]
+            owner: None,
+        }
+    }
+
+    fn tree(streams: Vec<Stream>, subtrees: Vec<(TreePath, Tree)>) -> Tree {
+        Tree {
+            permissions: 0o755,
+            streams,
+            subtrees,
+            symlinks: Vec::new(),
+            specials: Vec::new(),
+            meta: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_manifest_roundtrip() -> crate::Result<()> {
+        let mut original = tree(
+            vec![stream("a")],
+            vec![
+                (
+                    TreePath::new_unchecked("x"),
+                    tree(
+                        vec![stream("b")],
+                        vec![(
+                            TreePath::new_unchecked("y"),
+                            tree(vec![stream("c")], vec![]),
+                        )],
+                    ),
+                ),
+                (
+                    TreePath::new_unchecked("z"),
+                    tree(vec![stream("d")], vec![]),
+                ),
+            ],
+        );
+        original.symlinks.push(Symlink {
+            file_name: "link".into(),
+            target: "x/y/c".into(),
+        });
+
+        let mut manifest = Vec::new();
+        original.write_manifest(&mut manifest).await?;
+        let parsed = Tree::read_manifest(BufReader::new(&manifest[..])).await?;
+
+        let mut reserialized = Vec::new();
+        parsed.write_manifest(&mut reserialized).await?;
+        assert_eq!(manifest, reserialized);
+
+        assert_eq!(parsed.subtrees[0].0.as_path(), Path::new("x"));
+        assert_eq!(parsed.subtrees[0].1.subtrees[0].0.as_path(), Path::new("y"));
+        assert_eq!(parsed.subtrees[1].1.streams[0].file_name, "d");
+        assert_eq!(parsed.symlinks[0].target, Path::new("x/y/c"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_manifest_invalid() {
+        for manifest in [
+            &b""[..],
+            b"not json\n",
+            b"{\"type\":\"tree\",\"path\":\"a\",\"permissions\":0}\n",
+            b"{\"type\":\"tree\",\"path\":\"\",\"permissions\":0}\n{\"type\":\"tree\",\"path\":\"a/b\",\"permissions\":0}\n",
+            // Paths escaping the tree
+            b"{\"type\":\"tree\",\"path\":\"\",\"permissions\":0}\n{\"type\":\"tree\",\"path\":\"..\",\"permissions\":0}\n",
+            b"{\"type\":\"tree\",\"path\":\"\",\"permissions\":0}\n{\"type\":\"stream\",\"parent\":\"\",\"stream\":{\"hash\":\"af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262\",\"file_name\":\"../a\"}}\n",
+        ] {
+            let res = Tree::read_manifest(BufReader::new(manifest)).await;
+            assert!(res.is_err(), "{}", String::from_utf8_lossy(manifest));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch() -> crate::Result<()> {
+        let compression = CompressionKind::Zstd;
+        let local_stream_dir = TempDir::new()?;
+        let remote_stream_dir = TempDir::new()?;
+        let original_dir = TempDir::new()?;
+
+        let contents = b"contents";
+        let hash = blake3::hash(contents).to_hex().to_string();
+        std::fs::create_dir_all(original_dir.path().join("a"))?;
+        fs::write(original_dir.path().join("a/file"), contents).await?;
+
+        let tree = Tree::create(
+            &Store::new(remote_stream_dir.path()),
+            original_dir.path(),
+            compression,
+        )
+        .await?;
+        let mut manifest = Vec::new();
+        tree.write_manifest(&mut manifest).await?;
+
+        let server = MockServer::start();
+        let manifest_mock = server.mock(|when, then| {
+            when.method(GET).path("/trees/test");
+            then.status(200).body(&manifest);
+        });
+        let stream_mock = server.mock(|when, then| {
+            when.method(GET).path(format!("/streams/{hash}.zstd"));
+            then.status(200).body_from_file(
+                remote_stream_dir
+                    .path()
+                    .join(format!("{hash}.zstd"))
+                    .to_str()
+                    .expect("non unicode path to testdir"),
+            );
+        });
+
+        let local_store = Store::new(local_stream_dir.path());
+        let fetched = Tree::fetch(
+            &server.url("/trees/test"),
+            &server.base_url(),
+            &local_store,
+            compression,
+        )
+        .await?;
+
+        manifest_mock.assert();
+        stream_mock.assert();
+        assert_eq!(fetched.subtrees[0].1.streams[0].hash, hash);
+        assert!(local_store.contains(&hash));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+//! Layering trees on top of each other, like a base system with local overrides.
+use std::ffi::OsStr;
+
+use crate::stream::Stream;
+use crate::tree::builder::{remove_name, sort};
+use crate::tree::{Tree, TreePath};
+
+/// Which side wins when both trees of a [merge](Tree::merge) have something different at the
+/// same path.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MergePolicy {
+    /// Keeps the tree being merged into
+    PreferLeft,
+    /// Keeps the tree being merged in, as an overlay
+    #[default]
+    PreferRight,
+    /// Fails with [`Error::MergeConflict`](crate::Error::MergeConflict)
+    Error,
+}
+
+impl Tree {
+    /// Combines this tree with `other`. Directories in both are merged, and anything else in
+    /// both is a conflict unless it's identical, including a directory on one side and a file on
+    /// the other. Directories with different modes also conflict.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::MergeConflict`](crate::Error::MergeConflict) with the first conflicting path,
+    ///   for [`MergePolicy::Error`]
+    pub fn merge(&self, other: &Tree, policy: MergePolicy) -> crate::Result<Tree> {
+        let mut merged = self.clone();
+        merge_into(&mut merged, other, policy, &TreePath::root())?;
+        sort(&mut merged);
+        Ok(merged)
+    }
+}
+
+fn merge_into(
+    left: &mut Tree,
+    right: &Tree,
+    policy: MergePolicy,
+    path: &TreePath,
+) -> crate::Result<()> {
+    if left.permissions != right.permissions && right_wins(left, None, false, policy, path)? {
+        left.permissions = right.permissions;
+    }
+
+    for (name, subtree) in &right.subtrees {
+        if let Some((_, existing)) = left.subtrees.iter_mut().find(|(n, _)| n == name) {
+            merge_into(existing, subtree, policy, &path.join(name))?;
+        } else if right_wins(left, Some(name.as_os_str()), false, policy, path)? {
+            remove_name(left, name);
+            left.subtrees.push((name.clone(), subtree.clone()));
+        }
+    }
+
+    for stream in &right.streams {
+        // How a stream is compressed doesn't change the file
+        let same = left.streams.iter().any(|existing| {
+            *stream
+                == Stream {
+                    compression: stream.compression,
+                    network_size: stream.network_size,
+                    ..existing.clone()
+                }
+        });
+        if right_wins(left, Some(stream.file_name.as_os_str()), same, policy, path)? {
+            remove_name(left, &TreePath::new_unchecked(&stream.file_name));
+            left.streams.push(stream.clone());
+        }
+    }
+    for symlink in &right.symlinks {
+        let same = left.symlinks.contains(symlink);
+        if right_wins(
+            left,
+            Some(symlink.file_name.as_os_str()),
+            same,
+            policy,
+            path,
+        )? {
+            remove_name(left, &TreePath::new_unchecked(&symlink.file_name));
+            left.symlinks.push(symlink.clone());
+        }
+    }
+    for special in &right.specials {
+        let same = left.specials.contains(special);
+        if right_wins(
+            left,
+            Some(special.file_name.as_os_str()),
+            same,
+            policy,
+            path,
+        )? {
+            remove_name(left, &TreePath::new_unchecked(&special.file_name));
+            left.specials.push(special.clone());
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the right side replaces what the left has called `name` in the directory at `path`,
+/// or the directory itself for `None`.
+fn right_wins(
+    left: &Tree,
+    name: Option<&OsStr>,
+    same: bool,
+    policy: MergePolicy,
+    path: &TreePath,
+) -> crate::Result<bool> {
+    let conflict = match name {
+        Some(name) => has_name(left, name),
+        None => true,
+    };
+    if !conflict {
+        return Ok(true);
+    }
+    if same {
+        return Ok(false);
+    }
+
+    match policy {
+        MergePolicy::PreferLeft => Ok(false),
+        MergePolicy::PreferRight => Ok(true),
+        MergePolicy::Error => {
+            let path = match name {
+                Some(name) => path.join(&TreePath::new_unchecked(name)),
+                None => path.clone(),
+            };
+            Err(crate::Error::MergeConflict(path.as_path().to_path_buf()))
+        }
+    }
+}
+
+fn has_name(tree: &Tree, name: &OsStr) -> bool {
+    tree.streams.iter().any(|s| s.file_name == name)
+        || tree.symlinks.iter().any(|s| s.file_name == name)
+        || tree.specials.iter().any(|s| s.file_name == name)
+        || tree.subtrees.iter().any(|(n, _)| n.as_os_str() == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::ObjectHash;
+    use crate::tree::builder::TreeBuilder;
+
+    fn stream(contents: &str) -> Stream {
+        Stream {
+            hash: ObjectHash::of(contents.as_bytes()),
+            file_name: contents.into(),
+            compression: None,
+            dictionary: None,
+            disk_size: None,
+            network_size: None,
+            #[cfg(unix)]
+            mode: Some(0o644),
+            #[cfg(unix)]
+            mtime: None,
+            #[cfg(unix)]
+            owner: None,
+        }
+    }
+
+    fn hash_at(tree: &Tree, path: &str) -> Option<ObjectHash> {
+        match tree.get(path)? {
+            crate::tree::walk::TreeEntry::File(stream) => Some(stream.hash.clone()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_tree_merge() -> crate::Result<()> {
+        let base = TreeBuilder::new()
+            .add_file("etc/config", stream("base"))?
+            .add_file("etc/shared", stream("shared"))?
+            .add_file("bin/app", stream("app"))?
+            .add_file("lib", stream("lib"))?
+            .build();
+        let overrides = TreeBuilder::new()
+            .add_file("etc/config", stream("override"))?
+            .add_file("etc/shared", stream("shared"))?
+            .add_file("etc/extra", stream("extra"))?
+            .add_symlink("bin/tool", "app")?
+            .build();
+
+        // Identical files and new entries never conflict
+        let merged = base.merge(&overrides, MergePolicy::PreferRight)?;
+        assert_eq!(
+            hash_at(&merged, "etc/config"),
+            Some(stream("override").hash)
+        );
+        assert!(merged.get("etc/extra").is_some());
+        assert!(merged.get("bin/tool").is_some());
+        assert!(merged.get("bin/app").is_some());
+        assert_eq!(merged.len(), 8);
+
+        let merged = base.merge(&overrides, MergePolicy::PreferLeft)?;
+        assert_eq!(hash_at(&merged, "etc/config"), Some(stream("base").hash));
+
+        match base.merge(&overrides, MergePolicy::Error) {
+            Err(crate::Error::MergeConflict(path)) => assert_eq!(path.to_str(), Some("etc/config")),
+            other => panic!("expected a conflict, got {other:?}"),
+        }
+
+        // A directory replacing a file, and the other way around
+        let dirs = TreeBuilder::new()
+            .add_file("lib/libc", stream("libc"))?
+            .build();
+        let merged = base.merge(&dirs, MergePolicy::PreferRight)?;
+        assert!(hash_at(&merged, "lib/libc").is_some());
+        let merged = dirs.merge(&base, MergePolicy::PreferRight)?;
+        assert_eq!(hash_at(&merged, "lib"), Some(stream("lib").hash));
+        assert!(base.merge(&base, MergePolicy::Error).is_ok());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+//! Describing where a tree came from.
+//!
+//! A [`TreeMeta`] is attached to the root of a tree and travels with its manifest, so clients can
+//! show what they're about to deploy. It doesn't describe anything that gets deployed, so it's
+//! left out of [`Tree::hash`](crate::tree::Tree::hash): labelling a tree doesn't change which
+//! tree it is.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TreeMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// A semantic version, like `1.4.0`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Anything else worth showing, like the commit a tree was built from
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl TreeMeta {
+    /// Metadata for a tree called `name` at `version`, created now.
+    #[must_use]
+    pub fn new<N: Into<String>, V: Into<String>>(name: N, version: V) -> Self {
+        Self {
+            name: Some(name.into()),
+            version: Some(version.into()),
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|time| time.as_secs()),
+            ..Self::default()
+        }
+    }
+
+    #[must_use]
+    pub fn description<S: Into<String>>(mut self, description: S) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    #[must_use]
+    pub fn annotation<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.annotations.insert(key.into(), value.into());
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::CompressionKind;
+    use crate::fs;
+    use crate::store::Store;
+    use crate::tree::Tree;
+    use crate::tree::compact::CompactTree;
+    use crate::tree::view::TreeRef;
+
+    #[tokio::test]
+    async fn test_tree_meta() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let original_dir = TempDir::new()?;
+        std::fs::create_dir(original_dir.path().join("bin"))?;
+        fs::write(original_dir.path().join("bin/app"), b"app").await?;
+
+        let plain = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+        let mut tree = plain.clone();
+        tree.meta = Some(
+            TreeMeta::new("myapp", "1.4.0")
+                .description("The app")
+                .annotation("commit", "abc123"),
+        );
+        assert!(
+            tree.meta
+                .as_ref()
+                .is_some_and(|meta| meta.created_at.is_some())
+        );
+        assert_eq!(tree.hash(), plain.hash());
+
+        let mut manifest = Vec::new();
+        tree.write_manifest(&mut manifest).await?;
+        let read = Tree::read_manifest(&manifest[..]).await?;
+        assert_eq!(read.meta, tree.meta);
+        assert!(read.subtrees[0].1.meta.is_none());
+
+        let text = String::from_utf8(manifest.clone()).expect("manifests are UTF-8");
+        assert_eq!(TreeRef::parse(&text)?.to_tree()?.meta, tree.meta);
+        let compact = CompactTree::read_manifest(&manifest[..]).await?;
+        assert_eq!(compact.to_tree().meta, tree.meta);
+        assert_eq!(CompactTree::from(&tree).to_tree().meta, tree.meta);
+
+        // Manifests without metadata are unchanged
+        let mut plain_manifest = Vec::new();
+        plain.write_manifest(&mut plain_manifest).await?;
+        assert!(!String::from_utf8_lossy(&plain_manifest).contains("meta"));
+
+        // Only the root can carry it
+        let nested = text.replacen(
+            "\"path\":\"bin\"",
+            "\"path\":\"bin\",\"meta\":{\"name\":\"bin\"}",
+            1,
+        );
+        assert!(Tree::read_manifest(nested.as_bytes()).await.is_err());
+        assert!(TreeRef::parse(&nested).is_err());
+
+        Ok(())
+    }
+}
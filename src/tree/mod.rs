@@ -1,50 +1,199 @@
+pub mod builder;
+pub mod compact;
+pub mod create;
+pub mod delta;
+mod deploy;
+pub mod deploy_plan;
+pub mod deployment;
+mod dictionary;
+pub mod diff;
+#[cfg(feature = "tar")]
+mod export;
+pub mod filter;
+#[cfg(feature = "git")]
+mod git;
+mod hash;
+pub mod journal;
+pub mod manifest;
+pub mod merge;
+pub mod meta;
+mod name;
+#[cfg(feature = "nar")]
+mod nar;
+pub mod path;
+pub mod plan;
+pub mod profile;
+mod prune;
+pub(crate) mod refs;
+pub mod report;
+mod update;
+pub mod verify;
+pub mod view;
+pub mod walk;
+#[cfg(feature = "zip")]
+mod zip_archive;
+
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashSet;
 use std::ffi::OsString;
 use std::io;
-use std::os::unix::fs::{PermissionsExt, symlink};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub use path::TreePath;
 
-use crate::CompressionKind;
-use crate::stream::Stream;
+use crate::repo_set::{RepoSet, SourceReport};
+use crate::store::{SpecialFilePolicy, Store, SymlinkPolicy};
+use crate::stream::{ObjectHash, Stream};
+use crate::tree::create::{CreateOptions, FollowSymlinks};
+use crate::tree::deploy::Dir;
+use crate::tree::filter::Ignores;
+use crate::tree::journal::Journal;
+use crate::tree::meta::TreeMeta;
+use crate::{CompressionKind, Mirrors};
 
-#[derive(Clone, Debug, Hash)]
+#[derive(Clone, Debug, Hash, Serialize, Deserialize)]
 pub struct Tree {
     pub permissions: u32,
     pub streams: Vec<Stream>,
-    pub subtrees: Vec<(PathBuf, Tree)>,
+    pub subtrees: Vec<(TreePath, Tree)>,
     pub symlinks: Vec<Symlink>,
+    /// FIFOs and the like, only recorded if the store's [`SpecialFilePolicy`] says so
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub specials: Vec<SpecialFile>,
+    /// Where the tree came from, only on the root
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<TreeMeta>,
 }
 
-#[derive(Clone, Debug, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Symlink {
+    #[serde(with = "manifest::os_string")]
     pub file_name: OsString,
     pub target: PathBuf,
 }
 
+/// A file that is neither a regular file, directory nor symlink.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SpecialFile {
+    #[serde(with = "manifest::os_string")]
+    pub file_name: OsString,
+    pub kind: SpecialKind,
+    /// Permission bits
+    pub mode: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpecialKind {
+    /// A named pipe
+    Fifo,
+}
+
 impl Tree {
-    /// Downloads all streams required to build the tree
+    /// Downloads all streams required to build the tree. `compression` is only a fallback for
+    /// streams that don't record their kind, see [`Stream::download`].
     ///
     /// # Errors
     ///
     /// - Filesystem errors (Typically out of space)
     /// - Network errors (Non-2xx codes, etc)
-    pub async fn download(
+    pub async fn download<C: Into<Option<CompressionKind>>>(
         &self,
         repo_url: &str,
-        local_stream_path: &Path,
-        compression: CompressionKind,
+        store: &Store,
+        compression: C,
+    ) -> crate::Result<()> {
+        self.download_mirrored(&Mirrors::from(repo_url), store, compression)
+            .await
+    }
+
+    /// Downloads all streams required to build the tree, falling through to the next mirror
+    /// when one fails. Mirror health is shared across the whole tree.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically out of space)
+    /// - Network errors, if no mirror could serve a stream
+    pub async fn download_mirrored<C: Into<Option<CompressionKind>>>(
+        &self,
+        mirrors: &Mirrors,
+        store: &Store,
+        compression: C,
     ) -> crate::Result<()> {
-        for stream in &self.streams {
+        store.clean_before_download()?;
+        self.download_streams(mirrors, store, compression.into())
+            .await
+    }
+
+    /// Downloads all streams required to build the tree, each from the first repository that
+    /// has it.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically out of space)
+    /// - Network errors, if no repository could serve a stream
+    pub async fn download_from(
+        &self,
+        repos: &RepoSet,
+        store: &Store,
+    ) -> crate::Result<SourceReport> {
+        repos.download_streams(all_streams(self), store).await
+    }
+
+    async fn download_streams(
+        &self,
+        mirrors: &Mirrors,
+        store: &Store,
+        compression: Option<CompressionKind>,
+    ) -> crate::Result<()> {
+        let mut downloaded = HashSet::new();
+        for stream in all_streams(self) {
+            // Files with the same contents share their stream
+            if !downloaded.insert(&stream.hash) {
+                store.metrics().deduped();
+                continue;
+            }
             stream
-                .download(repo_url, local_stream_path, compression)
+                .download_mirrored(mirrors, store, compression)
                 .await?;
         }
-        for tree in &self.subtrees {
-            Box::pin(tree.1.download(repo_url, local_stream_path, compression)).await?;
+
+        Ok(())
+    }
+
+    /// Uploads every stream in the tree to a repository, see [`Stream::push`].
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically a stream missing from the store)
+    /// - Network errors (Non-2xx codes, etc)
+    pub async fn push(
+        &self,
+        repo_url: &str,
+        store: &Store,
+        compression: CompressionKind,
+    ) -> crate::Result<()> {
+        let mut pushed = HashSet::new();
+
+        for stream in all_streams(self) {
+            if pushed.insert(&stream.hash) {
+                stream.push(repo_url, store, compression).await?;
+            }
         }
 
         Ok(())
     }
 
+    /// Deploys the tree into the existing directory `deploy_path`, replacing any files already
+    /// in the way. Directories below `deploy_path` are never followed if they are symlinks.
+    ///
+    /// Once everything is in place, a [`Deployment`](deployment::Deployment) record is written
+    /// into `deploy_path`. Until then, a [journal](journal) of what was done lets an interrupted
+    /// deploy be [resumed](Tree::resume_deploy) or [undone](Tree::undo_deploy).
+    ///
     /// # Warning
     ///
     /// - Make sure that the tree is likely to be on the same partition as the store, as this internally uses
@@ -53,73 +202,339 @@ impl Tree {
     /// # Errors
     ///
     /// - Out of storage/Permissions Errors
-    pub fn deploy(&self, stream_dir: &Path, deploy_path: &Path) -> crate::Result<()> {
-        for subtree in &self.subtrees {
-            let next_deploy_path = &deploy_path.join(&subtree.0);
-            std::fs::create_dir_all(next_deploy_path)?;
-            subtree.1.deploy(stream_dir, next_deploy_path)?;
-        }
+    /// - [`Error::TreeNotAllowed`](crate::Error::TreeNotAllowed) if the store's
+    ///   [allowlist](Store::with_allowed_trees) doesn't include the tree
+    /// - [`Error::UnsafeSymlink`](crate::Error::UnsafeSymlink) if the store's
+    ///   [`SymlinkPolicy`] refuses a symlink, before anything is deployed
+    /// - [`Error::InvalidPath`](crate::Error::InvalidPath) for names that could escape their
+    ///   directory, like `../a`, before anything is deployed
+    /// - [`Error::NonPortableName`](crate::Error::NonPortableName) if the store's
+    ///   [`NamePolicy`](crate::store::NamePolicy) refuses a name, before anything is deployed
+    /// - [`Error::InterruptedDeploy`](crate::Error::InterruptedDeploy) if an earlier deploy into
+    ///   `deploy_path` has to be resumed or undone first
+    pub fn deploy(&self, store: &Store, deploy_path: &Path) -> crate::Result<()> {
+        check_deployable(self, store)?.deploy_as(self.hash(), store, deploy_path)
+    }
 
-        for stream in &self.streams {
-            let original_path = stream_dir.join(&stream.hash);
-            let target_path = deploy_path.join(&stream.file_name);
+    /// Deploys the tree without any checks, recording it as the tree with `hash`, which is the
+    /// one it was checked as before its names were sanitized.
+    pub(crate) fn deploy_as(
+        &self,
+        hash: String,
+        store: &Store,
+        deploy_path: &Path,
+    ) -> crate::Result<()> {
+        let root = Dir::open(deploy_path)?;
+        let plan = self.plan_as(hash, store, &root)?;
+        let journal = Journal::begin(&root, deploy_path, &plan)?;
+        plan.run(0, journal, store, &root)
+    }
+
+    /// Deploys the tree into a sibling of `deploy_path`, then swaps it into place, so that
+    /// nothing ever sees a half-deployed tree. Whatever was at `deploy_path` is removed
+    /// afterwards, and `deploy_path` gets the tree's permissions.
+    ///
+    /// # Errors
+    ///
+    /// - Out of storage/Permissions Errors
+    /// - [`Error::InvalidPath`](crate::Error::InvalidPath) if `deploy_path` has no parent
+    pub fn deploy_atomic(&self, store: &Store, deploy_path: &Path) -> crate::Result<()> {
+        // Each deploy gets its own sibling, so concurrent deploys never share one
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
 
-            if std::fs::hard_link(&original_path, &target_path).is_err() {
-                std::fs::copy(&original_path, &target_path)?;
+        let (Some(parent), Some(name)) = (deploy_path.parent(), deploy_path.file_name()) else {
+            return Err(crate::Error::InvalidPath(deploy_path.to_path_buf()));
+        };
+
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let mut tmp_name = OsString::from(".");
+        tmp_name.push(name);
+        tmp_name.push(format!(".{}-{n}.tmp", std::process::id()));
+        let tmp_path = parent.join(tmp_name);
+
+        // Only a crashed process that had the same pid, like pid 1 in a container, can have left
+        // one behind
+        match std::fs::create_dir(&tmp_path) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                std::fs::remove_dir_all(&tmp_path)?;
+                std::fs::create_dir(&tmp_path)?;
             }
+            res => res?,
         }
 
-        for link in &self.symlinks {
-            symlink(&link.target, &link.file_name)?;
+        let res = self.deploy(store, &tmp_path).and_then(|()| {
+            std::fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(self.permissions))?;
+            crate::fs::rename(tmp_path.as_path(), deploy_path)?;
+            Ok(())
+        });
+        if res.is_err() {
+            let _ = std::fs::remove_dir_all(&tmp_path);
         }
-
-        Ok(())
+        res
     }
 
     /// Create a `Tree` and the underlying `Stream`s inside the `Repository`.
     ///
+    /// Entries are sorted by name, so identical directories always produce identical trees and
+    /// manifests, whatever order the filesystem lists them in. Files are stored one at a time,
+    /// see [`CreateOptions::with_concurrency`].
+    ///
     /// # Errors
     ///
     /// - Out of storage/Permissions Errors
+    /// - [`io::ErrorKind::Unsupported`] for special files, if the store's [`SpecialFilePolicy`]
+    ///   is to refuse them
+    /// - [`Error::NonPortableName`](crate::Error::NonPortableName) if the store's
+    ///   [`NamePolicy`](crate::store::NamePolicy) refuses a name
+    /// - [`io::ErrorKind::InvalidInput`] for invalid patterns in ignore files
     pub async fn create(
-        remote_stream_path: &Path,
+        store: &Store,
+        original_path: &Path,
+        compression: CompressionKind,
+    ) -> io::Result<Tree> {
+        Self::create_with(store, original_path, compression, &CreateOptions::default()).await
+    }
+
+    /// Creates a `Tree` like [`Tree::create`], walking the directory as `options` say.
+    ///
+    /// # Errors
+    ///
+    /// - See [`Tree::create`]
+    /// - [`io::ErrorKind::InvalidInput`] for invalid patterns in the
+    ///   [filter](CreateOptions::with_filter)
+    pub async fn create_with(
+        store: &Store,
         original_path: &Path,
         compression: CompressionKind,
+        options: &CreateOptions,
+    ) -> io::Result<Tree> {
+        let ignores = options.filter().build(original_path)?;
+        let mut sources = Vec::new();
+        let mut tree = Tree::scan(store, options, original_path, &ignores, &[], &mut sources)?;
+
+        // Buffered keeps the results in the order the files were queued
+        let created = futures_util::StreamExt::buffered(
+            futures_util::stream::iter(
+                sources
+                    .iter()
+                    .map(|source| Stream::create(source, store, compression)),
+            ),
+            options.concurrency(),
+        );
+        let created: Vec<Stream> = futures_util::TryStreamExt::try_collect(created).await?;
+
+        fill_streams(&mut tree, &mut created.into_iter());
+        Ok(tree)
+    }
+
+    /// Walks the directory into a tree whose streams are placeholders, queueing the files to
+    /// store into `sources` in the order [`fill_streams`] visits them: a directory's own files,
+    /// then each subdirectory's. `ancestors` are the device and inode of every directory above
+    /// this one, to detect symlinks leading back into them.
+    fn scan(
+        store: &Store,
+        options: &CreateOptions,
+        original_path: &Path,
+        ignores: &Ignores,
+        ancestors: &[(u64, u64)],
+        sources: &mut Vec<PathBuf>,
     ) -> io::Result<Tree> {
+        let ignores = ignores.enter(original_path)?;
+        let metadata = original_path.metadata()?;
+        let mut ancestors = ancestors.to_vec();
+        ancestors.push((metadata.dev(), metadata.ino()));
+
         let mut base_tree = Tree {
-            permissions: original_path.metadata()?.permissions().mode(),
+            permissions: metadata.permissions().mode(),
             streams: Vec::new(),
             subtrees: Vec::new(),
             symlinks: Vec::new(),
+            specials: Vec::new(),
+            meta: None,
         };
+        let mut subdirs = Vec::new();
+        let mut names = HashSet::new();
 
-        for entry in std::fs::read_dir(original_path)? {
-            let entry = entry?;
+        // `read_dir` order depends on the filesystem, so sort for the same manifest every time
+        let mut entries = std::fs::read_dir(original_path)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(std::fs::DirEntry::file_name);
 
+        for entry in entries {
             let file_type = entry.file_type()?;
             let file_name = entry.file_name();
+            let target = if file_type.is_symlink() {
+                followed_target(options.follow_symlinks(), &entry.path(), &ancestors)
+            } else {
+                None
+            };
+            let is_dir = target
+                .as_ref()
+                .map_or(file_type.is_dir(), std::fs::Metadata::is_dir);
 
-            if file_type.is_file() {
-                let stream =
-                    Stream::create(&entry.path(), &remote_stream_path, compression).await?;
-                base_tree.streams.push(stream);
-            } else if file_type.is_dir() {
-                let sub_tree =
-                    Box::pin(Tree::create(remote_stream_path, &entry.path(), compression)).await?;
-                base_tree.subtrees.push((file_name.into(), sub_tree));
+            if ignores.is_excluded(&entry.path(), is_dir) {
+                continue;
+            }
+            let file_name = name::apply(store.name_policy(), original_path, &file_name, &mut names)
+                .map_err(io::Error::other)?
+                .into_owned();
+
+            if target
+                .as_ref()
+                .map_or(file_type.is_file(), std::fs::Metadata::is_file)
+            {
+                // The store hardlinks the file itself, not a symlink to it
+                sources.push(match target {
+                    Some(_) => std::fs::canonicalize(entry.path())?,
+                    None => entry.path(),
+                });
+                base_tree.streams.push(Stream {
+                    hash: ObjectHash::new_unchecked(String::new()),
+                    file_name,
+                    compression: None,
+                    dictionary: None,
+                    disk_size: None,
+                    network_size: None,
+                    #[cfg(unix)]
+                    mode: None,
+                    #[cfg(unix)]
+                    mtime: None,
+                    #[cfg(unix)]
+                    owner: None,
+                });
+            } else if is_dir {
+                // Names from `read_dir` are always a single normal component
+                subdirs.push((TreePath::new_unchecked(file_name), entry.path()));
             } else if file_type.is_symlink() {
                 let symlink = Symlink {
                     file_name,
                     target: std::fs::read_link(entry.path())?,
                 };
                 base_tree.symlinks.push(symlink);
+            } else {
+                match store.special_file_policy() {
+                    SpecialFilePolicy::Error => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Unsupported,
+                            format!("special file {}", entry.path().display()),
+                        ));
+                    }
+                    SpecialFilePolicy::Record if file_type.is_fifo() => {
+                        base_tree.specials.push(SpecialFile {
+                            file_name,
+                            kind: SpecialKind::Fifo,
+                            mode: entry.metadata()?.permissions().mode() & 0o7777,
+                        });
+                    }
+                    // Sockets belong to whichever process listens on them, and devices can
+                    // only be created by root, so only FIFOs can be recreated anywhere
+                    SpecialFilePolicy::Skip | SpecialFilePolicy::Record => {}
+                }
             }
         }
 
+        for (name, path) in subdirs {
+            let sub_tree = Tree::scan(store, options, &path, &ignores, &ancestors, sources)?;
+            base_tree.subtrees.push((name, sub_tree));
+        }
+
         Ok(base_tree)
     }
 }
 
+/// Replaces the placeholder streams from [`Tree::scan`] with the created ones, keeping their
+/// names.
+fn fill_streams(tree: &mut Tree, created: &mut impl Iterator<Item = Stream>) {
+    for stream in &mut tree.streams {
+        if let Some(created) = created.next() {
+            *stream = Stream {
+                file_name: std::mem::take(&mut stream.file_name),
+                ..created
+            };
+        }
+    }
+    for (_, subtree) in &mut tree.subtrees {
+        fill_streams(subtree, created);
+    }
+}
+
+/// What the symlink at `path` points to, if [`Tree::create`] should store that instead.
+fn followed_target(
+    follow: FollowSymlinks,
+    path: &Path,
+    ancestors: &[(u64, u64)],
+) -> Option<std::fs::Metadata> {
+    if follow == FollowSymlinks::Never {
+        return None;
+    }
+    // Dangling symlinks can only be kept as they are
+    let target = path.metadata().ok()?;
+
+    let follows = if target.is_dir() {
+        // A directory that's already being walked would never end
+        !ancestors.contains(&(target.dev(), target.ino()))
+    } else {
+        target.is_file() && follow == FollowSymlinks::Always
+    };
+    follows.then_some(target)
+}
+
+/// Fails if the store's [allowlist](Store::with_allowed_trees) doesn't include the tree, a name
+/// in it could escape its directory, or the store's [`SymlinkPolicy`] or
+/// [`NamePolicy`](crate::store::NamePolicy) refuses anything in it. Returns the tree as it will
+/// be deployed, with its names sanitized if the store asks for it.
+pub(crate) fn check_deployable<'a>(tree: &'a Tree, store: &Store) -> crate::Result<Cow<'a, Tree>> {
+    if store.restricts_trees() {
+        let hash = tree.hash();
+        if !store.is_tree_allowed(&hash) {
+            return Err(crate::Error::TreeNotAllowed(hash));
+        }
+    }
+
+    // Trees built or deserialized directly never went through the manifest reader's checks
+    let mut pending = vec![(TreePath::root(), tree)];
+    while let Some((path, tree)) = pending.pop() {
+        let names = (tree.streams.iter().map(|stream| &stream.file_name))
+            .chain(tree.symlinks.iter().map(|link| &link.file_name))
+            .chain(tree.specials.iter().map(|special| &special.file_name));
+        for name in names {
+            if !path::is_valid_name(name) {
+                return Err(crate::Error::InvalidPath(path.as_path().join(name)));
+            }
+        }
+
+        if store.symlink_policy() != SymlinkPolicy::Allow {
+            for link in &tree.symlinks {
+                if path::target_escapes(&path, &link.target) {
+                    return Err(crate::Error::UnsafeSymlink(
+                        path.join(&TreePath::new_unchecked(&link.file_name)).into(),
+                        link.target.clone(),
+                    ));
+                }
+            }
+        }
+
+        for (name, subtree) in &tree.subtrees {
+            if name.is_root() {
+                return Err(crate::Error::InvalidPath(path.into()));
+            }
+            pending.push((path.join(name), subtree));
+        }
+    }
+
+    name::deployed(tree, store.name_policy())
+}
+
+/// Every stream in a tree, depth-first.
+pub(crate) fn all_streams(tree: &Tree) -> impl Iterator<Item = &Stream> {
+    let mut pending = vec![tree];
+    std::iter::from_fn(move || {
+        let tree = pending.pop()?;
+        pending.extend(tree.subtrees.iter().map(|(_, subtree)| subtree));
+        Some(tree.streams.iter())
+    })
+    .flatten()
+}
+
 #[cfg(test)]
 mod tests {
     use httpmock::prelude::*;
@@ -127,7 +542,9 @@ mod tests {
 
     use super::*;
     use crate::CompressionKind;
+    use crate::core::CpuPool;
     use crate::fs;
+    use crate::store::NamePolicy;
 
     #[tokio::test]
     async fn test_e2e_tree() -> crate::Result<()> {
@@ -135,9 +552,10 @@ mod tests {
 
         // Create temporary directories
         let local_stream_dir = TempDir::new()?;
-        let local_stream_path = local_stream_dir.path();
+        let local_store = Store::new(local_stream_dir.path());
         let remote_stream_dir = TempDir::new()?;
         let remote_stream_path = remote_stream_dir.path();
+        let remote_store = Store::new(remote_stream_path);
 
         let original_dir = TempDir::new()?;
         let original_path = original_dir.path();
@@ -156,7 +574,7 @@ mod tests {
         fs::write(original_path.join("a/b/c"), b_contents).await?;
 
         // Create a tree and host it on a mock server
-        let tree = Tree::create(remote_stream_path, original_path, compression).await?;
+        let tree = Tree::create(&remote_store, original_path, compression).await?;
 
         let server = MockServer::start();
         let mock_a = server.mock(|when, then| {
@@ -179,14 +597,14 @@ mod tests {
         });
 
         // Download the streams from the mock server, and ensure it was accessed
-        tree.download(&server.base_url(), local_stream_path, compression)
+        tree.download(&server.base_url(), &local_store, compression)
             .await?;
 
         mock_a.assert();
         mock_b.assert();
 
         // Deploy the mock server
-        tree.deploy(local_stream_path, deploy_path)?;
+        tree.deploy(&local_store, deploy_path)?;
 
         // Ensure everything is correct
         assert_eq!(fs::read_to_end(deploy_path.join("file")).await?, a_contents);
@@ -197,4 +615,434 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_download_mirrored() -> crate::Result<()> {
+        let compression = CompressionKind::None;
+
+        let local_stream_dir = TempDir::new()?;
+        let remote_stream_dir = TempDir::new()?;
+        let original_dir = TempDir::new()?;
+
+        fs::write(original_dir.path().join("a"), b"a").await?;
+        fs::write(original_dir.path().join("b"), b"b").await?;
+        let tree = Tree::create(
+            &Store::new(remote_stream_dir.path()),
+            original_dir.path(),
+            compression,
+        )
+        .await?;
+
+        let dead = MockServer::start();
+        let dead_mock = dead.mock(|when, then| {
+            when.method(GET);
+            then.status(503);
+        });
+        let missing = MockServer::start();
+        let missing_mock = missing.mock(|when, then| {
+            when.method(GET);
+            then.status(404);
+        });
+        let origin = MockServer::start();
+        let origin_mock = origin.mock(|when, then| {
+            when.method(GET).path_prefix("/streams/");
+            then.status(200).body_from_file(
+                remote_stream_dir
+                    .path()
+                    .join(&tree.streams[0].hash)
+                    .to_str()
+                    .expect("non unicode path to testdir"),
+            );
+        });
+
+        let mirrors =
+            Mirrors::new([dead.base_url(), missing.base_url(), origin.base_url()]).max_failures(1);
+        let local_store = Store::new(local_stream_dir.path());
+
+        // Only download the first stream, from both places
+        let single = Tree {
+            streams: vec![tree.streams[0].clone()],
+            ..tree.clone()
+        };
+        single
+            .download_mirrored(&mirrors, &local_store, compression)
+            .await?;
+        std::fs::remove_file(local_store.path_of(&tree.streams[0].hash))?;
+        single
+            .download_mirrored(&mirrors, &local_store, compression)
+            .await?;
+
+        // The dead mirror is skipped after failing once, but a 404 isn't counted against a mirror
+        dead_mock.assert_calls(1);
+        missing_mock.assert_calls(2);
+        origin_mock.assert_calls(2);
+        assert!(!mirrors.is_healthy(&dead.base_url()));
+        assert!(mirrors.is_healthy(&missing.base_url()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_local_repository() -> crate::Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let repo_dir = TempDir::new()?;
+        let repo_stream_path = repo_dir.path().join("streams");
+        std::fs::create_dir_all(&repo_stream_path)?;
+        let repo_store = Store::new(&repo_stream_path);
+        let original_dir = TempDir::new()?;
+        fs::write(original_dir.path().join("file"), b"contents").await?;
+        let hash = blake3::hash(b"contents").to_hex().to_string();
+
+        for (compression, url) in [
+            (
+                CompressionKind::Zstd,
+                format!("file://{}", repo_dir.path().display()),
+            ),
+            (CompressionKind::None, repo_dir.path().display().to_string()),
+        ] {
+            let local_stream_dir = TempDir::new()?;
+            let local_store = Store::new(local_stream_dir.path());
+            let tree = Tree::create(&repo_store, original_dir.path(), compression).await?;
+
+            tree.download(&url, &local_store, compression).await?;
+
+            let local = local_store.path_of(&hash);
+            assert_eq!(fs::read_to_end(&local).await?, b"contents");
+
+            // Uncompressed objects are shared with the source store
+            let linked = local.metadata()?.ino() == repo_stream_path.join(&hash).metadata()?.ino();
+            assert_eq!(linked, matches!(compression, CompressionKind::None));
+        }
+
+        // Objects missing from a local mirror fall through to the next one
+        let empty_dir = TempDir::new()?;
+        let local_stream_dir = TempDir::new()?;
+        let local_store = Store::new(local_stream_dir.path());
+        let tree = Tree::create(&repo_store, original_dir.path(), CompressionKind::Xz).await?;
+        let mirrors = Mirrors::new([
+            empty_dir.path().display().to_string(),
+            repo_dir.path().display().to_string(),
+        ]);
+        tree.download_mirrored(&mirrors, &local_store, CompressionKind::Xz)
+            .await?;
+        assert!(mirrors.is_healthy(&empty_dir.path().display().to_string()));
+
+        let res = tree
+            .download(
+                &empty_dir.path().display().to_string(),
+                &local_store,
+                CompressionKind::Xz,
+            )
+            .await;
+        assert!(matches!(res, Err(crate::Error::NotFound(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_push() -> crate::Result<()> {
+        let compression = CompressionKind::Zstd;
+        let local_stream_dir = TempDir::new()?;
+        let local_store = Store::new(local_stream_dir.path());
+        let repo_dir = TempDir::new()?;
+        let original_dir = TempDir::new()?;
+        fs::write(original_dir.path().join("a"), b"same").await?;
+        fs::write(original_dir.path().join("b"), b"same").await?;
+        let hash = blake3::hash(b"same").to_hex().to_string();
+
+        let tree = Tree::create(&local_store, original_dir.path(), compression).await?;
+
+        // Local repositories
+        let repo_url = repo_dir.path().display().to_string();
+        tree.push(&repo_url, &local_store, compression).await?;
+        tree.push(&repo_url, &local_store, compression).await?;
+        assert!(
+            repo_dir
+                .path()
+                .join(format!("streams/{hash}.zstd"))
+                .exists()
+        );
+
+        let deploy_stream_dir = TempDir::new()?;
+        let deploy_store = Store::new(deploy_stream_dir.path());
+        tree.download(&repo_url, &deploy_store, compression).await?;
+        assert!(deploy_store.contains(&hash));
+
+        // HTTP repositories, with shared streams only uploaded once
+        let server = MockServer::start();
+        let upload = server.mock(|when, then| {
+            when.method(PUT).path(format!("/streams/{hash}.zstd"));
+            then.status(201);
+        });
+        tree.push(&server.base_url(), &local_store, compression)
+            .await?;
+        upload.assert_calls(1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deploy_atomic() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let original_dir = TempDir::new()?;
+        let parent_dir = TempDir::new()?;
+        let deploy_path = parent_dir.path().join("live");
+
+        fs::write(original_dir.path().join("old"), b"old").await?;
+        let old = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+        std::fs::remove_file(original_dir.path().join("old"))?;
+        fs::write(original_dir.path().join("new"), b"new").await?;
+        let new = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+
+        // The deployment doesn't have to exist yet
+        old.deploy_atomic(&store, &deploy_path)?;
+        assert!(deploy_path.join("old").exists());
+
+        new.deploy_atomic(&store, &deploy_path)?;
+        assert!(!deploy_path.join("old").exists());
+        assert_eq!(fs::read_to_end(deploy_path.join("new")).await?, b"new");
+
+        // Nothing but the deployment is left next to it
+        assert_eq!(std::fs::read_dir(parent_dir.path())?.count(), 1);
+
+        // Not even when deploying fails
+        std::fs::remove_file(store.path_of(&old.streams[0].hash))?;
+        assert!(old.deploy_atomic(&store, &deploy_path).is_err());
+        assert_eq!(std::fs::read_dir(parent_dir.path())?.count(), 1);
+        assert!(deploy_path.join("new").exists());
+
+        // Left behind by a crashed process that had the same pid
+        for n in 0..256 {
+            let stale = parent_dir
+                .path()
+                .join(format!(".live.{}-{n}.tmp", std::process::id()));
+            std::fs::create_dir(&stale)?;
+            fs::write(stale.join("partial"), b"partial").await?;
+        }
+        new.deploy_atomic(&store, &deploy_path)?;
+        assert!(!deploy_path.join("partial").exists());
+        assert_eq!(fs::read_to_end(deploy_path.join("new")).await?, b"new");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deploy_refuses_traversal() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let original_dir = TempDir::new()?;
+        let parent_dir = TempDir::new()?;
+        let deploy_path = parent_dir.path().join("live");
+        std::fs::create_dir(&deploy_path)?;
+
+        fs::write(original_dir.path().join("file"), b"contents").await?;
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+
+        // Trees deserialized directly skip the manifest reader, so deploys check every name
+        let mut json = serde_json::to_value(&tree)?;
+        json["streams"][0]["file_name"] = "../escaped".into();
+        let hostile: Tree = serde_json::from_value(json.clone())?;
+        assert!(matches!(
+            hostile.deploy(&store, &deploy_path),
+            Err(crate::Error::InvalidPath(_))
+        ));
+        assert!(matches!(
+            hostile.plan_deploy(&store, &deploy_path),
+            Err(crate::Error::InvalidPath(_))
+        ));
+        assert!(!parent_dir.path().join("escaped").exists());
+        assert_eq!(std::fs::read_dir(&deploy_path)?.count(), 0);
+
+        json["streams"][0]["file_name"] = "file".into();
+        json["subtrees"] = serde_json::json!([["../../etc", json.clone()]]);
+        assert!(serde_json::from_value::<Tree>(json).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_follow_symlinks() -> crate::Result<()> {
+        use std::os::unix::fs::symlink;
+
+        let store_dir = TempDir::new()?;
+        let original_dir = TempDir::new()?;
+        let shared_dir = TempDir::new()?;
+        let original = |path: &str| original_dir.path().join(path);
+
+        fs::write(shared_dir.path().join("file"), b"shared").await?;
+        fs::write(original("file"), b"file").await?;
+        symlink(shared_dir.path(), original("shared"))?;
+        symlink("file", original("link"))?;
+        symlink("missing", original("dangling"))?;
+        // Leads back into the tree, so it can't be followed
+        symlink(original_dir.path(), original("loop"))?;
+
+        let create = |follow| {
+            let store = Store::new(store_dir.path());
+            let options = CreateOptions::new().with_follow_symlinks(follow);
+            let path = original_dir.path().to_path_buf();
+            async move { Tree::create_with(&store, &path, CompressionKind::None, &options).await }
+        };
+        let links = |tree: &Tree| -> Vec<String> {
+            let mut links: Vec<String> = tree
+                .symlinks
+                .iter()
+                .map(|link| link.file_name.to_string_lossy().into_owned())
+                .collect();
+            links.sort();
+            links
+        };
+
+        let tree = create(FollowSymlinks::Never).await?;
+        assert_eq!(links(&tree), ["dangling", "link", "loop", "shared"]);
+
+        let tree = create(FollowSymlinks::Directories).await?;
+        assert_eq!(links(&tree), ["dangling", "link", "loop"]);
+        let (name, shared) = &tree.subtrees[0];
+        assert_eq!(name.to_string(), "shared");
+        assert_eq!(
+            shared.streams[0].hash,
+            blake3::hash(b"shared").to_hex().to_string()
+        );
+
+        let tree = create(FollowSymlinks::Always).await?;
+        assert_eq!(links(&tree), ["dangling", "loop"]);
+        let link = tree
+            .streams
+            .iter()
+            .find(|stream| stream.file_name == "link");
+        assert_eq!(
+            link.map(|stream| stream.hash.clone()),
+            Some(ObjectHash::of(b"file"))
+        );
+
+        // The store gets the file, not the symlink to it
+        let stored = store_dir
+            .path()
+            .join(blake3::hash(b"file").to_hex().to_string());
+        assert!(!stored.symlink_metadata()?.is_symlink());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_sorted() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let names = ["b", "a", "d", "c", "B", "10", "9"];
+
+        let mut manifests = Vec::new();
+        for order in [names.to_vec(), names.iter().rev().copied().collect()] {
+            let original_dir = TempDir::new()?;
+            for name in order {
+                std::fs::create_dir(original_dir.path().join(format!("dir-{name}")))?;
+                fs::write(original_dir.path().join(name), name).await?;
+            }
+
+            let tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+            let streams: Vec<_> = tree.streams.iter().map(|s| s.file_name.clone()).collect();
+            assert_eq!(streams, ["10", "9", "B", "a", "b", "c", "d"]);
+
+            let mut manifest = Vec::new();
+            tree.write_manifest(&mut manifest).await?;
+            manifests.push(manifest);
+        }
+
+        // Created in a different order, but listed the same way
+        assert_eq!(manifests[0], manifests[1]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_concurrent() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let original_dir = TempDir::new()?;
+
+        for dir in ["", "a", "a/b", "c"] {
+            let dir = original_dir.path().join(dir);
+            std::fs::create_dir_all(&dir)?;
+            for i in 0..20 {
+                // Some files share contents, and so their objects in the store
+                fs::write(dir.join(format!("file-{i}")), format!("{}", i % 7)).await?;
+            }
+        }
+
+        let manifest = |tree: Tree| async move {
+            let mut manifest = Vec::new();
+            tree.write_manifest(&mut manifest).await.map(|()| manifest)
+        };
+
+        let sequential = Store::new(store_dir.path());
+        let expected =
+            Tree::create(&sequential, original_dir.path(), CompressionKind::Zstd).await?;
+
+        let concurrent = Store::new(store_dir.path()).with_cpu_pool(CpuPool::new().threads(4));
+        let options = CreateOptions::new().with_concurrency(8);
+        let tree = Tree::create_with(
+            &concurrent,
+            original_dir.path(),
+            CompressionKind::Zstd,
+            &options,
+        )
+        .await?;
+
+        assert_eq!(tree.hash(), expected.hash());
+        assert_eq!(manifest(tree).await?, manifest(expected).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_name_policy() -> crate::Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let store_dir = TempDir::new()?;
+        let original_dir = TempDir::new()?;
+        let deploy_dir = TempDir::new()?;
+        let invalid = std::ffi::OsStr::from_bytes(b"caf\xe9");
+
+        std::fs::create_dir(original_dir.path().join("aux."))?;
+        fs::write(original_dir.path().join("aux./file"), b"file").await?;
+        fs::write(original_dir.path().join(invalid), b"invalid").await?;
+
+        let store = Store::new(store_dir.path());
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+
+        // Preserved names round-trip through manifests, even when they aren't UTF-8
+        let mut manifest = Vec::new();
+        tree.write_manifest(&mut manifest).await?;
+        let parsed = Tree::read_manifest(&manifest[..]).await?;
+        assert_eq!(parsed.hash(), tree.hash());
+
+        let refusing = Store::new(store_dir.path()).with_name_policy(NamePolicy::Error);
+        let refused = Tree::create(&refusing, original_dir.path(), CompressionKind::None)
+            .await
+            .map_err(crate::Error::from);
+        assert!(matches!(refused, Err(crate::Error::NonPortableName(_))));
+        assert!(matches!(
+            tree.deploy(&refusing, deploy_dir.path()),
+            Err(crate::Error::NonPortableName(_))
+        ));
+        assert_eq!(std::fs::read_dir(deploy_dir.path())?.count(), 0);
+
+        let sanitizing = Store::new(store_dir.path()).with_name_policy(NamePolicy::Sanitize);
+        tree.deploy(&sanitizing, deploy_dir.path())?;
+        assert_eq!(
+            fs::read_to_end(deploy_dir.path().join("aux_/file")).await?,
+            b"file"
+        );
+        assert_eq!(
+            fs::read_to_end(deploy_dir.path().join("caf\u{fffd}")).await?,
+            b"invalid"
+        );
+
+        let sanitized =
+            Tree::create(&sanitizing, original_dir.path(), CompressionKind::None).await?;
+        assert_eq!(sanitized.subtrees[0].0.as_path(), Path::new("aux_"));
+
+        Ok(())
+    }
 }
@@ -0,0 +1,280 @@
+//! File names that survive being synced to other platforms.
+//!
+//! Manifests write names as strings when they are valid UTF-8, and as their raw bytes in hex
+//! otherwise, so that any name a Unix filesystem allows round-trips. Names that Windows can't
+//! create, like `CON` or ones ending in a dot, are only handled when the store's [`NamePolicy`]
+//! asks for it.
+use serde::{Deserialize, Deserializer, Serializer, de::Error as _, ser::SerializeStruct};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::fmt::Write as _;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+
+use crate::store::NamePolicy;
+use crate::tree::{Tree, TreePath};
+
+/// Characters Windows doesn't allow in names, besides control characters.
+const RESERVED_CHARS: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// Names Windows reserves for devices, whatever their extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Whether a name can be created on Unix and Windows alike.
+pub(crate) fn is_portable(name: &OsStr) -> bool {
+    let Some(name) = name.to_str() else {
+        return false;
+    };
+    !name
+        .chars()
+        .any(|c| c.is_control() || RESERVED_CHARS.contains(&c))
+        && !name.ends_with(['.', ' '])
+        && !is_reserved(name)
+}
+
+fn is_reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or_default();
+    RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+}
+
+/// A portable name close to `name`: invalid UTF-8 and reserved characters are replaced, trailing
+/// dots and spaces become underscores, and reserved names get an underscore after their stem.
+pub(crate) fn sanitize(name: &OsStr) -> OsString {
+    let mut name: String = name
+        .to_string_lossy()
+        .chars()
+        .map(|c| {
+            if c.is_control() || RESERVED_CHARS.contains(&c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+
+    let kept = name.trim_end_matches(['.', ' ']).len();
+    let trailing = name.len() - kept;
+    name.truncate(kept);
+    name.extend(std::iter::repeat_n('_', trailing));
+
+    if is_reserved(&name) {
+        let stem = name.find('.').unwrap_or(name.len());
+        name.insert(stem, '_');
+    }
+    name.into()
+}
+
+/// Applies `policy` to the name of an entry in `dir`, checking that it doesn't take the name of
+/// an entry already in `taken`.
+///
+/// # Errors
+///
+/// - [`Error::NonPortableName`](crate::Error::NonPortableName) if the policy refuses the name, or
+///   sanitizing it collides with another entry
+pub(crate) fn apply<'a>(
+    policy: NamePolicy,
+    dir: &Path,
+    name: &'a OsStr,
+    taken: &mut HashSet<OsString>,
+) -> crate::Result<Cow<'a, OsStr>> {
+    let portable = match policy {
+        NamePolicy::Preserve => return Ok(Cow::Borrowed(name)),
+        _ if is_portable(name) => Cow::Borrowed(name),
+        NamePolicy::Error => return Err(non_portable(dir, name)),
+        NamePolicy::Sanitize => Cow::Owned(sanitize(name)),
+    };
+
+    if !taken.insert(portable.to_os_string()) {
+        return Err(non_portable(dir, name));
+    }
+    Ok(portable)
+}
+
+fn non_portable(dir: &Path, name: &OsStr) -> crate::Error {
+    crate::Error::NonPortableName(dir.join(name))
+}
+
+/// The tree as deploying it with `policy` names things, which is only a copy when something had
+/// to be sanitized.
+///
+/// # Errors
+///
+/// - [`Error::NonPortableName`](crate::Error::NonPortableName) if the policy refuses a name
+pub(crate) fn deployed(tree: &Tree, policy: NamePolicy) -> crate::Result<Cow<'_, Tree>> {
+    if policy == NamePolicy::Preserve {
+        return Ok(Cow::Borrowed(tree));
+    }
+    let mut deployed = tree.clone();
+    let changed = rename(&mut deployed, policy, &TreePath::root())?;
+    Ok(if changed {
+        Cow::Owned(deployed)
+    } else {
+        Cow::Borrowed(tree)
+    })
+}
+
+/// Applies `policy` to every name in `tree`, returning whether any changed.
+fn rename(tree: &mut Tree, policy: NamePolicy, path: &TreePath) -> crate::Result<bool> {
+    let mut taken = HashSet::new();
+    let mut changed = false;
+    let mut rename_one = |name: &mut OsString| {
+        if let Cow::Owned(portable) = apply(policy, path, name, &mut taken)? {
+            *name = portable;
+            changed = true;
+        }
+        Ok::<_, crate::Error>(())
+    };
+
+    for (name, _) in &mut tree.subtrees {
+        let mut os_name = name.as_os_str().to_os_string();
+        rename_one(&mut os_name)?;
+        *name = TreePath::new_unchecked(os_name);
+    }
+    for stream in &mut tree.streams {
+        rename_one(&mut stream.file_name)?;
+    }
+    for link in &mut tree.symlinks {
+        rename_one(&mut link.file_name)?;
+    }
+    for special in &mut tree.specials {
+        rename_one(&mut special.file_name)?;
+    }
+
+    for (name, subtree) in &mut tree.subtrees {
+        changed |= rename(subtree, policy, &path.join(name))?;
+    }
+    Ok(changed)
+}
+
+/// Writes a name as a string, or as `{"hex": ...}` if it isn't valid UTF-8.
+pub(crate) fn serialize<S: Serializer>(name: &OsStr, serializer: S) -> Result<S::Ok, S::Error> {
+    if let Some(name) = name.to_str() {
+        return serializer.serialize_str(name);
+    }
+
+    let mut hex = String::with_capacity(name.len() * 2);
+    for byte in name.as_bytes() {
+        let _ = write!(hex, "{byte:02x}");
+    }
+    let mut encoded = serializer.serialize_struct("Name", 1)?;
+    encoded.serialize_field("hex", &hex)?;
+    encoded.end()
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Encoded {
+    Utf8(String),
+    Bytes { hex: String },
+}
+
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<OsString, D::Error> {
+    match Encoded::deserialize(deserializer)? {
+        Encoded::Utf8(name) => Ok(name.into()),
+        Encoded::Bytes { hex } => {
+            let bytes = (0..hex.len())
+                .step_by(2)
+                .map(|i| {
+                    hex.get(i..i + 2)
+                        .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                })
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(|| D::Error::custom("invalid hex in file name"))?;
+            Ok(OsString::from_vec(bytes))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::{ObjectHash, Stream};
+
+    #[test]
+    fn test_portable_names() -> crate::Result<()> {
+        for portable in ["file.txt", "CONFIG", "con-1", ".hidden", "ünïcode"] {
+            assert!(is_portable(OsStr::new(portable)), "{portable}");
+        }
+        for (name, sanitized) in [
+            ("trailing.", "trailing_"),
+            ("spaces  ", "spaces__"),
+            ("con", "con_"),
+            ("NUL.txt", "NUL_.txt"),
+            ("a:b*c", "a_b_c"),
+            ("tab\t", "tab_"),
+        ] {
+            assert!(!is_portable(OsStr::new(name)), "{name}");
+            assert_eq!(sanitize(OsStr::new(name)), sanitized);
+        }
+        let invalid = OsStr::from_bytes(b"caf\xe9");
+        assert!(!is_portable(invalid));
+        assert_eq!(sanitize(invalid), "caf\u{fffd}");
+
+        // Names that aren't UTF-8 round-trip through manifests
+        let tree = Tree {
+            permissions: 0o755,
+            streams: vec![Stream {
+                hash: ObjectHash::of(b"contents"),
+                file_name: invalid.into(),
+                compression: None,
+                dictionary: None,
+                disk_size: None,
+                network_size: None,
+                mode: None,
+                mtime: None,
+                owner: None,
+            }],
+            subtrees: vec![(
+                TreePath::new(OsStr::from_bytes(b"dir\xff"))?,
+                Tree {
+                    permissions: 0o755,
+                    streams: Vec::new(),
+                    subtrees: Vec::new(),
+                    symlinks: Vec::new(),
+                    specials: Vec::new(),
+                    meta: None,
+                },
+            )],
+            symlinks: Vec::new(),
+            specials: Vec::new(),
+            meta: None,
+        };
+        let json = serde_json::to_string(&tree)?;
+        assert!(json.contains(r#"{"hex":"636166e9"}"#), "{json}");
+        let parsed: Tree = serde_json::from_str(&json)?;
+        assert_eq!(parsed.hash(), tree.hash());
+
+        assert!(matches!(
+            deployed(&tree, NamePolicy::Error),
+            Err(crate::Error::NonPortableName(_))
+        ));
+        let sanitized = deployed(&tree, NamePolicy::Sanitize)?;
+        assert_eq!(sanitized.streams[0].file_name, "caf\u{fffd}");
+        assert_eq!(sanitized.subtrees[0].0.as_path(), "dir\u{fffd}");
+        assert!(matches!(
+            deployed(&tree, NamePolicy::Preserve)?,
+            Cow::Borrowed(_)
+        ));
+
+        // Sanitizing can't merge two entries into one
+        let mut colliding = tree.clone();
+        colliding.streams.push(Stream {
+            file_name: "caf\u{fffd}".into(),
+            ..tree.streams[0].clone()
+        });
+        assert!(matches!(
+            deployed(&colliding, NamePolicy::Sanitize),
+            Err(crate::Error::NonPortableName(_))
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,272 @@
+//! Nix archives (NAR), a canonical encoding of trees.
+//!
+//! A NAR keeps only what Nix considers part of a tree's contents: names, file contents, symlink
+//! targets and whether files are executable. Entries are sorted by name and every field is
+//! padded the same way, so the same contents always give the same bytes, whatever modes,
+//! mtimes or owners they were captured with. That makes [`Tree::nar_hash`] comparable with a
+//! rebuild's, and with the `narHash` Nix records for a store path.
+use sha2::{Digest, Sha256};
+use std::ffi::OsStr;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+
+use crate::store::Store;
+use crate::tree::{Tree, TreePath};
+
+const MAGIC: &str = "nix-archive-1";
+const NIX_BASE32: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+impl Tree {
+    /// Writes the tree as a NAR, reading every file's contents from the store.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically a stream missing from the store)
+    /// - Errors writing to `writer`
+    /// - [`io::ErrorKind::Unsupported`] for special files, which NARs can't hold
+    pub fn write_nar<W: Write>(&self, writer: W, store: &Store) -> crate::Result<()> {
+        let mut nar = Nar { writer, store };
+        nar.str(MAGIC)?;
+        nar.dir(self, &TreePath::root())?;
+        nar.writer.flush()?;
+        Ok(())
+    }
+
+    /// The SHA-256 hash of the tree's [NAR](Tree::write_nar), written like Nix writes a
+    /// `narHash`: `sha256:` and Nix's base-32.
+    ///
+    /// # Errors
+    ///
+    /// - See [`Tree::write_nar`]
+    pub fn nar_hash(&self, store: &Store) -> crate::Result<String> {
+        let mut hasher = HashWriter(Sha256::new());
+        self.write_nar(&mut hasher, store)?;
+        Ok(format!("sha256:{}", nix_base32(&hasher.0.finalize())))
+    }
+}
+
+/// Something in a directory, borrowed from the tree.
+enum Node<'a> {
+    Dir(&'a Tree),
+    File(&'a crate::stream::Stream),
+    Symlink(&'a std::path::Path),
+    Special,
+}
+
+struct Nar<'a, W> {
+    writer: W,
+    store: &'a Store,
+}
+
+impl<W: Write> Nar<'_, W> {
+    /// Writes a length-prefixed string, padded to 8 bytes.
+    fn bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(&(bytes.len() as u64).to_le_bytes())?;
+        self.writer.write_all(bytes)?;
+        self.pad(bytes.len() as u64)
+    }
+
+    fn str(&mut self, s: &str) -> io::Result<()> {
+        self.bytes(s.as_bytes())
+    }
+
+    fn pad(&mut self, len: u64) -> io::Result<()> {
+        let padding = (8 - len % 8) % 8;
+        self.writer.write_all(&[0; 8][..padding as usize])
+    }
+
+    fn dir(&mut self, tree: &Tree, path: &TreePath) -> crate::Result<()> {
+        let mut entries: Vec<(&OsStr, Node)> = tree
+            .subtrees
+            .iter()
+            .map(|(name, subtree)| (name.as_os_str(), Node::Dir(subtree)))
+            .chain(
+                tree.streams
+                    .iter()
+                    .map(|s| (s.file_name.as_os_str(), Node::File(s))),
+            )
+            .chain(
+                (tree.symlinks.iter())
+                    .map(|s| (s.file_name.as_os_str(), Node::Symlink(s.target.as_path()))),
+            )
+            .chain((tree.specials.iter()).map(|s| (s.file_name.as_os_str(), Node::Special)))
+            .collect();
+        entries.sort_unstable_by_key(|(name, _)| name.as_bytes());
+
+        self.str("(")?;
+        self.str("type")?;
+        self.str("directory")?;
+        for (name, node) in entries {
+            let path = path.join(&TreePath::new_unchecked(name));
+            self.str("entry")?;
+            self.str("(")?;
+            self.str("name")?;
+            self.bytes(name.as_bytes())?;
+            self.str("node")?;
+            match node {
+                Node::Dir(subtree) => self.dir(subtree, &path)?,
+                Node::File(stream) => self.file(stream)?,
+                Node::Symlink(target) => {
+                    self.str("(")?;
+                    self.str("type")?;
+                    self.str("symlink")?;
+                    self.str("target")?;
+                    self.bytes(target.as_os_str().as_bytes())?;
+                    self.str(")")?;
+                }
+                Node::Special => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!("NARs can't hold special files: {path}"),
+                    )
+                    .into());
+                }
+            }
+            self.str(")")?;
+        }
+        self.str(")")?;
+        Ok(())
+    }
+
+    fn file(&mut self, stream: &crate::stream::Stream) -> io::Result<()> {
+        let mut file = std::fs::File::open(self.store.path_of(&stream.hash))?;
+        let len = file.metadata()?.len();
+
+        self.str("(")?;
+        self.str("type")?;
+        self.str("regular")?;
+        if stream.mode.is_some_and(|mode| mode & 0o100 != 0) {
+            self.str("executable")?;
+            self.str("")?;
+        }
+        self.str("contents")?;
+        self.writer.write_all(&len.to_le_bytes())?;
+        let copied = io::copy(&mut io::Read::take(&mut file, len), &mut self.writer)?;
+        if copied != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.pad(len)?;
+        self.str(")")
+    }
+}
+
+struct HashWriter(Sha256);
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Nix's base-32, which reads the bytes from the end and leaves out `e`, `o`, `u` and `t`.
+fn nix_base32(bytes: &[u8]) -> String {
+    let len = (bytes.len() * 8).div_ceil(5);
+    (0..len)
+        .rev()
+        .map(|n| {
+            let (i, j) = (n * 5 / 8, n * 5 % 8);
+            let low = bytes[i] >> j;
+            let high = bytes
+                .get(i + 1)
+                .map_or(0, |&byte| u16::from(byte) << (8 - j));
+            char::from(NIX_BASE32[usize::from((u16::from(low) | high) & 0x1f)])
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::CompressionKind;
+    use crate::fs;
+
+    #[test]
+    fn test_nix_base32() {
+        assert_eq!(
+            nix_base32(&Sha256::digest(b"")),
+            "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_nar() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path()).with_mtimes(true);
+        let original_dir = TempDir::new()?;
+
+        fs::write(original_dir.path().join("tool"), b"#!/bin/sh").await?;
+        std::fs::set_permissions(
+            original_dir.path().join("tool"),
+            std::fs::Permissions::from_mode(0o755),
+        )?;
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+
+        let mut nar = Vec::new();
+        tree.write_nar(&mut nar, &store)?;
+        let mut expected = Vec::new();
+        {
+            let mut expected = Nar {
+                writer: &mut expected,
+                store: &store,
+            };
+            for s in [
+                MAGIC,
+                "(",
+                "type",
+                "directory",
+                "entry",
+                "(",
+                "name",
+                "tool",
+                "node",
+                "(",
+                "type",
+                "regular",
+                "executable",
+                "",
+                "contents",
+                "#!/bin/sh",
+                ")",
+                ")",
+                ")",
+            ] {
+                expected.str(s)?;
+            }
+        }
+        assert_eq!(nar, expected);
+
+        // Only contents and the executable bit matter, not other modes or mtimes
+        let hash = tree.nar_hash(&store)?;
+        assert!(hash.starts_with("sha256:"));
+        std::fs::set_permissions(
+            original_dir.path().join("tool"),
+            std::fs::Permissions::from_mode(0o700),
+        )?;
+        reset_mtime(&original_dir.path().join("tool"))?;
+        let changed = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+        assert_ne!(changed.hash(), tree.hash());
+        assert_eq!(changed.nar_hash(&store)?, hash);
+
+        std::fs::set_permissions(
+            original_dir.path().join("tool"),
+            std::fs::Permissions::from_mode(0o644),
+        )?;
+        let plain = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+        assert_ne!(plain.nar_hash(&store)?, hash);
+
+        Ok(())
+    }
+
+    fn reset_mtime(path: &std::path::Path) -> io::Result<()> {
+        let file = std::fs::File::options().write(true).open(path)?;
+        file.set_modified(std::time::SystemTime::UNIX_EPOCH)
+    }
+}
@@ -0,0 +1,213 @@
+//! Paths inside a tree.
+//!
+//! Manifests come from remote repositories, so every path they contain has to stay inside the
+//! directory the tree is deployed to. A [`TreePath`] can only be built from a relative path
+//! without `..`, which is checked once when it is constructed or deserialized.
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error as _};
+use std::ffi::OsStr;
+use std::fmt;
+use std::ops::Deref;
+use std::path::{Component, Path, PathBuf};
+
+use crate::tree::name;
+
+/// A normalized path relative to the root of a tree. The root itself is the empty path.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TreePath(PathBuf);
+
+impl TreePath {
+    /// Validates and normalizes a path, dropping `.` components and redundant separators.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidPath`](crate::Error::InvalidPath) for absolute paths, or paths
+    ///   containing `..`
+    pub fn new<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
+        let path = path.as_ref();
+        if !is_valid_path(path) {
+            return Err(crate::Error::InvalidPath(path.to_path_buf()));
+        }
+
+        Ok(Self(
+            path.components()
+                .filter(|component| matches!(component, Component::Normal(_)))
+                .collect(),
+        ))
+    }
+
+    /// The root of the tree.
+    #[must_use]
+    pub fn root() -> Self {
+        Self::default()
+    }
+
+    /// Wraps a path that is already known to be valid and normalized, such as a single name
+    /// from `read_dir`.
+    pub(crate) fn new_unchecked<P: Into<PathBuf>>(path: P) -> Self {
+        Self(path.into())
+    }
+
+    #[must_use]
+    pub fn is_root(&self) -> bool {
+        self.0.as_os_str().is_empty()
+    }
+
+    #[must_use]
+    pub fn as_path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Appends another tree path, which can never escape the tree.
+    #[must_use]
+    pub fn join(&self, path: &TreePath) -> TreePath {
+        Self(self.0.join(&path.0))
+    }
+
+    /// The last component as a tree path of its own, or the root for the root.
+    #[must_use]
+    pub fn name(&self) -> TreePath {
+        self.0
+            .file_name()
+            .map(Self::new_unchecked)
+            .unwrap_or_default()
+    }
+}
+
+/// Checks that a path stays inside the tree, without allocating.
+pub(crate) fn is_valid_path(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Whether a symlink in `dir` pointing at `target` may resolve to somewhere outside the tree.
+///
+/// Targets may only go up with `..` before naming anything, and not past the root. Going up
+/// later is refused too, as it would go up from wherever another symlink along the way points.
+pub(crate) fn target_escapes(dir: &Path, target: &Path) -> bool {
+    let mut depth = dir
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .count();
+    let mut descended = false;
+
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => descended = true,
+            Component::CurDir => {}
+            Component::ParentDir if !descended && depth > 0 => depth -= 1,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return true,
+        }
+    }
+
+    false
+}
+
+/// Checks that a file name is a single component, which can't escape its directory.
+pub(crate) fn is_valid_name(name: &OsStr) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(component)), None) if component == name
+    )
+}
+
+impl Deref for TreePath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TreePath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl From<TreePath> for PathBuf {
+    fn from(path: TreePath) -> Self {
+        path.0
+    }
+}
+
+impl TryFrom<PathBuf> for TreePath {
+    type Error = crate::Error;
+
+    fn try_from(path: PathBuf) -> crate::Result<Self> {
+        Self::new(path)
+    }
+}
+
+impl TryFrom<&str> for TreePath {
+    type Error = crate::Error;
+
+    fn try_from(path: &str) -> crate::Result<Self> {
+        Self::new(path)
+    }
+}
+
+impl fmt::Display for TreePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.display().fmt(f)
+    }
+}
+
+impl Serialize for TreePath {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        name::serialize(self.0.as_os_str(), serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for TreePath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let path = name::deserialize(deserializer)?;
+        Self::new(path).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tree_path() -> crate::Result<()> {
+        assert_eq!(TreePath::new("./a//b/./c/")?.as_path(), Path::new("a/b/c"));
+        assert!(TreePath::new("")?.is_root());
+        assert!(TreePath::new(".")?.is_root());
+
+        for invalid in ["/etc", "../a", "a/../../b", "a/.."] {
+            assert!(
+                matches!(TreePath::new(invalid), Err(crate::Error::InvalidPath(_))),
+                "{invalid}"
+            );
+        }
+
+        let a = TreePath::new("a/b")?;
+        assert_eq!(a.join(&TreePath::new("c")?).as_path(), Path::new("a/b/c"));
+        assert_eq!(a.name().as_path(), Path::new("b"));
+
+        assert!(serde_json::from_str::<TreePath>("\"../escape\"").is_err());
+        assert_eq!(serde_json::to_string(&a)?, "\"a/b\"");
+
+        assert!(is_valid_name(OsStr::new("file")));
+        for invalid in ["", ".", "..", "a/b", "/a", "a/"] {
+            assert!(!is_valid_name(OsStr::new(invalid)), "{invalid}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_target_escapes() {
+        let dir = Path::new("a/b");
+        for inside in ["c", "./c", "..", "../../c", "../c/d"] {
+            assert!(!target_escapes(dir, Path::new(inside)), "{inside}");
+        }
+        // Going up after descending may be through another symlink
+        for outside in ["/etc", "../../..", "c/../..", "s/s/../.."] {
+            assert!(target_escapes(dir, Path::new(outside)), "{outside}");
+        }
+        assert!(target_escapes(Path::new(""), Path::new("..")));
+    }
+}
@@ -0,0 +1,203 @@
+use crate::repo_set::{RepoSet, SourceReport};
+use crate::store::Store;
+use crate::stream::Stream;
+use crate::tree::{Tree, all_streams};
+use crate::{CompressionKind, Mirrors};
+use std::collections::HashSet;
+
+/// The streams that have to be downloaded before a tree can be deployed.
+///
+/// Each stream is only listed once, even if several files share it.
+#[derive(Clone, Debug, Default)]
+pub struct DownloadPlan {
+    streams: Vec<Stream>,
+}
+
+impl DownloadPlan {
+    /// Plans the download of everything in `tree` that isn't already in the store.
+    #[must_use]
+    pub fn for_tree(tree: &Tree, store: &Store) -> Self {
+        Self::plan(tree, &HashSet::new(), store)
+    }
+
+    /// Plans an upgrade from the currently deployed `old` tree to `new`.
+    ///
+    /// Streams which `old` already uses are expected to be in the store, so only streams that
+    /// are new to `new` and missing from the store are downloaded.
+    #[must_use]
+    pub fn for_upgrade(old: &Tree, new: &Tree, store: &Store) -> Self {
+        let existing = all_streams(old).map(|s| s.hash.as_str()).collect();
+        Self::plan(new, &existing, store)
+    }
+
+    fn plan(tree: &Tree, existing: &HashSet<&str>, store: &Store) -> Self {
+        let mut seen = HashSet::new();
+        let streams = all_streams(tree)
+            .filter(|s| !existing.contains(s.hash.as_str()))
+            .filter(|s| seen.insert(s.hash.as_str()))
+            .filter(|s| !store.contains(&s.hash))
+            .cloned()
+            .collect();
+
+        Self { streams }
+    }
+
+    /// The streams to download.
+    #[must_use]
+    pub fn streams(&self) -> &[Stream] {
+        &self.streams
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.streams.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.streams.is_empty()
+    }
+
+    /// The planned streams that none of the mirrors has, checked without downloading them, so
+    /// that a long download that can't complete isn't started.
+    ///
+    /// # Errors
+    ///
+    /// - See [`Mirrors::has_stream`]
+    pub async fn unavailable<C: Into<Option<CompressionKind>>>(
+        &self,
+        mirrors: &Mirrors,
+        compression: C,
+    ) -> crate::Result<Vec<&Stream>> {
+        let compression = compression.into();
+        let mut unavailable = Vec::new();
+        for stream in &self.streams {
+            if !mirrors.has_stream(stream, compression).await? {
+                unavailable.push(stream);
+            }
+        }
+
+        Ok(unavailable)
+    }
+
+    /// Downloads every planned stream.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically out of space)
+    /// - Network errors (Non-2xx codes, etc)
+    pub async fn download<C: Into<Option<CompressionKind>>>(
+        &self,
+        repo_url: &str,
+        store: &Store,
+        compression: C,
+    ) -> crate::Result<()> {
+        self.download_mirrored(&Mirrors::from(repo_url), store, compression)
+            .await
+    }
+
+    /// Downloads every planned stream, see [`Tree::download_mirrored`].
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically out of space)
+    /// - Network errors, if no mirror could serve a stream
+    pub async fn download_mirrored<C: Into<Option<CompressionKind>>>(
+        &self,
+        mirrors: &Mirrors,
+        store: &Store,
+        compression: C,
+    ) -> crate::Result<()> {
+        store.clean_before_download()?;
+        let compression = compression.into();
+        for stream in &self.streams {
+            stream
+                .download_mirrored(mirrors, store, compression)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Downloads every planned stream, see [`Tree::download_from`].
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically out of space)
+    /// - Network errors, if no repository could serve a stream
+    pub async fn download_from(
+        &self,
+        repos: &RepoSet,
+        store: &Store,
+    ) -> crate::Result<SourceReport> {
+        repos.download_streams(&self.streams, store).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use httpmock::prelude::*;
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::fs;
+
+    #[tokio::test]
+    async fn test_plan_for_upgrade() -> crate::Result<()> {
+        let compression = CompressionKind::None;
+        let remote_stream_dir = TempDir::new()?;
+        let local_stream_dir = TempDir::new()?;
+        let old_dir = TempDir::new()?;
+        let new_dir = TempDir::new()?;
+        let remote_store = Store::new(remote_stream_dir.path());
+        let local_store = Store::new(local_stream_dir.path());
+
+        fs::write(old_dir.path().join("kept"), b"kept").await?;
+        fs::write(old_dir.path().join("changed"), b"old").await?;
+        std::fs::create_dir_all(new_dir.path().join("sub"))?;
+        fs::write(new_dir.path().join("kept"), b"kept").await?;
+        fs::write(new_dir.path().join("changed"), b"new").await?;
+        fs::write(new_dir.path().join("sub/copy"), b"new").await?;
+        fs::write(new_dir.path().join("sub/cached"), b"cached").await?;
+
+        let old = Tree::create(&remote_store, old_dir.path(), compression).await?;
+        let new = Tree::create(&remote_store, new_dir.path(), compression).await?;
+
+        // Pretend that one of the new streams was already fetched by something else
+        let cached_hash = blake3::hash(b"cached").to_hex().to_string();
+        fs::write(local_store.path_of(&cached_hash), b"cached").await?;
+
+        let full = DownloadPlan::for_tree(&new, &local_store);
+        assert_eq!(full.len(), 2);
+
+        let upgrade = DownloadPlan::for_upgrade(&old, &new, &local_store);
+        let new_hash = blake3::hash(b"new").to_hex().to_string();
+        assert_eq!(upgrade.len(), 1);
+        assert_eq!(upgrade.streams()[0].hash, new_hash);
+
+        let server = MockServer::start();
+        let mirrors = Mirrors::from(server.base_url().as_str());
+        let unavailable = upgrade.unavailable(&mirrors, compression).await?;
+        assert_eq!(unavailable, [&upgrade.streams()[0]]);
+        server.mock(|when, then| {
+            when.method(Method::HEAD)
+                .path(format!("/streams/{new_hash}"));
+            then.status(200);
+        });
+        assert!(upgrade.unavailable(&mirrors, compression).await?.is_empty());
+
+        let mock = server.mock(|when, then| {
+            when.method(GET).path(format!("/streams/{new_hash}"));
+            then.status(200).body("new");
+        });
+
+        upgrade
+            .download(&server.base_url(), &local_store, compression)
+            .await?;
+        mock.assert_calls(1);
+
+        assert!(DownloadPlan::for_upgrade(&old, &new, &local_store).is_empty());
+
+        Ok(())
+    }
+}
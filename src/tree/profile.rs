@@ -0,0 +1,246 @@
+//! Numbered generations of a deployment, with instant rollback.
+//!
+//! A [`Profile`] deploys every tree into a new directory named after its generation number, then
+//! points the `current` symlink at it in one step. Switching back to an older generation only
+//! replaces the symlink, so a failed update is undone without downloading or copying anything.
+//!
+//! ```text
+//! {root}/1
+//! {root}/2
+//! {root}/current -> 2
+//! ```
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::store::Store;
+use crate::tree::Tree;
+use crate::tree::deployment::Deployment;
+
+/// The name of the symlink to the current generation, in the root of the profile.
+pub const CURRENT_NAME: &str = "current";
+
+/// Generations of trees deployed under one root, see the [module docs](self).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Profile {
+    root: PathBuf,
+}
+
+/// A deployed generation of a [`Profile`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Generation {
+    pub number: u64,
+    pub path: PathBuf,
+    /// The record the deploy wrote, `None` if it was removed
+    pub deployment: Option<Deployment>,
+}
+
+impl Profile {
+    #[must_use]
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    #[must_use]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The path that always leads to the current generation.
+    #[must_use]
+    pub fn current_path(&self) -> PathBuf {
+        self.root.join(CURRENT_NAME)
+    }
+
+    /// Deploys `tree` as a new generation and switches to it, returning its number. The
+    /// generation is [deployed atomically](Tree::deploy_atomic), so `current` never points at a
+    /// half-deployed tree.
+    ///
+    /// # Errors
+    ///
+    /// - See [`Tree::deploy`]
+    pub fn deploy(&self, tree: &Tree, store: &Store) -> crate::Result<u64> {
+        std::fs::create_dir_all(&self.root)?;
+        let number = self.numbers()?.last().map_or(1, |last| last + 1);
+        tree.deploy_atomic(store, &self.generation_path(number))?;
+        self.switch_to(number)?;
+        Ok(number)
+    }
+
+    /// Every generation, oldest first.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically permissions)
+    /// - [`Error::ManifestError`](crate::Error::ManifestError) if a deployment record is
+    ///   corrupted
+    pub fn generations(&self) -> crate::Result<Vec<Generation>> {
+        self.numbers()?
+            .into_iter()
+            .map(|number| {
+                let path = self.generation_path(number);
+                Ok(Generation {
+                    number,
+                    deployment: Deployment::load(&path)?,
+                    path,
+                })
+            })
+            .collect()
+    }
+
+    /// The generation `current` points at, or `None` before the first deploy.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically permissions)
+    /// - [`Error::InvalidPath`](crate::Error::InvalidPath) if `current` doesn't point at a
+    ///   generation
+    pub fn current(&self) -> crate::Result<Option<u64>> {
+        let target = match std::fs::read_link(self.current_path()) {
+            Ok(target) => target,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        target
+            .to_str()
+            .and_then(|target| target.parse().ok())
+            .map(Some)
+            .ok_or_else(|| crate::Error::InvalidPath(target))
+    }
+
+    /// Points `current` at `generation`, replacing the symlink atomically.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically permissions)
+    /// - [`Error::NotFound`](crate::Error::NotFound) if there is no such generation
+    pub fn switch_to(&self, generation: u64) -> crate::Result<()> {
+        if !self.generation_path(generation).is_dir() {
+            return Err(crate::Error::NotFound(format!("generation {generation}")));
+        }
+
+        let tmp_path = self
+            .root
+            .join(format!(".{CURRENT_NAME}.{}.tmp", std::process::id()));
+        match std::fs::remove_file(&tmp_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        std::os::unix::fs::symlink(generation.to_string(), &tmp_path)?;
+        std::fs::rename(&tmp_path, self.current_path())?;
+        Ok(())
+    }
+
+    /// Switches to the generation `steps` before the current one, returning its number.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically permissions)
+    /// - [`Error::NotFound`](crate::Error::NotFound) if there is no current generation, or not
+    ///   that many before it
+    pub fn rollback(&self, steps: usize) -> crate::Result<u64> {
+        let current = self
+            .current()?
+            .ok_or_else(|| crate::Error::NotFound("current generation".to_string()))?;
+        let numbers = self.numbers()?;
+        let index = numbers
+            .iter()
+            .position(|&number| number == current)
+            .ok_or_else(|| crate::Error::NotFound(format!("generation {current}")))?;
+        let target = index
+            .checked_sub(steps)
+            .map(|index| numbers[index])
+            .ok_or_else(|| {
+                crate::Error::NotFound(format!("generation {steps} before {current}"))
+            })?;
+
+        self.switch_to(target)?;
+        Ok(target)
+    }
+
+    fn generation_path(&self, number: u64) -> PathBuf {
+        self.root.join(number.to_string())
+    }
+
+    /// The numbers of every generation, sorted.
+    fn numbers(&self) -> io::Result<Vec<u64>> {
+        let mut numbers = Vec::new();
+        let entries = match std::fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(numbers),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let number = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok());
+            if let Some(number) = number {
+                if entry.file_type()?.is_dir() {
+                    numbers.push(number);
+                }
+            }
+        }
+        numbers.sort_unstable();
+        Ok(numbers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::CompressionKind;
+    use crate::fs;
+
+    #[tokio::test]
+    async fn test_profile_rollback() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let original_dir = TempDir::new()?;
+        let profile_dir = TempDir::new()?;
+        let profile = Profile::new(profile_dir.path().join("app"));
+        let read = || std::fs::read(profile.current_path().join("version"));
+
+        assert_eq!(profile.current()?, None);
+        assert!(profile.generations()?.is_empty());
+
+        fs::write(original_dir.path().join("version"), b"1").await?;
+        let first = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+        fs::write(original_dir.path().join("version"), b"2").await?;
+        let second = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+
+        assert_eq!(profile.deploy(&first, &store)?, 1);
+        assert_eq!(profile.deploy(&second, &store)?, 2);
+        assert_eq!(profile.current()?, Some(2));
+        assert_eq!(read()?, b"2");
+
+        let generations = profile.generations()?;
+        assert_eq!(generations.len(), 2);
+        assert_eq!(generations[0].path, profile_dir.path().join("app/1"));
+        assert_eq!(
+            generations[0].deployment.as_ref().map(|d| &d.tree_hash),
+            Some(&first.hash())
+        );
+
+        assert_eq!(profile.rollback(1)?, 1);
+        assert_eq!(read()?, b"1");
+        assert!(matches!(
+            profile.rollback(1),
+            Err(crate::Error::NotFound(_))
+        ));
+
+        // Deploying after a rollback still adds a new generation
+        profile.switch_to(2)?;
+        assert_eq!(read()?, b"2");
+        assert_eq!(profile.deploy(&first, &store)?, 3);
+        assert_eq!(profile.rollback(2)?, 1);
+        assert!(matches!(
+            profile.switch_to(4),
+            Err(crate::Error::NotFound(_))
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+//! Removing what a tree doesn't describe from a deployment, like `rsync --delete`.
+//!
+//! Deploying never deletes anything, so files from older trees or made by hand accumulate. Pruning
+//! is opt-in, and [`Tree::extraneous`] lists what would be removed so callers can confirm first.
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::path::Path;
+
+use crate::tree::deploy::Dir;
+use crate::tree::deployment::RECORD_NAME;
+use crate::tree::journal::JOURNAL_NAME;
+use crate::tree::{Tree, TreePath};
+
+impl Tree {
+    /// Lists everything in `deploy_path` that the tree doesn't describe, without changing
+    /// anything. Extraneous directories are listed once, rather than everything inside them.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically permissions)
+    pub fn extraneous(&self, deploy_path: &Path) -> crate::Result<Vec<TreePath>> {
+        let mut found = Vec::new();
+        self.walk_extraneous(
+            &Dir::open(deploy_path)?,
+            &TreePath::root(),
+            &mut |_, path, _| {
+                found.push(path);
+                Ok(())
+            },
+        )?;
+
+        found.sort();
+        Ok(found)
+    }
+
+    /// Removes everything in `deploy_path` that the tree doesn't describe, returning what was
+    /// removed. Meant to follow [`Tree::deploy`], see [`Tree::extraneous`] for a dry run.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically permissions)
+    pub fn prune(&self, deploy_path: &Path) -> crate::Result<Vec<TreePath>> {
+        let mut removed = Vec::new();
+        self.walk_extraneous(
+            &Dir::open(deploy_path)?,
+            &TreePath::root(),
+            &mut |dir, path, is_dir| {
+                dir.remove_all(path.file_name().unwrap_or_default(), is_dir)?;
+                removed.push(path);
+                Ok(())
+            },
+        )?;
+
+        removed.sort();
+        Ok(removed)
+    }
+
+    fn walk_extraneous<F: FnMut(&Dir, TreePath, bool) -> std::io::Result<()>>(
+        &self,
+        dir: &Dir,
+        path: &TreePath,
+        found: &mut F,
+    ) -> std::io::Result<()> {
+        let files: HashSet<&OsStr> = self
+            .streams
+            .iter()
+            .map(|stream| stream.file_name.as_os_str())
+            .chain(self.symlinks.iter().map(|link| link.file_name.as_os_str()))
+            .chain(
+                self.specials
+                    .iter()
+                    .map(|special| special.file_name.as_os_str()),
+            )
+            .collect();
+        let subtrees: HashMap<&OsStr, &Tree> = self
+            .subtrees
+            .iter()
+            .map(|(name, subtree)| (name.as_os_str(), subtree))
+            .collect();
+
+        for (name, is_dir) in dir.entries()? {
+            // The deployment's own record, and the journal of a deploy in progress
+            if path.is_root() && (name == RECORD_NAME || name == JOURNAL_NAME) {
+                continue;
+            }
+
+            let entry_path = path.join(&TreePath::new_unchecked(&name));
+            match subtrees.get(name.as_os_str()) {
+                Some(subtree) if is_dir => {
+                    let subdir = dir.open_path(Path::new(&name))?;
+                    subtree.walk_extraneous(&subdir, &entry_path, found)?;
+                }
+                _ if files.contains(name.as_os_str()) => {}
+                _ => found(dir, entry_path, is_dir)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use crate::CompressionKind;
+    use crate::fs;
+    use crate::store::Store;
+    use crate::tree::{Tree, TreePath};
+
+    #[tokio::test]
+    async fn test_prune() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let original_dir = TempDir::new()?;
+        let deploy_dir = TempDir::new()?;
+        let deployed = |path: &str| deploy_dir.path().join(path);
+
+        std::fs::create_dir(original_dir.path().join("dir"))?;
+        fs::write(original_dir.path().join("dir/kept"), b"kept").await?;
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+        tree.deploy(&store, deploy_dir.path())?;
+
+        std::fs::create_dir_all(deployed("old/nested"))?;
+        fs::write(deployed("old/nested/file"), b"old").await?;
+        fs::write(deployed("dir/stray"), b"stray").await?;
+        std::os::unix::fs::symlink(original_dir.path(), deployed("link"))?;
+
+        let expected = ["dir/stray", "link", "old"].map(|path| TreePath::new(path).unwrap());
+
+        // Dry runs don't change anything
+        assert_eq!(tree.extraneous(deploy_dir.path())?, expected);
+        assert!(deployed("old/nested/file").exists());
+
+        assert_eq!(tree.prune(deploy_dir.path())?, expected);
+        assert!(tree.extraneous(deploy_dir.path())?.is_empty());
+        assert!(deployed("dir/kept").exists());
+        // Symlinks are removed, never followed
+        assert!(original_dir.path().join("dir/kept").exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,554 @@
+//! Trees published under a name, like `myapp/stable`, so that clients can follow a channel
+//! instead of hard-coding manifest URLs.
+//!
+//! A repository keeps the manifest of each name at `trees/{name}`, next to `streams/`. Publishing
+//! pushes the tree's streams before replacing the manifest in one step, so a client fetching the
+//! name never sees a tree whose streams are missing.
+use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::mirrors::{Fallthrough, Mirrors};
+use crate::net::{self, Location};
+use crate::store::Store;
+use crate::stream::{Stream, response_reader};
+use crate::tree::diff::DiffStats;
+use crate::tree::manifest::ManifestValidators;
+use crate::tree::path::is_valid_name;
+use crate::tree::{Tree, all_streams};
+use crate::{CompressionKind, fs, ssh};
+
+/// Whether `name` is usable as a reference: `/`-separated names that can't escape `trees/`.
+pub(crate) fn is_valid_ref(name: &str) -> bool {
+    !name.is_empty() && name.split('/').all(|part| is_valid_name(OsStr::new(part)))
+}
+
+fn ref_object(name: &str) -> crate::Result<String> {
+    if is_valid_ref(name) {
+        Ok(format!("trees/{name}"))
+    } else {
+        Err(crate::Error::InvalidRef(name.to_string()))
+    }
+}
+
+/// A tree a reference moved on to, see [`Tree::check_for_update`].
+#[derive(Clone, Debug)]
+pub struct Update {
+    /// The tree the reference points at now. None of its streams were downloaded yet.
+    pub tree: Tree,
+    /// How it differs from the current tree
+    pub stats: DiffStats,
+}
+
+/// How a broken reference can be fixed, see [`Tree::check_refs`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Repair {
+    /// Push the tree again, as the store has every stream the repository is missing
+    Reupload,
+    /// Publish an older, complete tree under the name, as the tree can't be restored
+    RollBack,
+}
+
+/// What [`Tree::check_refs`] found for a reference.
+#[derive(Clone, Debug)]
+pub struct RefCheck {
+    pub name: String,
+    /// The tree the reference points at, `None` if its manifest is missing or malformed
+    pub tree: Option<Tree>,
+    /// Why the manifest couldn't be read
+    pub error: Option<String>,
+    /// Hashes of the tree's streams the repository doesn't have, out of the ones checked
+    pub missing: Vec<String>,
+    /// What fixes the reference, `None` if nothing needs fixing
+    pub repair: Option<Repair>,
+}
+
+impl Tree {
+    /// Pushes the tree, then points the reference `name` at it, replacing whichever tree it
+    /// pointed at before. HTTP repositories must accept `PUT` uploads of manifests, like the
+    /// built-in server.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidRef`](crate::Error::InvalidRef) for names that aren't usable as
+    ///   references, before anything is pushed
+    /// - See [`Tree::push`]
+    pub async fn publish(
+        &self,
+        repo_url: &str,
+        name: &str,
+        store: &Store,
+        compression: CompressionKind,
+    ) -> crate::Result<()> {
+        // Every publish gets its own temporary file, so concurrent ones never share one
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let object = ref_object(name)?;
+        self.push(repo_url, store, compression).await?;
+
+        let mut manifest = Vec::new();
+        self.write_manifest(&mut manifest).await?;
+
+        match Location::parse(repo_url) {
+            Location::Local(root) => {
+                let target = root.join(&object);
+                if let Some(parent) = target.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+                let tmp = root.join(format!("{object}.{}-{n}.tmp", std::process::id()));
+                let res = std::fs::write(&tmp, &manifest).and_then(|()| fs::rename(&tmp, &target));
+                if res.is_err() {
+                    let _ = std::fs::remove_file(&tmp);
+                }
+                res?;
+            }
+            Location::Ssh(remote) => {
+                let tmp = store.temp_path("manifest");
+                std::fs::write(&tmp, &manifest)?;
+                let res = remote.write(&object, &tmp).await;
+                std::fs::remove_file(&tmp)?;
+                res?;
+            }
+            Location::Http(url) => {
+                net::default_client()
+                    .put(format!("{url}/{object}"))
+                    .body(manifest)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fetches the tree the reference `name` currently points at, downloading all of its
+    /// streams.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidRef`](crate::Error::InvalidRef) for names that aren't usable as
+    ///   references
+    /// - [`Error::NotFound`](crate::Error::NotFound) if a local or SSH repository doesn't have
+    ///   the reference
+    /// - Filesystem errors (Typically out of space)
+    /// - Network errors (Non-2xx codes, etc)
+    /// - Malformed manifests
+    pub async fn fetch_ref<C: Into<Option<CompressionKind>>>(
+        repo_url: &str,
+        name: &str,
+        store: &Store,
+        compression: C,
+    ) -> crate::Result<Tree> {
+        let mut validators = ManifestValidators::default();
+        Self::fetch_ref_if_modified(repo_url, name, store, compression, &mut validators).await
+    }
+
+    /// Fetches the tree the reference `name` points at like [`Tree::fetch_ref`], unless it
+    /// still points at the version `validators` describe, see [`Tree::fetch_if_modified`]. SSH
+    /// repositories have no validators, so the tree is always fetched from them.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::NotModified`](crate::Error::NotModified) if the reference didn't change
+    /// - See [`Tree::fetch_ref`]
+    pub async fn fetch_ref_if_modified<C: Into<Option<CompressionKind>>>(
+        repo_url: &str,
+        name: &str,
+        store: &Store,
+        compression: C,
+        validators: &mut ManifestValidators,
+    ) -> crate::Result<Tree> {
+        let compression = compression.into();
+        let object = ref_object(name)?;
+
+        let (tree, fetched) = match Location::parse(repo_url) {
+            Location::Local(root) => {
+                let path = root.join(&object);
+                let fetched = ManifestValidators {
+                    etag: path.metadata().ok().as_ref().and_then(net::etag),
+                    last_modified: None,
+                };
+                if fetched.etag.is_some() && fetched.etag == validators.etag {
+                    return Err(crate::Error::NotModified);
+                }
+                (read_local(&path).await?, fetched)
+            }
+            Location::Ssh(_) => (
+                read_ref(repo_url, &object).await?,
+                ManifestValidators::default(),
+            ),
+            Location::Http(url) => {
+                // Streams are downloaded as their entries arrive
+                let manifest_url = format!("{url}/{object}");
+                return Tree::fetch_if_modified(&manifest_url, url, store, compression, validators)
+                    .await;
+            }
+        };
+
+        tree.download(repo_url, store, compression).await?;
+        *validators = fetched;
+        Ok(tree)
+    }
+
+    /// Checks whether the reference `name` moved on from `current`, the deployed tree, only
+    /// fetching the manifest. The update's [stats](DiffStats) can be shown to users before
+    /// committing to downloading it.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidRef`](crate::Error::InvalidRef) for names that aren't usable as
+    ///   references
+    /// - [`Error::NotFound`](crate::Error::NotFound) if a local or SSH repository doesn't have
+    ///   the reference
+    /// - Network errors (Non-2xx codes, etc)
+    /// - Malformed manifests
+    pub async fn check_for_update(
+        repo_url: &str,
+        name: &str,
+        current: &Tree,
+    ) -> crate::Result<Option<Update>> {
+        let tree = read_ref(repo_url, &ref_object(name)?).await?;
+        if tree.hash() == current.hash() {
+            return Ok(None);
+        }
+
+        let stats = current.diff(&tree).stats();
+        Ok(Some(Update { tree, stats }))
+    }
+
+    /// Checks that each reference in `names` points at a valid tree whose streams the
+    /// repository has, in any of the kinds a download would try, see [`Stream::download`]. With
+    /// `sample`, only that many of each tree's streams are checked, spread across the tree.
+    ///
+    /// Broken references come with a [`Repair`]: pushing the tree again if `store` has
+    /// everything that's missing, and rolling the reference back otherwise. HTTP repositories
+    /// can't be listed, so the names to check are given.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidRef`](crate::Error::InvalidRef) for names that aren't usable as
+    ///   references
+    /// - Network and SSH errors, if the repository couldn't be asked
+    pub async fn check_refs<S: AsRef<str>, C: Into<Option<CompressionKind>>>(
+        repo_url: &str,
+        names: &[S],
+        store: &Store,
+        compression: C,
+        sample: Option<usize>,
+    ) -> crate::Result<Vec<RefCheck>> {
+        let compression = compression.into();
+        let mirrors = Mirrors::from(repo_url);
+        let mut checks = Vec::new();
+
+        for name in names {
+            let name = name.as_ref();
+            let tree = match read_ref(repo_url, &ref_object(name)?).await {
+                Ok(tree) => tree,
+                Err(e) if matches!(Fallthrough::classify(&e), Fallthrough::Unhealthy) => {
+                    return Err(e);
+                }
+                Err(e) => {
+                    checks.push(RefCheck {
+                        name: name.to_string(),
+                        tree: None,
+                        error: Some(e.to_string()),
+                        missing: Vec::new(),
+                        repair: Some(Repair::RollBack),
+                    });
+                    continue;
+                }
+            };
+
+            let mut streams: Vec<&Stream> = all_streams(&tree).collect();
+            streams.sort_by(|a, b| a.hash.cmp(&b.hash));
+            streams.dedup_by(|a, b| a.hash == b.hash);
+            if let Some(sample) = sample {
+                let step = streams.len().div_ceil(sample.max(1)).max(1);
+                streams = streams.into_iter().step_by(step).collect();
+            }
+
+            let mut missing = Vec::new();
+            let mut restorable = true;
+            for stream in streams {
+                if !mirrors.has_stream(stream, compression).await? {
+                    missing.push(stream.hash.to_string());
+                    let kind = compression
+                        .or(stream.compression)
+                        .unwrap_or(CompressionKind::None);
+                    restorable &= stream.push_source(store, kind).0.exists();
+                }
+            }
+
+            let repair = match (missing.is_empty(), restorable) {
+                (true, _) => None,
+                (false, true) => Some(Repair::Reupload),
+                (false, false) => Some(Repair::RollBack),
+            };
+            checks.push(RefCheck {
+                name: name.to_string(),
+                tree: Some(tree),
+                error: None,
+                missing,
+                repair,
+            });
+        }
+
+        Ok(checks)
+    }
+}
+
+/// Reads the manifest of a reference, without downloading any of its streams.
+async fn read_ref(repo_url: &str, object: &str) -> crate::Result<Tree> {
+    match Location::parse(repo_url) {
+        Location::Local(root) => read_local(&root.join(object)).await,
+        Location::Ssh(remote) => {
+            let (reader, child) = remote.read(object)?;
+            let res = Tree::read_manifest(reader).await;
+            // A failed remote command explains any error from reading its output
+            ssh::finish(child, object).await?;
+            res
+        }
+        Location::Http(url) => {
+            let res = net::default_client()
+                .get(format!("{url}/{object}"))
+                .send()
+                .await?
+                .error_for_status()?;
+            Tree::read_manifest(response_reader(res)).await
+        }
+    }
+}
+
+async fn read_local(path: &Path) -> crate::Result<Tree> {
+    if !path.exists() {
+        return Err(crate::Error::NotFound(path.display().to_string()));
+    }
+    Tree::read_manifest(fs::open_buffered(path).await?).await
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_ref_names() {
+        assert!(is_valid_ref("stable"));
+        assert!(is_valid_ref("myapp/stable"));
+        assert!(!is_valid_ref(""));
+        assert!(!is_valid_ref("myapp/"));
+        assert!(!is_valid_ref("/myapp"));
+        assert!(!is_valid_ref("myapp//stable"));
+        assert!(!is_valid_ref("../stable"));
+        assert!(!is_valid_ref("myapp/./stable"));
+    }
+
+    #[tokio::test]
+    async fn test_publish_and_fetch_ref() -> crate::Result<()> {
+        let repo_dir = TempDir::new()?;
+        let repo_url = repo_dir.path().to_str().unwrap();
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let local_dir = TempDir::new()?;
+        let original_dir = TempDir::new()?;
+
+        fs::write(original_dir.path().join("app"), b"v1").await?;
+        let v1 = Tree::create(&store, original_dir.path(), CompressionKind::Zstd).await?;
+        v1.publish(repo_url, "myapp/stable", &store, CompressionKind::Zstd)
+            .await?;
+
+        let local_store = Store::new(local_dir.path());
+        let fetched = Tree::fetch_ref(
+            repo_url,
+            "myapp/stable",
+            &local_store,
+            CompressionKind::Zstd,
+        )
+        .await?;
+        assert_eq!(fetched.hash(), v1.hash());
+        assert!(local_store.contains(&fetched.streams[0].hash));
+
+        // Publishing again moves the reference
+        fs::write(original_dir.path().join("app"), b"v2").await?;
+        let v2 = Tree::create(&store, original_dir.path(), CompressionKind::Zstd).await?;
+        v2.publish(repo_url, "myapp/stable", &store, CompressionKind::Zstd)
+            .await?;
+        let fetched = Tree::fetch_ref(
+            repo_url,
+            "myapp/stable",
+            &local_store,
+            CompressionKind::Zstd,
+        )
+        .await?;
+        assert_eq!(fetched.hash(), v2.hash());
+
+        assert!(matches!(
+            Tree::fetch_ref(repo_url, "myapp/beta", &local_store, CompressionKind::Zstd).await,
+            Err(crate::Error::NotFound(_))
+        ));
+        assert!(matches!(
+            v2.publish(repo_url, "../escape", &store, CompressionKind::Zstd)
+                .await,
+            Err(crate::Error::InvalidRef(_))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_ref_if_modified() -> crate::Result<()> {
+        let repo_dir = TempDir::new()?;
+        let repo_url = repo_dir.path().to_str().unwrap();
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let local_dir = TempDir::new()?;
+        let local_store = Store::new(local_dir.path());
+        let original_dir = TempDir::new()?;
+        let compression = CompressionKind::Zstd;
+        let mut validators = ManifestValidators::default();
+
+        fs::write(original_dir.path().join("app"), b"v1").await?;
+        let v1 = Tree::create(&store, original_dir.path(), compression).await?;
+        v1.publish(repo_url, "stable", &store, compression).await?;
+        let fetched = Tree::fetch_ref_if_modified(
+            repo_url,
+            "stable",
+            &local_store,
+            compression,
+            &mut validators,
+        )
+        .await?;
+        assert_eq!(fetched.hash(), v1.hash());
+        assert!(validators.etag.is_some());
+
+        assert!(matches!(
+            Tree::fetch_ref_if_modified(
+                repo_url,
+                "stable",
+                &local_store,
+                compression,
+                &mut validators
+            )
+            .await,
+            Err(crate::Error::NotModified)
+        ));
+
+        fs::write(original_dir.path().join("app"), b"v2").await?;
+        let v2 = Tree::create(&store, original_dir.path(), compression).await?;
+        v2.publish(repo_url, "stable", &store, compression).await?;
+        let fetched = Tree::fetch_ref_if_modified(
+            repo_url,
+            "stable",
+            &local_store,
+            compression,
+            &mut validators,
+        )
+        .await?;
+        assert_eq!(fetched.hash(), v2.hash());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_check_refs() -> crate::Result<()> {
+        let repo_dir = TempDir::new()?;
+        let repo_url = repo_dir.path().to_str().unwrap();
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let original_dir = TempDir::new()?;
+        let compression = CompressionKind::Zstd;
+
+        for i in 0..4 {
+            fs::write(original_dir.path().join(format!("file{i}")), format!("{i}")).await?;
+        }
+        let tree = Tree::create(&store, original_dir.path(), compression).await?;
+        tree.publish(repo_url, "stable", &store, compression)
+            .await?;
+        tree.publish(repo_url, "beta", &store, compression).await?;
+        fs::write(repo_dir.path().join("trees/garbage"), b"not a manifest").await?;
+
+        let names = ["stable", "absent", "garbage"];
+        let checks = Tree::check_refs(repo_url, &names, &store, compression, None).await?;
+        assert_eq!(checks[0].repair, None);
+        assert_eq!(checks[0].tree.as_ref().map(Tree::hash), Some(tree.hash()));
+        for check in &checks[1..] {
+            assert!(check.tree.is_none());
+            assert!(check.error.is_some());
+            assert_eq!(check.repair, Some(Repair::RollBack));
+        }
+
+        // A stream lost from the repository can be pushed again while the store has it
+        let lost = &tree.streams[0].hash;
+        let object = repo_dir.path().join(format!("streams/{lost}.zstd"));
+        std::fs::remove_file(&object)?;
+        let checks = Tree::check_refs(repo_url, &["beta"], &store, compression, None).await?;
+        assert_eq!(checks[0].missing, [lost.to_string()]);
+        assert_eq!(checks[0].repair, Some(Repair::Reupload));
+
+        let empty_dir = TempDir::new()?;
+        let empty = Store::new(empty_dir.path());
+        let checks = Tree::check_refs(repo_url, &["beta"], &empty, compression, None).await?;
+        assert_eq!(checks[0].repair, Some(Repair::RollBack));
+
+        // Sampling checks fewer streams
+        let checks = Tree::check_refs(repo_url, &["beta"], &store, compression, Some(2)).await?;
+        assert!(checks[0].missing.len() <= 1);
+
+        tree.push(repo_url, &store, compression).await?;
+        let checks = Tree::check_refs(repo_url, &["beta"], &store, compression, None).await?;
+        assert_eq!(checks[0].repair, None);
+
+        assert!(matches!(
+            Tree::check_refs(repo_url, &["../escape"], &store, compression, None).await,
+            Err(crate::Error::InvalidRef(_))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_check_for_update() -> crate::Result<()> {
+        let repo_dir = TempDir::new()?;
+        let repo_url = repo_dir.path().to_str().unwrap();
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let original_dir = TempDir::new()?;
+        let compression = CompressionKind::Zstd;
+
+        fs::write(original_dir.path().join("app"), b"v1").await?;
+        let v1 = Tree::create(&store, original_dir.path(), compression).await?;
+        v1.publish(repo_url, "stable", &store, compression).await?;
+        assert!(
+            Tree::check_for_update(repo_url, "stable", &v1)
+                .await?
+                .is_none()
+        );
+
+        fs::write(original_dir.path().join("app"), b"v2").await?;
+        fs::write(original_dir.path().join("data"), b"data").await?;
+        let v2 = Tree::create(&store, original_dir.path(), compression).await?;
+        v2.publish(repo_url, "stable", &store, compression).await?;
+        let update = Tree::check_for_update(repo_url, "stable", &v1)
+            .await?
+            .expect("the reference moved");
+        assert_eq!(update.tree.hash(), v2.hash());
+        assert_eq!(
+            update.stats,
+            DiffStats {
+                added: 1,
+                modified: 1,
+                ..DiffStats::default()
+            }
+        );
+
+        assert!(matches!(
+            Tree::check_for_update(repo_url, "beta", &v1).await,
+            Err(crate::Error::NotFound(_))
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+//! Downloads that carry on past streams that fail.
+//!
+//! [`Tree::download`] stops at the first stream no mirror could serve, leaving the caller to
+//! start over. [`Tree::download_with_report`] tries every stream instead, and lists what failed
+//! in a [`TreeReport`] that can be [retried](TreeReport::retry) on its own.
+use std::collections::HashSet;
+
+use crate::store::Store;
+use crate::stream::{ObjectHash, Stream};
+use crate::tree::{Tree, all_streams};
+use crate::{CompressionKind, Mirrors};
+
+/// A stream that couldn't be downloaded, and why.
+#[derive(Debug)]
+pub struct StreamFailure {
+    pub stream: Stream,
+    pub error: crate::Error,
+}
+
+/// What [`Tree::download_with_report`] downloaded, and what it couldn't.
+#[derive(Debug, Default)]
+pub struct TreeReport {
+    /// Streams that are now in the store, listed once however many files share them
+    pub downloaded: Vec<ObjectHash>,
+    pub failed: Vec<StreamFailure>,
+}
+
+impl TreeReport {
+    /// Whether every stream was downloaded.
+    #[must_use]
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    /// Downloads the streams that failed again, moving those that succeed to
+    /// [`downloaded`](Self::downloaded).
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors cleaning up the store before downloading
+    pub async fn retry<C: Into<Option<CompressionKind>>>(
+        &mut self,
+        mirrors: &Mirrors,
+        store: &Store,
+        compression: C,
+    ) -> crate::Result<()> {
+        store.clean_before_download()?;
+        let failed = std::mem::take(&mut self.failed);
+        self.download(
+            failed.into_iter().map(|failure| failure.stream),
+            mirrors,
+            store,
+            compression.into(),
+        )
+        .await;
+        Ok(())
+    }
+
+    pub(crate) async fn download<I: IntoIterator<Item = Stream>>(
+        &mut self,
+        streams: I,
+        mirrors: &Mirrors,
+        store: &Store,
+        compression: Option<CompressionKind>,
+    ) {
+        for stream in streams {
+            match stream.download_mirrored(mirrors, store, compression).await {
+                Ok(_) => self.downloaded.push(stream.hash),
+                Err(error) => self.failed.push(StreamFailure { stream, error }),
+            }
+        }
+    }
+}
+
+impl Tree {
+    /// Downloads all streams required to build the tree like [`Tree::download_mirrored`], but
+    /// carries on when a stream fails, reporting which did.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors cleaning up the store before downloading. Failures of single streams
+    ///   are in the report instead.
+    pub async fn download_with_report<C: Into<Option<CompressionKind>>>(
+        &self,
+        mirrors: &Mirrors,
+        store: &Store,
+        compression: C,
+    ) -> crate::Result<TreeReport> {
+        store.clean_before_download()?;
+        let mut seen = HashSet::new();
+        let streams = all_streams(self)
+            .filter(|stream| {
+                let first = seen.insert(&stream.hash);
+                if !first {
+                    store.metrics().deduped();
+                }
+                first
+            })
+            .cloned();
+
+        let mut report = TreeReport::default();
+        report
+            .download(streams, mirrors, store, compression.into())
+            .await;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::fs;
+
+    #[tokio::test]
+    async fn test_download_with_report() -> crate::Result<()> {
+        let compression = CompressionKind::Zstd;
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let repo_dir = TempDir::new()?;
+        let repo_url = repo_dir.path().to_str().unwrap();
+        let local_dir = TempDir::new()?;
+        let local_store = Store::new(local_dir.path());
+        let original_dir = TempDir::new()?;
+
+        fs::write(original_dir.path().join("a"), b"a").await?;
+        fs::write(original_dir.path().join("b"), b"b").await?;
+        fs::write(original_dir.path().join("copy of a"), b"a").await?;
+        let tree = Tree::create(&store, original_dir.path(), compression).await?;
+        let [a, b, _] = &tree.streams[..] else {
+            panic!("expected three streams");
+        };
+
+        // Only one of the streams made it to the repository
+        a.push(repo_url, &store, compression).await?;
+        let mirrors = Mirrors::from(repo_url);
+        let mut report = tree
+            .download_with_report(&mirrors, &local_store, compression)
+            .await?;
+        assert!(!report.is_complete());
+        assert_eq!(report.downloaded, std::slice::from_ref(&a.hash));
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].stream, *b);
+        assert!(matches!(report.failed[0].error, crate::Error::NotFound(_)));
+        assert!(local_store.contains(&a.hash));
+
+        b.push(repo_url, &store, compression).await?;
+        report.retry(&mirrors, &local_store, compression).await?;
+        assert!(report.is_complete());
+        assert_eq!(report.downloaded, [a.hash.clone(), b.hash.clone()]);
+        assert!(local_store.contains(&b.hash));
+
+        Ok(())
+    }
+}
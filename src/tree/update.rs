@@ -0,0 +1,246 @@
+//! Updating a deployment in place, from one tree to another.
+//!
+//! Only what [`Tree::diff`] reports is touched: moved files are renamed, changed ones replaced,
+//! and removed ones deleted, along with directories that are no longer in the tree once they are
+//! empty. Files that aren't in either tree are left alone.
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+
+use crate::store::Store;
+use crate::stream::Stream;
+use crate::tree::deploy::{Dir, FileMeta, name, parent};
+use crate::tree::deployment::Deployment;
+use crate::tree::diff::{Change, Node};
+use crate::tree::{Tree, TreePath, all_streams, check_deployable, name};
+
+/// Every stream by path, and every directory's permissions by path, including the root.
+fn walk(tree: &Tree) -> (HashMap<TreePath, &Stream>, BTreeMap<TreePath, u32>) {
+    let mut streams = HashMap::new();
+    let mut dirs = BTreeMap::new();
+    let mut pending = vec![(TreePath::root(), tree)];
+
+    while let Some((path, tree)) = pending.pop() {
+        for stream in &tree.streams {
+            streams.insert(
+                path.join(&TreePath::new_unchecked(&stream.file_name)),
+                stream,
+            );
+        }
+        for (name, subtree) in &tree.subtrees {
+            pending.push((path.join(name), subtree));
+        }
+        dirs.insert(path, tree.permissions);
+    }
+
+    (streams, dirs)
+}
+
+impl Tree {
+    /// Updates a deployment of `old` into this tree, only linking new and changed files, and
+    /// removing files that are no longer in the tree. Files whose mode changed are only
+    /// `chmod`ed, and retargeted symlinks replaced. Unlike [`Tree::deploy`], everything else
+    /// is left alone.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::DeploymentMismatch`](crate::Error::DeploymentMismatch) if `deploy_path` records
+    ///   a different tree than `old` as deployed
+    /// - [`Error::TreeNotAllowed`](crate::Error::TreeNotAllowed) if the store's
+    ///   [allowlist](Store::with_allowed_trees) doesn't include this tree
+    /// - [`Error::UnsafeSymlink`](crate::Error::UnsafeSymlink) if the store's
+    ///   [`SymlinkPolicy`](crate::store::SymlinkPolicy) refuses a symlink in this tree
+    /// - [`Error::InvalidPath`](crate::Error::InvalidPath) for names that could escape their
+    ///   directory, like `../a`, before anything is deployed
+    /// - [`Error::NonPortableName`](crate::Error::NonPortableName) if the store's
+    ///   [`NamePolicy`](crate::store::NamePolicy) refuses a name
+    /// - Out of storage/Permissions Errors
+    pub fn deploy_update(
+        &self,
+        old: &Tree,
+        store: &Store,
+        deploy_path: &Path,
+    ) -> crate::Result<()> {
+        let new = check_deployable(self, store)?;
+        let old_hash = old.hash();
+        if let Some(deployment) = Deployment::load(deploy_path)? {
+            if deployment.tree_hash != old_hash {
+                return Err(crate::Error::DeploymentMismatch(
+                    old_hash,
+                    deployment.tree_hash,
+                ));
+            }
+        }
+
+        // Names were sanitized the same way when `old` was deployed
+        let old = name::deployed(old, store.name_policy())?;
+        let store_dir = Dir::open(store.root())?;
+        let root = Dir::open(deploy_path)?;
+        let (_, old_dirs) = walk(&old);
+        let (new_streams, new_dirs) = walk(&new);
+        let diff = old.diff(&new);
+
+        // Moved files are staged in the root first, as their new directory may not exist yet
+        let mut staged = Vec::new();
+        for (i, change) in diff.changes().iter().enumerate() {
+            if let Change::Renamed { from, to, .. } = change {
+                let staging = OsString::from(format!(".syncstream-rename-{i}.tmp"));
+                root.open_path(parent(from))?
+                    .rename(name(from), &root, &staging)?;
+                staged.push((staging, to));
+            }
+        }
+
+        for change in diff.changes() {
+            if let Change::Removed { path, .. } = change {
+                root.open_path(parent(path))?.remove_file(name(path))?;
+            }
+        }
+
+        // Deepest first, so that parents are empty by the time they are reached
+        for path in old_dirs.keys().rev() {
+            if !path.is_root() && !new_dirs.contains_key(path) {
+                match root.open_path(parent(path)) {
+                    Ok(dir) => {
+                        dir.remove_empty_dir(name(path))?;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+
+        for path in new_dirs.keys() {
+            root.create_path(path)?;
+        }
+
+        for (staging, to) in staged {
+            root.rename(&staging, &root.open_path(parent(to))?, name(to))?;
+        }
+
+        for change in diff.changes() {
+            match change {
+                Change::Added { path, node }
+                | Change::Modified {
+                    path, new: node, ..
+                } => {
+                    let dir = root.open_path(parent(path))?;
+                    match node {
+                        Node::File { hash } => {
+                            let stream = new_streams.get(path);
+                            dir.deploy_stream(
+                                store,
+                                &store_dir,
+                                hash,
+                                name(path),
+                                stream.copied().map(FileMeta::from).unwrap_or_default(),
+                            )?;
+                        }
+                        Node::Symlink { target } => dir.symlink(target, name(path))?,
+                        Node::Special { kind, mode } => {
+                            dir.create_special(*kind, name(path), *mode)?;
+                        }
+                    }
+                }
+                // Metadata only, so the contents are left in place
+                Change::ModeChanged {
+                    path,
+                    new: Some(mode),
+                    ..
+                } => root
+                    .open_path(parent(path))?
+                    .set_file_mode(name(path), mode & store.mode_mask())?,
+                Change::Retargeted { path, new, .. } => {
+                    root.open_path(parent(path))?.symlink(new, name(path))?;
+                }
+                Change::ModeChanged { new: None, .. }
+                | Change::Removed { .. }
+                | Change::Renamed { .. } => {}
+            }
+        }
+
+        // Children before their parents, in case a parent is read-only
+        for (path, permissions) in new_dirs.iter().rev() {
+            if !path.is_root() && old_dirs.get(path) != Some(permissions) {
+                root.open_path(path)?.set_permissions(*permissions)?;
+            }
+        }
+
+        Deployment::new(self.hash(), store).write(&root)?;
+        store.record_access(all_streams(&new).map(|stream| stream.hash.as_str()))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt, symlink};
+    use temp_dir::TempDir;
+
+    use crate::CompressionKind;
+    use crate::fs;
+    use crate::store::Store;
+    use crate::tree::Tree;
+
+    #[tokio::test]
+    async fn test_deploy_update() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let original_dir = TempDir::new()?;
+        let deploy_dir = TempDir::new()?;
+        let original = |path: &str| original_dir.path().join(path);
+        let deployed = |path: &str| deploy_dir.path().join(path);
+
+        std::fs::create_dir_all(original("dir/gone"))?;
+        fs::write(original("same"), b"same").await?;
+        fs::write(original("changed"), b"old").await?;
+        fs::write(original("dir/moved"), b"moved").await?;
+        fs::write(original("dir/gone/removed"), b"removed").await?;
+        fs::write(original("chmod"), b"chmod").await?;
+        symlink("same", original("link"))?;
+        let old = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+
+        // Replace rather than overwrite, as the original is hardlinked into the store
+        std::fs::remove_file(original("changed"))?;
+        fs::write(original("changed"), b"new").await?;
+        std::fs::create_dir(original("new"))?;
+        std::fs::rename(original("dir/moved"), original("new/moved"))?;
+        std::fs::remove_dir_all(original("dir/gone"))?;
+        fs::write(original("new/added"), b"added").await?;
+        std::fs::remove_file(original("link"))?;
+        symlink("changed", original("link"))?;
+        std::fs::set_permissions(original("chmod"), std::fs::Permissions::from_mode(0o750))?;
+        let new = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+
+        old.deploy(&store, deploy_dir.path())?;
+
+        // Local changes to unchanged files and untracked files survive
+        std::fs::remove_file(deployed("same"))?;
+        fs::write(deployed("same"), b"local").await?;
+        fs::write(deployed("dir/untracked"), b"untracked").await?;
+        let chmod_inode = deployed("chmod").metadata()?.ino();
+
+        new.deploy_update(&old, &store, deploy_dir.path())?;
+
+        assert_eq!(fs::read_to_end(deployed("same")).await?, b"local");
+        assert_eq!(fs::read_to_end(deployed("changed")).await?, b"new");
+        assert_eq!(fs::read_to_end(deployed("new/moved")).await?, b"moved");
+        assert_eq!(fs::read_to_end(deployed("new/added")).await?, b"added");
+        assert_eq!(fs::read_to_end(deployed("link")).await?, b"new");
+        assert!(deployed("dir/untracked").exists());
+        assert!(!deployed("dir/moved").exists());
+        assert!(!deployed("dir/gone").exists());
+        // Mode changes don't relink
+        let chmod = deployed("chmod").metadata()?;
+        assert_eq!(chmod.ino(), chmod_inode);
+        assert_eq!(chmod.permissions().mode() & 0o777, 0o750);
+
+        // Updating from the wrong tree is refused
+        let res = new.deploy_update(&old, &store, deploy_dir.path());
+        assert!(matches!(res, Err(crate::Error::DeploymentMismatch(..))));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,286 @@
+//! Checking a deployment against its tree.
+//!
+//! Deployed files are ordinary files that anything can change. [`Tree::verify_deployed`] re-hashes
+//! them and compares permissions and symlink targets, to find manual edits or bit rot. Files the
+//! tree doesn't describe are left to [`Tree::extraneous`].
+use blake3::Hasher;
+use nix::errno::Errno;
+use nix::sys::stat::SFlag;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::tree::deploy::Dir;
+use crate::tree::{SpecialKind, Tree, TreePath};
+
+/// How a deployed path differs from the tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Drift {
+    /// Nothing is deployed at the path
+    Missing { path: TreePath },
+    /// Something of another type is deployed, like a directory instead of a file
+    WrongType { path: TreePath },
+    /// A file's contents don't match its stream
+    Modified {
+        path: TreePath,
+        expected: String,
+        actual: String,
+    },
+    /// A file, directory or special file has other permission bits
+    Permissions {
+        path: TreePath,
+        expected: u32,
+        actual: u32,
+    },
+    /// A symlink points somewhere else
+    SymlinkTarget {
+        path: TreePath,
+        expected: PathBuf,
+        actual: PathBuf,
+    },
+}
+
+/// What [`Tree::verify_deployed`] found.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DriftReport {
+    /// Files, directories, symlinks and special files that were checked
+    pub checked: usize,
+    pub drift: Vec<Drift>,
+}
+
+impl DriftReport {
+    /// Whether the deployment matches the tree.
+    #[must_use]
+    pub fn is_clean(&self) -> bool {
+        self.drift.is_empty()
+    }
+}
+
+fn file_type(mode: u32) -> SFlag {
+    SFlag::from_bits_truncate(mode) & SFlag::S_IFMT
+}
+
+impl Tree {
+    /// Compares what is deployed in `deploy_path` with the tree, re-hashing every file.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically permissions)
+    pub fn verify_deployed(&self, deploy_path: &Path) -> crate::Result<DriftReport> {
+        let mut report = DriftReport::default();
+        self.verify_into(&Dir::open(deploy_path)?, &TreePath::root(), &mut report)?;
+        Ok(report)
+    }
+
+    fn verify_into(&self, dir: &Dir, path: &TreePath, report: &mut DriftReport) -> io::Result<()> {
+        for (name, subtree) in &self.subtrees {
+            let sub_path = path.join(name);
+            report.checked += 1;
+
+            let subdir = match dir.open_path(name) {
+                Ok(subdir) => subdir,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    report.drift.push(Drift::Missing { path: sub_path });
+                    continue;
+                }
+                // Not a directory, or a symlink, which is never followed
+                Err(e)
+                    if [Errno::ENOTDIR, Errno::ELOOP]
+                        .iter()
+                        .any(|errno| e.raw_os_error() == Some(*errno as i32)) =>
+                {
+                    report.drift.push(Drift::WrongType { path: sub_path });
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            let actual = subdir.metadata()?.st_mode & 0o7777;
+            if actual != subtree.permissions & 0o7777 {
+                report.drift.push(Drift::Permissions {
+                    path: sub_path.clone(),
+                    expected: subtree.permissions & 0o7777,
+                    actual,
+                });
+            }
+
+            subtree.verify_into(&subdir, &sub_path, report)?;
+        }
+
+        for stream in &self.streams {
+            let stream_path = path.join(&TreePath::new_unchecked(&stream.file_name));
+            report.checked += 1;
+
+            let Some(stat) = dir.stat(&stream.file_name)? else {
+                report.drift.push(Drift::Missing { path: stream_path });
+                continue;
+            };
+            if file_type(stat.st_mode) != SFlag::S_IFREG {
+                report.drift.push(Drift::WrongType { path: stream_path });
+                continue;
+            }
+
+            let actual = stat.st_mode & 0o7777;
+            if let Some(expected) = stream.mode.map(|mode| mode & 0o7777) {
+                if actual != expected {
+                    report.drift.push(Drift::Permissions {
+                        path: stream_path.clone(),
+                        expected,
+                        actual,
+                    });
+                }
+            }
+
+            let mut hasher = Hasher::new();
+            io::copy(&mut dir.open_file(&stream.file_name)?, &mut hasher)?;
+            let actual = hasher.finalize().to_hex().to_string();
+            if actual != stream.hash {
+                report.drift.push(Drift::Modified {
+                    path: stream_path,
+                    expected: stream.hash.to_string(),
+                    actual,
+                });
+            }
+        }
+
+        for link in &self.symlinks {
+            let link_path = path.join(&TreePath::new_unchecked(&link.file_name));
+            report.checked += 1;
+
+            let Some(stat) = dir.stat(&link.file_name)? else {
+                report.drift.push(Drift::Missing { path: link_path });
+                continue;
+            };
+            if file_type(stat.st_mode) != SFlag::S_IFLNK {
+                report.drift.push(Drift::WrongType { path: link_path });
+                continue;
+            }
+
+            let actual = dir.read_link(&link.file_name)?;
+            if actual != link.target {
+                report.drift.push(Drift::SymlinkTarget {
+                    path: link_path,
+                    expected: link.target.clone(),
+                    actual,
+                });
+            }
+        }
+
+        self.verify_specials(dir, path, report)
+    }
+
+    fn verify_specials(
+        &self,
+        dir: &Dir,
+        path: &TreePath,
+        report: &mut DriftReport,
+    ) -> io::Result<()> {
+        for special in &self.specials {
+            let special_path = path.join(&TreePath::new_unchecked(&special.file_name));
+            report.checked += 1;
+
+            let Some(stat) = dir.stat(&special.file_name)? else {
+                report.drift.push(Drift::Missing { path: special_path });
+                continue;
+            };
+            let expected_type = match special.kind {
+                SpecialKind::Fifo => SFlag::S_IFIFO,
+            };
+            if file_type(stat.st_mode) != expected_type {
+                report.drift.push(Drift::WrongType { path: special_path });
+                continue;
+            }
+
+            let actual = stat.st_mode & 0o7777;
+            if actual != special.mode & 0o7777 {
+                report.drift.push(Drift::Permissions {
+                    path: special_path,
+                    expected: special.mode & 0o7777,
+                    actual,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{PermissionsExt, symlink};
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::CompressionKind;
+    use crate::fs;
+    use crate::store::Store;
+
+    #[tokio::test]
+    async fn test_verify_deployed() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let original_dir = TempDir::new()?;
+        let deploy_dir = TempDir::new()?;
+        let deployed = |path: &str| deploy_dir.path().join(path);
+        let path = |path: &str| TreePath::new(path).unwrap();
+
+        std::fs::create_dir(original_dir.path().join("dir"))?;
+        fs::write(original_dir.path().join("dir/file"), b"contents").await?;
+        fs::write(original_dir.path().join("tampered"), b"original").await?;
+        fs::write(original_dir.path().join("missing"), b"missing").await?;
+        symlink("dir/file", original_dir.path().join("link"))?;
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+        let tampered_hash = tree
+            .streams
+            .iter()
+            .find(|stream| stream.file_name == "tampered")
+            .map(|stream| stream.hash.clone())
+            .unwrap();
+
+        tree.deploy(&store, deploy_dir.path())?;
+        let report = tree.verify_deployed(deploy_dir.path())?;
+        assert!(report.is_clean());
+        assert_eq!(report.checked, 5);
+
+        // Replace rather than overwrite, as deployed files are hardlinked into the store
+        std::fs::remove_file(deployed("tampered"))?;
+        fs::write(deployed("tampered"), b"tampered").await?;
+        std::fs::remove_file(deployed("missing"))?;
+        std::fs::remove_file(deployed("link"))?;
+        symlink("elsewhere", deployed("link"))?;
+        std::fs::set_permissions(deployed("dir"), std::fs::Permissions::from_mode(0o700))?;
+
+        let mut drift = tree.verify_deployed(deploy_dir.path())?.drift;
+        drift.sort_by_key(|drift| format!("{drift:?}"));
+        let expected_dir = tree.subtrees[0].1.permissions & 0o7777;
+        let mut expected = vec![
+            Drift::Missing {
+                path: path("missing"),
+            },
+            Drift::Modified {
+                path: path("tampered"),
+                expected: tampered_hash.to_string(),
+                actual: blake3::hash(b"tampered").to_hex().to_string(),
+            },
+            Drift::Permissions {
+                path: path("dir"),
+                expected: expected_dir,
+                actual: 0o700,
+            },
+            Drift::SymlinkTarget {
+                path: path("link"),
+                expected: "dir/file".into(),
+                actual: "elsewhere".into(),
+            },
+        ];
+        expected.sort_by_key(|drift| format!("{drift:?}"));
+        assert_eq!(drift, expected);
+
+        // Something else in the place of a directory
+        std::fs::remove_dir_all(deployed("dir"))?;
+        fs::write(deployed("dir"), b"").await?;
+        let drift = tree.verify_deployed(deploy_dir.path())?.drift;
+        assert!(drift.contains(&Drift::WrongType { path: path("dir") }));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+//! Borrowed views over a manifest buffer.
+//!
+//! A [`TreeRef`] deserializes a manifest without copying names or hashes out of the buffer
+//! (unless they contain JSON escapes), which is much cheaper than building a full [`Tree`] when
+//! only a quick query is needed. Names that aren't valid UTF-8 can't be borrowed, so manifests
+//! with them only parse into a [`Tree`].
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use crate::CompressionKind;
+use crate::store::Store;
+use crate::stream::{ObjectHash, Owner, Stream};
+use crate::tree::manifest::{Entry, TreeAssembler, invalid};
+use crate::tree::meta::TreeMeta;
+use crate::tree::path::{is_valid_name, is_valid_path};
+use crate::tree::{SpecialFile, SpecialKind, Symlink, Tree, TreePath};
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct StreamRef<'a> {
+    #[serde(borrow)]
+    pub hash: Cow<'a, str>,
+    #[serde(borrow)]
+    pub file_name: Cow<'a, str>,
+    #[serde(default)]
+    pub compression: Option<CompressionKind>,
+    #[serde(default, borrow)]
+    pub dictionary: Option<Cow<'a, str>>,
+    #[serde(default)]
+    pub disk_size: Option<u64>,
+    #[serde(default)]
+    pub network_size: Option<u64>,
+    #[serde(default)]
+    pub mode: Option<u32>,
+    #[serde(default)]
+    pub mtime: Option<i64>,
+    #[serde(default)]
+    pub owner: Option<Owner>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SpecialRef<'a> {
+    #[serde(borrow)]
+    pub file_name: Cow<'a, str>,
+    pub kind: SpecialKind,
+    pub mode: u32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SymlinkRef<'a> {
+    #[serde(borrow)]
+    pub file_name: Cow<'a, str>,
+    #[serde(borrow)]
+    pub target: Cow<'a, str>,
+}
+
+/// A borrowed [`Entry`].
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EntryRef<'a> {
+    Tree {
+        #[serde(borrow)]
+        path: Cow<'a, str>,
+        permissions: u32,
+        #[serde(default)]
+        meta: Option<TreeMeta>,
+    },
+    Stream {
+        #[serde(borrow)]
+        parent: Cow<'a, str>,
+        #[serde(borrow)]
+        stream: StreamRef<'a>,
+    },
+    Symlink {
+        #[serde(borrow)]
+        parent: Cow<'a, str>,
+        #[serde(borrow)]
+        symlink: SymlinkRef<'a>,
+    },
+    Special {
+        #[serde(borrow)]
+        parent: Cow<'a, str>,
+        #[serde(borrow)]
+        special: SpecialRef<'a>,
+    },
+}
+
+impl EntryRef<'_> {
+    /// Checks the entry like [`TreePath::new`], without copying anything out of the buffer.
+    fn validate(&self) -> crate::Result<()> {
+        let (path, name) = match self {
+            EntryRef::Tree { path, meta, .. } if meta.is_some() && !path.is_empty() => {
+                return Err(invalid("only the root tree can have metadata"));
+            }
+            EntryRef::Tree { path, .. } => (path, None),
+            EntryRef::Stream { parent, stream } => (parent, Some(&stream.file_name)),
+            EntryRef::Symlink { parent, symlink } => (parent, Some(&symlink.file_name)),
+            EntryRef::Special { parent, special } => (parent, Some(&special.file_name)),
+        };
+
+        if !is_valid_path(Path::new(path.as_ref())) {
+            return Err(crate::Error::InvalidPath(path.as_ref().into()));
+        }
+        match name {
+            Some(name) if !is_valid_name(OsStr::new(name.as_ref())) => {
+                Err(crate::Error::InvalidPath(name.as_ref().into()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Copies the entry out of the manifest buffer.
+    ///
+    /// # Errors
+    ///
+    /// - Paths or file names that would escape the tree
+    pub fn to_entry(&self) -> crate::Result<Entry> {
+        let entry = match self {
+            EntryRef::Tree {
+                path,
+                permissions,
+                meta,
+            } => Entry::Tree {
+                path: TreePath::new(path.as_ref())?,
+                permissions: *permissions,
+                meta: meta.clone(),
+            },
+            EntryRef::Stream { parent, stream } => Entry::Stream {
+                parent: TreePath::new(parent.as_ref())?,
+                stream: Stream {
+                    hash: ObjectHash::new(stream.hash.as_ref())?,
+                    file_name: stream.file_name.as_ref().into(),
+                    compression: stream.compression,
+                    dictionary: stream
+                        .dictionary
+                        .as_deref()
+                        .map(ObjectHash::new)
+                        .transpose()?,
+                    disk_size: stream.disk_size,
+                    network_size: stream.network_size,
+                    #[cfg(unix)]
+                    mode: stream.mode,
+                    #[cfg(unix)]
+                    mtime: stream.mtime,
+                    #[cfg(unix)]
+                    owner: stream.owner,
+                },
+            },
+            EntryRef::Symlink { parent, symlink } => Entry::Symlink {
+                parent: TreePath::new(parent.as_ref())?,
+                symlink: Symlink {
+                    file_name: symlink.file_name.as_ref().into(),
+                    target: PathBuf::from(symlink.target.as_ref()),
+                },
+            },
+            EntryRef::Special { parent, special } => Entry::Special {
+                parent: TreePath::new(parent.as_ref())?,
+                special: SpecialFile {
+                    file_name: special.file_name.as_ref().into(),
+                    kind: special.kind,
+                    mode: special.mode,
+                },
+            },
+        };
+
+        entry.validate()?;
+        Ok(entry)
+    }
+}
+
+/// A read-only view of a manifest, borrowing from the buffer it was parsed from.
+#[derive(Clone, Debug)]
+pub struct TreeRef<'a> {
+    entries: Vec<EntryRef<'a>>,
+}
+
+impl<'a> TreeRef<'a> {
+    /// Parses a streaming manifest held entirely in memory.
+    ///
+    /// # Errors
+    ///
+    /// - Malformed manifests
+    /// - Paths or file names that would escape the tree
+    pub fn parse(manifest: &'a str) -> crate::Result<Self> {
+        let entries = manifest
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<EntryRef<'a>>, _>>()?;
+
+        for entry in &entries {
+            entry.validate()?;
+        }
+
+        match entries.first() {
+            Some(EntryRef::Tree { path, .. }) if path.is_empty() => Ok(Self { entries }),
+            Some(_) => Err(invalid("the first entry must be the root tree")),
+            None => Err(invalid("the manifest is empty")),
+        }
+    }
+
+    /// Every entry, in manifest order.
+    #[must_use]
+    pub fn entries(&self) -> &[EntryRef<'a>] {
+        &self.entries
+    }
+
+    /// Every stream, alongside the path of its parent directory.
+    pub fn streams(&self) -> impl Iterator<Item = (&str, &StreamRef<'a>)> {
+        self.entries.iter().filter_map(|entry| match entry {
+            EntryRef::Stream { parent, stream } => Some((parent.as_ref(), stream)),
+            _ => None,
+        })
+    }
+
+    /// The deduplicated set of stream hashes the tree needs.
+    #[must_use]
+    pub fn hashes(&self) -> HashSet<&str> {
+        self.streams()
+            .map(|(_, stream)| stream.hash.as_ref())
+            .collect()
+    }
+
+    /// The hashes which are not yet present in the store.
+    #[must_use]
+    pub fn missing_hashes(&self, store: &Store) -> HashSet<&str> {
+        self.hashes()
+            .into_iter()
+            .filter(|hash| !store.contains(hash))
+            .collect()
+    }
+
+    /// Materializes the full owned [`Tree`].
+    ///
+    /// # Errors
+    ///
+    /// - Malformed manifests
+    pub fn to_tree(&self) -> crate::Result<Tree> {
+        let mut assembler = TreeAssembler::default();
+        for entry in &self.entries {
+            assembler.push(entry.to_entry()?)?;
+        }
+        assembler.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::fs;
+
+    #[tokio::test]
+    async fn test_tree_ref() -> crate::Result<()> {
+        let stream_dir = TempDir::new()?;
+        let store = Store::new(stream_dir.path());
+        let original_dir = TempDir::new()?;
+
+        std::fs::create_dir_all(original_dir.path().join("a"))?;
+        fs::write(original_dir.path().join("file"), b"same").await?;
+        fs::write(original_dir.path().join("a/file"), b"same").await?;
+        fs::write(original_dir.path().join("a/other"), b"other").await?;
+
+        let tree = Tree::create(&store, original_dir.path(), crate::CompressionKind::None).await?;
+        let mut manifest = Vec::new();
+        tree.write_manifest(&mut manifest).await?;
+        let manifest = String::from_utf8(manifest).expect("manifests are UTF-8");
+
+        let view = TreeRef::parse(&manifest)?;
+        assert_eq!(view.streams().count(), 3);
+        assert_eq!(view.hashes().len(), 2);
+        assert!(view.missing_hashes(&store).is_empty());
+
+        // Names are borrowed straight from the manifest
+        let (_, stream) = view.streams().next().expect("tree has streams");
+        assert!(matches!(stream.hash, Cow::Borrowed(_)));
+
+        std::fs::remove_file(store.path_of(&stream.hash))?;
+        assert_eq!(view.missing_hashes(&store).len(), 1);
+
+        let mut reserialized = Vec::new();
+        view.to_tree()?.write_manifest(&mut reserialized).await?;
+        assert_eq!(manifest.as_bytes(), reserialized);
+
+        assert!(TreeRef::parse("").is_err());
+        assert!(matches!(
+            TreeRef::parse(&manifest.replacen("\"file\"", "\"../file\"", 1)),
+            Err(crate::Error::InvalidPath(_))
+        ));
+        assert!(TreeRef::parse("{\"type\":\"tree\",\"path\":\"a\",\"permissions\":0}").is_err());
+
+        Ok(())
+    }
+}
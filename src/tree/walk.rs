@@ -0,0 +1,259 @@
+//! Looking things up in a tree without recursing over `subtrees` by hand.
+use std::collections::HashSet;
+use std::io;
+use std::path::{Component, Path};
+
+use crate::store::Store;
+use crate::stream::Stream;
+use crate::tree::{SpecialFile, Symlink, Tree, TreePath};
+
+/// Something in a tree, borrowed from it.
+#[derive(Clone, Copy, Debug)]
+pub enum TreeEntry<'a> {
+    Dir(&'a Tree),
+    File(&'a Stream),
+    Symlink(&'a Symlink),
+    Special(&'a SpecialFile),
+}
+
+impl Tree {
+    /// The entry at `path`, with the empty path being this tree itself. Paths that could never
+    /// be in a tree, like ones containing `..`, are never found.
+    #[must_use]
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<TreeEntry<'_>> {
+        let mut components = path
+            .as_ref()
+            .components()
+            .filter(|component| !matches!(component, Component::CurDir))
+            .peekable();
+        let mut tree = self;
+
+        while let Some(component) = components.next() {
+            let Component::Normal(name) = component else {
+                return None;
+            };
+
+            if let Some((_, subtree)) = tree
+                .subtrees
+                .iter()
+                .find(|(subtree, _)| subtree.as_os_str() == name)
+            {
+                tree = subtree;
+                continue;
+            }
+
+            // Anything else can only be the last component
+            if components.peek().is_some() {
+                return None;
+            }
+            let stream = tree.streams.iter().find(|s| s.file_name == name);
+            let symlink = tree.symlinks.iter().find(|s| s.file_name == name);
+            let special = tree.specials.iter().find(|s| s.file_name == name);
+            return stream
+                .map(TreeEntry::File)
+                .or(symlink.map(TreeEntry::Symlink))
+                .or(special.map(TreeEntry::Special));
+        }
+
+        Some(TreeEntry::Dir(tree))
+    }
+
+    /// The directory at `path`, as a tree of its own. Downloading, planning or deploying it only
+    /// touches what's inside, so a large tree can be fetched a part at a time.
+    #[must_use]
+    pub fn subtree<P: AsRef<Path>>(&self, path: P) -> Option<&Tree> {
+        match self.get(path)? {
+            TreeEntry::Dir(tree) => Some(tree),
+            _ => None,
+        }
+    }
+
+    /// Every entry in the tree with its path, starting with the tree itself at the root. Each
+    /// directory comes right before its contents: its files, symlinks and special files, then
+    /// each subdirectory in turn.
+    pub fn walk(&self) -> impl Iterator<Item = (TreePath, TreeEntry<'_>)> {
+        let mut pending = vec![(TreePath::root(), TreeEntry::Dir(self))];
+
+        std::iter::from_fn(move || {
+            let (path, entry) = pending.pop()?;
+
+            if let TreeEntry::Dir(tree) = entry {
+                let child = |name: &std::ffi::OsStr| path.join(&TreePath::new_unchecked(name));
+                // Pushed in reverse, so they come out in order
+                for (name, subtree) in tree.subtrees.iter().rev() {
+                    pending.push((path.join(name), TreeEntry::Dir(subtree)));
+                }
+                for special in tree.specials.iter().rev() {
+                    pending.push((child(&special.file_name), TreeEntry::Special(special)));
+                }
+                for symlink in tree.symlinks.iter().rev() {
+                    pending.push((child(&symlink.file_name), TreeEntry::Symlink(symlink)));
+                }
+                for stream in tree.streams.iter().rev() {
+                    pending.push((child(&stream.file_name), TreeEntry::File(stream)));
+                }
+            }
+
+            Some((path, entry))
+        })
+    }
+
+    /// How many entries are below the tree's root, counting directories.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.walk().count() - 1
+    }
+
+    /// Whether the tree is an empty directory.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.streams.is_empty()
+            && self.subtrees.is_empty()
+            && self.symlinks.is_empty()
+            && self.specials.is_empty()
+    }
+
+    /// The combined size of every file once deployed, counting files that share a stream each
+    /// time. Sizes the streams didn't record are read from the store's copies.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically a stream missing from the store)
+    pub fn total_size(&self, store: &Store) -> io::Result<u64> {
+        let mut total = 0;
+        for (_, entry) in self.walk() {
+            if let TreeEntry::File(stream) = entry {
+                total += match stream.disk_size {
+                    Some(size) => size,
+                    None => store.path_of(&stream.hash).metadata()?.len(),
+                };
+            }
+        }
+        Ok(total)
+    }
+
+    /// How much downloading the whole tree fetches, counting streams shared by several files
+    /// once. `None` if any stream didn't record its [size](Stream::network_size), like those
+    /// in manifests written before it was.
+    ///
+    /// Streams are downloaded compressed the way they were created, so this is only exact when
+    /// the repository has them that way. Dictionaries aren't counted.
+    #[must_use]
+    pub fn total_download_size(&self) -> Option<u64> {
+        let mut seen = HashSet::new();
+        let mut total = 0;
+        for (_, entry) in self.walk() {
+            if let TreeEntry::File(stream) = entry {
+                let size = stream.network_size?;
+                if seen.insert(&stream.hash) {
+                    total += size;
+                }
+            }
+        }
+        Some(total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::CompressionKind;
+    use crate::fs;
+
+    #[tokio::test]
+    async fn test_tree_walk() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let original_dir = TempDir::new()?;
+        let original = |path: &str| original_dir.path().join(path);
+
+        std::fs::create_dir_all(original("a/b"))?;
+        std::fs::create_dir(original("c"))?;
+        fs::write(original("top"), b"top").await?;
+        fs::write(original("a/file"), b"file").await?;
+        fs::write(original("a/b/deep"), b"deeper").await?;
+        fs::write(original("c/same"), b"file").await?;
+        symlink("top", original("a/link"))?;
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+
+        let paths: Vec<String> = tree.walk().map(|(path, _)| path.to_string()).collect();
+        assert_eq!(
+            paths,
+            [
+                "", "top", "a", "a/file", "a/link", "a/b", "a/b/deep", "c", "c/same"
+            ]
+        );
+        assert_eq!(tree.len(), 8);
+        assert!(!tree.is_empty());
+
+        assert!(matches!(tree.get(""), Some(TreeEntry::Dir(_))));
+        assert!(matches!(tree.get("./a/b"), Some(TreeEntry::Dir(b)) if b.streams.len() == 1));
+        assert!(matches!(tree.get("a/b/deep"), Some(TreeEntry::File(s)) if s.file_name == "deep"));
+        assert!(matches!(tree.get("a/link"), Some(TreeEntry::Symlink(_))));
+        assert!(tree.get("a/link/inside").is_none());
+        assert!(tree.get("top/inside").is_none());
+        assert!(tree.get("a/../top").is_none());
+        assert!(tree.get("missing").is_none());
+
+        // Both files sharing a stream count, but it's only downloaded once
+        assert_eq!(tree.total_size(&store)?, 3 + 4 + 6 + 4);
+        assert_eq!(tree.total_download_size(), Some(3 + 4 + 6));
+
+        // Sizes are recorded in manifests, but older ones don't have them
+        let mut manifest = Vec::new();
+        tree.write_manifest(&mut manifest).await?;
+        let read = Tree::read_manifest(&manifest[..]).await?;
+        assert_eq!(read.total_download_size(), Some(3 + 4 + 6));
+        let mut unrecorded = read.clone();
+        unrecorded.streams[0].network_size = None;
+        unrecorded.streams[0].disk_size = None;
+        assert_eq!(unrecorded.total_download_size(), None);
+        assert_eq!(unrecorded.total_size(&store)?, 3 + 4 + 6 + 4);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_partial_download() -> crate::Result<()> {
+        let remote_dir = TempDir::new()?;
+        let remote_streams = remote_dir.path().join("streams");
+        std::fs::create_dir(&remote_streams)?;
+        let remote_store = Store::new(&remote_streams);
+        let local_dir = TempDir::new()?;
+        let local_store = Store::new(local_dir.path());
+        let deploy_dir = TempDir::new()?;
+        let original_dir = TempDir::new()?;
+        let original = |path: &str| original_dir.path().join(path);
+
+        std::fs::create_dir_all(original("assets/textures"))?;
+        std::fs::create_dir_all(original("assets/sounds"))?;
+        fs::write(original("assets/textures/wall"), b"wall").await?;
+        fs::write(original("assets/sounds/step"), b"step").await?;
+        let tree = Tree::create(&remote_store, original_dir.path(), CompressionKind::Zstd).await?;
+
+        let textures = tree.subtree("assets/textures").expect("is a directory");
+        assert!(tree.subtree("assets/textures/wall").is_none());
+        assert!(tree.subtree("missing").is_none());
+
+        textures
+            .download(
+                remote_dir.path().to_str().unwrap(),
+                &local_store,
+                CompressionKind::Zstd,
+            )
+            .await?;
+        textures.deploy(&local_store, deploy_dir.path())?;
+
+        assert_eq!(
+            fs::read_to_end(deploy_dir.path().join("wall")).await?,
+            b"wall"
+        );
+        // Nothing outside the subtree was fetched
+        assert!(!local_store.contains(&blake3::hash(b"step").to_hex()));
+
+        Ok(())
+    }
+}
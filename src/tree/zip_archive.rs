@@ -0,0 +1,230 @@
+//! Writing trees out as zip archives and reading them back in, for tools that only speak zip.
+//!
+//! Modes and symlinks are kept in the entries' Unix external attributes, which most unzip tools
+//! restore and Windows tools ignore. Zip times have no timezone, so mtimes aren't kept, and every
+//! entry is dated like the other side of a reproducible build: 1980-01-01.
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+
+use zip::result::ZipError;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, DateTime, ZipArchive, ZipWriter};
+
+use crate::CompressionKind;
+use crate::store::Store;
+use crate::stream::Stream;
+use crate::tree::builder::TreeBuilder;
+use crate::tree::walk::TreeEntry;
+use crate::tree::{Tree, check_deployable};
+
+const S_IFDIR: u32 = 0o40_000;
+const S_IFREG: u32 = 0o100_000;
+
+/// Permission bits of files and directories whose entries don't have any.
+const DEFAULT_FILE_MODE: u32 = 0o644;
+const DEFAULT_DIR_MODE: u32 = 0o755;
+
+/// Entries of at least this size need zip64 headers.
+const LARGE_FILE: u64 = u32::MAX as u64;
+
+impl Tree {
+    /// Writes the tree into `writer` as a zip archive, reading every file's contents from the
+    /// store. Names are checked and sanitized as they would be for a deploy.
+    ///
+    /// # Errors
+    ///
+    /// - Filesystem errors (Typically a stream missing from the store)
+    /// - Errors writing to `writer`
+    /// - [`io::ErrorKind::Unsupported`] for special files, which zip can't hold
+    /// - [`Error::NonPortableName`](crate::Error::NonPortableName) for names that aren't UTF-8
+    /// - See [`Tree::deploy`] for the checks on names and symlinks
+    pub fn export_zip<W: Write + Seek>(&self, writer: W, store: &Store) -> crate::Result<()> {
+        let tree = check_deployable(self, store)?;
+        let mut archive = ZipWriter::new(writer);
+        let options = SimpleFileOptions::default().last_modified_time(DateTime::DEFAULT);
+
+        for (path, entry) in tree.walk() {
+            if path.is_root() {
+                continue;
+            }
+            let name = path
+                .to_str()
+                .ok_or_else(|| crate::Error::NonPortableName(path.clone().into()))?;
+
+            match entry {
+                TreeEntry::Dir(tree) => {
+                    let options = options.unix_permissions(tree.permissions & 0o7777);
+                    archive.add_directory(name, options).map_err(zip_error)?;
+                }
+                TreeEntry::File(stream) => {
+                    let mut file = std::fs::File::open(store.path_of(&stream.hash))?;
+                    let mode = stream.mode.unwrap_or(DEFAULT_FILE_MODE) & store.mode_mask();
+                    let options = options
+                        .compression_method(CompressionMethod::Deflated)
+                        .unix_permissions(mode & 0o7777)
+                        .large_file(file.metadata()?.len() >= LARGE_FILE);
+                    archive.start_file(name, options).map_err(zip_error)?;
+                    io::copy(&mut file, &mut archive)?;
+                }
+                TreeEntry::Symlink(symlink) => {
+                    let target = symlink
+                        .target
+                        .to_str()
+                        .ok_or_else(|| crate::Error::NonPortableName(symlink.target.clone()))?;
+                    archive
+                        .add_symlink(name, target, options)
+                        .map_err(zip_error)?;
+                }
+                TreeEntry::Special(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!("zip archives can't hold special files: {path}"),
+                    )
+                    .into());
+                }
+            }
+        }
+
+        archive.finish().map_err(zip_error)?.flush()?;
+        Ok(())
+    }
+
+    /// Stores every file in the zip archive `reader` holds, returning the tree it describes.
+    /// Entries without Unix attributes, like ones made on Windows, get typical modes.
+    ///
+    /// # Errors
+    ///
+    /// - Out of storage/Permissions Errors
+    /// - [`Error::InvalidArchive`](crate::Error::InvalidArchive) for malformed or encrypted
+    ///   archives
+    /// - [`Error::InvalidPath`](crate::Error::InvalidPath) for names that would escape the tree
+    pub async fn import_zip<R: Read + Seek>(
+        store: &Store,
+        reader: R,
+        compression: CompressionKind,
+    ) -> crate::Result<Tree> {
+        let temp_dir = store.temp_path("import");
+        std::fs::create_dir(&temp_dir)?;
+        let res = import(store, reader, compression, &temp_dir).await;
+        std::fs::remove_dir_all(&temp_dir)?;
+        res
+    }
+}
+
+/// Imports the archive, unpacking files into `temp_dir` to store them.
+async fn import<R: Read + Seek>(
+    store: &Store,
+    reader: R,
+    compression: CompressionKind,
+    temp_dir: &Path,
+) -> crate::Result<Tree> {
+    let mut archive = ZipArchive::new(reader).map_err(zip_error)?;
+    let mut builder = TreeBuilder::new();
+
+    for index in 0..archive.len() {
+        let temp_path = temp_dir.join(index.to_string());
+        let (path, mode) = {
+            let mut entry = archive.by_index(index).map_err(zip_error)?;
+            let path = entry
+                .enclosed_name()
+                .ok_or_else(|| crate::Error::InvalidPath(entry.name().into()))?;
+            let mode = entry.unix_mode().map(|mode| mode & 0o7777);
+
+            if entry.is_dir() {
+                let mode = mode.unwrap_or(DEFAULT_DIR_MODE);
+                builder = builder.add_dir_with_mode(path, S_IFDIR | mode)?;
+                continue;
+            }
+            if entry.is_symlink() {
+                let mut target = String::new();
+                entry.read_to_string(&mut target)?;
+                builder = builder.add_symlink(path, target)?;
+                continue;
+            }
+
+            io::copy(&mut entry, &mut std::fs::File::create_new(&temp_path)?)?;
+            (path, mode)
+        };
+
+        let mut stream = Stream::create(&temp_path, store, compression).await?;
+        std::fs::remove_file(&temp_path)?;
+        // Nothing about the unpacked copy says anything about the original
+        stream.mode = Some(S_IFREG | mode.unwrap_or(DEFAULT_FILE_MODE));
+        stream.mtime = None;
+        stream.owner = None;
+        builder = builder.add_file(path, stream)?;
+    }
+
+    Ok(builder.build())
+}
+
+fn zip_error(e: ZipError) -> crate::Error {
+    match e {
+        ZipError::Io(e) => e.into(),
+        e => crate::Error::InvalidArchive(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::os::unix::fs::PermissionsExt;
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::fs;
+
+    #[tokio::test]
+    async fn test_zip_round_trip() -> crate::Result<()> {
+        let store_dir = TempDir::new()?;
+        let store = Store::new(store_dir.path());
+        let original_dir = TempDir::new()?;
+        let deploy_dir = TempDir::new()?;
+
+        std::fs::create_dir(original_dir.path().join("bin"))?;
+        fs::write(original_dir.path().join("bin/tool"), b"#!/bin/sh").await?;
+        std::fs::set_permissions(
+            original_dir.path().join("bin/tool"),
+            std::fs::Permissions::from_mode(0o755),
+        )?;
+        fs::write(original_dir.path().join("readme"), b"hello").await?;
+        std::os::unix::fs::symlink("bin/tool", original_dir.path().join("tool"))?;
+        let tree = Tree::create(&store, original_dir.path(), CompressionKind::None).await?;
+
+        let mut archive = Cursor::new(Vec::new());
+        tree.export_zip(&mut archive, &store)?;
+        archive.set_position(0);
+        let imported = Tree::import_zip(&store, archive, CompressionKind::Zstd).await?;
+
+        // The same files, modes and symlinks come back out
+        assert!(tree.diff(&imported).is_empty());
+        imported.deploy(&store, deploy_dir.path())?;
+        let tool = deploy_dir.path().join("bin/tool");
+        assert_eq!(std::fs::read(&tool)?, b"#!/bin/sh");
+        assert_eq!(
+            std::fs::metadata(&tool)?.permissions().mode() & 0o777,
+            0o755
+        );
+        assert_eq!(
+            std::fs::read_link(deploy_dir.path().join("tool"))?,
+            Path::new("bin/tool")
+        );
+
+        // Archives made without Unix attributes, with a name escaping the tree
+        let mut archive = ZipWriter::new(Cursor::new(Vec::new()));
+        archive
+            .start_file("../escape", SimpleFileOptions::default())
+            .map_err(zip_error)?;
+        let archive = archive.finish().map_err(zip_error)?;
+        assert!(matches!(
+            Tree::import_zip(&store, archive, CompressionKind::Zstd).await,
+            Err(crate::Error::InvalidPath(_))
+        ));
+        assert!(matches!(
+            Tree::import_zip(&store, Cursor::new(b"not a zip"), CompressionKind::Zstd).await,
+            Err(crate::Error::InvalidArchive(_))
+        ));
+
+        Ok(())
+    }
+}